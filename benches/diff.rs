@@ -0,0 +1,50 @@
+//! Throughput of [`Delta::diff`] on synthetic files, run with `cargo bench --bench diff`.
+//!
+//! Each case diffs a few MB against a base that is identical, differs in a single byte, or
+//! shares nothing with it, at several block sizes. Criterion reports the throughput in bytes
+//! of the new file per second.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use oxide_sync::cryptography::Delta;
+
+const FILE_LEN: usize = 4 * 1024 * 1024;
+const BLOCK_SIZES: [usize; 3] = [64, 512, 4096];
+
+/// Deterministic incompressible bytes, so runs compare across machines and changes.
+fn pseudo_random_bytes(len: usize, mut state: u64) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn diff(c: &mut Criterion) {
+    let base = pseudo_random_bytes(FILE_LEN, 1);
+    let mut one_byte = base.clone();
+    one_byte[FILE_LEN / 2] ^= 0xff;
+    let different = pseudo_random_bytes(FILE_LEN, 2);
+    let cases = [
+        ("identical", &base),
+        ("one byte changed", &one_byte),
+        ("fully different", &different),
+    ];
+
+    let mut group = c.benchmark_group("diff");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(FILE_LEN as u64));
+    for (name, new) in cases {
+        for block_size in BLOCK_SIZES {
+            group.bench_with_input(BenchmarkId::new(name, block_size), new, |b, new| {
+                b.iter(|| Delta::diff(&base, new, block_size))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, diff);
+criterion_main!(benches);
@@ -0,0 +1,339 @@
+//! Default options read from a config file, by default `~/.config/oxide_sync/config.toml`.
+//!
+//! The file is TOML, with keys named after the long flags and dashes written as underscores.
+//! Values that the flag parses from text, such as sizes and durations, are written the same
+//! way as on the command line:
+//!
+//! ```toml
+//! # Always recurse and skip build output
+//! recursive = true
+//! exclude = ["target", ".git"]
+//! max_size = "512M"
+//! port = 2222
+//! ```
+//!
+//! Unknown keys are an error. Options given on the command line always win over the file.
+
+use std::{
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::{ArgMatches, CommandFactory, FromArgMatches, ValueEnum, parser::ValueSource};
+use color_eyre::{Result, eyre::eyre};
+use directories::ProjectDirs;
+use serde::{Deserialize, Deserializer, de::Error as _};
+
+use super::{
+    Chmod, Chown, Chunker, Cli, Error, Ratio, parse_chmod, parse_chown, parse_duration,
+    parse_ratio, parse_size, parse_ssh_option,
+};
+
+pub const CONFIG_FILENAME: &str = "config.toml";
+
+/// `config.toml` in the platform config directory, e.g. `~/.config/oxide_sync` on Linux.
+pub fn default_config_path() -> Option<PathBuf> {
+    ProjectDirs::from("com", "oxide_sync", env!("CARGO_PKG_NAME"))
+        .map(|dirs| dirs.config_dir().join(CONFIG_FILENAME))
+}
+
+/// The options a config file may set, each named and typed after its flag in [`Cli`].
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub port: Option<u16>,
+    pub rsh: Option<String>,
+    #[serde(deserialize_with = "ssh_options")]
+    pub ssh_options: Option<Vec<String>>,
+    pub password_env: Option<String>,
+    pub exclude: Option<Vec<PathBuf>>,
+    pub exclude_from: Option<PathBuf>,
+    pub ignore_case: Option<bool>,
+    pub verbose: Option<bool>,
+    pub delete: Option<bool>,
+    pub existing: Option<bool>,
+    pub ignore_existing: Option<bool>,
+    pub update: Option<bool>,
+    pub append: Option<bool>,
+    pub fuzzy: Option<bool>,
+    pub modify_window: Option<u32>,
+    pub recursive: Option<bool>,
+    pub quiet: Option<bool>,
+    pub log_stderr: Option<bool>,
+    pub data_dir: Option<PathBuf>,
+    pub ignore_file: Option<PathBuf>,
+    pub no_ignore: Option<bool>,
+    #[serde(deserialize_with = "chunker")]
+    pub chunker: Option<Chunker>,
+    pub stop_on_error: Option<bool>,
+    #[serde(deserialize_with = "size")]
+    pub max_size: Option<u64>,
+    #[serde(deserialize_with = "size")]
+    pub min_size: Option<u64>,
+    #[serde(deserialize_with = "duration")]
+    pub exclude_older_than: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    pub exclude_newer_than: Option<Duration>,
+    pub hard_links: Option<bool>,
+    pub max_depth: Option<usize>,
+    pub sort: Option<bool>,
+    pub checksum: Option<bool>,
+    pub no_cache: Option<bool>,
+    pub numeric_ids: Option<bool>,
+    pub itemize_changes: Option<bool>,
+    pub devices: Option<bool>,
+    pub no_devices: Option<bool>,
+    pub archive: Option<bool>,
+    pub perms: Option<bool>,
+    pub no_perms: Option<bool>,
+    pub times: Option<bool>,
+    pub no_times: Option<bool>,
+    pub owner: Option<bool>,
+    pub no_owner: Option<bool>,
+    pub group: Option<bool>,
+    pub no_group: Option<bool>,
+    #[serde(deserialize_with = "chown")]
+    pub chown: Option<Chown>,
+    #[serde(deserialize_with = "chmod")]
+    pub chmod: Option<Chmod>,
+    pub sparse: Option<bool>,
+    pub reflink: Option<bool>,
+    pub relative: Option<bool>,
+    pub mkpath: Option<bool>,
+    pub no_mkpath: Option<bool>,
+    pub whole_file: Option<bool>,
+    pub no_whole_file: Option<bool>,
+    pub weak_only: Option<bool>,
+    #[serde(deserialize_with = "percent")]
+    pub delta_threshold: Option<u8>,
+    #[serde(deserialize_with = "ratio")]
+    pub min_transfer_ratio: Option<Ratio>,
+    pub pre_cmd: Option<String>,
+    pub post_cmd: Option<String>,
+    pub json: Option<bool>,
+    pub retries: Option<u32>,
+    #[serde(deserialize_with = "duration")]
+    pub retry_delay: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    pub heartbeat: Option<Duration>,
+    pub compress: Option<bool>,
+    pub temp_dir: Option<PathBuf>,
+    pub partial_dir: Option<PathBuf>,
+    pub backup: Option<bool>,
+    pub suffix: Option<String>,
+    pub backup_dir: Option<PathBuf>,
+    pub human_readable: Option<bool>,
+    pub stats: Option<bool>,
+    pub progress: Option<bool>,
+}
+
+impl Config {
+    /// Reads the config at `path`. A missing file is an empty config.
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(Self::parse(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(eyre!("Failed to read config {:?}: {}", path, e)),
+        }
+    }
+
+    pub fn parse(contents: &str) -> std::result::Result<Self, Error> {
+        toml::from_str(contents).map_err(|e| Error::Config {
+            line: e
+                .span()
+                .map_or(1, |span| contents[..span.start].matches('\n').count() + 1),
+            reason: e.message().to_string(),
+        })
+    }
+
+    /// Sets every option in the config that was not given on the command line.
+    pub fn apply(&self, cli: &mut Cli, matches: &ArgMatches) {
+        let command = Cli::command();
+        let given = |id: &str| {
+            // `--x` and `--no-x` override each other, so either one on the command line wins
+            let negation = match id.strip_prefix("no_") {
+                Some(flag) => flag.to_string(),
+                None => format!("no_{}", id),
+            };
+            [id, &negation].into_iter().any(|id| {
+                // Looking up an id clap does not know panics in debug builds
+                command.get_arguments().any(|arg| arg.get_id() == id)
+                    && matches.value_source(id) == Some(ValueSource::CommandLine)
+            })
+        };
+        macro_rules! merge {
+            ($($field:ident),* $(,)?) => {$(
+                if let Some(value) = &self.$field
+                    && !given(stringify!($field))
+                {
+                    cli.$field = value.clone().into();
+                }
+            )*};
+        }
+        merge!(
+            port,
+            rsh,
+            ssh_options,
+            password_env,
+            exclude,
+            exclude_from,
+            ignore_case,
+            verbose,
+            delete,
+            existing,
+            ignore_existing,
+            update,
+            append,
+            fuzzy,
+            modify_window,
+            recursive,
+            quiet,
+            log_stderr,
+            data_dir,
+            ignore_file,
+            no_ignore,
+            chunker,
+            stop_on_error,
+            max_size,
+            min_size,
+            exclude_older_than,
+            exclude_newer_than,
+            hard_links,
+            max_depth,
+            sort,
+            checksum,
+            no_cache,
+            numeric_ids,
+            itemize_changes,
+            devices,
+            no_devices,
+            archive,
+            perms,
+            no_perms,
+            times,
+            no_times,
+            owner,
+            no_owner,
+            group,
+            no_group,
+            chown,
+            chmod,
+            sparse,
+            reflink,
+            relative,
+            mkpath,
+            no_mkpath,
+            whole_file,
+            no_whole_file,
+            weak_only,
+            delta_threshold,
+            min_transfer_ratio,
+            pre_cmd,
+            post_cmd,
+            json,
+            retries,
+            retry_delay,
+            heartbeat,
+            compress,
+            temp_dir,
+            partial_dir,
+            backup,
+            suffix,
+            backup_dir,
+            human_readable,
+            stats,
+            progress,
+        );
+    }
+}
+
+impl Cli {
+    /// Parses the command line and fills in the options it leaves unset from the config file.
+    /// Invalid arguments, `--help` and `--version` are returned as a [`clap::Error`] to print.
+    pub fn parse_with_config() -> Result<Self> {
+        Self::from_matches_with_config(&Self::command().try_get_matches()?)
+    }
+
+    /// Builds the CLI from `matches`, then applies the config named by `--config`, or the
+    /// default one unless `--no-config` was given.
+    pub fn from_matches_with_config(matches: &ArgMatches) -> Result<Self> {
+        let mut cli = Self::from_arg_matches(matches)?;
+        let path = match &cli.config {
+            Some(path) => Some(path.clone()),
+            None if cli.no_config => None,
+            None => default_config_path(),
+        };
+        if let Some(path) = path {
+            Config::load(&path)?.apply(&mut cli, matches);
+        }
+        cli.apply_command();
+        Ok(cli)
+    }
+}
+
+/// A string or a bare number, as the text its flag would have been given.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Text {
+    Number(u64),
+    String(String),
+}
+
+/// Deserializes an option written as its flag's text, with the flag's `parse`.
+fn parsed<'de, D, T, E>(
+    deserializer: D,
+    parse: impl FnOnce(&str) -> std::result::Result<T, E>,
+) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    E: Display,
+{
+    let text = match Text::deserialize(deserializer)? {
+        Text::Number(n) => n.to_string(),
+        Text::String(s) => s,
+    };
+    parse(&text).map(Some).map_err(D::Error::custom)
+}
+
+fn size<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Option<u64>, D::Error> {
+    parsed(d, parse_size)
+}
+
+fn duration<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Option<Duration>, D::Error> {
+    parsed(d, parse_duration)
+}
+
+fn chown<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Option<Chown>, D::Error> {
+    parsed(d, parse_chown)
+}
+
+fn chmod<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Option<Chmod>, D::Error> {
+    parsed(d, parse_chmod)
+}
+
+fn ratio<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Option<Ratio>, D::Error> {
+    parsed(d, parse_ratio)
+}
+
+fn chunker<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Option<Chunker>, D::Error> {
+    parsed(d, |s| Chunker::from_str(s, true))
+}
+
+fn percent<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Option<u8>, D::Error> {
+    parsed(d, |s| match s.parse() {
+        Ok(percent @ 0..=100) => Ok(percent),
+        _ => Err(format!("{} is not a percentage between 0 and 100", s)),
+    })
+}
+
+fn ssh_options<'de, D: Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<Option<Vec<String>>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|option| parse_ssh_option(option).map_err(D::Error::custom))
+        .collect::<std::result::Result<_, _>>()
+        .map(Some)
+}
@@ -1,51 +1,935 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use color_eyre::{Result, eyre::eyre};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+mod config;
+#[cfg(test)]
+mod tests;
+
+pub use config::*;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("No path given on the server side")]
+    EmptyRemotePath,
+    #[error("--delete requires --recursive")]
+    DeleteWithoutRecursive,
+    #[error("--verify does not transfer anything, so it cannot be combined with --{0}")]
+    VerifyWith(&'static str),
+    #[error("--existing and --ignore-existing together would skip every file")]
+    ExistingAndIgnoreExisting,
+    #[error("--weak-only skips the strong checks that --checksum asks for")]
+    WeakOnlyAndChecksum,
+    #[error("--partial-dir {0:?} must be a relative path below the destination, without . or ..")]
+    PartialDir(PathBuf),
+    #[error("--min-size ({min}) is larger than --max-size ({max})")]
+    SizeRange { min: u64, max: u64 },
+    #[error(
+        "--exclude-older-than ({older:?}) must be longer than --exclude-newer-than ({newer:?})"
+    )]
+    AgeRange { older: Duration, newer: Duration },
+    #[error("Destination {destination:?} is the same as or inside the source {from:?}")]
+    DestinationInsideSource { from: PathBuf, destination: PathBuf },
+    #[error("Config line {line}: {reason}")]
+    Config { line: usize, reason: String },
+    #[error("At most one of the source and destination may be remote (user@host:path)")]
+    BothRemote,
+    #[error("No path given after {username}@{host}: (use `.` for the remote home directory)")]
+    NoPathAfterHost { username: String, host: String },
+    #[error("Listing without a destination needs a remote source (user@host:path)")]
+    ListWithoutRemote,
+    #[error("Password variable {0} is not set")]
+    PasswordVarUnset(String),
+    #[error("--chown: no user named {0:?} on this host")]
+    UnknownUser(String),
+    #[error("--chown: no group named {0:?} on this host")]
+    UnknownGroup(String),
+}
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true,
+    after_help = "Exit codes: 0 success, 1 some files failed or differ, 2 connection, protocol or I/O error, 3 usage error"
+)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     #[arg(short, long, default_value_t = false)]
     pub server: bool,
-    #[arg(required_if_eq("server", "false"), required = false)]
+    #[arg(required_unless_present("server"))]
     pub from: Option<PathBuf>,
-    #[arg(required_if_eq("server", "false"), required = false)]
+    /// Omitted with --read-batch, where the only path is the destination, and with
+    /// --list-only, where the only path is the remote one
+    #[arg(required_unless_present_any(["server", "read_batch", "list_only"]))]
     pub to: Option<PathBuf>,
-    #[arg(short, long, default_value_t = 22)]
-    pub port: u16,
+    /// Port to connect to, instead of the one ssh is configured with
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// Remote shell to connect with instead of `ssh`, e.g. "ssh -F ~/.ssh/backup_config".
+    /// Login banners on stdout are skipped; "ssh -T" also keeps a terminal from being set up
+    #[arg(short = 'e', long, value_name = "COMMAND")]
+    pub rsh: Option<String>,
+    /// Passed to ssh as `-o KEY=VALUE`, e.g. ControlMaster=auto; may be repeated
+    #[arg(long = "ssh-option", value_name = "KEY=VALUE", value_parser = parse_ssh_option)]
+    pub ssh_options: Vec<String>,
+    /// Authenticate with the password stored in this environment variable (requires `sshpass`)
+    #[arg(long, value_name = "VAR")]
+    pub password_env: Option<String>,
     #[arg(long)]
     pub exclude: Option<Vec<PathBuf>>,
+    /// Read additional exclude patterns from this file, one per line
+    #[arg(long)]
+    pub exclude_from: Option<PathBuf>,
+    /// Match --exclude patterns regardless of case. Stored filenames keep their case
+    #[arg(long)]
+    pub ignore_case: bool,
     #[arg(long)]
     pub dry_run: bool,
     #[arg(short, long, default_value_t = false)]
     pub verbose: bool,
     #[arg(short, long, default_value_t = false)]
     pub delete: bool,
+    /// Only update files that already exist on the destination, never create new ones
+    #[arg(long, default_value_t = false)]
+    pub existing: bool,
+    /// Only create files missing on the destination, never update existing ones
+    #[arg(long, default_value_t = false, conflicts_with = "existing")]
+    pub ignore_existing: bool,
+    /// Skip files that are newer on the destination than on the source
+    #[arg(short = 'u', long, default_value_t = false)]
+    pub update: bool,
+    /// Assume files on the destination only grew since they were last synced, e.g. logs: keep
+    /// what each already holds and only compare what lies past it. A copy that changed
+    /// otherwise fails its checksum and is sent whole
+    #[arg(long, default_value_t = false)]
+    pub append: bool,
+    /// When a destination file is missing, diff against a similar file next to it, such as the
+    /// same file under its old name
+    #[arg(short = 'y', long, default_value_t = false)]
+    pub fuzzy: bool,
+    /// Treat modification times this many seconds apart as equal, e.g. for FAT's 2s resolution
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    pub modify_window: u32,
     #[arg(short, long, default_value_t = false)]
     pub recursive: bool,
+    /// Print only errors, and write no log file
     #[arg(long, default_value_t = false)]
     pub quiet: bool,
+    /// Also write logs to stderr
+    #[arg(long, default_value_t = false)]
+    pub log_stderr: bool,
+    /// Read default options from this file instead of `~/.config/oxide_sync/config.toml`
+    #[arg(long, conflicts_with = "no_config")]
+    pub config: Option<PathBuf>,
+    /// Ignore the config file
+    #[arg(long, default_value_t = false)]
+    pub no_config: bool,
+    /// Directory for logs and other local state, overriding `OXIDE_SYNC_DATA`
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+    /// Additional gitignore-style file applied to the recursive walk
+    #[arg(long)]
+    pub ignore_file: Option<PathBuf>,
+    /// Do not honor `.oxideignore`/`.gitignore` files found in the sync root
+    #[arg(long, default_value_t = false)]
+    pub no_ignore: bool,
+    /// How files are split into blocks for matching
+    #[arg(long, value_enum, default_value_t = Chunker::Fixed)]
+    pub chunker: Chunker,
+    /// Abort the whole run on the first file that fails to transfer
+    #[arg(long, default_value_t = false)]
+    pub stop_on_error: bool,
+    /// Skip files larger than this size (e.g. `10M`, `512K`)
+    #[arg(long, value_parser = parse_size)]
+    pub max_size: Option<u64>,
+    /// Skip files smaller than this size (e.g. `10M`, `512K`)
+    #[arg(long, value_parser = parse_size)]
+    pub min_size: Option<u64>,
+    /// Skip files last modified longer ago than this (e.g. `7d`, `24h`)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub exclude_older_than: Option<Duration>,
+    /// Skip files last modified more recently than this (e.g. `7d`, `24h`)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub exclude_newer_than: Option<Duration>,
+    /// Compare the destination against the source without transferring anything
+    #[arg(long, default_value_t = false)]
+    pub verify: bool,
+    /// Recreate hardlinks between source files instead of transferring each copy
+    #[arg(short = 'H', long, default_value_t = false)]
+    pub hard_links: bool,
+    /// Descend at most this many directories below the source root, like `find -maxdepth`
+    #[arg(long, value_name = "N")]
+    pub max_depth: Option<usize>,
+    /// Send files sorted by path, so every run over the same tree lists them in the same order
+    #[arg(long, default_value_t = false)]
+    pub sort: bool,
+    /// Skip files whose size and strong checksum already match on the receiver, whatever their
+    /// mtime; costs a full read of every file on both sides
+    #[arg(short = 'c', long, default_value_t = false)]
+    pub checksum: bool,
+    /// Transfer only the paths listed in this file (`-` for stdin), relative to the source root
+    #[arg(long)]
+    pub files_from: Option<PathBuf>,
+    /// Recompute signatures of base files instead of reusing cached ones
+    #[arg(long, default_value_t = false)]
+    pub no_cache: bool,
+    /// Apply uids/gids as-is instead of mapping them by user and group name
+    #[arg(long, default_value_t = false)]
+    pub numeric_ids: bool,
+    /// Print a change summary line for every transferred file
+    #[arg(short, long, default_value_t = false)]
+    pub itemize_changes: bool,
+    /// Recreate device and FIFO nodes instead of skipping them
+    #[arg(short = 'D', long, overrides_with = "no_devices")]
+    pub devices: bool,
+    /// Skip device and FIFO nodes, even with --archive
+    #[arg(long, overrides_with = "devices")]
+    pub no_devices: bool,
+    /// Archive mode: recursive, preserving permissions, times, owner, group and special files
+    #[arg(short, long, default_value_t = false)]
+    pub archive: bool,
+    /// Preserve permissions
+    #[arg(long, overrides_with = "no_perms")]
+    pub perms: bool,
+    /// Do not preserve permissions, even with --archive
+    #[arg(long, overrides_with = "perms")]
+    pub no_perms: bool,
+    /// Preserve modification times
+    #[arg(short, long, overrides_with = "no_times")]
+    pub times: bool,
+    /// Do not preserve modification times, even with --archive
+    #[arg(long, overrides_with = "times")]
+    pub no_times: bool,
+    /// Preserve the owner (requires root on the receiving side)
+    #[arg(short, long, overrides_with = "no_owner")]
+    pub owner: bool,
+    /// Do not preserve the owner, even with --archive
+    #[arg(long, overrides_with = "owner")]
+    pub no_owner: bool,
+    /// Preserve the group
+    #[arg(short, long, overrides_with = "no_group")]
+    pub group: bool,
+    /// Do not preserve the group, even with --archive
+    #[arg(long, overrides_with = "group")]
+    pub no_group: bool,
+    /// Give every received file this owner and/or group, e.g. `www:www`, `:staff` or `1000`,
+    /// whatever the source's ids are
+    #[arg(long, value_name = "USER:GROUP", value_parser = parse_chown)]
+    pub chown: Option<Chown>,
+    /// Change the permissions of received files and their directories, e.g. `D755,F644` or
+    /// `go-w`; rules starting with `D` or `F` only apply to directories or files
+    #[arg(long, value_name = "SPEC", value_parser = parse_chmod)]
+    pub chmod: Option<Chmod>,
+    /// Leave long runs of zeros in received files as holes
+    #[arg(short = 'S', long, default_value_t = false)]
+    pub sparse: bool,
+    /// Copy unchanged blocks from the old version of a file in the kernel, sharing their
+    /// extents on filesystems with reflinks (Btrfs, XFS)
+    #[arg(long, default_value_t = false)]
+    pub reflink: bool,
+    /// Keep the source path's leading directories in the destination, e.g. `a/b/c.txt` is
+    /// written to `<dest>/a/b/c.txt` instead of `<dest>/c.txt`
+    #[arg(short = 'R', long, default_value_t = false)]
+    pub relative: bool,
+    /// Create the destination with any missing parent directories, like `mkdir -p`. On by
+    /// default with --recursive; otherwise only the destination itself may be missing
+    #[arg(long, overrides_with = "no_mkpath")]
+    pub mkpath: bool,
+    /// Fail instead of creating missing parents of the destination, even with --recursive
+    #[arg(long, overrides_with = "mkpath")]
+    pub no_mkpath: bool,
+    /// Send files whole instead of as deltas against the destination's copy. The default when
+    /// both ends are local
+    #[arg(short = 'W', long, overrides_with = "no_whole_file")]
+    pub whole_file: bool,
+    /// Use deltas even when both ends are local
+    #[arg(long, overrides_with = "whole_file")]
+    pub no_whole_file: bool,
+    /// Trust rolling-hash matches without confirming them with the strong hash. UNSAFE: a hash
+    /// collision silently corrupts the destination file
+    #[arg(long, default_value_t = false, conflicts_with = "checksum")]
+    pub weak_only: bool,
+    /// Send a file whole when its delta reuses less than this percentage of it
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub delta_threshold: Option<u8>,
+    /// Send a file whole when its delta would save less than this fraction (0.0-1.0) of its size
+    #[arg(long, value_name = "RATIO", value_parser = parse_ratio)]
+    pub min_transfer_ratio: Option<Ratio>,
+    /// Shell command run locally before connecting; a non-zero exit aborts the sync
+    #[arg(long, value_name = "COMMAND")]
+    pub pre_cmd: Option<String>,
+    /// Shell command run locally after the sync, even if it failed. The outcome is passed in
+    /// `OXIDE_SYNC_EXIT_STATUS` (`0` on success)
+    #[arg(long, value_name = "COMMAND")]
+    pub post_cmd: Option<String>,
+    /// List the remote files instead of transferring anything
+    #[arg(long, default_value_t = false, conflicts_with = "verify")]
+    pub list_only: bool,
+    /// Print the --list-only listing as JSON, one object per line
+    #[arg(long, default_value_t = false, requires = "list_only")]
+    pub json: bool,
+    /// Reconnect up to this many times when the connection drops and start the sync over;
+    /// files that already made it match their basis and go out as block references
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub retries: u32,
+    /// Wait before the first reconnect, doubled for each one after it (e.g. `500ms`, `2s`)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "1s")]
+    pub retry_delay: Duration,
+    /// Ping the remote side after this long without traffic, so idle connections aren't
+    /// dropped; `0` turns it off
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "30s")]
+    pub heartbeat: Duration,
+    /// Compress the connection with the best codec both ends support (zstd, then gzip). If the
+    /// remote side supports neither, only the literal data of each delta is compressed
+    #[arg(short = 'z', long, default_value_t = false)]
+    pub compress: bool,
+    /// Rebuild files in this directory instead of next to their destination
+    #[arg(short = 'T', long, value_name = "DIR")]
+    pub temp_dir: Option<PathBuf>,
+    /// Rebuild files at the same relative path below this directory, relative to the
+    /// destination, and keep them there if interrupted so the next run resumes from them. It is
+    /// never part of the file list
+    #[arg(long, value_name = "DIR", conflicts_with = "temp_dir")]
+    pub partial_dir: Option<PathBuf>,
+    /// Keep the previous version of every replaced file, see --suffix and --backup-dir
+    #[arg(short = 'b', long, default_value_t = false)]
+    pub backup: bool,
+    /// Appended to backup names; "~" unless --backup-dir is given
+    #[arg(long, value_name = "SUFFIX")]
+    pub suffix: Option<String>,
+    /// Keep backups in this tree, relative to the destination unless absolute. Implies --backup
+    #[arg(long, value_name = "DIR")]
+    pub backup_dir: Option<PathBuf>,
+    /// Print sizes in the listing and statistics with binary suffixes (KiB, MiB, GiB)
+    #[arg(long, default_value_t = false)]
+    pub human_readable: bool,
+    /// Print how many blocks of each transferred file were reused and how many were sent
+    #[arg(long, default_value_t = false)]
+    pub stats: bool,
+    /// Print each file as its transfer starts, and the bytes sent or received for it so far
+    #[arg(long, default_value_t = false)]
+    pub progress: bool,
+    /// Also record the deltas of this sync to a batch file, for --read-batch
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["list_only", "verify"])]
+    pub write_batch: Option<PathBuf>,
+    /// Apply a batch written by --write-batch to the directory given as the only path, without
+    /// connecting to anything
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["write_batch", "server"])]
+    pub read_batch: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// List the files at a remote path, the same as `--list-only user@host:path`
+    List {
+        /// The remote path to list, as user@host:path
+        remote: PathBuf,
+        /// Print the listing as JSON, one object per line
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+}
+
+impl Cli {
+    /// Turns a subcommand into the equivalent flags and paths, so the rest of the client only
+    /// has to look at those.
+    pub fn apply_command(&mut self) {
+        match self.command.take() {
+            Some(Command::List { remote, json }) => {
+                self.list_only = true;
+                self.json |= json;
+                self.from = Some(remote);
+                self.to = None;
+            }
+            None => {}
+        }
+    }
+}
+
+/// A fraction between 0.0 and 1.0, see [`parse_ratio`].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, PartialOrd)]
+pub struct Ratio(f64);
+
+/// Never NaN, since the only way to build one is through [`Ratio::new`].
+impl Eq for Ratio {}
+
+impl Ratio {
+    /// `None` unless `value` is between 0.0 and 1.0.
+    pub fn new(value: f64) -> Option<Self> {
+        (0.0..=1.0).contains(&value).then_some(Self(value))
+    }
+
+    pub fn get(self) -> f64 {
+        self.0
+    }
+}
+
+/// The owner forced by `--chown`. Names are looked up on the receiving host, see
+/// [`Chown::resolve`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct Chown {
+    pub user: Option<String>,
+    pub group: Option<String>,
+    /// Filled in by [`Chown::resolve`]
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Chown {
+    /// Looks up the user and group, which may also be given as numeric ids, on this host.
+    pub fn resolve(&mut self) -> std::result::Result<(), Error> {
+        if let Some(user) = &self.user {
+            self.uid = Some(
+                user.parse()
+                    .ok()
+                    .or_else(|| crate::flist::uid_by_name(user))
+                    .ok_or_else(|| Error::UnknownUser(user.clone()))?,
+            );
+        }
+        if let Some(group) = &self.group {
+            self.gid = Some(
+                group
+                    .parse()
+                    .ok()
+                    .or_else(|| crate::flist::gid_by_name(group))
+                    .ok_or_else(|| Error::UnknownGroup(group.clone()))?,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The permission changes forced by `--chmod`, applied in order.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct Chmod {
+    pub rules: Vec<ChmodRule>,
+}
+
+/// One comma-separated rule of a [`Chmod`]: clears the `clear` permission bits, then sets
+/// the `set` ones.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChmodRule {
+    pub applies_to: ChmodTarget,
+    pub clear: u32,
+    pub set: u32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ChmodTarget {
+    All,
+    /// Rules prefixed with `D`
+    Dirs,
+    /// Rules prefixed with `F`
+    Files,
+}
+
+impl Chmod {
+    /// `mode` with the rules for directories or files applied to its permission bits. The
+    /// file type bits are kept.
+    pub fn apply(&self, mode: u32, is_dir: bool) -> u32 {
+        self.rules
+            .iter()
+            .filter(|rule| match rule.applies_to {
+                ChmodTarget::All => true,
+                ChmodTarget::Dirs => is_dir,
+                ChmodTarget::Files => !is_dir,
+            })
+            .fold(mode, |mode, rule| (mode & !rule.clear) | rule.set)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default, ValueEnum)]
+pub enum Chunker {
+    /// Fixed-size blocks matched with a rolling weak hash
+    #[default]
+    Fixed,
+    /// Variable-size content-defined chunks (FastCDC)
+    Cdc,
+}
+
+/// Which end of the tunnel holds the source files.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum Direction {
+    /// The client sends its local files to the server
+    Push,
+    /// The server sends its files to the client
+    #[default]
+    Pull,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
 pub struct ClientServerOpts {
+    /// Path on the server: the destination when pushing, the source when pulling
     pub to: PathBuf,
+    pub direction: Direction,
     pub delete: bool,
+    /// Have the receiver decline files missing on its side, see [`crate::pipeline::Message::NoSend`]
+    pub existing: bool,
+    /// Have the receiver decline files it already has
+    pub ignore_existing: bool,
+    /// Have the receiver decline files whose copy is newer by more than `modify_window`
+    pub update: bool,
+    /// Have the receiver keep its copy of each file and only sign what follows it, see
+    /// [`crate::pipeline::FlistEntry::synced_size`]
+    pub append: bool,
+    /// Have the receiver sign a similar file for each missing one, see `pipeline::fuzzy`
+    pub fuzzy: bool,
+    /// Seconds by which modification times may differ and still count as equal
+    pub modify_window: u32,
     pub recursive: bool,
     pub dry_run: bool,
     pub verbose: bool,
     pub exclude: Vec<PathBuf>,
+    /// Lowercase exclude patterns and paths before matching them
+    pub ignore_case: bool,
+    pub ignore_file: Option<PathBuf>,
+    pub no_ignore: bool,
+    pub chunker: Chunker,
+    pub stop_on_error: bool,
+    pub max_size: Option<u64>,
+    pub min_size: Option<u64>,
+    /// Age limits of the files listed, measured from the sending side's clock when it walks
+    pub exclude_older_than: Option<Duration>,
+    pub exclude_newer_than: Option<Duration>,
+    pub verify: bool,
+    pub hard_links: bool,
+    /// Explicit list of paths to send instead of walking the source root
+    pub files_from: Option<Vec<PathBuf>>,
+    pub no_cache: bool,
+    pub numeric_ids: bool,
+    pub itemize_changes: bool,
+    pub devices: bool,
+    pub perms: bool,
+    pub times: bool,
+    pub owner: bool,
+    pub group: bool,
+    /// Owner applied to every received file instead of the entry's own
+    pub chown: Option<Chown>,
+    /// Permission changes applied after any preserved mode, see [`Chmod::apply`]
+    pub chmod: Option<Chmod>,
+    pub sparse: bool,
+    /// Rebuild files with [`crate::cryptography::Delta::apply_cloned`] where possible
+    pub reflink: bool,
+    pub relative: bool,
+    /// Have the receiver create the missing parents of its root, see [`crate::pipeline::Pipeline::make_root`]
+    pub mkpath: bool,
+    /// Skip signatures and send every file as a single literal block
+    pub whole_file: bool,
+    /// Reuse percentage below which a file is sent whole instead of as its delta
+    pub delta_threshold: Option<u8>,
+    /// Skip strong signature checks of matched blocks, see [`crate::cryptography::Delta::diff_table_weak_only`]
+    pub weak_only: bool,
+    /// Fraction of a file a delta must save, by its estimated transfer size, to be sent instead
+    /// of the whole file
+    pub min_transfer_ratio: Option<Ratio>,
+    /// Only send the flist, see [`crate::pipeline::Pipeline::list`]
+    pub list_only: bool,
+    /// Depth below the source root past which a recursive walk stops
+    pub max_depth: Option<usize>,
+    /// Sort the flist by path before indexing it
+    pub sort: bool,
+    /// Fill in [`crate::pipeline::FlistEntry::checksum`] while building the flist
+    pub flist_checksums: bool,
+    /// Directory on the receiving side for files being rebuilt
+    pub temp_dir: Option<PathBuf>,
+    /// Keep interrupted files to resume from, see [`ClientServerOpts::partial_path`]
+    pub partial_dir: Option<PathBuf>,
+    /// Keep replaced files, see [`ClientServerOpts::backup_path`]
+    pub backup: bool,
+    pub backup_suffix: Option<String>,
+    pub backup_dir: Option<PathBuf>,
 }
 
 impl From<&Cli> for ClientServerOpts {
     fn from(cli: &Cli) -> Self {
+        // `--archive` turns a preservation flag on unless its `--no-*` form was given
+        let archived = |on: bool, off: bool| (cli.archive || on) && !off;
         ClientServerOpts {
             to: cli.to.clone().unwrap_or_default(),
+            direction: Direction::default(),
             delete: cli.delete,
-            recursive: cli.recursive,
+            existing: cli.existing,
+            ignore_existing: cli.ignore_existing,
+            update: cli.update,
+            append: cli.append,
+            fuzzy: cli.fuzzy,
+            modify_window: cli.modify_window,
+            recursive: cli.recursive || cli.archive,
             dry_run: cli.dry_run,
             verbose: cli.verbose,
             exclude: cli.exclude.clone().unwrap_or_default(),
+            ignore_case: cli.ignore_case,
+            ignore_file: cli.ignore_file.clone(),
+            no_ignore: cli.no_ignore,
+            chunker: cli.chunker,
+            stop_on_error: cli.stop_on_error,
+            max_size: cli.max_size,
+            min_size: cli.min_size,
+            exclude_older_than: cli.exclude_older_than,
+            exclude_newer_than: cli.exclude_newer_than,
+            verify: cli.verify,
+            hard_links: cli.hard_links,
+            files_from: None,
+            no_cache: cli.no_cache,
+            numeric_ids: cli.numeric_ids,
+            itemize_changes: cli.itemize_changes,
+            devices: archived(cli.devices, cli.no_devices),
+            perms: archived(cli.perms, cli.no_perms),
+            times: archived(cli.times, cli.no_times),
+            owner: archived(cli.owner, cli.no_owner),
+            group: archived(cli.group, cli.no_group),
+            chown: cli.chown.clone(),
+            chmod: cli.chmod.clone(),
+            sparse: cli.sparse,
+            reflink: cli.reflink,
+            relative: cli.relative,
+            mkpath: (cli.mkpath || cli.recursive || cli.archive) && !cli.no_mkpath,
+            whole_file: cli.whole_file,
+            weak_only: cli.weak_only,
+            delta_threshold: cli.delta_threshold,
+            min_transfer_ratio: cli.min_transfer_ratio,
+            list_only: cli.list_only,
+            max_depth: cli.max_depth,
+            sort: cli.sort,
+            flist_checksums: cli.checksum,
+            temp_dir: cli.temp_dir.clone(),
+            partial_dir: cli.partial_dir.clone(),
+            backup: cli.backup || cli.backup_dir.is_some(),
+            backup_suffix: cli.suffix.clone(),
+            backup_dir: cli.backup_dir.clone(),
         }
     }
 }
+
+impl ClientServerOpts {
+    /// Checks that the options make sense together, before they are sent to the server.
+    pub fn validate(&self) -> std::result::Result<(), Error> {
+        if self.to.as_os_str().is_empty() {
+            return Err(Error::EmptyRemotePath);
+        }
+        if self.delete && !self.recursive {
+            return Err(Error::DeleteWithoutRecursive);
+        }
+        if self.verify {
+            if self.delete {
+                return Err(Error::VerifyWith("delete"));
+            }
+            if self.dry_run {
+                return Err(Error::VerifyWith("dry-run"));
+            }
+            if self.weak_only {
+                return Err(Error::VerifyWith("weak-only"));
+            }
+        }
+        if self.existing && self.ignore_existing {
+            return Err(Error::ExistingAndIgnoreExisting);
+        }
+        if self.weak_only && self.flist_checksums {
+            return Err(Error::WeakOnlyAndChecksum);
+        }
+        // Partial files are removed by name, which must not reach outside the directory
+        if let Some(dir) = &self.partial_dir
+            && (dir.as_os_str().is_empty()
+                || !dir.components().all(|c| matches!(c, Component::Normal(_))))
+        {
+            return Err(Error::PartialDir(dir.clone()));
+        }
+        if let (Some(min), Some(max)) = (self.min_size, self.max_size)
+            && min > max
+        {
+            return Err(Error::SizeRange { min, max });
+        }
+        if let (Some(older), Some(newer)) = (self.exclude_older_than, self.exclude_newer_than)
+            && older <= newer
+        {
+            return Err(Error::AgeRange { older, newer });
+        }
+        Ok(())
+    }
+
+    /// Resolves the `--chown` names to ids. Runs on the receiving side, whose user database
+    /// they refer to, before anything is transferred.
+    pub fn resolve_chown(&mut self) -> std::result::Result<(), Error> {
+        match &mut self.chown {
+            Some(chown) => chown.resolve(),
+            None => Ok(()),
+        }
+    }
+
+    /// Adjusts the defaults for a sync between two local paths, where reading both copies to
+    /// compute a delta costs more than copying: files are sent whole unless `--no-whole-file`.
+    pub fn assume_local(&mut self, cli: &Cli) {
+        self.whole_file = !cli.no_whole_file;
+    }
+
+    /// Where the previous version of `filename` below `local_root` is kept, if backups are on:
+    /// next to it with a `~` suffix, or at the same relative path below `backup_dir`.
+    pub fn backup_path(&self, local_root: &Path, filename: &Path) -> Option<PathBuf> {
+        if !self.backup {
+            return None;
+        }
+        let default_suffix = if self.backup_dir.is_some() { "" } else { "~" };
+        let mut name = filename.as_os_str().to_owned();
+        name.push(self.backup_suffix.as_deref().unwrap_or(default_suffix));
+        Some(match &self.backup_dir {
+            Some(dir) => local_root.join(dir).join(name),
+            None => local_root.join(name),
+        })
+    }
+
+    /// Where `filename` below `local_root` is rebuilt, and left if the transfer is interrupted,
+    /// when `partial_dir` is set: at the same relative path below it.
+    pub fn partial_path(&self, local_root: &Path, filename: &Path) -> Option<PathBuf> {
+        let dir = self.partial_dir.as_ref()?;
+        Some(local_root.join(dir).join(filename))
+    }
+
+    /// Loads the lists named by `--files-from` and `--exclude-from`. Both are read on the
+    /// client so the server never needs access to them.
+    pub fn read_lists(&mut self, cli: &Cli) -> Result<()> {
+        if let Some(files_from) = &cli.files_from {
+            self.files_from = Some(
+                read_list(files_from)?
+                    .into_iter()
+                    .map(PathBuf::from)
+                    .collect(),
+            );
+        }
+        if let Some(exclude_from) = &cli.exclude_from {
+            self.exclude
+                .extend(read_list(exclude_from)?.into_iter().map(PathBuf::from));
+        }
+        Ok(())
+    }
+}
+
+/// Refuses to sync a local directory into itself or into one of its own subdirectories, which
+/// would overwrite files while they are still being read.
+pub fn check_not_nested(source: &Path, destination: &Path) -> std::result::Result<(), Error> {
+    let source = canonicalize_existing(source);
+    let destination = canonicalize_existing(destination);
+    if destination.starts_with(&source) {
+        return Err(Error::DestinationInsideSource {
+            from: source,
+            destination,
+        });
+    }
+    Ok(())
+}
+
+/// Canonicalizes the longest existing prefix of `path` and appends the rest unchanged, so
+/// destinations that do not exist yet still resolve symlinks in their parents.
+fn canonicalize_existing(path: &Path) -> PathBuf {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    for ancestor in path.ancestors() {
+        if let Ok(canonical) = fs::canonicalize(ancestor) {
+            let rest = path.strip_prefix(ancestor).unwrap_or(Path::new(""));
+            return canonical.join(rest);
+        }
+    }
+    path
+}
+
+/// Reads a newline-separated list from `path`, or from stdin if `path` is `-`. Blank lines
+/// and lines starting with `#` are skipped.
+pub fn read_list(path: &Path) -> Result<Vec<String>> {
+    let contents = if path == Path::new("-") {
+        let mut contents = String::new();
+        io::stdin().read_to_string(&mut contents)?;
+        contents
+    } else {
+        fs::read_to_string(path).map_err(|e| eyre!("Failed to read {:?}: {}", path, e))?
+    };
+    Ok(contents
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Parses a human-readable size such as `512`, `10M` or `1.5G` into bytes. Suffixes are
+/// binary (`K` = 1024) and may be followed by an optional `B`.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let digits = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, suffix) = s.split_at(digits);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| eyre!("Invalid size {:?}: expected a number", s))?;
+    let multiplier: u64 = match suffix.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(eyre!("Invalid size {:?}: unknown suffix {:?}", s, suffix)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Formats a byte count with a binary suffix, e.g. `512 B`, `1.5 KiB` or `2.0 GiB`.
+pub fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Expands a leading `~` to the current user's home directory and `~user` to that user's,
+/// leaving the path as is if the home directory is unknown.
+pub fn expand_tilde(path: &Path) -> PathBuf {
+    let Some(rest) = path.to_str().and_then(|p| p.strip_prefix('~')) else {
+        return path.to_path_buf();
+    };
+    let (user, rest) = rest.split_once('/').unwrap_or((rest, ""));
+    let home = if user.is_empty() {
+        directories::BaseDirs::new().map(|dirs| dirs.home_dir().to_path_buf())
+    } else {
+        crate::flist::home_dir_by_name(user)
+    };
+    match home {
+        Some(home) if rest.is_empty() => home,
+        Some(home) => home.join(rest),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Checks that an ssh option has the `KEY=VALUE` form ssh's `-o` expects.
+pub fn parse_ssh_option(s: &str) -> Result<String> {
+    match s.split_once('=') {
+        Some((key, _)) if !key.trim().is_empty() => Ok(s.to_string()),
+        _ => Err(eyre!("Invalid ssh option {:?}: expected KEY=VALUE", s)),
+    }
+}
+
+/// Parses a fraction between 0.0 and 1.0, e.g. `0.25`.
+pub fn parse_ratio(s: &str) -> Result<Ratio> {
+    let value: f64 = s
+        .trim()
+        .parse()
+        .map_err(|_| eyre!("Invalid ratio {:?}: expected a number", s))?;
+    Ratio::new(value).ok_or_else(|| eyre!("Invalid ratio {:?}: must be between 0.0 and 1.0", s))
+}
+
+/// Parses `USER:GROUP`, `USER` or `:GROUP`.
+pub fn parse_chown(s: &str) -> Result<Chown> {
+    let (user, group) = s.split_once(':').unwrap_or((s, ""));
+    let part = |part: &str| (!part.is_empty()).then(|| part.to_string());
+    let chown = Chown {
+        user: part(user),
+        group: part(group),
+        ..Default::default()
+    };
+    if chown.user.is_none() && chown.group.is_none() {
+        return Err(eyre!("Invalid owner {:?}: expected USER:GROUP", s));
+    }
+    Ok(chown)
+}
+
+/// Parses `--chmod` rules, separated by commas. Each is an optional `D` or `F`, then either an
+/// octal mode such as `644`, or `[ugoa]*` followed by `+`, `-` or `=` and `[rwx]*`, as in
+/// `go-w` or `u=rw`. Leaving out who means `a`.
+pub fn parse_chmod(s: &str) -> Result<Chmod> {
+    let invalid = || {
+        eyre!(
+            "Invalid chmod {:?}: expected rules such as D755, F644 or go-w, separated by commas",
+            s
+        )
+    };
+    let rules = s
+        .split(',')
+        .map(|rule| {
+            let (applies_to, change) = match rule.as_bytes().first() {
+                Some(b'D') => (ChmodTarget::Dirs, &rule[1..]),
+                Some(b'F') => (ChmodTarget::Files, &rule[1..]),
+                _ => (ChmodTarget::All, rule),
+            };
+            if !change.is_empty() && change.bytes().all(|b| b.is_ascii_digit()) {
+                let set = u32::from_str_radix(change, 8)
+                    .ok()
+                    .filter(|&mode| mode <= 0o7777)
+                    .ok_or_else(invalid)?;
+                return Ok(ChmodRule {
+                    applies_to,
+                    clear: 0o7777,
+                    set,
+                });
+            }
+            let op_at = change.find(['+', '-', '=']).ok_or_else(invalid)?;
+            let (who, perms) = (&change[..op_at], &change[op_at + 1..]);
+            let who = match who {
+                "" => Some(0o777),
+                who => who.chars().try_fold(0, |bits, c| match c {
+                    'u' => Some(bits | 0o700),
+                    'g' => Some(bits | 0o070),
+                    'o' => Some(bits | 0o007),
+                    'a' => Some(bits | 0o777),
+                    _ => None,
+                }),
+            }
+            .ok_or_else(invalid)?;
+            let perms = perms
+                .chars()
+                .try_fold(0, |bits, c| match c {
+                    'r' => Some(bits | 0o444),
+                    'w' => Some(bits | 0o222),
+                    'x' => Some(bits | 0o111),
+                    _ => None,
+                })
+                .ok_or_else(invalid)?
+                & who;
+            let (clear, set) = match &change[op_at..op_at + 1] {
+                "+" => (0, perms),
+                "-" => (perms, 0),
+                _ => (who, perms),
+            };
+            Ok(ChmodRule {
+                applies_to,
+                clear,
+                set,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(Chmod { rules })
+}
+
+/// Parses a duration such as `500ms`, `2s`, `1.5m`, `24h`, `7d` or a plain number of seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let digits = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, suffix) = s.split_at(digits);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| eyre!("Invalid duration {:?}: expected a number", s))?;
+    let seconds = match suffix {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 60.0 * 60.0,
+        "d" => number * 24.0 * 60.0 * 60.0,
+        _ => return Err(eyre!("Invalid duration {:?}: unknown unit {:?}", s, suffix)),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| eyre!("Invalid duration {:?}: too long", s))
+}
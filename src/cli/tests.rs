@@ -0,0 +1,484 @@
+use super::*;
+use clap::CommandFactory;
+use pretty_assertions::assert_eq;
+
+#[test]
+fn parse_plain_and_suffixed_sizes() {
+    assert_eq!(parse_size("0").unwrap(), 0);
+    assert_eq!(parse_size("512").unwrap(), 512);
+    assert_eq!(parse_size("512K").unwrap(), 512 * 1024);
+    assert_eq!(parse_size("10M").unwrap(), 10 * 1024 * 1024);
+    assert_eq!(parse_size("10mb").unwrap(), 10 * 1024 * 1024);
+    assert_eq!(parse_size("2G").unwrap(), 2 * 1024 * 1024 * 1024);
+    assert_eq!(parse_size("1.5K").unwrap(), 1536);
+}
+
+#[test]
+fn parse_invalid_sizes() {
+    assert!(parse_size("").is_err());
+    assert!(parse_size("M").is_err());
+    assert!(parse_size("10X").is_err());
+    assert!(parse_size("1.2.3K").is_err());
+}
+
+#[test]
+fn human_bytes_uses_binary_suffixes() {
+    assert_eq!(human_bytes(0), "0 B");
+    assert_eq!(human_bytes(1023), "1023 B");
+    assert_eq!(human_bytes(1024), "1.0 KiB");
+    assert_eq!(human_bytes(1536), "1.5 KiB");
+    assert_eq!(human_bytes(10 * 1024 * 1024), "10.0 MiB");
+    assert_eq!(human_bytes(3 << 30), "3.0 GiB");
+    assert_eq!(human_bytes(5 << 40), "5.0 TiB");
+    assert_eq!(human_bytes(u64::MAX), "16384.0 PiB");
+}
+
+#[test]
+fn ratios_must_be_fractions() {
+    assert_eq!(parse_ratio("0.25").unwrap().get(), 0.25);
+    assert_eq!(parse_ratio("1").unwrap().get(), 1.0);
+    assert!(parse_ratio("1.5").is_err());
+    assert!(parse_ratio("-0.1").is_err());
+    assert!(parse_ratio("NaN").is_err());
+}
+
+#[test]
+fn parse_durations_with_and_without_units() {
+    assert_eq!(parse_duration("2").unwrap(), Duration::from_secs(2));
+    assert_eq!(parse_duration("2s").unwrap(), Duration::from_secs(2));
+    assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+    assert_eq!(parse_duration("1.5m").unwrap(), Duration::from_secs(90));
+    assert_eq!(parse_duration("24h").unwrap(), Duration::from_secs(86400));
+    assert_eq!(
+        parse_duration("7d").unwrap(),
+        Duration::from_secs(7 * 86400)
+    );
+    assert!(parse_duration("").is_err());
+    assert!(parse_duration("5w").is_err());
+    assert!(parse_duration("1e400").is_err());
+}
+
+#[test]
+fn read_list_skips_blank_lines_and_comments() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("list");
+    std::fs::write(&path, "# header\na.txt\n\nnested/b.txt  \n#c.txt\n").unwrap();
+
+    assert_eq!(read_list(&path).unwrap(), vec!["a.txt", "nested/b.txt"]);
+}
+
+fn valid_opts() -> ClientServerOpts {
+    ClientServerOpts {
+        to: PathBuf::from("/srv/backup"),
+        recursive: true,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn valid_opts_pass_validation() {
+    assert_eq!(valid_opts().validate(), Ok(()));
+}
+
+#[test]
+fn empty_remote_path_is_rejected() {
+    let opts = ClientServerOpts {
+        to: PathBuf::new(),
+        ..valid_opts()
+    };
+    assert_eq!(opts.validate(), Err(Error::EmptyRemotePath));
+}
+
+#[test]
+fn delete_requires_recursive() {
+    let opts = ClientServerOpts {
+        delete: true,
+        recursive: false,
+        ..valid_opts()
+    };
+    assert_eq!(opts.validate(), Err(Error::DeleteWithoutRecursive));
+}
+
+#[test]
+fn verify_rejects_transfer_flags() {
+    let opts = ClientServerOpts {
+        verify: true,
+        delete: true,
+        ..valid_opts()
+    };
+    assert_eq!(opts.validate(), Err(Error::VerifyWith("delete")));
+
+    let opts = ClientServerOpts {
+        verify: true,
+        dry_run: true,
+        ..valid_opts()
+    };
+    assert_eq!(opts.validate(), Err(Error::VerifyWith("dry-run")));
+
+    let opts = ClientServerOpts {
+        verify: true,
+        weak_only: true,
+        ..valid_opts()
+    };
+    assert_eq!(opts.validate(), Err(Error::VerifyWith("weak-only")));
+}
+
+#[test]
+fn existing_and_ignore_existing_are_exclusive() {
+    let opts = ClientServerOpts {
+        existing: true,
+        ignore_existing: true,
+        ..valid_opts()
+    };
+    assert_eq!(opts.validate(), Err(Error::ExistingAndIgnoreExisting));
+    assert!(
+        Cli::try_parse_from(["oxide_sync", "--existing", "--ignore-existing", "a", "b"]).is_err()
+    );
+}
+
+#[test]
+fn weak_only_and_checksum_are_exclusive() {
+    let opts = ClientServerOpts {
+        weak_only: true,
+        flist_checksums: true,
+        ..valid_opts()
+    };
+    assert_eq!(opts.validate(), Err(Error::WeakOnlyAndChecksum));
+    assert!(Cli::try_parse_from(["oxide_sync", "--weak-only", "-c", "a", "b"]).is_err());
+}
+
+#[test]
+fn inverted_size_range_is_rejected() {
+    let opts = ClientServerOpts {
+        min_size: Some(2048),
+        max_size: Some(1024),
+        ..valid_opts()
+    };
+    assert_eq!(
+        opts.validate(),
+        Err(Error::SizeRange {
+            min: 2048,
+            max: 1024
+        })
+    );
+}
+
+#[test]
+fn inverted_age_range_is_rejected() {
+    let opts = opts_from_args(&["--exclude-older-than", "1d", "--exclude-newer-than", "2d"]);
+    assert_eq!(
+        opts.validate(),
+        Err(Error::AgeRange {
+            older: Duration::from_secs(86400),
+            newer: Duration::from_secs(2 * 86400),
+        })
+    );
+}
+
+fn opts_from_args(args: &[&str]) -> ClientServerOpts {
+    let cli = Cli::parse_from(["oxide_sync"].iter().chain(args).chain(&["from", "to"]));
+    (&cli).into()
+}
+
+#[test]
+fn archive_enables_recursion_and_preservation() {
+    let opts = opts_from_args(&["-a"]);
+
+    assert!(opts.recursive);
+    assert!(opts.perms);
+    assert!(opts.times);
+    assert!(opts.owner);
+    assert!(opts.group);
+    assert!(opts.devices);
+    assert!(!opts.delete);
+}
+
+#[test]
+fn explicit_no_flags_override_archive() {
+    let opts = opts_from_args(&["-a", "--no-perms", "--no-devices"]);
+
+    assert!(!opts.perms);
+    assert!(!opts.devices);
+    assert!(opts.times);
+    assert!(opts.owner);
+}
+
+#[test]
+fn preservation_is_off_without_archive() {
+    let opts = opts_from_args(&["--times"]);
+
+    assert!(opts.times);
+    assert!(!opts.perms);
+    assert!(!opts.recursive);
+
+    // The last of a flag and its `--no-*` form wins
+    assert!(opts_from_args(&["--no-perms", "--perms"]).perms);
+}
+
+#[test]
+fn mkpath_defaults_to_recursion() {
+    assert!(opts_from_args(&["-r"]).mkpath);
+    assert!(opts_from_args(&["-a"]).mkpath);
+    assert!(opts_from_args(&["--mkpath"]).mkpath);
+    assert!(!opts_from_args(&[]).mkpath);
+    assert!(!opts_from_args(&["-r", "--no-mkpath"]).mkpath);
+}
+
+#[test]
+fn destination_inside_source_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("src");
+    std::fs::create_dir(&source).unwrap();
+
+    assert!(matches!(
+        check_not_nested(&source, &source.join("backup")),
+        Err(Error::DestinationInsideSource { .. })
+    ));
+    assert!(matches!(
+        check_not_nested(&source, &source.join("nested/../.")),
+        Err(Error::DestinationInsideSource { .. })
+    ));
+    assert_eq!(check_not_nested(&source, &dir.path().join("dst")), Ok(()));
+    // The source living inside the destination is fine
+    assert_eq!(check_not_nested(&source, dir.path()), Ok(()));
+}
+
+#[test]
+fn nesting_is_detected_through_symlinks() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("src");
+    std::fs::create_dir(&source).unwrap();
+    let alias = dir.path().join("alias");
+    std::os::unix::fs::symlink(&source, &alias).unwrap();
+
+    assert!(matches!(
+        check_not_nested(&source, &alias.join("copy")),
+        Err(Error::DestinationInsideSource { .. })
+    ));
+}
+
+#[test]
+fn local_syncs_send_whole_files_unless_told_otherwise() {
+    for (args, expected) in [
+        (&[][..], true),
+        (&["--no-whole-file"][..], false),
+        (&["-W", "--no-whole-file"][..], false),
+    ] {
+        let cli = Cli::parse_from(["oxide_sync"].iter().chain(args).chain(&["from", "to"]));
+        let mut opts = ClientServerOpts::from(&cli);
+        assert!(!opts.whole_file || args.contains(&"-W"));
+        opts.assume_local(&cli);
+        assert_eq!(opts.whole_file, expected, "{:?}", args);
+    }
+}
+
+fn cli_with_config(config: &str, args: &[&str]) -> Cli {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(CONFIG_FILENAME);
+    fs::write(&path, config).unwrap();
+    let path = path.to_str().unwrap();
+    let matches = Cli::command().get_matches_from(
+        ["oxide_sync", "--config", path]
+            .iter()
+            .chain(args)
+            .chain(&["from", "to"]),
+    );
+    Cli::from_matches_with_config(&matches).unwrap()
+}
+
+#[test]
+fn config_fills_in_options_missing_from_the_command_line() {
+    let config = "# defaults\nport = 2222\nrecursive = true\nexclude = [\"target\", \".git\"] # build\nmax_size = \"1K\"\nchunker = \"cdc\"\n";
+
+    let cli = cli_with_config(config, &[]);
+    assert_eq!(cli.port, Some(2222));
+    assert!(cli.recursive);
+    assert_eq!(
+        cli.exclude,
+        Some(vec![PathBuf::from("target"), PathBuf::from(".git")])
+    );
+    assert_eq!(cli.max_size, Some(1024));
+    assert_eq!(cli.chunker, Chunker::Cdc);
+
+    let cli = cli_with_config(
+        config,
+        &["--port", "22", "--exclude", "other", "--max-size", "2K"],
+    );
+    assert_eq!(cli.port, Some(22));
+    assert_eq!(cli.exclude, Some(vec![PathBuf::from("other")]));
+    assert_eq!(cli.max_size, Some(2048));
+    assert!(cli.recursive);
+}
+
+#[test]
+fn config_errors_name_the_line() {
+    let line = |config: &str| match Config::parse(config) {
+        Err(Error::Config { line, .. }) => line,
+        result => panic!("{:?}", result),
+    };
+    assert_eq!(line("recursive = true\nport 22\n"), 2);
+    assert_eq!(line("recursive = true\n\nno_such_option = 1\n"), 3);
+    assert_eq!(line("max_size = \"12Q\""), 1);
+    assert_eq!(line("delta_threshold = 101"), 1);
+}
+
+#[test]
+fn config_strings_are_full_toml() {
+    let config = Config::parse(
+        "pre_cmd = \"echo \\\"# not a comment\\\"\"\nexclude = [\n  \"a,b\",\n  'c',\n]\nperms = false\n",
+    )
+    .unwrap();
+    assert_eq!(config.pre_cmd.as_deref(), Some("echo \"# not a comment\""));
+    assert_eq!(
+        config.exclude,
+        Some(vec![PathBuf::from("a,b"), PathBuf::from("c")])
+    );
+    assert_eq!(config.perms, Some(false));
+}
+
+#[test]
+fn negated_flag_on_the_command_line_wins_over_the_config() {
+    let cli = cli_with_config(
+        "no_perms = true\ntimes = true\n",
+        &["--perms", "--no-times"],
+    );
+    assert!(cli.perms && !cli.no_perms);
+    assert!(!cli.times && cli.no_times);
+
+    let cli = cli_with_config("no_perms = true\ntimes = true\n", &[]);
+    assert!(cli.no_perms && cli.times);
+}
+
+#[test]
+fn leading_tilde_expands_to_the_home_directory() {
+    let home = directories::BaseDirs::new()
+        .unwrap()
+        .home_dir()
+        .to_path_buf();
+
+    assert_eq!(expand_tilde(Path::new("~")), home);
+    assert_eq!(
+        expand_tilde(Path::new("~/backup/notes")),
+        home.join("backup/notes")
+    );
+    assert_eq!(expand_tilde(Path::new("/tmp/~")), PathBuf::from("/tmp/~"));
+    assert_eq!(expand_tilde(Path::new("a/~/b")), PathBuf::from("a/~/b"));
+    assert_eq!(
+        expand_tilde(Path::new("~no_such_user_oxide_sync/x")),
+        PathBuf::from("~no_such_user_oxide_sync/x")
+    );
+}
+
+#[test]
+fn tilde_user_expands_to_that_users_home() {
+    let expanded = expand_tilde(Path::new("~root/etc"));
+    assert!(expanded.is_absolute(), "{:?}", expanded);
+    assert!(expanded.ends_with("etc"), "{:?}", expanded);
+}
+
+#[test]
+fn list_subcommand_needs_only_the_remote_path() {
+    let mut cli =
+        Cli::try_parse_from(["oxide_sync", "-r", "list", "--json", "user@host:docs"]).unwrap();
+    assert_eq!(cli.from, None);
+    assert_eq!(cli.to, None);
+    assert_eq!(
+        cli.command,
+        Some(Command::List {
+            remote: PathBuf::from("user@host:docs"),
+            json: true,
+        })
+    );
+
+    cli.apply_command();
+    assert!(cli.list_only && cli.json && cli.recursive);
+    assert_eq!(cli.from, Some(PathBuf::from("user@host:docs")));
+    assert_eq!(cli.to, None);
+    assert_eq!(cli.command, None);
+}
+
+#[test]
+fn list_only_may_leave_out_the_destination() {
+    let cli = Cli::try_parse_from(["oxide_sync", "--list-only", "user@host:docs"]).unwrap();
+    assert_eq!(cli.from, Some(PathBuf::from("user@host:docs")));
+    assert_eq!(cli.to, None);
+
+    assert!(Cli::try_parse_from(["oxide_sync", "user@host:docs"]).is_err());
+}
+
+#[test]
+fn chown_parses_user_and_group_parts() {
+    let chown = parse_chown("www:staff").unwrap();
+    assert_eq!(
+        (chown.user.as_deref(), chown.group.as_deref()),
+        (Some("www"), Some("staff"))
+    );
+    let chown = parse_chown(":staff").unwrap();
+    assert_eq!((chown.user, chown.group.as_deref()), (None, Some("staff")));
+    let chown = parse_chown("www").unwrap();
+    assert_eq!((chown.user.as_deref(), chown.group), (Some("www"), None));
+    assert!(parse_chown(":").is_err());
+    assert!(parse_chown("").is_err());
+}
+
+#[test]
+fn chmod_parses_octal_and_symbolic_rules() {
+    let chmod = parse_chmod("D755,F644").unwrap();
+    assert_eq!(chmod.apply(0o040700, true), 0o040755);
+    assert_eq!(chmod.apply(0o100777, false), 0o100644);
+
+    let chmod = parse_chmod("go-w,u+x").unwrap();
+    assert_eq!(chmod.apply(0o666, false), 0o744);
+    assert_eq!(chmod.apply(0o777, true), 0o755);
+    assert_eq!(parse_chmod("Fa=r").unwrap().apply(0o4755, false), 0o4444);
+    assert_eq!(
+        parse_chmod("Du=rwx,Dgo=").unwrap().apply(0o755, true),
+        0o700
+    );
+    assert_eq!(parse_chmod("+x").unwrap().apply(0o644, false), 0o755);
+
+    for invalid in ["", "F", "D9", "F10000", "u+z", "q-w", "D755,", "644x"] {
+        assert!(parse_chmod(invalid).is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn chown_resolves_names_and_numeric_ids() {
+    let mut chown = parse_chown("root:1234").unwrap();
+    chown.resolve().unwrap();
+    assert_eq!((chown.uid, chown.gid), (Some(0), Some(1234)));
+
+    let mut chown = parse_chown("no-such-user-oxide").unwrap();
+    assert_eq!(
+        chown.resolve(),
+        Err(Error::UnknownUser("no-such-user-oxide".into()))
+    );
+    let mut chown = parse_chown(":no-such-group-oxide").unwrap();
+    assert_eq!(
+        chown.resolve(),
+        Err(Error::UnknownGroup("no-such-group-oxide".into()))
+    );
+}
+
+#[test]
+fn partial_dir_must_stay_below_the_destination() {
+    for valid in [".partial", "tmp/partial"] {
+        let opts = ClientServerOpts {
+            partial_dir: Some(valid.into()),
+            ..valid_opts()
+        };
+        assert_eq!(opts.validate(), Ok(()), "{}", valid);
+    }
+    for invalid in ["", ".", "..", "/tmp/partial", "a/../b", "./partial"] {
+        let opts = ClientServerOpts {
+            partial_dir: Some(invalid.into()),
+            ..valid_opts()
+        };
+        assert_eq!(
+            opts.validate(),
+            Err(Error::PartialDir(invalid.into())),
+            "{}",
+            invalid
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Content-defined chunking in the style of FastCDC.
+//!
+//! Chunk boundaries are picked from a gear hash over the content itself, so an insertion only
+//! disturbs the chunk it lands in and every later boundary stays where it was.
+
+use std::ops::Range;
+
+/// Per-byte random values fed into the gear hash, generated with splitmix64.
+static GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6f78_6964_655f_7379;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FastCdc {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+impl Default for FastCdc {
+    fn default() -> Self {
+        Self::new(2 * 1024, 8 * 1024, 64 * 1024)
+    }
+}
+
+impl FastCdc {
+    pub fn new(min_size: usize, avg_size: usize, max_size: usize) -> Self {
+        assert!(
+            0 < min_size && min_size <= avg_size && avg_size <= max_size,
+            "chunk sizes must satisfy 0 < min <= avg <= max"
+        );
+        Self {
+            min_size,
+            avg_size,
+            max_size,
+        }
+    }
+
+    /// Masks over the high bits of the gear hash. Before the average size a stricter mask is
+    /// used, after it a looser one, which keeps chunk sizes close to the average.
+    fn masks(&self) -> (u64, u64) {
+        let bits = self.avg_size.ilog2();
+        let mask = |n: u32| !0u64 << (64 - n.clamp(1, 63));
+        (mask(bits + 2), mask(bits.saturating_sub(2)))
+    }
+
+    /// Length of the chunk starting at the beginning of `data`.
+    fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min_size {
+            return data.len();
+        }
+        let max = data.len().min(self.max_size);
+        let normal = self.avg_size.min(max);
+        let (mask_small, mask_large) = self.masks();
+
+        let mut hash: u64 = 0;
+        let mut i = self.min_size;
+        while i < normal {
+            hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+            if hash & mask_small == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        while i < max {
+            hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+            if hash & mask_large == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        max
+    }
+
+    /// Splits `data` into consecutive chunks covering the whole buffer.
+    pub fn chunks<'a>(&'a self, data: &'a [u8]) -> impl Iterator<Item = Range<usize>> + 'a {
+        let mut start = 0;
+        std::iter::from_fn(move || {
+            if start >= data.len() {
+                return None;
+            }
+            let len = self.cut(&data[start..]);
+            let range = start..start + len;
+            start += len;
+            Some(range)
+        })
+    }
+}
@@ -1,23 +1,56 @@
 use std::fmt::{Debug, Write as _};
 use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
 use std::path::Path;
 
-use crate::cryptography::MODULUS;
+use serde::{Deserialize, Serialize};
 
-use super::{IndexTable, WeakSignature, WeakSignatureBlock, compute_strong_signature, index_table};
+use super::{
+    ChunkRef, FastCdc, IndexTable, WeakSignature, WeakSignatureBlock, compute_strong_signature,
+};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Ops {
     Index(usize),
     Block(Vec<u8>),
+    /// A content-defined chunk of the base, copied by offset and length.
+    Chunk(ChunkRef),
+    /// A literal block stored zstd-compressed, see [`Delta::compress_blocks`].
+    CompressedBlock(Vec<u8>),
 }
 
-#[derive(Debug, Clone, Default)]
+/// Zero runs of at least this many bytes, aligned to the start of a literal block, are turned
+/// into holes when writing sparsely.
+pub const SPARSE_MIN_RUN: usize = 4096;
+
+/// Upper bound on the bytes an op takes on the wire besides its literal data: the variant tag
+/// and a varint length or index.
+pub const OP_OVERHEAD: usize = 10;
+
+/// Compression level used for literal blocks.
+const BLOCK_COMPRESSION_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Delta {
     pub ops: Vec<Ops>,
 }
 
+/// Computes the delta turning `base` into `new`, entirely in memory. It is undone by
+/// [`Delta::apply`] with the same `block_size`:
+///
+/// ```
+/// use oxide_sync::cryptography::rsync_delta;
+///
+/// let (base, new) = (b"hello, world", b"hello there, world");
+/// let delta = rsync_delta(base, new, 4);
+/// assert_eq!(delta.apply(base, 4).unwrap(), new);
+/// ```
+pub fn rsync_delta(base: &[u8], new: &[u8], block_size: usize) -> Delta {
+    Delta::diff(base, new, block_size)
+}
+
 impl Delta {
     pub fn new() -> Self {
         Self { ops: Vec::new() }
@@ -31,6 +64,10 @@ impl Delta {
         self.ops.push(Ops::Index(index));
     }
 
+    pub fn add_chunk(&mut self, chunk: ChunkRef) {
+        self.ops.push(Ops::Chunk(chunk));
+    }
+
     pub fn add_byte(&mut self, byte: u8) {
         if self.ops.is_empty() {
             self.add_block(vec![byte]);
@@ -38,10 +75,116 @@ impl Delta {
         }
         match self.ops.last_mut().unwrap() {
             Ops::Block(block) => block.push(byte),
-            Ops::Index(_) => self.add_block(vec![byte]),
+            Ops::Index(_) | Ops::Chunk(_) | Ops::CompressedBlock(_) => self.add_block(vec![byte]),
+        }
+    }
+
+    /// Compresses every literal block in place, keeping the raw bytes where compressing
+    /// would not make the block smaller. Index and chunk ops are left untouched.
+    pub fn compress_blocks(&mut self) {
+        for op in &mut self.ops {
+            if let Ops::Block(bytes) = op {
+                match zstd::bulk::compress(bytes, BLOCK_COMPRESSION_LEVEL) {
+                    Ok(compressed) if compressed.len() < bytes.len() => {
+                        *op = Ops::CompressedBlock(compressed)
+                    }
+                    _ => {}
+                }
+            }
         }
     }
 
+    /// Bytes of literal data carried by the delta, compressed blocks counted as compressed.
+    pub fn literal_size(&self) -> usize {
+        self.ops
+            .iter()
+            .map(|op| match op {
+                Ops::Block(bytes) | Ops::CompressedBlock(bytes) => bytes.len(),
+                Ops::Index(_) | Ops::Chunk(_) => 0,
+            })
+            .sum()
+    }
+
+    /// Estimated serialized size: the literal data plus [`OP_OVERHEAD`] per op.
+    pub fn transfer_size(&self) -> usize {
+        self.literal_size() + self.ops.len() * OP_OVERHEAD
+    }
+
+    /// Splits the ops into runs of at most `max_size` bytes by [`Delta::transfer_size`],
+    /// cutting long literal blocks into pieces. Applied one after another, the runs rebuild
+    /// the same file as the whole delta. A compressed block is never cut, so a run holding
+    /// one may be larger.
+    pub fn chunks(&self, max_size: usize) -> impl Iterator<Item = Vec<Ops>> + '_ {
+        let (mut index, mut offset) = (0, 0);
+        std::iter::from_fn(move || {
+            let mut chunk = Vec::new();
+            let mut size = 0;
+            while let Some(op) = self.ops.get(index) {
+                let room = max_size.saturating_sub(size);
+                match op {
+                    Ops::Block(bytes) => {
+                        let fits = room.saturating_sub(OP_OVERHEAD);
+                        if fits == 0 && !chunk.is_empty() {
+                            break;
+                        }
+                        let end = bytes.len().min(offset + fits.max(1));
+                        chunk.push(Ops::Block(bytes[offset..end].to_vec()));
+                        size += end - offset + OP_OVERHEAD;
+                        offset = end;
+                        if offset == bytes.len() {
+                            (index, offset) = (index + 1, 0);
+                        }
+                    }
+                    op => {
+                        let len = match op {
+                            Ops::CompressedBlock(bytes) => bytes.len(),
+                            _ => 0,
+                        };
+                        if len + OP_OVERHEAD > room && !chunk.is_empty() {
+                            break;
+                        }
+                        chunk.push(op.clone());
+                        size += len + OP_OVERHEAD;
+                        index += 1;
+                    }
+                }
+            }
+            (!chunk.is_empty()).then_some(chunk)
+        })
+    }
+
+    /// Fraction of a `total_len` byte file that is copied from the base rather than sent as
+    /// literal data. An empty file counts as fully reused.
+    pub fn reuse_ratio(&self, total_len: usize) -> f64 {
+        if total_len == 0 {
+            return 1.0;
+        }
+        1.0 - (self.literal_size().min(total_len) as f64 / total_len as f64)
+    }
+
+    /// Output ranges, as `(start, end)` offsets, written from literal blocks rather than the
+    /// base. Index ops are counted as a full `block_size`, so ranges after a short last base
+    /// block are shifted by the difference.
+    pub fn literal_regions(&self, block_size: usize) -> Vec<(usize, usize)> {
+        let mut regions = Vec::new();
+        let mut offset = 0usize;
+        for op in &self.ops {
+            let (len, literal) = match op {
+                Ops::Index(_) => (block_size, false),
+                Ops::Chunk(chunk) => (chunk.len, false),
+                Ops::Block(bytes) => (bytes.len(), true),
+                Ops::CompressedBlock(bytes) => {
+                    (inflate(bytes).map_or(0, |bytes| bytes.len()), true)
+                }
+            };
+            if literal && len > 0 {
+                regions.push((offset, offset.saturating_add(len)));
+            }
+            offset = offset.saturating_add(len);
+        }
+        regions
+    }
+
     pub fn is_valid(&self) -> bool {
         !self.ops.is_empty()
     }
@@ -51,42 +194,81 @@ impl Delta {
         for op in self.ops.iter() {
             match op {
                 Ops::Index(index) => write!(&mut s, "<b*{}*>", index).unwrap(),
+                Ops::Chunk(chunk) => write!(&mut s, "<c*{}+{}*>", chunk.offset, chunk.len).unwrap(),
                 Ops::Block(block) => {
                     s.push_str(core::str::from_utf8(block).expect("Error with UTF-8 string"))
                 }
+                Ops::CompressedBlock(block) => write!(&mut s, "<z*{}*>", block.len()).unwrap(),
             }
         }
         s
     }
 
-    /// Apply this delta to the given base file bytes.
+    /// Apply this delta to the given base file bytes. `block_size` may only be 0 if the delta
+    /// holds no block indices.
     pub fn apply(&self, base: &[u8], block_size: usize) -> io::Result<Vec<u8>> {
-        let mut output = Vec::new();
+        let mut output = Cursor::new(Vec::new());
+        self.apply_to(base, block_size, &mut output, false)?;
+        Ok(output.into_inner())
+    }
 
+    /// Apply this delta to `base`, streaming the result into `out`. With `sparse`, long runs of
+    /// zeros in literal blocks are skipped with a seek instead of being written.
+    pub fn apply_to<W: Write + Seek>(
+        &self,
+        base: &[u8],
+        block_size: usize,
+        out: &mut W,
+        sparse: bool,
+    ) -> io::Result<()> {
+        let mut writer = SparseWriter::new(out, sparse);
         for op in &self.ops {
             match op {
-                Ops::Index(index) => {
-                    let start = index * block_size;
-                    let end = std::cmp::min(start + block_size, base.len());
-                    if start >= base.len() {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            format!(
-                                "Invalid block index {} for base length {}",
-                                index,
-                                base.len()
-                            ),
-                        ));
-                    }
-                    output.extend_from_slice(&base[start..end]);
-                }
-                Ops::Block(bytes) => {
-                    output.extend_from_slice(bytes);
-                }
+                Ops::Block(bytes) => writer.write(bytes)?,
+                Ops::CompressedBlock(bytes) => writer.write(&inflate(bytes)?)?,
+                op => writer.write_dense(&base[base_range(op, block_size, base.len())?])?,
             }
         }
+        writer.finish()
+    }
 
-        Ok(output)
+    /// Apply this delta to the file `base`, writing the result into `out` from its start. The
+    /// base regions are copied between the two files by the kernel with `copy_file_range`,
+    /// which shares their extents instead of duplicating them on filesystems with reflinks
+    /// (Btrfs, XFS). Fails with [`io::ErrorKind::Unsupported`] where the kernel cannot copy
+    /// between the two files, leaving `out` partly written.
+    pub fn apply_cloned(&self, base: &File, block_size: usize, out: &File) -> io::Result<()> {
+        let base_len = base.metadata()?.len() as usize;
+        let mut pos = 0;
+        // Runs of adjacent base regions are copied in one go
+        let mut pending: Option<Range<usize>> = None;
+        for op in &self.ops {
+            let literal = match op {
+                Ops::Block(bytes) => bytes,
+                Ops::CompressedBlock(bytes) => &inflate(bytes)?,
+                op => {
+                    let range = base_range(op, block_size, base_len)?;
+                    pending = match pending {
+                        Some(run) if run.end == range.start => Some(run.start..range.end),
+                        Some(run) => {
+                            pos = copy_range(base, out, run, pos)?;
+                            Some(range)
+                        }
+                        None => Some(range),
+                    };
+                    continue;
+                }
+            };
+            if let Some(run) = pending.take() {
+                pos = copy_range(base, out, run, pos)?;
+            }
+            out.write_all_at(literal, pos)?;
+            pos += literal.len() as u64;
+        }
+        if let Some(run) = pending {
+            pos = copy_range(base, out, run, pos)?;
+        }
+        out.set_len(pos)
     }
 
     /// Apply this delta to a base file and write the result to another file.
@@ -110,38 +292,41 @@ impl Delta {
         Ok(())
     }
 
+    /// A delta sending all of `new` as a single literal block, without looking at any base.
+    /// An empty `new` gives an empty delta.
+    pub fn whole_file(new: &[u8]) -> Self {
+        let mut delta = Delta::new();
+        if !new.is_empty() {
+            delta.add_block(new.to_vec());
+        }
+        delta
+    }
+
     pub fn diff(base: &[u8], new: &[u8], block_size: usize) -> Self {
-        use std::mem;
+        Self::diff_table(&IndexTable::from_bytes(base, block_size), new, block_size)
+    }
 
-        let mut index_table = IndexTable::new();
-
-        // Build index table from base file
-        let signer_base = WeakSignature::new(block_size, base.into());
-        if base.len() < block_size {
-            let strong = compute_strong_signature(base);
-            // store a dummy weak signature (e.g. hash of entire base)
-            let weak_val: i64 = base.iter().map(|&b| b as i64).sum::<i64>() % MODULUS;
-            let weak = WeakSignatureBlock::new(0, weak_val, weak_val, weak_val);
-            index_table.add(weak, strong, 0);
-        } else {
-            // Normal case: compute rolling weak + strong for each base block
-            let mut prev_hash: Option<WeakSignatureBlock> = None;
-            for (i, block) in base.chunks_exact(block_size).enumerate() {
-                if i == 0 {
-                    let sign = signer_base.sign(0);
-                    let strong = compute_strong_signature(block);
-                    index_table.add(sign.clone(), strong, 0);
-                    prev_hash = Some(sign);
-                } else {
-                    // roll from previous
-                    let rolling = signer_base.compute_next_signature(prev_hash.clone().unwrap());
-                    let strong = compute_strong_signature(block);
-                    index_table.add(rolling.clone(), strong, i);
-                    prev_hash = Some(rolling);
-                }
-            }
-        }
+    /// Computes the delta of `new` against a base known only through its signature table, such
+    /// as one received from the other side. `block_size` must be the one the table was built
+    /// with.
+    pub fn diff_table(index_table: &IndexTable, new: &[u8], block_size: usize) -> Self {
+        Self::scan(index_table, new, block_size, true)
+    }
 
+    /// Like [`Delta::diff_table`], but trusts weak signature matches without confirming them
+    /// with the strong signature.
+    ///
+    /// This is unsafe against collisions: two different blocks with the same weak signature
+    /// are treated as identical, silently corrupting the reconstructed file. Only use it where
+    /// speed matters more than correctness.
+    pub fn diff_table_weak_only(index_table: &IndexTable, new: &[u8], block_size: usize) -> Self {
+        Self::scan(index_table, new, block_size, false)
+    }
+
+    fn scan(index_table: &IndexTable, new: &[u8], block_size: usize, verify_strong: bool) -> Self {
+        use std::mem;
+
+        assert!(block_size > 0, "block size must be non-zero");
         let mut delta = Delta::new();
 
         // If the new file is shorter than block_size, nothing to roll — emit whole new as block.
@@ -158,26 +343,29 @@ impl Delta {
         let mut i: usize = 0;
 
         // Initialize prev_hash for position 0
-        let mut prev_hash: Option<WeakSignatureBlock> = Some(signer_new.sign(0));
+        let mut prev_hash: Option<WeakSignatureBlock> = signer_new.sign(0);
 
         // Slide while there is a full window
         while i + block_size <= new.len() {
-            // Ensure we have a hash for current position
-            let cur_hash = match prev_hash.clone() {
-                Some(h) => h,
-                None => {
-                    // If we don't have a prev_hash, compute it directly
-                    let s = signer_new.sign(i);
-                    prev_hash = Some(s.clone());
-                    s
-                }
+            // Ensure we have a hash for current position, computing it directly if needed
+            let Some(cur_hash) = prev_hash.clone().or_else(|| signer_new.sign(i)) else {
+                break;
             };
 
             // Check index table for weak match
-            if let Some((base_index, strong)) = index_table.find(cur_hash.get_signature()) {
-                // Verify with strong signature on the new window
-                let strong2 = compute_strong_signature(&new[i..i + block_size]);
-                if strong == strong2 {
+            if index_table.contains(cur_hash.get_signature()) {
+                // Verify with strong signature on the new window, picking the colliding block it matches
+                let base_index = if verify_strong {
+                    let strong = compute_strong_signature(&new[i..i + block_size]);
+                    index_table.find_verified(cur_hash.get_signature(), &strong)
+                } else {
+                    index_table
+                        .find(cur_hash.get_signature())
+                        .map(|(index, _)| index)
+                }
+                // A corrupt table can name blocks past the end of the base
+                .filter(|&index| index_table.in_bounds(index));
+                if let Some(base_index) = base_index {
                     // Found a match — flush any unmatched data first
                     if !unmatched_buffer.is_empty() {
                         delta.add_block(mem::take(&mut unmatched_buffer));
@@ -188,12 +376,8 @@ impl Delta {
                     // Jump forward by a full block
                     i += block_size;
 
-                    // If we still can produce full windows, set prev_hash to sign(i)
-                    if i + block_size <= new.len() {
-                        prev_hash = Some(signer_new.sign(i));
-                    } else {
-                        prev_hash = None;
-                    }
+                    // None once there is no full window left
+                    prev_hash = signer_new.sign(i);
                     continue;
                 }
             }
@@ -226,6 +410,33 @@ impl Delta {
 
         delta
     }
+
+    /// Computes a delta using content-defined chunks instead of fixed blocks.
+    pub fn diff_cdc(base: &[u8], new: &[u8], chunker: &FastCdc) -> Self {
+        Self::diff_chunks(&IndexTable::from_chunks(base, chunker), new, chunker)
+    }
+
+    /// Chunks `new` and matches each chunk against a table built with [`IndexTable::from_chunks`].
+    pub fn diff_chunks(index_table: &IndexTable, new: &[u8], chunker: &FastCdc) -> Self {
+        let mut delta = Delta::new();
+        let mut unmatched_buffer: Vec<u8> = Vec::new();
+        for range in chunker.chunks(new) {
+            let bytes = &new[range];
+            match index_table.find_chunk(&compute_strong_signature(bytes)) {
+                Some(chunk) => {
+                    if !unmatched_buffer.is_empty() {
+                        delta.add_block(std::mem::take(&mut unmatched_buffer));
+                    }
+                    delta.add_chunk(chunk);
+                }
+                None => unmatched_buffer.extend_from_slice(bytes),
+            }
+        }
+        if !unmatched_buffer.is_empty() {
+            delta.add_block(unmatched_buffer);
+        }
+        delta
+    }
 }
 
 impl IntoIterator for Delta {
@@ -236,3 +447,155 @@ impl IntoIterator for Delta {
         self.ops.into_iter()
     }
 }
+
+/// Writer that turns long zero runs into holes by seeking over them. Seeking past the end and
+/// writing again is valid on every filesystem; those without sparse file support simply
+/// allocate the zeros, which falls back to a dense file.
+pub struct SparseWriter<'a, W: Write + Seek> {
+    out: &'a mut W,
+    sparse: bool,
+    /// Zero bytes skipped but not yet accounted for in the output position
+    hole: u64,
+}
+
+impl<'a, W: Write + Seek> SparseWriter<'a, W> {
+    pub fn new(out: &'a mut W, sparse: bool) -> Self {
+        Self {
+            out,
+            sparse,
+            hole: 0,
+        }
+    }
+
+    /// Writes `data`, skipping zero runs of at least `SPARSE_MIN_RUN` bytes.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if !self.sparse {
+            return self.write_dense(data);
+        }
+        for chunk in data.chunks(SPARSE_MIN_RUN) {
+            if chunk.len() == SPARSE_MIN_RUN && chunk.iter().all(|&b| b == 0) {
+                self.hole += chunk.len() as u64;
+            } else {
+                self.write_dense(chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `data` as-is.
+    pub fn write_dense(&mut self, data: &[u8]) -> io::Result<()> {
+        self.skip_hole()?;
+        self.out.write_all(data)
+    }
+
+    /// Ends the output, writing a single trailing zero if it ends in a hole so the logical
+    /// length is right.
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.hole > 0 {
+            self.hole -= 1;
+            self.write_dense(&[0])?;
+        }
+        self.out.flush()
+    }
+
+    fn skip_hole(&mut self) -> io::Result<()> {
+        if self.hole > 0 {
+            self.out.seek(SeekFrom::Current(self.hole as i64))?;
+            self.hole = 0;
+        }
+        Ok(())
+    }
+}
+
+/// The region of a base of `base_len` bytes that an `Index` or `Chunk` op copies. The op may
+/// come from the other side, so offsets that overflow are invalid rather than wrapped.
+fn base_range(op: &Ops, block_size: usize, base_len: usize) -> io::Result<Range<usize>> {
+    match op {
+        Ops::Index(_) if block_size == 0 => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Block index in a delta applied with a block size of 0",
+        )),
+        Ops::Index(index) => match index.checked_mul(block_size) {
+            Some(start) if start < base_len => {
+                Ok(start..std::cmp::min(start.saturating_add(block_size), base_len))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid block index {} for base length {}", index, base_len),
+            )),
+        },
+        Ops::Chunk(chunk) => match chunk.offset.checked_add(chunk.len) {
+            Some(end) if end <= base_len => Ok(chunk.offset..end),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid chunk {}+{} for base length {}",
+                    chunk.offset, chunk.len, base_len
+                ),
+            )),
+        },
+        Ops::Block(_) | Ops::CompressedBlock(_) => {
+            unreachable!("literal blocks do not refer to the base")
+        }
+    }
+}
+
+fn inflate(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::decode_all(bytes).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid compressed block: {}", e),
+        )
+    })
+}
+
+/// Copies `range` of `base` to `out` at `pos`, returning the position after it.
+#[cfg(target_os = "linux")]
+fn copy_range(base: &File, out: &File, range: Range<usize>, pos: u64) -> io::Result<u64> {
+    use std::os::fd::AsRawFd;
+
+    let mut src = range.start as i64;
+    let mut dst = pos as i64;
+    let mut left = range.len();
+    while left > 0 {
+        // SAFETY: both descriptors are open for the duration of the call, and the kernel only
+        // writes back through the two offset pointers
+        let copied = unsafe {
+            libc::copy_file_range(
+                base.as_raw_fd(),
+                &mut src,
+                out.as_raw_fd(),
+                &mut dst,
+                left,
+                0,
+            )
+        };
+        match copied {
+            -1 => {
+                let e = io::Error::last_os_error();
+                return Err(match e.raw_os_error() {
+                    Some(libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP | libc::EINVAL) => {
+                        io::Error::new(io::ErrorKind::Unsupported, e)
+                    }
+                    _ => e,
+                });
+            }
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Base file shrank while copying from it",
+                ));
+            }
+            n => left -= n as usize,
+        }
+    }
+    Ok(dst as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_range(_base: &File, _out: &File, _range: Range<usize>, _pos: u64) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "copy_file_range is only available on Linux",
+    ))
+}
@@ -1,7 +1,7 @@
 use rustc_hash::FxHashMap as HashMap;
 use serde::{Deserialize, Serialize};
 
-use super::WeakSignatureBlock;
+use super::{FastCdc, MODULUS, WeakSignature, WeakSignatureBlock, compute_strong_signature};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
 pub struct IndexTableChunk {
@@ -9,16 +9,68 @@ pub struct IndexTableChunk {
     index: usize,
 }
 
+/// A variable-length region of the base file, as produced by content-defined chunking.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct ChunkRef {
+    pub offset: usize,
+    pub len: usize,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
 pub struct IndexTable {
-    map: HashMap<i64, IndexTableChunk>,
+    /// Weak signatures can collide, so each one maps to every block that produced it.
+    map: HashMap<i64, Vec<IndexTableChunk>>,
+    chunks: HashMap<String, ChunkRef>,
+    /// Number of blocks in the base the table was built over, when known. Block indices at or
+    /// past it cannot be applied, so matching ignores them.
+    block_count: Option<usize>,
 }
 
 impl IndexTable {
     pub fn new() -> Self {
         Self {
             map: HashMap::default(),
+            chunks: HashMap::default(),
+            block_count: None,
+        }
+    }
+    /// Builds a table over the fixed-size blocks of `base`, block `i` starting at
+    /// `i * block_size`. A trailing partial block is left out. A base shorter than one block
+    /// gets a single entry with index 0 under a placeholder weak signature, so it can still be
+    /// found by strong signature but never matches a rolling window.
+    pub fn from_bytes(base: &[u8], block_size: usize) -> Self {
+        let mut index_table = Self::new();
+
+        // Build index table from base file
+        let signer_base = WeakSignature::new(block_size, base.into());
+        if base.len() < block_size {
+            let strong = compute_strong_signature(base);
+            // store a dummy weak signature (e.g. hash of entire base)
+            let weak_val: i64 = base.iter().map(|&b| b as i64).sum::<i64>() % MODULUS;
+            let weak = WeakSignatureBlock::new(0, weak_val, weak_val, weak_val);
+            index_table.add(weak, strong, 0);
+            index_table.block_count = Some(1);
+        } else {
+            // Normal case: compute weak + strong signatures for each base block
+            for (i, block) in base.chunks_exact(block_size).enumerate() {
+                let sign = signer_base
+                    .sign(i * block_size)
+                    .expect("full block is in bounds");
+                let strong = compute_strong_signature(block);
+                index_table.add(sign, strong, i);
+            }
+            index_table.block_count = Some(base.len() / block_size);
+        }
+        index_table
+    }
+    /// Builds a table over the content-defined chunks of `base`, keyed by strong signature.
+    pub fn from_chunks(base: &[u8], chunker: &FastCdc) -> Self {
+        let mut table = Self::new();
+        for range in chunker.chunks(base) {
+            let strong = compute_strong_signature(&base[range.clone()]);
+            table.add_chunk(strong, range.start, range.len());
         }
+        table
     }
     pub fn add(
         &mut self,
@@ -26,24 +78,73 @@ impl IndexTable {
         strong_signature: String,
         index: usize,
     ) {
-        self.map.insert(
-            weak_signature.get_signature(),
-            IndexTableChunk {
-                strong_signature,
-                index,
-            },
-        );
+        let bucket = self.map.entry(weak_signature.get_signature()).or_default();
+        // Identical blocks only need to be referenced once
+        if bucket
+            .iter()
+            .any(|chunk| chunk.strong_signature == strong_signature)
+        {
+            return;
+        }
+        bucket.push(IndexTableChunk {
+            strong_signature,
+            index,
+        });
     }
+    /// Returns the first block registered under `signature`.
     pub fn find(&self, signature: i64) -> Option<(usize, String)> {
-        let chunk = self.map.get(&signature)?;
+        let chunk = self.map.get(&signature)?.first()?;
         Some((chunk.index, chunk.strong_signature.clone()))
     }
+    /// Number of fixed-size blocks plus content-defined chunks in the table.
+    pub fn len(&self) -> usize {
+        self.map.values().map(Vec::len).sum::<usize>() + self.chunks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty() && self.chunks.is_empty()
+    }
+    /// Whether block `index` lies within the base, always true when the base is unknown.
+    pub fn in_bounds(&self, index: usize) -> bool {
+        self.block_count.is_none_or(|count| index < count)
+    }
+    pub fn contains(&self, signature: i64) -> bool {
+        self.map.contains_key(&signature)
+    }
     pub fn find_index(&self, strong_signature: String) -> Option<usize> {
-        for (_, chunk) in self.map.iter() {
-            if chunk.strong_signature == strong_signature {
-                return Some(chunk.index);
-            }
+        self.map
+            .values()
+            .flatten()
+            .find(|chunk| chunk.strong_signature == strong_signature)
+            .map(|chunk| chunk.index)
+    }
+    /// Like [`IndexTable::find_index`], but only searches the blocks sharing `weak_signature`.
+    pub fn find_verified(&self, weak_signature: i64, strong_signature: &str) -> Option<usize> {
+        self.map
+            .get(&weak_signature)?
+            .iter()
+            .find(|chunk| chunk.strong_signature == strong_signature)
+            .map(|chunk| chunk.index)
+    }
+    pub fn add_chunk(&mut self, strong_signature: String, offset: usize, len: usize) {
+        self.chunks
+            .entry(strong_signature)
+            .or_insert(ChunkRef { offset, len });
+    }
+    pub fn find_chunk(&self, strong_signature: &str) -> Option<ChunkRef> {
+        self.chunks.get(strong_signature).copied()
+    }
+    /// Moves a table built over the part of a base from `offset` on to where that part lies in
+    /// the whole base. `offset` must be a multiple of `block_size` for block indices to stay
+    /// meaningful.
+    pub fn shifted(mut self, offset: usize, block_size: usize) -> Self {
+        let blocks = offset.checked_div(block_size).unwrap_or(0);
+        for chunk in self.map.values_mut().flatten() {
+            chunk.index += blocks;
+        }
+        for chunk in self.chunks.values_mut() {
+            chunk.offset += offset;
         }
-        None
+        self.block_count = self.block_count.map(|count| count + blocks);
+        self
     }
 }
@@ -1,12 +1,14 @@
 //! A large part of the cryptography is based on the work of https://github.com/bartols/rust_rsync.
 //! The code is licensed under the MIT license.
 
+mod cdc;
 mod delta;
 mod index_table;
 mod signatures;
 mod structs;
 #[cfg(test)]
 mod tests;
+pub use cdc::*;
 pub use delta::*;
 pub use index_table::*;
 pub use signatures::*;
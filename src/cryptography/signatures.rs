@@ -1,5 +1,9 @@
 use blake2::{Blake2s256, Digest};
-use std::fmt::Write;
+use std::{
+    fmt::Write,
+    io::{self, Read},
+    iter,
+};
 
 pub const MODULUS: i64 = 1 << 16;
 
@@ -22,8 +26,12 @@ impl WeakSignature {
         Self { block_size, data }
     }
 
-    pub fn sign(&self, offset: usize) -> WeakSignatureBlock {
-        let block = &self.data[offset..offset + self.block_size];
+    /// Signs the full block starting at `offset`. Returns `None` if fewer than `block_size`
+    /// bytes are left from there; a trailing partial block is never signed.
+    pub fn sign(&self, offset: usize) -> Option<WeakSignatureBlock> {
+        let block = self
+            .data
+            .get(offset..offset.checked_add(self.block_size)?)?;
 
         let r1 = block.iter().map(|&b| b as i64).sum::<i64>() % MODULUS;
 
@@ -35,7 +43,7 @@ impl WeakSignature {
             % MODULUS;
 
         let r = (r1 + MODULUS * r2) % (MODULUS * MODULUS);
-        WeakSignatureBlock::new(offset as u64, r, r1, r2)
+        Some(WeakSignatureBlock::new(offset as u64, r, r1, r2))
     }
 
     pub fn compute_next_signature(&self, prev: WeakSignatureBlock) -> WeakSignatureBlock {
@@ -61,6 +69,15 @@ impl WeakSignature {
 
         WeakSignatureBlock::new(new_offset, r, r1, r2)
     }
+
+    /// Every full window of the data, rolled forward one byte at a time from offset 0. Yields
+    /// nothing if the data is shorter than a block.
+    pub fn signatures(&self) -> impl Iterator<Item = WeakSignatureBlock> + '_ {
+        iter::successors(self.sign(0), move |prev| {
+            (prev.offset as usize + self.block_size < self.data.len())
+                .then(|| self.compute_next_signature(prev.clone()))
+        })
+    }
 }
 
 impl WeakSignatureBlock {
@@ -80,6 +97,24 @@ impl WeakSignatureBlock {
 pub fn compute_strong_signature(data: &[u8]) -> String {
     let mut hasher = Blake2s256::new();
     hasher.update(data);
+    hex(hasher)
+}
+
+/// [`compute_strong_signature`] of everything `reader` yields, without holding it in memory.
+pub fn compute_strong_signature_from<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut hasher = Blake2s256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(hex(hasher)),
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn hex(hasher: Blake2s256) -> String {
     let hash = hasher.finalize();
     let mut out = String::with_capacity(hash.len() * 2);
     for byte in hash {
@@ -13,4 +13,8 @@ impl OutputBlock {
     pub fn new(data: Vec<u8>) -> Self {
         Self { data }
     }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
 }
@@ -10,10 +10,118 @@ fn calc_rolling_hash() {
     let test_str = "abcdefghijklmnopqrstuvwxyz";
     let bytes = test_str.as_bytes();
     let signer = WeakSignature::new(2, bytes.into());
-    let hash_1 = signer.sign(0);
-    dbg!(hash_1.get_signature());
+    let hash_1 = signer.sign(0).unwrap();
     let hash_2 = signer.compute_next_signature(hash_1);
-    dbg!(hash_2.get_signature());
+    assert_eq!(
+        hash_2.get_signature(),
+        signer.sign(1).unwrap().get_signature()
+    );
+}
+
+/// Rolling a window forward must give the same signature as signing it from scratch.
+mod rolling {
+    use super::{WeakSignature, pseudo_random_bytes};
+    use pretty_assertions::assert_eq;
+
+    fn inputs() -> Vec<(&'static str, Vec<u8>)> {
+        vec![
+            (
+                "text",
+                b"the quick brown fox jumps over the lazy dog".repeat(4),
+            ),
+            ("repeated", vec![b'a'; 300]),
+            ("zeros", vec![0; 300]),
+            ("high bytes", vec![0xff; 300]),
+            (
+                "alternating",
+                (0..300)
+                    .map(|i| if i % 2 == 0 { 0 } else { 0xff })
+                    .collect(),
+            ),
+            ("random", pseudo_random_bytes(300, 11)),
+        ]
+    }
+
+    #[test]
+    fn rolled_signatures_match_signing_each_offset() {
+        for (name, data) in inputs() {
+            for block_size in [1, 2, 3, 7, 16, 64, 128] {
+                let signer = WeakSignature::new(block_size, data.clone().into());
+                for offset in 0..data.len() - block_size {
+                    let rolled = signer.compute_next_signature(signer.sign(offset).unwrap());
+                    let signed = signer.sign(offset + 1).unwrap();
+                    let context = (name, block_size, offset);
+                    assert_eq!(rolled.offset, signed.offset, "{:?}", context);
+                    assert_eq!(rolled.signature, signed.signature, "{:?}", context);
+                    assert_eq!(rolled.r1, signed.r1, "{:?}", context);
+                    assert_eq!(rolled.r2, signed.r2, "{:?}", context);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rolling_from_the_first_window_reaches_the_last() {
+        for (name, data) in inputs() {
+            for block_size in [1, 16, 128] {
+                let signer = WeakSignature::new(block_size, data.clone().into());
+                let mut rolled = signer.sign(0).unwrap();
+                for offset in 1..=data.len() - block_size {
+                    rolled = signer.compute_next_signature(rolled);
+                    let signed = signer.sign(offset).unwrap();
+                    let context = (name, block_size, offset);
+                    assert_eq!(rolled.offset, signed.offset, "{:?}", context);
+                    assert_eq!(rolled.signature, signed.signature, "{:?}", context);
+                    assert_eq!(
+                        (rolled.r1, rolled.r2),
+                        (signed.r1, signed.r2),
+                        "{:?}",
+                        context
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn the_last_window_does_not_roll_past_the_end() {
+        let data = pseudo_random_bytes(100, 3);
+        let signer = WeakSignature::new(10, data.into());
+        let last = signer.sign(90).unwrap();
+
+        let rolled = signer.compute_next_signature(last.clone());
+
+        assert_eq!(rolled.offset, 90);
+        assert_eq!(rolled.signature, last.signature);
+        assert!(signer.sign(91).is_none());
+    }
+}
+
+#[test]
+fn signatures_iterator_matches_manual_rolling() {
+    let bytes = b"the quick brown fox";
+    let signer = WeakSignature::new(4, bytes.to_vec().into());
+
+    let mut manual = vec![signer.sign(0).unwrap()];
+    while manual.len() < bytes.len() - 4 + 1 {
+        let prev = manual.last().unwrap().clone();
+        manual.push(signer.compute_next_signature(prev));
+    }
+
+    let rolled: Vec<_> = signer.signatures().collect();
+    let key = |s: &WeakSignatureBlock| (s.offset, s.signature, s.r1, s.r2);
+    assert_eq!(
+        rolled.iter().map(key).collect::<Vec<_>>(),
+        manual.iter().map(key).collect::<Vec<_>>()
+    );
+    assert_eq!(rolled.last().unwrap().offset, 15);
+    assert_eq!(key(rolled.last().unwrap()), key(&signer.sign(15).unwrap()));
+}
+
+#[test]
+fn signatures_iterator_is_empty_below_a_block() {
+    let signer = WeakSignature::new(8, b"short".to_vec().into());
+    assert_eq!(signer.signatures().count(), 0);
 }
 
 #[test]
@@ -23,7 +131,7 @@ fn find_item() {
     let test_str = "abcdefghijklmnopqrstuvwxyz";
     let bytes = test_str.as_bytes();
     let signer = WeakSignature::new(2, bytes.into());
-    let hash_1 = signer.sign(0);
+    let hash_1 = signer.sign(0).unwrap();
     sig.add(hash_1.clone(), "pippo".to_owned(), 0);
 
     assert_eq!(
@@ -204,3 +312,550 @@ fn test_diff_base_smaller_than_block() {
         "Should work when base is smaller than block size"
     );
 }
+
+/// Deterministic xorshift bytes, so chunk boundaries don't depend on the run.
+fn pseudo_random_bytes(len: usize, mut state: u64) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+#[test]
+fn cdc_chunks_cover_input_within_bounds() {
+    let data = pseudo_random_bytes(512 * 1024, 7);
+    let chunker = FastCdc::default();
+    let chunks: Vec<_> = chunker.chunks(&data).collect();
+
+    assert_eq!(chunks.first().unwrap().start, 0);
+    assert_eq!(chunks.last().unwrap().end, data.len());
+    for pair in chunks.windows(2) {
+        assert_eq!(pair[0].end, pair[1].start);
+    }
+    for chunk in &chunks[..chunks.len() - 1] {
+        assert!(chunk.len() >= chunker.min_size && chunk.len() <= chunker.max_size);
+    }
+}
+
+#[test]
+fn cdc_diff_and_apply_roundtrip() {
+    let base = pseudo_random_bytes(200 * 1024, 3);
+    let mut new = base.clone();
+    new.splice(50_000..50_010, b"replacement bytes".iter().copied());
+    new.extend_from_slice(b"trailing data");
+
+    let delta = Delta::diff_cdc(&base, &new, &FastCdc::default());
+    let reconstructed = delta.apply(&base, 0).expect("apply should succeed");
+
+    assert_eq!(reconstructed, new);
+}
+
+#[test]
+fn cdc_delta_is_smaller_than_fixed_after_insertion() {
+    let base = pseudo_random_bytes(1024 * 1024, 11);
+    let mut new = base.clone();
+    new.insert(1, b'!');
+    let chunker = FastCdc::default();
+    let block_size = 128;
+
+    let fixed = Delta::diff(&base, &new, block_size);
+    let cdc = Delta::diff_cdc(&base, &new, &chunker);
+
+    assert_eq!(fixed.apply(&base, block_size).unwrap(), new);
+    assert_eq!(cdc.apply(&base, block_size).unwrap(), new);
+
+    // Only the chunk containing the insertion is sent as literal data
+    let literal: usize = cdc
+        .ops
+        .iter()
+        .map(|op| match op {
+            Ops::Block(bytes) => bytes.len(),
+            _ => 0,
+        })
+        .sum();
+    assert!(literal <= chunker.max_size + 1);
+    assert!(cdc.ops.len() * 10 < fixed.ops.len());
+}
+
+#[test]
+fn cdc_chunk_out_of_range_returns_error() {
+    let mut delta = Delta::new();
+    delta.add_chunk(ChunkRef { offset: 4, len: 10 });
+
+    assert!(delta.apply(b"short", 0).is_err());
+}
+
+#[test]
+fn overflowing_base_references_return_errors() {
+    let base = pseudo_random_bytes(1024, 9);
+    let mut delta = Delta::new();
+    delta.add_index(usize::MAX);
+    let err = delta.apply(&base, 128).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    let mut delta = Delta::new();
+    delta.add_chunk(ChunkRef {
+        offset: usize::MAX,
+        len: 2,
+    });
+    let err = delta.apply(&base, 128).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn block_index_with_zero_block_size_returns_error() {
+    let mut delta = Delta::new();
+    delta.add_block(b"literal".to_vec());
+    delta.add_index(0);
+
+    let err = delta.apply(b"base data", 0).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    // Literal and chunk ops do not depend on the block size
+    delta.ops.pop();
+    delta.add_chunk(ChunkRef { offset: 0, len: 4 });
+    assert_eq!(delta.apply(b"base data", 0).unwrap(), b"literalbase");
+}
+
+#[test]
+fn fixed_diff_realigns_after_insertion() {
+    let base = pseudo_random_bytes(64 * 1024, 5);
+    let mut new = base.clone();
+    new.insert(1, b'!');
+    let block_size = 128;
+
+    let delta = Delta::diff(&base, &new, block_size);
+    let literal: usize = delta
+        .ops
+        .iter()
+        .map(|op| match op {
+            Ops::Block(bytes) => bytes.len(),
+            _ => 0,
+        })
+        .sum();
+
+    assert_eq!(literal, block_size + 1);
+    assert_eq!(delta.apply(&base, block_size).unwrap(), new);
+}
+
+#[test]
+fn weak_collisions_resolve_to_their_own_index() {
+    // Same byte sum and same weighted sum, so both blocks share a weak signature
+    let block_a = [0u8, 1, 1, 0];
+    let block_b = [1u8, 0, 0, 1];
+    let block_size = block_a.len();
+    let base = [block_a, block_b].concat();
+    let signer = WeakSignature::new(block_size, base.clone().into());
+    let weak_a = signer.sign(0).unwrap();
+    let weak_b = signer.sign(block_size).unwrap();
+    assert_eq!(weak_a.get_signature(), weak_b.get_signature());
+
+    let mut table = IndexTable::new();
+    table.add(weak_a.clone(), compute_strong_signature(&block_a), 0);
+    table.add(weak_b.clone(), compute_strong_signature(&block_b), 1);
+    let strong_a = compute_strong_signature(&block_a);
+    let strong_b = compute_strong_signature(&block_b);
+
+    assert_eq!(
+        table.find_verified(weak_a.get_signature(), &strong_a),
+        Some(0)
+    );
+    assert_eq!(
+        table.find_verified(weak_b.get_signature(), &strong_b),
+        Some(1)
+    );
+    assert_eq!(table.find_index(strong_b), Some(1));
+
+    let new = [block_b, block_a].concat();
+    let delta = Delta::diff(&base, &new, block_size);
+    assert_eq!(delta.dump(), "<b*1*><b*0*>");
+}
+
+#[test]
+fn compressed_blocks_roundtrip() {
+    let block_size = 64;
+    let base = pseudo_random_bytes(4 * block_size, 7);
+    let text = "fn main() {\n    println!(\"hello, world\");\n}\n".repeat(200);
+    let mut new = base[..2 * block_size].to_vec();
+    new.extend_from_slice(text.as_bytes());
+    new.extend_from_slice(&base[2 * block_size..]);
+
+    let mut delta = Delta::diff(&base, &new, block_size);
+    let raw = delta.clone();
+    delta.compress_blocks();
+
+    assert!(
+        delta
+            .ops
+            .iter()
+            .any(|op| matches!(op, Ops::CompressedBlock(bytes) if bytes.len() < text.len()))
+    );
+    assert_eq!(
+        delta
+            .ops
+            .iter()
+            .filter(|op| matches!(op, Ops::Index(_)))
+            .count(),
+        raw.ops
+            .iter()
+            .filter(|op| matches!(op, Ops::Index(_)))
+            .count()
+    );
+    assert_eq!(delta.apply(&base, block_size).unwrap(), new);
+    assert_eq!(raw.apply(&base, block_size).unwrap(), new);
+}
+
+#[test]
+fn corrupt_compressed_block_returns_error() {
+    let delta = Delta {
+        ops: vec![Ops::CompressedBlock(vec![0xff, 0xff, 0xff])],
+    };
+    assert!(delta.apply(&[], 16).is_err());
+}
+
+#[test]
+fn sparse_apply_leaves_holes_for_zero_runs() {
+    use std::os::unix::fs::MetadataExt;
+
+    let block_size = 64;
+    let base = pseudo_random_bytes(4 * block_size, 11);
+    let mut delta = Delta::new();
+    delta.add_index(0);
+    delta.add_block(vec![0u8; 64 * SPARSE_MIN_RUN]);
+    delta.add_index(1);
+    delta.add_block(vec![0u8; 16 * SPARSE_MIN_RUN]);
+    let expected = delta.apply(&base, block_size).unwrap();
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("sparse.img");
+    let mut file = File::create(&path).unwrap();
+    delta.apply_to(&base, block_size, &mut file, true).unwrap();
+    drop(file);
+
+    let metadata = fs::metadata(&path).unwrap();
+    assert_eq!(metadata.len(), expected.len() as u64);
+    assert_eq!(fs::read(&path).unwrap(), expected);
+    // Linux temp filesystems support holes; elsewhere only the contents are checked
+    if cfg!(target_os = "linux") {
+        assert!(metadata.blocks() * 512 < metadata.len());
+    }
+}
+
+#[test]
+fn dense_apply_to_matches_apply() {
+    let block_size = 64;
+    let base = pseudo_random_bytes(4 * block_size, 12);
+    let new = [
+        &base[..block_size],
+        &[0u8; 3 * SPARSE_MIN_RUN][..],
+        &base[2 * block_size..],
+    ]
+    .concat();
+    let delta = Delta::diff(&base, &new, block_size);
+
+    let mut out = std::io::Cursor::new(Vec::new());
+    delta.apply_to(&base, block_size, &mut out, false).unwrap();
+    assert_eq!(out.into_inner(), new);
+
+    let mut out = std::io::Cursor::new(Vec::new());
+    delta.apply_to(&base, block_size, &mut out, true).unwrap();
+    assert_eq!(out.into_inner(), new);
+}
+
+#[test]
+fn diff_against_received_table_matches_diff_against_base() {
+    let block_size = 32;
+    let base = pseudo_random_bytes(40 * block_size, 21);
+    let mut new = base.clone();
+    new.splice(300..300, *b"inserted");
+    new.truncate(new.len() - 5);
+    new[900] ^= 0x55;
+
+    // The receiver builds the table and ships it over the wire
+    let table = IndexTable::from_bytes(&base, block_size);
+    let encoded = bincode::serde::encode_to_vec(&table, bincode::config::standard()).unwrap();
+    let (received, _): (IndexTable, usize) =
+        bincode::serde::decode_from_slice(&encoded, bincode::config::standard()).unwrap();
+
+    let delta = Delta::diff_table(&received, &new, block_size);
+    assert_eq!(delta, Delta::diff(&base, &new, block_size));
+    assert_eq!(delta.apply(&base, block_size).unwrap(), new);
+}
+
+#[test]
+fn from_bytes_indexes_every_full_block() {
+    let block_size = 16;
+    let base = pseudo_random_bytes(4 * block_size + 5, 9);
+
+    let table = IndexTable::from_bytes(&base, block_size);
+
+    // The trailing 5 bytes do not make up a block
+    assert_eq!(table.len(), 4);
+    let signer = WeakSignature::new(block_size, base.clone().into());
+    for (i, block) in base.chunks_exact(block_size).enumerate() {
+        let weak = signer.sign(i * block_size).unwrap();
+        let strong = compute_strong_signature(block);
+        assert_eq!(table.find_verified(weak.get_signature(), &strong), Some(i));
+    }
+}
+
+#[test]
+fn from_bytes_keeps_a_short_base_as_a_single_entry() {
+    let base = b"short";
+
+    let table = IndexTable::from_bytes(base, 16);
+
+    assert_eq!(table.len(), 1);
+    assert_eq!(table.find_index(compute_strong_signature(base)), Some(0));
+    assert_eq!(IndexTable::from_bytes(b"", 16).len(), 1);
+}
+
+#[test]
+fn from_chunks_counts_its_chunks() {
+    let base = pseudo_random_bytes(64 * 1024, 3);
+    let chunker = FastCdc::default();
+
+    let table = IndexTable::from_chunks(&base, &chunker);
+
+    assert_eq!(table.len(), chunker.chunks(&base).count());
+    assert!(!table.is_empty());
+    assert!(IndexTable::from_chunks(b"", &chunker).is_empty());
+    assert_eq!(IndexTable::from_chunks(b"", &chunker).len(), 0);
+}
+
+#[test]
+fn sign_near_the_end_returns_none_instead_of_panicking() {
+    let signer = WeakSignature::new(4, b"abcdefghij".to_vec().into());
+
+    assert_eq!(signer.sign(6).map(|s| s.offset), Some(6));
+    assert!(signer.sign(7).is_none());
+    assert!(signer.sign(10).is_none());
+    assert!(signer.sign(usize::MAX).is_none());
+}
+
+#[test]
+fn weak_only_delta_reconstructs_without_collisions() {
+    let block_size = 64;
+    let base = pseudo_random_bytes(64 * 1024, 5);
+    let mut new = base.clone();
+    new.splice(10_000..10_000, *b"inserted");
+    new[40_000] ^= 0x01;
+    let table = IndexTable::from_bytes(&base, block_size);
+
+    let weak_only = Delta::diff_table_weak_only(&table, &new, block_size);
+
+    assert_eq!(weak_only.apply(&base, block_size).unwrap(), new);
+    assert_eq!(weak_only, Delta::diff_table(&table, &new, block_size));
+}
+
+#[test]
+#[ignore] // timing-based, run manually with `cargo test --release -- --ignored`
+fn weak_only_is_cheaper_than_verified() {
+    use std::time::Instant;
+
+    let block_size = 1024;
+    let base = pseudo_random_bytes(32 * 1024 * 1024, 13);
+    let table = IndexTable::from_bytes(&base, block_size);
+
+    let start = Instant::now();
+    let verified = Delta::diff_table(&table, &base, block_size);
+    let verified_time = start.elapsed();
+    let start = Instant::now();
+    let weak_only = Delta::diff_table_weak_only(&table, &base, block_size);
+    let weak_only_time = start.elapsed();
+
+    println!(
+        "verified: {:?}, weak only: {:?}",
+        verified_time, weak_only_time
+    );
+    assert_eq!(weak_only.apply(&base, block_size).unwrap(), base);
+    assert_eq!(verified.ops.len(), weak_only.ops.len());
+    assert!(weak_only_time < verified_time);
+}
+
+#[test]
+fn transfer_size_estimates_the_serialized_delta() {
+    let block_size = 64;
+    let base = pseudo_random_bytes(64 * 1024, 17);
+    let mut new = base.clone();
+    new.splice(1_000..1_000, pseudo_random_bytes(300, 2));
+    new.splice(30_000..30_500, pseudo_random_bytes(5_000, 4));
+
+    let delta = Delta::diff(&base, &new, block_size);
+    let serialized = bincode::serde::encode_to_vec(&delta, bincode::config::standard())
+        .unwrap()
+        .len();
+
+    let estimate = delta.transfer_size();
+    assert!(estimate >= delta.literal_size());
+    assert!(serialized.abs_diff(estimate) <= delta.ops.len() * OP_OVERHEAD);
+    let reuse = delta.reuse_ratio(new.len());
+    assert!(reuse > 0.9 && reuse < 1.0, "{}", reuse);
+    assert_eq!(Delta::new().reuse_ratio(0), 1.0);
+}
+
+#[test]
+fn delta_chunks_apply_to_the_same_file() {
+    let block_size = 64;
+    let base = pseudo_random_bytes(64 * 1024, 17);
+    let mut new = base.clone();
+    new.splice(1_000..1_000, pseudo_random_bytes(300, 2));
+    new.splice(30_000..30_500, pseudo_random_bytes(5_000, 4));
+    let delta = Delta::diff(&base, &new, block_size);
+
+    let chunks: Vec<_> = delta.chunks(1024).collect();
+
+    assert!(chunks.len() > 1);
+    assert!(
+        chunks
+            .iter()
+            .all(|ops| Delta { ops: ops.clone() }.transfer_size() <= 1024)
+    );
+    let mut out = std::io::Cursor::new(Vec::new());
+    for ops in chunks {
+        Delta { ops }
+            .apply_to(&base, block_size, &mut out, false)
+            .unwrap();
+    }
+    assert_eq!(out.into_inner(), new);
+    assert_eq!(Delta::new().chunks(1024).count(), 0);
+}
+
+#[test]
+fn shifted_tables_refer_to_the_whole_base() {
+    let base = pseudo_random_bytes(1024, 5);
+    let tail = &base[256..];
+
+    let table = IndexTable::from_bytes(tail, 128).shifted(256, 128);
+    let delta = Delta::diff_table(&table, tail, 128);
+
+    assert_eq!(delta.ops.first(), Some(&Ops::Index(2)));
+    assert_eq!(delta.apply(&base, 128).unwrap(), tail);
+    let chunks = IndexTable::from_chunks(tail, &FastCdc::default()).shifted(256, 128);
+    let delta = Delta::diff_chunks(&chunks, tail, &FastCdc::default());
+    assert_eq!(delta.apply(&base, 128).unwrap(), tail);
+}
+
+#[test]
+fn literal_regions_locate_unmatched_bytes() {
+    let base = b"aaaabbbbccccdddd";
+    let new = b"aaaaXXbbbbccccYdddd";
+    let delta = Delta::diff(base, new, 4);
+
+    let regions = delta.literal_regions(4);
+
+    assert_eq!(regions, vec![(4, 6), (14, 15)]);
+    let rebuilt = delta.apply(base, 4).unwrap();
+    assert_eq!(rebuilt, new);
+    assert_eq!(&rebuilt[4..6], b"XX");
+    assert_eq!(&rebuilt[14..15], b"Y");
+}
+
+#[test]
+fn literal_regions_count_compressed_blocks_uncompressed() {
+    let mut delta = Delta::new();
+    delta.add_index(0);
+    delta.add_block(vec![0u8; 256]);
+    delta.compress_blocks();
+
+    assert!(matches!(delta.ops[1], Ops::CompressedBlock(_)));
+    assert_eq!(delta.literal_regions(8), vec![(8, 264)]);
+}
+
+#[test]
+fn whole_file_rebuilds_new_against_any_base() {
+    let new = b"entirely new contents".to_vec();
+
+    for base in [&b""[..], b"entirely old contents", &[0u8; 4096]] {
+        let delta = Delta::whole_file(&new);
+        assert_eq!(delta.ops, vec![Ops::Block(new.clone())]);
+        assert_eq!(delta.apply(base, 4).unwrap(), new);
+    }
+    assert!(Delta::whole_file(b"").ops.is_empty());
+}
+
+#[test]
+fn rsync_delta_roundtrips_random_edits() {
+    for seed in 1..=64u64 {
+        let mut rng = pseudo_random_bytes(8, seed).into_iter().cycle();
+        let base = pseudo_random_bytes(seed as usize * 97, seed);
+        let mut new = base.clone();
+        // A few random replacements, insertions and truncations
+        for _ in 0..rng.next().unwrap() % 5 {
+            let at = rng.next().unwrap() as usize * new.len() / 256;
+            let len = rng.next().unwrap() as usize % 32;
+            let end = (at + len).min(new.len());
+            let insert = pseudo_random_bytes(rng.next().unwrap() as usize % 48, seed + 1000);
+            new.splice(at..end, insert);
+        }
+        let block_size = 1 + rng.next().unwrap() as usize % 64;
+
+        let delta = rsync_delta(&base, &new, block_size);
+        assert_eq!(
+            delta.apply(&base, block_size).unwrap(),
+            new,
+            "seed {} block size {}",
+            seed,
+            block_size
+        );
+    }
+}
+
+#[test]
+fn out_of_range_table_entries_are_ignored() {
+    let block_size = 8;
+    let base = pseudo_random_bytes(4 * block_size, 31);
+    let extra = pseudo_random_bytes(block_size, 32);
+    let mut table = IndexTable::from_bytes(&base, block_size);
+    // Claims a block of `new` lives far past the end of the base
+    let signer = WeakSignature::new(block_size, extra.clone().into());
+    table.add(
+        signer.sign(0).unwrap(),
+        compute_strong_signature(&extra),
+        99,
+    );
+    assert!(!table.in_bounds(99));
+
+    let new = [&base[..block_size], &extra[..]].concat();
+    let delta = Delta::diff_table(&table, &new, block_size);
+
+    assert!(!delta.ops.contains(&Ops::Index(99)), "{:?}", delta.ops);
+    assert_eq!(delta.apply(&base, block_size).unwrap(), new);
+}
+
+#[test]
+fn cloned_apply_matches_apply() {
+    let block_size = 128;
+    let base = pseudo_random_bytes(64 * 1024, 41);
+    let mut new = base.clone();
+    new.splice(10_000..10_050, b"edited in the middle".iter().copied());
+    new.extend_from_slice(&base[..4096]);
+    let mut delta = Delta::diff(&base, &new, block_size);
+    delta.compress_blocks();
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("base"), &base).unwrap();
+    let base_file = std::fs::File::open(dir.path().join("base")).unwrap();
+    let out = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dir.path().join("out"))
+        .unwrap();
+    // Leftovers past the end of the result are truncated away
+    out.set_len(2 * new.len() as u64).unwrap();
+    match delta.apply_cloned(&base_file, block_size, &out) {
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+            eprintln!("skipping, no copy_file_range here: {}", e);
+            return;
+        }
+        result => result.unwrap(),
+    }
+
+    assert_eq!(std::fs::read(dir.path().join("out")).unwrap(), new);
+    assert_eq!(delta.apply(&base, block_size).unwrap(), new);
+}
@@ -0,0 +1,496 @@
+//! The filesystem operations the sending and receiving sides go through, so a sync can run
+//! against something other than the local disk.
+//!
+//! [`RealFs`] is the default everywhere. [`MemFs`] keeps a whole tree in memory, which makes
+//! tests fast and hermetic. Features that only make sense on a real disk (`.oxideignore` files,
+//! ownership and reflinks) still use `std::fs` directly, as does the signature cache for the
+//! tables it stores.
+
+mod structs;
+#[cfg(test)]
+mod tests;
+
+pub use structs::*;
+
+use std::{
+    ffi::CString,
+    fs::{self, File},
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    cli::ClientServerOpts,
+    flist,
+    pipeline::{FlistEntry, SpecialFile, TransferStats},
+};
+
+/// Mode of the regular files [`MemFs`] creates. Spelled out rather than built from
+/// `libc::S_IFREG` and `libc::S_IFDIR`, which are `u16` on macOS.
+const MEM_FILE_MODE: u32 = 0o100644;
+const MEM_DIR_MODE: u32 = 0o040755;
+
+pub trait FileSystem: Send + Sync {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>>;
+    /// Creates `path`, or truncates it if it exists. Its parent must exist.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteSeek>>;
+    /// The paths of the entries directly inside the directory `path`.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+    fn metadata(&self, path: &Path) -> io::Result<FsMetadata>;
+    /// Like [`FileSystem::metadata`], but about a symlink itself rather than what it points at.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<FsMetadata> {
+        self.metadata(path)
+    }
+    /// Sets the permission bits of `path` to those of `mode`.
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()>;
+    fn set_modified(&self, path: &Path, mtime: SystemTime) -> io::Result<()>;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    /// Removes the directory `path`, which must be empty.
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+    /// Makes `link` another name for the file `target`.
+    fn hard_link(&self, target: &Path, link: &Path) -> io::Result<()>;
+    /// Creates a `special` node at `path` with the permission bits of `mode`.
+    fn make_special(&self, path: &Path, special: SpecialFile, mode: u32) -> io::Result<()>;
+
+    /// The absolute path of `path` with symlinks resolved, or `path` itself where there are
+    /// none.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(path.to_path_buf())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        self.open(path)?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|metadata| metadata.is_dir)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|metadata| !metadata.is_dir)
+    }
+
+    /// Lists the files below `root` to send, see [`flist::build_in`].
+    fn build_flist(
+        &self,
+        root: &Path,
+        opts: &ClientServerOpts,
+        stats: &mut TransferStats,
+    ) -> io::Result<Vec<FlistEntry>> {
+        flist::build_in(self, root, opts, stats)
+    }
+}
+
+/// A file being written through [`FileSystem::create`].
+pub trait WriteSeek: Write + Seek + Send {
+    /// The file on disk behind this, for what only a real file supports.
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+impl WriteSeek for File {
+    fn as_file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+impl WriteSeek for MemFile {}
+
+impl From<&fs::Metadata> for FsMetadata {
+    fn from(metadata: &fs::Metadata) -> Self {
+        Self {
+            is_dir: metadata.is_dir(),
+            len: metadata.len(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        }
+    }
+}
+
+impl FileSystem for RealFs {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteSeek>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FsMetadata> {
+        Ok((&fs::metadata(path)?).into())
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<FsMetadata> {
+        Ok((&fs::symlink_metadata(path)?).into())
+    }
+
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))
+    }
+
+    fn set_modified(&self, path: &Path, mtime: SystemTime) -> io::Result<()> {
+        File::options().write(true).open(path)?.set_modified(mtime)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn hard_link(&self, target: &Path, link: &Path) -> io::Result<()> {
+        fs::hard_link(target, link)
+    }
+
+    fn make_special(&self, path: &Path, special: SpecialFile, mode: u32) -> io::Result<()> {
+        // `makedev` takes signed numbers and `mode_t` is 16 bits on some platforms
+        let makedev = |major: u32, minor: u32| libc::makedev(major as _, minor as _);
+        let (file_type, dev) = match special {
+            SpecialFile::Fifo => (libc::S_IFIFO, 0),
+            SpecialFile::CharDevice { major, minor } => (libc::S_IFCHR, makedev(major, minor)),
+            SpecialFile::BlockDevice { major, minor } => (libc::S_IFBLK, makedev(major, minor)),
+        };
+        let mode = file_type | (mode & 0o7777) as libc::mode_t;
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        // SAFETY: `path` is a valid NUL-terminated string for the duration of the call.
+        let rc = unsafe { libc::mknod(path.as_ptr(), mode, dev) };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    /// Walks with [`flist::build`], which also honors ignore files and lists hard links and
+    /// special files.
+    fn build_flist(
+        &self,
+        root: &Path,
+        opts: &ClientServerOpts,
+        stats: &mut TransferStats,
+    ) -> io::Result<Vec<FlistEntry>> {
+        flist::build(root, opts, stats)
+    }
+}
+
+impl MemFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes `contents` to `path`, creating its parent directories.
+    pub fn write(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        self.create(path)?.write_all(contents.as_ref())
+    }
+
+    fn file(&self, path: &Path) -> io::Result<Arc<Mutex<Vec<u8>>>> {
+        match self.nodes.lock().unwrap().get(path) {
+            Some(MemNode::File { data, .. }) => Ok(data.clone()),
+            Some(MemNode::Dir) => Err(is_a_directory(path)),
+            None => Err(not_found(path)),
+        }
+    }
+
+    /// Whether `path` is a directory, counting the root that every tree has.
+    fn has_dir(&self, path: &Path) -> bool {
+        path.parent().is_none()
+            || path.as_os_str().is_empty()
+            || matches!(self.nodes.lock().unwrap().get(path), Some(MemNode::Dir))
+    }
+
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if !self.has_dir(parent) => Err(not_found(parent)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FileSystem for MemFs {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let contents = self.file(path)?.lock().unwrap().clone();
+        Ok(Box::new(Cursor::new(contents)))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteSeek>> {
+        self.check_parent(path)?;
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let mut nodes = self.nodes.lock().unwrap();
+        let mode = match nodes.get(path) {
+            Some(MemNode::Dir) => return Err(is_a_directory(path)),
+            Some(MemNode::File { mode, .. }) => *mode,
+            None => MEM_FILE_MODE,
+        };
+        nodes.insert(
+            path.to_path_buf(),
+            MemNode::File {
+                data: data.clone(),
+                mode,
+                mtime: SystemTime::now(),
+            },
+        );
+        Ok(Box::new(MemFile { data, pos: 0 }))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        if !self.has_dir(path) {
+            return Err(not_found(path));
+        }
+        Ok(self
+            .nodes
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|child| child.parent() == Some(path))
+            .cloned()
+            .collect())
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FsMetadata> {
+        if self.has_dir(path) {
+            return Ok(FsMetadata {
+                is_dir: true,
+                len: 0,
+                mtime: 0,
+                mtime_nsec: 0,
+                mode: MEM_DIR_MODE,
+                uid: 0,
+                gid: 0,
+            });
+        }
+        match self.nodes.lock().unwrap().get(path) {
+            Some(MemNode::File { data, mode, mtime }) => Ok(FsMetadata {
+                is_dir: false,
+                len: data.lock().unwrap().len() as u64,
+                mtime: unix_seconds(*mtime),
+                mtime_nsec: i64::from(
+                    mtime
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |since| since.subsec_nanos()),
+                ),
+                mode: *mode,
+                uid: 0,
+                gid: 0,
+            }),
+            _ => Err(not_found(path)),
+        }
+    }
+
+    fn set_permissions(&self, path: &Path, new_mode: u32) -> io::Result<()> {
+        match self.nodes.lock().unwrap().get_mut(path) {
+            Some(MemNode::File { mode, .. }) => {
+                *mode = (*mode & !0o7777) | (new_mode & 0o7777);
+                Ok(())
+            }
+            Some(MemNode::Dir) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn set_modified(&self, path: &Path, new_mtime: SystemTime) -> io::Result<()> {
+        match self.nodes.lock().unwrap().get_mut(path) {
+            Some(MemNode::File { mtime, .. }) => {
+                *mtime = new_mtime;
+                Ok(())
+            }
+            Some(MemNode::Dir) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        for dir in path.ancestors().filter(|dir| dir.parent().is_some()) {
+            match nodes.get(dir) {
+                Some(MemNode::File { .. }) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotADirectory,
+                        format!("{:?} is a file", dir),
+                    ));
+                }
+                Some(MemNode::Dir) => {}
+                None => {
+                    nodes.insert(dir.to_path_buf(), MemNode::Dir);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check_parent(to)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get(from) {
+            Some(MemNode::File { .. }) => {}
+            Some(MemNode::Dir) => return Err(is_a_directory(from)),
+            None => return Err(not_found(from)),
+        }
+        if let Some(MemNode::Dir) = nodes.get(to) {
+            return Err(is_a_directory(to));
+        }
+        let node = nodes.remove(from).expect("checked above");
+        nodes.insert(to.to_path_buf(), node);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get(path) {
+            Some(MemNode::File { .. }) => {
+                nodes.remove(path);
+                Ok(())
+            }
+            Some(MemNode::Dir) => Err(is_a_directory(path)),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get(path) {
+            Some(MemNode::Dir) if nodes.keys().any(|child| child.parent() == Some(path)) => {
+                Err(io::Error::new(
+                    io::ErrorKind::DirectoryNotEmpty,
+                    format!("{:?} is not empty", path),
+                ))
+            }
+            Some(MemNode::Dir) => {
+                nodes.remove(path);
+                Ok(())
+            }
+            Some(MemNode::File { .. }) => Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{:?} is a file", path),
+            )),
+            None => Err(not_found(path)),
+        }
+    }
+
+    /// The link shares the contents of `target`, as on disk.
+    fn hard_link(&self, target: &Path, link: &Path) -> io::Result<()> {
+        self.check_parent(link)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = match nodes.get(target) {
+            Some(node @ MemNode::File { .. }) => node.clone(),
+            Some(MemNode::Dir) => return Err(is_a_directory(target)),
+            None => return Err(not_found(target)),
+        };
+        if nodes.contains_key(link) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists", link),
+            ));
+        }
+        nodes.insert(link.to_path_buf(), node);
+        Ok(())
+    }
+
+    fn make_special(&self, path: &Path, _: SpecialFile, _: u32) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot create the special file {:?} in memory", path),
+        ))
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = self.data.lock().unwrap();
+        let start = self.pos as usize;
+        let end = start + buf.len();
+        // Writing past the end leaves zeros in between, like a hole in a real file
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        self.pos = end as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.data.lock().unwrap().len() as i64;
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset as i64),
+            SeekFrom::End(offset) => len.checked_add(offset),
+            SeekFrom::Current(offset) => (self.pos as i64).checked_add(offset),
+        };
+        match pos {
+            Some(pos) if pos >= 0 => {
+                self.pos = pos as u64;
+                Ok(self.pos)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the file",
+            )),
+        }
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{:?} does not exist", path),
+    )
+}
+
+fn is_a_directory(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::IsADirectory,
+        format!("{:?} is a directory", path),
+    )
+}
@@ -0,0 +1,55 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// What [`super::FileSystem::metadata`] reports about a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsMetadata {
+    pub is_dir: bool,
+    pub len: u64,
+    /// Seconds since the Unix epoch
+    pub mtime: i64,
+    /// Nanoseconds on top of `mtime`
+    pub mtime_nsec: i64,
+    /// File type and permission bits, as in `st_mode`
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl FsMetadata {
+    /// Whether this is a regular file, rather than a directory, symlink or special file.
+    pub fn is_file(&self) -> bool {
+        self.mode & 0o170000 == 0o100000
+    }
+}
+
+/// The local filesystem, through `std::fs`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFs;
+
+/// A filesystem held entirely in memory, for hermetic tests. Clones share the same tree.
+#[derive(Debug, Clone, Default)]
+pub struct MemFs {
+    pub(super) nodes: Arc<Mutex<BTreeMap<PathBuf, MemNode>>>,
+}
+
+#[derive(Debug, Clone)]
+pub(super) enum MemNode {
+    Dir,
+    File {
+        data: Arc<Mutex<Vec<u8>>>,
+        mode: u32,
+        mtime: SystemTime,
+    },
+}
+
+/// A file of a [`MemFs`] opened for writing.
+#[derive(Debug)]
+pub struct MemFile {
+    pub(super) data: Arc<Mutex<Vec<u8>>>,
+    pub(super) pos: u64,
+}
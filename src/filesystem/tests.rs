@@ -0,0 +1,75 @@
+use super::*;
+use pretty_assertions::assert_eq;
+
+#[test]
+fn mem_files_are_written_read_and_renamed() {
+    let fs = MemFs::new();
+    fs.write("/root/dir/a.txt", b"alpha").unwrap();
+
+    assert_eq!(fs.read(Path::new("/root/dir/a.txt")).unwrap(), b"alpha");
+    assert!(fs.is_dir(Path::new("/root/dir")));
+    let metadata = fs.metadata(Path::new("/root/dir/a.txt")).unwrap();
+    assert_eq!((metadata.is_dir, metadata.len), (false, 5));
+
+    fs.rename(Path::new("/root/dir/a.txt"), Path::new("/root/b.txt"))
+        .unwrap();
+    assert_eq!(
+        fs.read_dir(Path::new("/root")).unwrap(),
+        [PathBuf::from("/root/b.txt"), PathBuf::from("/root/dir")]
+    );
+    assert_eq!(
+        fs.read(Path::new("/root/dir/a.txt")).unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn mem_files_need_their_parent_directory() {
+    let fs = MemFs::new();
+    assert_eq!(
+        fs.create(Path::new("/missing/a.txt")).err().unwrap().kind(),
+        io::ErrorKind::NotFound
+    );
+    fs.write("/file", b"").unwrap();
+    assert!(fs.create_dir_all(Path::new("/file/nested")).is_err());
+}
+
+#[test]
+fn seeking_past_the_end_of_a_mem_file_leaves_zeros() {
+    let fs = MemFs::new();
+    let mut file = fs.create(Path::new("/sparse")).unwrap();
+    file.write_all(b"ab").unwrap();
+    file.seek(SeekFrom::Current(3)).unwrap();
+    file.write_all(b"c").unwrap();
+    drop(file);
+
+    assert_eq!(fs.read(Path::new("/sparse")).unwrap(), b"ab\0\0\0c");
+}
+
+#[test]
+fn mem_permissions_keep_the_file_type() {
+    let fs = MemFs::new();
+    fs.write("/a", b"").unwrap();
+    fs.set_permissions(Path::new("/a"), 0o100600).unwrap();
+    let mtime = UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+    fs.set_modified(Path::new("/a"), mtime).unwrap();
+
+    let metadata = fs.metadata(Path::new("/a")).unwrap();
+    assert_eq!(metadata.mode, 0o100600);
+    assert_eq!(metadata.mtime, 1_700_000_000);
+}
+
+#[test]
+fn real_fs_matches_std() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a.txt");
+    RealFs.create(&path).unwrap().write_all(b"alpha").unwrap();
+
+    assert_eq!(RealFs.read(&path).unwrap(), fs::read(&path).unwrap());
+    assert_eq!(RealFs.read_dir(dir.path()).unwrap(), [path.as_path()]);
+    assert_eq!(
+        RealFs.metadata(&path).unwrap(),
+        FsMetadata::from(&fs::metadata(&path).unwrap())
+    );
+    assert!(RealFs.create(&path).unwrap().as_file().is_some());
+}
@@ -0,0 +1,416 @@
+//! Building the file list the sending side advertises to the receiving side.
+
+mod owner;
+#[cfg(test)]
+mod tests;
+
+pub use owner::*;
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, FileType, Metadata, read_dir},
+    io::{self, BufReader},
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use globset::Glob;
+use ignore::WalkBuilder;
+use tracing::{info, warn};
+
+use crate::{
+    cli::ClientServerOpts,
+    cryptography::compute_strong_signature_from,
+    filesystem::{FileSystem, FsMetadata, RealFs},
+    pipeline::{FlistEntry, SpecialFile, TransferStats},
+};
+
+/// Name of the gitignore-style file honored in every directory of a recursive walk.
+pub const IGNORE_FILENAME: &str = ".oxideignore";
+
+/// Collects the entries below `root`, indexed in the order they will be sent. Filenames are
+/// relative to `root`, see [`name_base`]; entries filtered out by size or age are counted in
+/// `stats`. With `opts.hard_links`, later paths to an inode already listed point back at its
+/// first entry. With `opts.sort`, entries are indexed in byte order of their filenames.
+pub fn build(
+    root: &Path,
+    opts: &ClientServerOpts,
+    stats: &mut TransferStats,
+) -> io::Result<Vec<FlistEntry>> {
+    let base = name_base(&RealFs, root, opts);
+    let mut files = if let Some(paths) = &opts.files_from {
+        listed(root, &base, paths, opts)
+    } else if opts.recursive || !root.is_dir() {
+        walk(root, &base, opts)
+    } else {
+        list_dir(root, &base, opts)?
+    };
+    filter_and_sort(&mut files, opts, stats);
+
+    let mut inodes = HashMap::new();
+    let mut names = Names::default();
+    Ok(files
+        .into_iter()
+        .zip(0..)
+        .map(|((entry, metadata), index)| {
+            let hardlink_to = if opts.hard_links && !entry.is_dir && metadata.nlink() > 1 {
+                let first = *inodes
+                    .entry((metadata.dev(), metadata.ino()))
+                    .or_insert(index);
+                (first != index).then_some(first)
+            } else {
+                None
+            };
+            let (user, group) = if opts.numeric_ids {
+                (None, None)
+            } else {
+                (names.user(entry.uid), names.group(entry.gid))
+            };
+            let checksum = (opts.flist_checksums && entry.is_regular())
+                .then(|| checksum(&base.join(&entry.filename)))
+                .flatten();
+            FlistEntry {
+                index,
+                hardlink_to,
+                user,
+                group,
+                checksum,
+                ..entry
+            }
+        })
+        .collect())
+}
+
+/// [`build`] for any [`FileSystem`]: a plain walk with `read_dir`, honoring the excludes, size
+/// and age limits, `opts.max_depth` and `opts.sort`, but not ignore files, hard links or
+/// special files.
+pub fn build_in<F: FileSystem + ?Sized>(
+    fs: &F,
+    root: &Path,
+    opts: &ClientServerOpts,
+    stats: &mut TransferStats,
+) -> io::Result<Vec<FlistEntry>> {
+    let base = name_base(fs, root, opts);
+    let mut files = Vec::new();
+    if let Some(paths) = &opts.files_from {
+        for relative in paths {
+            let path = root.join(relative);
+            match fs.metadata(&path) {
+                Ok(metadata) if !is_excluded(opts, &path) => {
+                    files.push((fs_entry(&base, &path, &metadata), ()))
+                }
+                Ok(_) => info!("skipping {:?}", path),
+                Err(e) => warn!("skipping listed path {:?}: {}", relative, e),
+            }
+        }
+    } else {
+        let max_depth = match opts.max_depth {
+            _ if !opts.recursive => Some(1),
+            depth => depth,
+        };
+        walk_in(fs, root, &base, opts, max_depth, &mut files)?;
+    }
+    filter_and_sort(&mut files, opts, stats);
+    Ok(files
+        .into_iter()
+        .zip(0..)
+        .map(|((entry, ()), index)| {
+            let checksum = (opts.flist_checksums && entry.is_regular())
+                .then(|| fs.open(&base.join(&entry.filename)))
+                .and_then(|file| checksum_of(file, &entry.filename));
+            FlistEntry {
+                index,
+                checksum,
+                ..entry
+            }
+        })
+        .collect())
+}
+
+/// Collects the files below `path`, descending at most `depth` directories.
+fn walk_in<F: FileSystem + ?Sized>(
+    fs: &F,
+    path: &Path,
+    base: &Path,
+    opts: &ClientServerOpts,
+    depth: Option<usize>,
+    files: &mut Vec<(FlistEntry, ())>,
+) -> io::Result<()> {
+    let metadata = fs.metadata(path)?;
+    if !metadata.is_dir {
+        files.push((fs_entry(base, path, &metadata), ()));
+        return Ok(());
+    }
+    if depth == Some(0) {
+        return Ok(());
+    }
+    for child in fs.read_dir(path)? {
+        if is_excluded(opts, &child) {
+            info!("skipping {:?}", child);
+            continue;
+        }
+        walk_in(fs, &child, base, opts, depth.map(|d| d - 1), files)?;
+    }
+    Ok(())
+}
+
+/// Drops the regular files outside the size and age limits, counting them in `stats`, and
+/// sorts the rest with `opts.sort`.
+fn filter_and_sort<M>(
+    files: &mut Vec<(FlistEntry, M)>,
+    opts: &ClientServerOpts,
+    stats: &mut TransferStats,
+) {
+    let total = files.len();
+    files.retain(|(e, _)| !e.is_regular() || in_size_range(opts, e.size));
+    stats.excluded_by_size += (total - files.len()) as u32;
+    let total = files.len();
+    let now = SystemTime::now();
+    files.retain(|(e, _)| !e.is_regular() || in_age_range(opts, e.mtime, now));
+    stats.excluded_by_age += (total - files.len()) as u32;
+    if opts.sort {
+        files.sort_by(|(a, _), (b, _)| a.filename.cmp(&b.filename));
+    }
+}
+
+/// The path flist filenames are made relative to. That is `root` itself, or its parent when
+/// `root` is a single file. With `opts.relative` only the leading `/`, `.` or `..` components
+/// of `root` are dropped, so the filenames keep the directories the source was named with.
+pub fn name_base<F: FileSystem + ?Sized>(fs: &F, root: &Path, opts: &ClientServerOpts) -> PathBuf {
+    if opts.relative {
+        root.components()
+            .take_while(|c| !matches!(c, Component::Normal(_)))
+            .collect()
+    } else if fs.is_dir(root) {
+        root.to_path_buf()
+    } else {
+        root.parent().unwrap_or(Path::new("")).to_path_buf()
+    }
+}
+
+/// Whether `path` starts or ends with an exclude pattern, or its file name matches one as a
+/// glob. With `opts.ignore_case` both sides are lowercased first. Anything below a directory
+/// named like `opts.partial_dir` is excluded too, so partial files are neither sent nor
+/// deleted.
+fn is_excluded(opts: &ClientServerOpts, path: &Path) -> bool {
+    if let Some(dir) = &opts.partial_dir
+        && path.ancestors().any(|ancestor| ancestor.ends_with(dir))
+    {
+        return true;
+    }
+    let fold = |p: &Path| {
+        if opts.ignore_case {
+            PathBuf::from(p.to_string_lossy().to_lowercase())
+        } else {
+            p.to_path_buf()
+        }
+    };
+    let path = fold(path);
+    opts.exclude
+        .iter()
+        .map(|p| fold(p))
+        .any(|p| path.starts_with(&p) || path.ends_with(&p) || matches_glob(&p, &path))
+}
+
+fn matches_glob(pattern: &Path, path: &Path) -> bool {
+    let (Some(pattern), Some(name)) = (pattern.to_str(), path.file_name()) else {
+        return false;
+    };
+    pattern.contains(['*', '?', '['])
+        && Glob::new(pattern).is_ok_and(|glob| glob.compile_matcher().is_match(name))
+}
+
+/// Strong signature of the file at `path`, `None` with a warning if it cannot be read.
+fn checksum(path: &Path) -> Option<String> {
+    checksum_of(File::open(path), path)
+}
+
+fn checksum_of<R: io::Read>(file: io::Result<R>, path: impl std::fmt::Debug) -> Option<String> {
+    match file.and_then(|file| compute_strong_signature_from(BufReader::new(file))) {
+        Ok(checksum) => Some(checksum),
+        Err(e) => {
+            warn!("error checksumming {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+fn in_size_range(opts: &ClientServerOpts, size: u64) -> bool {
+    opts.min_size.is_none_or(|min| size >= min) && opts.max_size.is_none_or(|max| size <= max)
+}
+
+/// Whether a file last modified at `mtime` is within `opts.exclude_older_than` and
+/// `opts.exclude_newer_than` of `now`.
+fn in_age_range(opts: &ClientServerOpts, mtime: i64, now: SystemTime) -> bool {
+    let modified = match u64::try_from(mtime) {
+        Ok(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+        Err(_) => UNIX_EPOCH - Duration::from_secs(mtime.unsigned_abs()),
+    };
+    // Modified in the future counts as zero age
+    let age = now.duration_since(modified).unwrap_or_default();
+    opts.exclude_older_than.is_none_or(|max| age <= max)
+        && opts.exclude_newer_than.is_none_or(|min| age >= min)
+}
+
+/// Whether an entry of this type belongs in the flist. Special files are only listed with
+/// `opts.devices`, and sockets never are.
+fn is_listed(opts: &ClientServerOpts, path: &Path, file_type: FileType) -> bool {
+    if file_type.is_socket() {
+        warn!("skipping socket {:?}", path);
+        return false;
+    }
+    let special = file_type.is_fifo() || file_type.is_char_device() || file_type.is_block_device();
+    if special && !opts.devices {
+        info!("skipping special file {:?}", path);
+        return false;
+    }
+    true
+}
+
+fn special(metadata: &Metadata) -> Option<SpecialFile> {
+    let file_type = metadata.file_type();
+    // `dev_t` and the numbers in it are narrower and signed on some platforms
+    let rdev = metadata.rdev() as libc::dev_t;
+    let (major, minor) = (libc::major(rdev) as u32, libc::minor(rdev) as u32);
+    if file_type.is_fifo() {
+        Some(SpecialFile::Fifo)
+    } else if file_type.is_char_device() {
+        Some(SpecialFile::CharDevice { major, minor })
+    } else if file_type.is_block_device() {
+        Some(SpecialFile::BlockDevice { major, minor })
+    } else {
+        None
+    }
+}
+
+fn entry(base: &Path, path: &Path, metadata: &Metadata) -> FlistEntry {
+    let file_type = metadata.file_type();
+    let relative = path.strip_prefix(base).unwrap_or(path);
+    FlistEntry {
+        index: 0,
+        filename: relative.into(),
+        size: metadata.len(),
+        mtime: metadata.mtime(),
+        mode: metadata.mode(),
+        uid: Some(metadata.uid()),
+        gid: Some(metadata.gid()),
+        is_dir: file_type.is_dir(),
+        is_symlink: file_type.is_symlink(),
+        hardlink_to: None,
+        user: None,
+        group: None,
+        special: special(metadata),
+        checksum: None,
+        synced_size: None,
+    }
+}
+
+/// The entry for a file listed through a [`FileSystem`], which knows no owners.
+fn fs_entry(base: &Path, path: &Path, metadata: &FsMetadata) -> FlistEntry {
+    let relative = path.strip_prefix(base).unwrap_or(path);
+    FlistEntry {
+        index: 0,
+        filename: relative.into(),
+        size: metadata.len,
+        mtime: metadata.mtime,
+        mode: metadata.mode,
+        uid: None,
+        gid: None,
+        is_dir: metadata.is_dir,
+        is_symlink: false,
+        hardlink_to: None,
+        user: None,
+        group: None,
+        special: None,
+        checksum: None,
+        synced_size: None,
+    }
+}
+
+fn walk(root: &Path, base: &Path, opts: &ClientServerOpts) -> Vec<(FlistEntry, Metadata)> {
+    let mut builder = WalkBuilder::new(root);
+    builder.max_depth(opts.max_depth);
+    if opts.no_ignore {
+        builder
+            .ignore(false)
+            .git_ignore(false)
+            .git_global(false)
+            .git_exclude(false);
+    } else {
+        builder.add_custom_ignore_filename(IGNORE_FILENAME);
+    }
+    if let Some(ignore_file) = &opts.ignore_file
+        && let Some(err) = builder.add_ignore(ignore_file)
+    {
+        warn!("error while reading ignore file {:?}: {}", ignore_file, err);
+    }
+
+    builder
+        .build()
+        .filter_map(|e| {
+            let e = e.ok()?;
+            let file_type = e.file_type()?;
+            if file_type.is_dir() || file_type.is_symlink() {
+                return None;
+            }
+            if !is_listed(opts, e.path(), file_type) {
+                return None;
+            }
+            if is_excluded(opts, e.path()) {
+                info!("skipping {:?}", e.path());
+                return None;
+            }
+            let metadata = e.metadata().ok()?;
+            Some((entry(base, e.path(), &metadata), metadata))
+        })
+        .collect()
+}
+
+/// Entries for exactly the given paths, in the order given. Paths that cannot be read are
+/// skipped with a warning.
+fn listed(
+    root: &Path,
+    base: &Path,
+    paths: &[PathBuf],
+    opts: &ClientServerOpts,
+) -> Vec<(FlistEntry, Metadata)> {
+    paths
+        .iter()
+        .filter_map(|relative| {
+            let path = root.join(relative);
+            if is_excluded(opts, &path) {
+                info!("skipping {:?}", path);
+                return None;
+            }
+            match fs::metadata(&path) {
+                Ok(metadata) => Some((entry(base, &path, &metadata), metadata)),
+                Err(e) => {
+                    warn!("skipping listed path {:?}: {}", relative, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn list_dir(
+    root: &Path,
+    base: &Path,
+    opts: &ClientServerOpts,
+) -> io::Result<Vec<(FlistEntry, Metadata)>> {
+    Ok(read_dir(root)?
+        .filter_map(|e| {
+            let e = e.ok()?;
+            if !is_listed(opts, &e.path(), e.file_type().ok()?) {
+                return None;
+            }
+            if is_excluded(opts, &e.path()) {
+                info!("skipping {:?}", e.path());
+                return None;
+            }
+            let metadata = e.metadata().ok()?;
+            Some((entry(base, &e.path(), &metadata), metadata))
+        })
+        .collect())
+}
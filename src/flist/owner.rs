@@ -0,0 +1,174 @@
+//! Mapping file ownership between hosts.
+//!
+//! The sender lists the user and group names behind each entry's uid/gid. Unless
+//! `--numeric-ids` is given, the receiver looks those names up again locally and only falls
+//! back to the raw ids when a name is unknown there.
+
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString, OsStr},
+    mem,
+    os::unix::{ffi::OsStrExt, fs::chown},
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use crate::{cli::ClientServerOpts, pipeline::FlistEntry};
+
+/// Size of the scratch buffer handed to the reentrant passwd/group lookups.
+const LOOKUP_BUF_LEN: usize = 4096;
+
+/// Caches uid/gid to name lookups for the duration of a walk.
+#[derive(Debug, Default)]
+pub struct Names {
+    users: HashMap<u32, Option<String>>,
+    groups: HashMap<u32, Option<String>>,
+}
+
+impl Names {
+    pub fn user(&mut self, uid: Option<u32>) -> Option<String> {
+        let uid = uid?;
+        self.users
+            .entry(uid)
+            .or_insert_with(|| user_name(uid))
+            .clone()
+    }
+
+    pub fn group(&mut self, gid: Option<u32>) -> Option<String> {
+        let gid = gid?;
+        self.groups
+            .entry(gid)
+            .or_insert_with(|| group_name(gid))
+            .clone()
+    }
+}
+
+/// The uid and gid `entry` should be owned by on this host.
+pub fn resolve_ids(entry: &FlistEntry, numeric_ids: bool) -> (Option<u32>, Option<u32>) {
+    if numeric_ids {
+        return (entry.uid, entry.gid);
+    }
+    let uid = entry.user.as_deref().and_then(uid_by_name).or(entry.uid);
+    let gid = entry.group.as_deref().and_then(gid_by_name).or(entry.gid);
+    (uid, gid)
+}
+
+/// Changes the owner and/or group of `path` to match `entry`, as far as `opts.owner` and
+/// `opts.group` ask for, or to the ids forced by `opts.chown`.
+pub fn apply_owner(
+    path: &Path,
+    entry: &FlistEntry,
+    opts: &ClientServerOpts,
+) -> std::io::Result<()> {
+    if let Some(forced) = &opts.chown {
+        return chown(path, forced.uid, forced.gid);
+    }
+    if !opts.owner && !opts.group {
+        return Ok(());
+    }
+    let (uid, gid) = resolve_ids(entry, opts.numeric_ids);
+    chown(path, uid.filter(|_| opts.owner), gid.filter(|_| opts.group))
+}
+
+/// Whether this process may give files away to other users.
+pub fn can_chown() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    unsafe { libc::geteuid() == 0 }
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; LOOKUP_BUF_LEN];
+    // SAFETY: an all-zero passwd is a valid out-parameter for getpwuid_r.
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+    // SAFETY: every pointer is valid for the duration of the call and buf.len() is its size.
+    let rc = unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if rc != 0 || result.is_null() {
+        return None;
+    }
+    // SAFETY: on success pw_name points at a NUL-terminated string inside buf.
+    Some(
+        unsafe { CStr::from_ptr(pwd.pw_name) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+fn group_name(gid: u32) -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; LOOKUP_BUF_LEN];
+    // SAFETY: an all-zero group is a valid out-parameter for getgrgid_r.
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+    // SAFETY: every pointer is valid for the duration of the call and buf.len() is its size.
+    let rc = unsafe { libc::getgrgid_r(gid, &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if rc != 0 || result.is_null() {
+        return None;
+    }
+    // SAFETY: on success gr_name points at a NUL-terminated string inside buf.
+    Some(
+        unsafe { CStr::from_ptr(grp.gr_name) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+/// Looks up the local user `name` and hands its passwd entry to `read`, whose strings point
+/// into a buffer that only lives for the call.
+fn passwd_by_name<T>(name: &str, read: impl FnOnce(&libc::passwd) -> Option<T>) -> Option<T> {
+    let name = CString::new(name).ok()?;
+    let mut buf = vec![0 as libc::c_char; LOOKUP_BUF_LEN];
+    // SAFETY: an all-zero passwd is a valid out-parameter for getpwnam_r.
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+    // SAFETY: every pointer is valid for the duration of the call and buf.len() is its size.
+    let rc = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 || result.is_null() {
+        return None;
+    }
+    read(&pwd)
+}
+
+/// Uid of the local user `name`.
+pub fn uid_by_name(name: &str) -> Option<u32> {
+    passwd_by_name(name, |pwd| Some(pwd.pw_uid))
+}
+
+/// Home directory of the local user `name`, from the passwd database.
+pub fn home_dir_by_name(name: &str) -> Option<PathBuf> {
+    passwd_by_name(name, |pwd| {
+        if pwd.pw_dir.is_null() {
+            return None;
+        }
+        // SAFETY: getpwnam_r succeeded, so pw_dir points to a NUL-terminated string in buf.
+        let dir = unsafe { CStr::from_ptr(pwd.pw_dir) };
+        Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes())))
+    })
+}
+
+/// Gid of the local group `name`.
+pub fn gid_by_name(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    let mut buf = vec![0 as libc::c_char; LOOKUP_BUF_LEN];
+    // SAFETY: an all-zero group is a valid out-parameter for getgrnam_r.
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+    // SAFETY: every pointer is valid for the duration of the call and buf.len() is its size.
+    let rc = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    (rc == 0 && !result.is_null()).then_some(grp.gr_gid)
+}
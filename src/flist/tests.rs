@@ -0,0 +1,450 @@
+use super::*;
+use crate::cli::Cli;
+use crate::cryptography::compute_strong_signature;
+use clap::Parser;
+use pretty_assertions::assert_eq;
+use std::fs;
+use tempfile::tempdir;
+
+fn filenames(flist: &[FlistEntry]) -> Vec<String> {
+    let mut names: Vec<String> = flist.iter().map(|e| e.filename.to_string()).collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn oxideignore_excludes_matching_files() {
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join(IGNORE_FILENAME), "*.log\n").unwrap();
+    fs::write(dir.path().join("keep.txt"), "keep").unwrap();
+    fs::write(dir.path().join("debug.log"), "noise").unwrap();
+    fs::create_dir(dir.path().join("nested")).unwrap();
+    fs::write(dir.path().join("nested/trace.log"), "noise").unwrap();
+    fs::write(dir.path().join("nested/keep.rs"), "keep").unwrap();
+
+    let opts = ClientServerOpts {
+        recursive: true,
+        ..Default::default()
+    };
+    let flist = build(dir.path(), &opts, &mut TransferStats::default()).unwrap();
+
+    assert_eq!(filenames(&flist), vec!["keep.txt", "nested/keep.rs"]);
+}
+
+#[test]
+fn no_ignore_disables_oxideignore() {
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join(IGNORE_FILENAME), "*.log\n").unwrap();
+    fs::write(dir.path().join("debug.log"), "noise").unwrap();
+
+    let opts = ClientServerOpts {
+        recursive: true,
+        no_ignore: true,
+        ..Default::default()
+    };
+    let flist = build(dir.path(), &opts, &mut TransferStats::default()).unwrap();
+
+    assert_eq!(filenames(&flist), vec!["debug.log"]);
+}
+
+#[test]
+fn ignore_file_is_layered_on_exclude() {
+    let dir = tempdir().unwrap();
+    let rules = tempdir().unwrap();
+    let ignore_file = rules.path().join("rules");
+    fs::write(&ignore_file, "*.tmp\n").unwrap();
+    fs::write(dir.path().join("a.tmp"), "").unwrap();
+    fs::write(dir.path().join("b.txt"), "").unwrap();
+    fs::write(dir.path().join("c.txt"), "").unwrap();
+
+    let opts = ClientServerOpts {
+        recursive: true,
+        ignore_file: Some(ignore_file),
+        exclude: vec!["c.txt".into()],
+        ..Default::default()
+    };
+    let flist = build(dir.path(), &opts, &mut TransferStats::default()).unwrap();
+
+    assert_eq!(filenames(&flist), vec!["b.txt"]);
+}
+
+#[test]
+fn size_limits_drop_entries_and_count_them() {
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join("tiny.txt"), [0u8; 10]).unwrap();
+    fs::write(dir.path().join("medium.txt"), [0u8; 2048]).unwrap();
+    fs::write(dir.path().join("large.txt"), [0u8; 8192]).unwrap();
+
+    let opts = ClientServerOpts {
+        recursive: true,
+        min_size: Some(crate::cli::parse_size("1K").unwrap()),
+        max_size: Some(crate::cli::parse_size("4K").unwrap()),
+        ..Default::default()
+    };
+    let mut stats = TransferStats::default();
+    let flist = build(dir.path(), &opts, &mut stats).unwrap();
+
+    assert_eq!(filenames(&flist), vec!["medium.txt"]);
+    assert_eq!(flist[0].index, 0);
+    assert_eq!(stats.excluded_by_size, 2);
+}
+
+#[test]
+fn files_from_lists_exactly_the_given_paths_in_order() {
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join("a.txt"), "a").unwrap();
+    fs::write(dir.path().join("c.txt"), "c").unwrap();
+    fs::write(dir.path().join("unlisted.txt"), "skip").unwrap();
+    fs::create_dir(dir.path().join("nested")).unwrap();
+    fs::write(dir.path().join("nested/b.txt"), "b").unwrap();
+
+    let opts = ClientServerOpts {
+        recursive: true,
+        files_from: Some(
+            ["c.txt", "missing.txt", "nested/b.txt", "a.txt"]
+                .into_iter()
+                .map(PathBuf::from)
+                .collect(),
+        ),
+        ..Default::default()
+    };
+    let flist = build(dir.path(), &opts, &mut TransferStats::default()).unwrap();
+
+    let listed: Vec<_> = flist
+        .iter()
+        .map(|e| (e.index, e.filename.to_string()))
+        .collect();
+    assert_eq!(
+        listed,
+        [(0, "c.txt"), (1, "nested/b.txt"), (2, "a.txt")].map(|(i, name)| (i, name.to_string()))
+    );
+}
+
+#[test]
+fn exclude_from_patterns_are_merged_into_exclude() {
+    let dir = tempdir().unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("target")).unwrap();
+    fs::write(root.join("keep.rs"), "keep").unwrap();
+    fs::write(root.join("secret.env"), "skip").unwrap();
+    fs::write(root.join("target/build.o"), "skip").unwrap();
+    fs::write(root.join("commented.txt"), "keep").unwrap();
+    let exclude_from = dir.path().join("excludes");
+    fs::write(
+        &exclude_from,
+        "# build output\ntarget/build.o\nsecret.env\n#commented.txt\n",
+    )
+    .unwrap();
+
+    let cli = Cli::parse_from([
+        "oxide_sync".as_ref(),
+        "--exclude-from".as_ref(),
+        exclude_from.as_os_str(),
+        "from".as_ref(),
+        "to".as_ref(),
+    ]);
+    let mut opts = ClientServerOpts {
+        recursive: true,
+        ..(&cli).into()
+    };
+    opts.read_lists(&cli).unwrap();
+    let flist = build(&root, &opts, &mut TransferStats::default()).unwrap();
+
+    assert_eq!(opts.exclude.len(), 2);
+    assert_eq!(filenames(&flist), vec!["commented.txt", "keep.rs"]);
+}
+
+fn owned_entry(uid: u32, gid: u32, user: &str, group: &str) -> FlistEntry {
+    FlistEntry {
+        index: 0,
+        filename: "owned.txt".into(),
+        size: 0,
+        mtime: 0,
+        mode: 0o644,
+        uid: Some(uid),
+        gid: Some(gid),
+        is_dir: false,
+        is_symlink: false,
+        hardlink_to: None,
+        user: Some(user.to_string()),
+        group: Some(group.to_string()),
+        special: None,
+        checksum: None,
+        synced_size: None,
+    }
+}
+
+#[test]
+fn numeric_ids_apply_raw_ids_and_names_are_remapped() {
+    // `root` is uid/gid 0 on every Unix host, whatever the sender's ids were
+    let entry = owned_entry(12345, 23456, "root", "root");
+
+    assert_eq!(resolve_ids(&entry, true), (Some(12345), Some(23456)));
+    assert_eq!(resolve_ids(&entry, false), (Some(0), Some(0)));
+}
+
+#[test]
+fn unknown_names_fall_back_to_numeric_ids() {
+    let entry = owned_entry(12345, 23456, "no-such-user-oxide", "no-such-group-oxide");
+
+    assert_eq!(resolve_ids(&entry, false), (Some(12345), Some(23456)));
+}
+
+#[test]
+fn flist_carries_owner_names_unless_numeric() {
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join("a.txt"), "a").unwrap();
+    let metadata = fs::metadata(dir.path().join("a.txt")).unwrap();
+
+    let named = build(
+        dir.path(),
+        &ClientServerOpts::default(),
+        &mut TransferStats::default(),
+    )
+    .unwrap();
+    let numeric = build(
+        dir.path(),
+        &ClientServerOpts {
+            numeric_ids: true,
+            ..Default::default()
+        },
+        &mut TransferStats::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        resolve_ids(&named[0], false),
+        (Some(metadata.uid()), Some(metadata.gid()))
+    );
+    assert!(named[0].user.is_some());
+    assert_eq!(numeric[0].user, None);
+    assert_eq!(numeric[0].group, None);
+}
+
+#[test]
+fn special_files_are_skipped_without_devices() {
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join("regular.txt"), "a").unwrap();
+    let fifo = std::ffi::CString::new(
+        dir.path()
+            .join("pipe")
+            .into_os_string()
+            .into_encoded_bytes(),
+    )
+    .unwrap();
+    // SAFETY: `fifo` is a valid NUL-terminated path.
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+    for recursive in [true, false] {
+        let opts = ClientServerOpts {
+            recursive,
+            ..Default::default()
+        };
+        let flist = build(dir.path(), &opts, &mut TransferStats::default()).unwrap();
+        assert_eq!(filenames(&flist), vec!["regular.txt"]);
+
+        let opts = ClientServerOpts {
+            recursive,
+            devices: true,
+            ..Default::default()
+        };
+        let flist = build(dir.path(), &opts, &mut TransferStats::default()).unwrap();
+        let pipe = flist.iter().find(|e| e.filename == "pipe").unwrap();
+        assert_eq!(pipe.special, Some(SpecialFile::Fifo));
+        assert!(!pipe.is_regular());
+    }
+}
+
+#[test]
+fn single_file_source_is_named_by_its_file_name() {
+    let dir = tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("a/b")).unwrap();
+    fs::write(dir.path().join("a/b/c.txt"), "c").unwrap();
+
+    let flist = build(
+        &dir.path().join("a/b/c.txt"),
+        &ClientServerOpts::default(),
+        &mut TransferStats::default(),
+    )
+    .unwrap();
+
+    assert_eq!(filenames(&flist), vec!["c.txt"]);
+}
+
+#[test]
+fn relative_keeps_the_source_path_components() {
+    let dir = tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("a/b")).unwrap();
+    fs::write(dir.path().join("a/b/c.txt"), "c").unwrap();
+    let opts = ClientServerOpts {
+        recursive: true,
+        relative: true,
+        ..Default::default()
+    };
+
+    let file = build(
+        &dir.path().join("a/b/c.txt"),
+        &opts,
+        &mut TransferStats::default(),
+    )
+    .unwrap();
+    let tree = build(&dir.path().join("a"), &opts, &mut TransferStats::default()).unwrap();
+
+    let expected = dir.path().strip_prefix("/").unwrap().join("a/b/c.txt");
+    assert_eq!(filenames(&file), vec![expected.to_string_lossy()]);
+    assert_eq!(filenames(&tree), vec![expected.to_string_lossy()]);
+}
+
+#[test]
+fn max_depth_omits_deeper_entries() {
+    let dir = tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("one/two")).unwrap();
+    fs::write(dir.path().join("top.txt"), "").unwrap();
+    fs::write(dir.path().join("one/mid.txt"), "").unwrap();
+    fs::write(dir.path().join("one/two/deep.txt"), "").unwrap();
+
+    for (max_depth, expected) in [
+        (Some(1), vec!["top.txt"]),
+        (Some(2), vec!["one/mid.txt", "top.txt"]),
+        (None, vec!["one/mid.txt", "one/two/deep.txt", "top.txt"]),
+    ] {
+        let opts = ClientServerOpts {
+            recursive: true,
+            max_depth,
+            ..Default::default()
+        };
+        let flist = build(dir.path(), &opts, &mut TransferStats::default()).unwrap();
+        assert_eq!(filenames(&flist), expected, "{:?}", max_depth);
+    }
+}
+
+#[test]
+fn ignore_case_folds_exclude_patterns() {
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join("foo.tmp"), "").unwrap();
+    fs::write(dir.path().join("keep.txt"), "").unwrap();
+
+    for (ignore_case, expected) in [
+        (false, vec!["foo.tmp", "keep.txt"]),
+        (true, vec!["keep.txt"]),
+    ] {
+        let opts = ClientServerOpts {
+            recursive: true,
+            exclude: vec!["*.TMP".into()],
+            ignore_case,
+            ..Default::default()
+        };
+        let flist = build(dir.path(), &opts, &mut TransferStats::default()).unwrap();
+
+        assert_eq!(filenames(&flist), expected, "ignore_case: {}", ignore_case);
+    }
+}
+
+#[test]
+fn partial_dir_is_left_out_of_the_flist() {
+    let dir = tempdir().unwrap();
+    fs::create_dir_all(dir.path().join(".partial/nested")).unwrap();
+    fs::write(dir.path().join(".partial/nested/a.txt"), "half").unwrap();
+    fs::write(dir.path().join("keep.txt"), "").unwrap();
+
+    let opts = ClientServerOpts {
+        recursive: true,
+        partial_dir: Some(".partial".into()),
+        ..Default::default()
+    };
+    let flist = build(dir.path(), &opts, &mut TransferStats::default()).unwrap();
+
+    assert_eq!(filenames(&flist), vec!["keep.txt"]);
+}
+
+#[test]
+fn sort_orders_the_flist_by_path() {
+    let dir = tempdir().unwrap();
+    for name in ["m.txt", "b/z.txt", "a.txt", "b/a.txt", "Z.txt", "c/d/e.txt"] {
+        let path = dir.path().join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, name).unwrap();
+    }
+    let opts = ClientServerOpts {
+        recursive: true,
+        sort: true,
+        ..Default::default()
+    };
+
+    let flist = build(dir.path(), &opts, &mut TransferStats::default()).unwrap();
+
+    let listed: Vec<_> = flist
+        .iter()
+        .map(|e| (e.index, e.filename.to_string()))
+        .collect();
+    let expected = ["Z.txt", "a.txt", "b/a.txt", "b/z.txt", "c/d/e.txt", "m.txt"];
+    assert_eq!(
+        listed,
+        expected
+            .iter()
+            .zip(0..)
+            .map(|(name, index)| (index, name.to_string()))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn flist_checksums_match_the_contents() {
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join("a.txt"), "same").unwrap();
+    fs::write(dir.path().join("b.txt"), "same").unwrap();
+    fs::write(dir.path().join("c.txt"), "different").unwrap();
+    let opts = ClientServerOpts {
+        recursive: true,
+        sort: true,
+        flist_checksums: true,
+        ..Default::default()
+    };
+
+    let flist = build(dir.path(), &opts, &mut TransferStats::default()).unwrap();
+
+    assert_eq!(
+        flist[0].checksum.as_deref(),
+        Some(compute_strong_signature(b"same").as_str())
+    );
+    assert_eq!(flist[0].same_contents(&flist[1]), Some(true));
+    assert_eq!(flist[0].same_contents(&flist[2]), Some(false));
+
+    let unchecked = build(
+        dir.path(),
+        &ClientServerOpts::default(),
+        &mut TransferStats::default(),
+    )
+    .unwrap();
+    assert!(unchecked.iter().all(|e| e.checksum.is_none()));
+    assert_eq!(unchecked[0].same_contents(&flist[0]), None);
+}
+
+#[test]
+fn age_limits_drop_entries_and_count_them() {
+    let dir = tempdir().unwrap();
+    let now = std::time::SystemTime::now();
+    for (name, age_days) in [("fresh.log", 0), ("week.log", 3), ("ancient.log", 30)] {
+        let path = dir.path().join(name);
+        fs::write(&path, name).unwrap();
+        let mtime = now - Duration::from_secs(age_days * 24 * 60 * 60);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+
+    let opts = ClientServerOpts {
+        recursive: true,
+        exclude_older_than: Some(crate::cli::parse_duration("7d").unwrap()),
+        exclude_newer_than: Some(crate::cli::parse_duration("24h").unwrap()),
+        ..Default::default()
+    };
+    let mut stats = TransferStats::default();
+    let flist = build(dir.path(), &opts, &mut stats).unwrap();
+
+    assert_eq!(filenames(&flist), vec!["week.log"]);
+    assert_eq!(stats.excluded_by_age, 2);
+}
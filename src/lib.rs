@@ -0,0 +1,5 @@
+pub mod cli;
+pub mod cryptography;
+pub mod filesystem;
+pub mod flist;
+pub mod pipeline;
@@ -1,15 +1,30 @@
 use color_eyre::Result;
 use directories::ProjectDirs;
-use std::{env, path::PathBuf, sync::LazyLock};
+use oxide_sync::cli::Cli;
+use std::{
+    env,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+use tracing::Subscriber;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_error::ErrorLayer;
 use tracing_subscriber::{
-    EnvFilter,
-    fmt::{self, Layer},
-    prelude::*,
+    EnvFilter, Layer, filter::LevelFilter, fmt, fmt::MakeWriter, prelude::*, registry::LookupSpan,
 };
 
 pub const PROJECT_NAME: &str = "oxide_sync";
 pub static LOG_ENV: LazyLock<String> = LazyLock::new(|| format!("{}_LOG_LEVEL", PROJECT_NAME));
+pub static LOG_STDERR_ENV: LazyLock<String> =
+    LazyLock::new(|| format!("{}_LOG_STDERR", PROJECT_NAME.to_uppercase()));
+pub static LOG_KEEP_ENV: LazyLock<String> =
+    LazyLock::new(|| format!("{}_LOG_KEEP", PROJECT_NAME.to_uppercase()));
+pub static LOG_JSON_ENV: LazyLock<String> =
+    LazyLock::new(|| format!("{}_LOG_JSON", PROJECT_NAME.to_uppercase()));
+
+/// Number of daily logs kept when `OXIDE_SYNC_LOG_KEEP` is unset.
+pub const DEFAULT_LOG_KEEP: usize = 5;
 
 pub static LOG_FILE: LazyLock<String> = LazyLock::new(|| format!("{}.log", env!("CARGO_PKG_NAME")));
 pub static DATA_FOLDER: LazyLock<Option<PathBuf>> = LazyLock::new(|| {
@@ -18,48 +33,254 @@ pub static DATA_FOLDER: LazyLock<Option<PathBuf>> = LazyLock::new(|| {
         .map(PathBuf::from)
 });
 
-pub fn init() -> Result<()> {
-    let directory = get_data_dir();
-    dbg!(&directory);
+/// Installs the log file subscriber, writing below `--data-dir` if given and the default data
+/// directory otherwise, plus a console one on stderr when `--log-stderr` is set or the
+/// `OXIDE_SYNC_LOG_STDERR` environment variable is non-empty. The file gets one JSON object per
+/// event instead of plain lines when `OXIDE_SYNC_LOG_JSON` is set.
+pub fn init(cli: &Cli) -> Result<()> {
+    let directory = cli.data_dir.clone().unwrap_or_else(get_data_dir);
     std::fs::create_dir_all(&directory)?;
-    let log_path = directory.join(&*LOG_FILE);
-    let log_file = std::fs::File::create(log_path)?;
+    let keep = env::var(&*LOG_KEEP_ENV)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_LOG_KEEP);
+    let log_file = log_file(&directory, keep)?;
+
+    let env_flag = |name: &str| env::var(name).is_ok_and(|v| !v.is_empty() && v != "0");
+    let log_stderr = cli.log_stderr || env_flag(&LOG_STDERR_ENV);
+    subscriber(log_file, log_stderr, env_flag(&LOG_JSON_ENV))?.try_init()?;
+    tracing::debug!("logging to {:?}", directory.join(&*LOG_FILE));
+
+    Ok(())
+}
+
+/// Installs the subscriber used with `--quiet`: errors only, on stderr, and no log file.
+pub fn init_quiet() -> Result<()> {
+    quiet_subscriber(io::stderr).try_init()?;
+    Ok(())
+}
+
+fn quiet_subscriber<W>(writer: W) -> impl Subscriber + Send + Sync
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    tracing_subscriber::registry()
+        .with(
+            fmt::layer()
+                .with_writer(writer)
+                .with_target(false)
+                .with_ansi(false)
+                .with_filter(LevelFilter::ERROR),
+        )
+        .with(ErrorLayer::default())
+}
 
+fn env_filter() -> Result<EnvFilter> {
     let env_filter = EnvFilter::builder().with_default_directive(tracing::Level::DEBUG.into());
 
     // If the `RUST_LOG` environment variable is set, use that as the default,
     // otherwise use the value of the `LOG_ENV` environment variable.
-    let env_filter = env_filter
+    Ok(env_filter
         .try_from_env()
-        .or_else(|_| env_filter.with_env_var(&*LOG_ENV).from_env())?;
+        .or_else(|_| env_filter.with_env_var(&*LOG_ENV).from_env())?)
+}
 
-    let file_subscriber = fmt::layer()
+/// The log file layer: plain lines, or with `json` one object per event that also records the
+/// target.
+fn file_layer<S, W>(writer: W, json: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = fmt::layer()
         .with_file(true)
         .with_line_number(true)
-        .with_writer(log_file)
-        .with_target(false)
-        .with_ansi(false)
-        .with_filter(env_filter);
+        .with_writer(writer)
+        .with_ansi(false);
+    if json {
+        layer.json().with_target(true).boxed()
+    } else {
+        layer.with_target(false).boxed()
+    }
+}
 
-    tracing_subscriber::registry()
+fn subscriber(
+    log_file: RollingFileAppender,
+    log_stderr: bool,
+    json: bool,
+) -> Result<impl Subscriber + Send + Sync> {
+    let file_subscriber = file_layer(log_file, json).with_filter(env_filter()?);
+
+    let stderr_subscriber = if log_stderr {
+        Some(
+            fmt::layer()
+                .with_writer(io::stderr)
+                .with_ansi(io::stderr().is_terminal())
+                .with_filter(env_filter()?),
+        )
+    } else {
+        None
+    };
+
+    Ok(tracing_subscriber::registry()
         .with(file_subscriber)
-        .with(ErrorLayer::default())
-        .try_init()?;
+        .with(stderr_subscriber)
+        .with(ErrorLayer::default()))
+}
 
-    Ok(())
+/// Log file in `directory` that starts a new `oxide_sync.<date>.log` every day and keeps at most
+/// `keep` of them, with `oxide_sync.log` linking to the one being written.
+pub fn log_file(directory: &Path, keep: usize) -> Result<RollingFileAppender> {
+    let (prefix, suffix) = LOG_FILE.rsplit_once('.').unwrap_or((&LOG_FILE, ""));
+    Ok(RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(prefix)
+        .filename_suffix(suffix)
+        .max_log_files(keep.max(1))
+        .latest_symlink(&*LOG_FILE)
+        .build(directory)?)
 }
 
 pub fn get_data_dir() -> PathBuf {
-    let directory = if let Some(s) = DATA_FOLDER.clone() {
+    if let Some(s) = DATA_FOLDER.clone() {
         s
     } else if let Some(proj_dirs) = project_directory() {
         proj_dirs.data_local_dir().to_path_buf()
     } else {
         PathBuf::from(".").join(".data")
-    };
-    directory
+    }
 }
 
 fn project_directory() -> Option<ProjectDirs> {
     ProjectDirs::from("com", "oxide_sync", env!("CARGO_PKG_NAME"))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn data_dir_flag_overrides_the_log_location() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("state");
+        let cli = Cli::parse_from([
+            "oxide_sync".as_ref(),
+            "--data-dir".as_ref(),
+            data_dir.as_os_str(),
+            "from".as_ref(),
+            "to".as_ref(),
+        ]);
+
+        init(&cli).unwrap();
+        tracing::info!("written below --data-dir");
+
+        assert!(data_dir.join(&*LOG_FILE).exists());
+    }
+
+    #[test]
+    fn file_and_stderr_layers_compose() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_file = log_file(dir.path(), 1).unwrap();
+        let subscriber = subscriber(log_file, true, false).unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("logged to both layers");
+        });
+    }
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_file_layer_composes_and_names_its_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_file = log_file(dir.path(), 1).unwrap();
+        tracing::subscriber::with_default(subscriber(log_file, true, true).unwrap(), || {
+            tracing::info!("logged as json");
+        });
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(file_layer(move || writer.clone(), true))
+            .with(ErrorLayer::default());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(index = 3, "sent file");
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let line = output.trim_end();
+        assert!(line.starts_with('{') && line.ends_with('}'), "{}", line);
+        for field in [
+            r#""level":"INFO""#,
+            r#""fields":{"message":"sent file","index":3}"#,
+            r#""target":"oxide_sync::logging::tests""#,
+            r#""filename":"src/logging.rs""#,
+            r#""line_number":"#,
+        ] {
+            assert!(line.contains(field), "{} not in {}", field, line);
+        }
+    }
+
+    #[test]
+    fn quiet_subscriber_only_passes_errors() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = quiet_subscriber(move || writer.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("routine progress");
+            tracing::warn!("minor trouble");
+            tracing::error!("catastrophic failure");
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("catastrophic failure"), "{}", output);
+        assert!(!output.contains("routine progress"), "{}", output);
+        assert!(!output.contains("minor trouble"), "{}", output);
+    }
+
+    #[test]
+    fn only_the_newest_daily_logs_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        for day in ["2024-01-01", "2024-01-02", "2024-01-03"] {
+            fs::write(dir.path().join(format!("oxide_sync.{}.log", day)), day).unwrap();
+            // Pruning goes by creation time, which may be coarser than the loop
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+
+        let mut log_file = log_file(dir.path(), 2).unwrap();
+        log_file.write_all(b"today\n").unwrap();
+        log_file.flush().unwrap();
+
+        let mut names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 3, "{:?}", names);
+        assert_eq!(names[0], "oxide_sync.2024-01-03.log");
+        assert_eq!(names[2], *LOG_FILE);
+        assert_eq!(
+            fs::read_to_string(dir.path().join(&*LOG_FILE)).unwrap(),
+            "today\n"
+        );
+    }
+}
@@ -1,348 +1,296 @@
-use clap::Parser;
-use cli::{Cli, ClientServerOpts};
-use color_eyre::eyre::eyre;
-use cryptography::{
-    Delta, IndexTable, MODULUS, WeakSignature, WeakSignatureBlock, compute_strong_signature,
+use color_eyre::eyre::Report;
+use oxide_sync::{
+    cli::{self, Cli, ClientServerOpts, Direction, check_not_nested, expand_tilde, human_bytes},
+    pipeline::{
+        self, Batch, CancellationToken, ClientOutcome, ExitCode, HEARTBEAT_INTERVAL,
+        HeartbeatTunnel, Hooks, Pipeline, ProgressEvent, ReceiverSSHTunnel, RetryPolicy,
+        SSHCommand, SignatureCache, list_json, list_line, run_server,
+    },
 };
-use ignore::Walk;
-use itertools::Itertools;
-use pipeline::{
-    DataMessage, FlistEntry, Message, Pipeline, ReceiverSSHTunnel, SSHCommand, SSHMessageError,
-    Tunnel,
-};
-use regex_lite::Regex;
-use std::mem;
-use std::path::Path;
 use std::{
-    fs::{File, read_dir},
-    io::{Read, Seek},
-    os::unix::fs::MetadataExt,
-    path::PathBuf,
+    env,
+    path::{Path, PathBuf},
 };
-use tracing::info;
 
-pub mod cli;
-pub mod cryptography;
 mod errors;
 mod logging;
-pub mod pipeline;
 
 // #[global_allocator]
 // static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
+/// Exits with one of the codes documented on [`ExitCode`].
 #[tokio::main]
-async fn main() -> color_eyre::Result<()> {
+async fn main() -> std::process::ExitCode {
+    match run().await {
+        Ok(code) => code.into(),
+        Err(report) => {
+            if let Some(e) = report.downcast_ref::<clap::Error>() {
+                // Also covers --help and --version, which print to stdout
+                let _ = e.print();
+                return if e.use_stderr() {
+                    ExitCode::Usage
+                } else {
+                    ExitCode::Success
+                }
+                .into();
+            }
+            eprintln!("Error: {:?}", report);
+            exit_code(&report).into()
+        }
+    }
+}
+
+/// Maps errors to exit codes by their type; anything unrecognized failed the run as a whole.
+fn exit_code(report: &Report) -> ExitCode {
+    if let Some(e) = report.downcast_ref::<pipeline::Error>() {
+        e.exit_code()
+    } else if report.downcast_ref::<cli::Error>().is_some() {
+        ExitCode::Usage
+    } else {
+        ExitCode::Protocol
+    }
+}
+
+async fn run() -> color_eyre::Result<ExitCode> {
     crate::errors::init()?;
-    let cli = Cli::parse();
-    if !cli.quiet {
-        crate::logging::init()?;
+    let cli = Cli::parse_with_config()?;
+    if cli.quiet {
+        crate::logging::init_quiet()?;
+    } else {
+        crate::logging::init(&cli)?;
     }
+    let signature_cache = SignatureCache::new(
+        cli.data_dir
+            .clone()
+            .unwrap_or_else(logging::get_data_dir)
+            .join("signatures"),
+    );
     let server = cli.server;
     if server {
-        let mut tunnel = ReceiverSSHTunnel::new();
-        let mut flist: Vec<FlistEntry> = Vec::new();
-        let mut opts = ClientServerOpts::default();
-        loop {
-            let msg = tunnel.read_message().await?;
-            match msg {
-                Message::SYNC => {
-                    info!("SYNC");
-                    let msg = Message::ACK;
-                    tunnel.write_message(msg).await?;
-                }
-                Message::ACK => {
-                    info!("ACK");
-
-                    let files = if opts.recursive {
-                        Walk::new(&opts.to)
-                            .filter_map(|e| {
-                                e.ok().and_then(|e| {
-                                    if e.file_type()?.is_file() {
-                                        if opts.exclude.iter().any(|p| {
-                                            e.path().starts_with(p) || e.path().ends_with(p)
-                                        }) {
-                                            info!("skipping {:?}", opts.exclude);
-                                            return None;
-                                        }
-                                        Some(e)
-                                    } else {
-                                        None
-                                    }
-                                })
-                            })
-                            .enumerate()
-                            .map(|(idx, e)| {
-                                let uid = match e.metadata() {
-                                    Ok(m) => Some(m.uid()),
-                                    Err(_) => None,
-                                };
-                                let gid = match e.metadata() {
-                                    Ok(m) => Some(m.gid()),
-                                    Err(_) => None,
-                                };
-                                FlistEntry {
-                                    index: idx as u32,
-                                    filename: e.path().to_string_lossy().to_string(),
-                                    size: e.metadata().unwrap().len(),
-                                    mtime: e.metadata().unwrap().mtime(),
-                                    mode: e.metadata().unwrap().mode(),
-                                    uid,
-                                    gid,
-                                    is_dir: false,
-                                    is_symlink: false,
-                                }
-                            })
-                            .collect_vec()
-                    } else {
-                        let read_dir_res = read_dir(&opts.to);
-                        if let Err(e) = read_dir_res {
-                            return Err(eyre!(
-                                "Error while reading directory {:?}: {}",
-                                opts.to,
-                                e
-                            ));
-                        }
-                        let files = read_dir_res.unwrap();
-                        files
-                            .filter_map(|e| {
-                                let Ok(e) = e else {
-                                    return None;
-                                };
-                                let Ok(file_type) = e.file_type() else {
-                                    return None;
-                                };
-                                let uid = match e.metadata() {
-                                    Ok(m) => Some(m.uid()),
-                                    Err(_) => None,
-                                };
-                                let gid = match e.metadata() {
-                                    Ok(m) => Some(m.gid()),
-                                    Err(_) => None,
-                                };
-                                if !opts
-                                    .exclude
-                                    .iter()
-                                    .any(|p| e.path().starts_with(p) || e.path().ends_with(p))
-                                {
-                                    return None;
-                                }
-
-                                Some(FlistEntry {
-                                    index: 0,
-                                    filename: e.path().to_string_lossy().to_string(),
-                                    size: e.metadata().unwrap().len(),
-                                    mtime: e.metadata().unwrap().mtime(),
-                                    mode: e.metadata().unwrap().mode(),
-                                    uid,
-                                    gid,
-                                    is_dir: file_type.is_dir(),
-                                    is_symlink: file_type.is_symlink(),
-                                })
-                            })
-                            .collect_vec()
-                    };
-                    info!("server: flist start");
-                    for (entry, idx) in files.iter().zip(0..) {
-                        let indexed_file = FlistEntry {
-                            index: idx,
-                            ..entry.clone()
-                        };
-                        let msg = Message::FlistEntry(indexed_file.clone());
-                        tunnel.write_message(msg).await?;
-                        flist.push(indexed_file);
-                        info!("server: flist entry: {:?}", entry);
-                    }
-                    let msg = Message::FlistEnd;
-                    tunnel.write_message(msg).await?;
-                    info!("server: flist end");
-                }
-                Message::Arguments(args) => {
-                    info!("arguments: {:?}", args);
-                    opts = args;
-                }
-                Message::FileIndex(index) => {
-                    let block_size = 128;
-                    let file = flist[index as usize].clone();
-                    let mut base = Vec::new();
-                    File::open(&file.filename)?.read_to_end(&mut base)?;
-                    let mut index_table = IndexTable::new();
-
-                    // Build index table from base file
-                    let signer_base = WeakSignature::new(block_size, base.clone().into());
-                    if base.len() < block_size {
-                        let strong = compute_strong_signature(&base);
-                        // store a dummy weak signature (e.g. hash of entire base)
-                        let weak_val: i64 = base.iter().map(|&b| b as i64).sum::<i64>() % MODULUS;
-                        let weak = WeakSignatureBlock::new(0, weak_val, weak_val, weak_val);
-                        index_table.add(weak, strong, 0);
-                    } else {
-                        // Normal case: compute rolling weak + strong for each base block
-                        let mut prev_hash: Option<WeakSignatureBlock> = None;
-                        for (i, block) in base.chunks_exact(block_size).enumerate() {
-                            if i == 0 {
-                                let sign = signer_base.sign(0);
-                                let strong = compute_strong_signature(block);
-                                index_table.add(sign.clone(), strong, 0);
-                                prev_hash = Some(sign);
-                            } else {
-                                // roll from previous
-                                let rolling =
-                                    signer_base.compute_next_signature(prev_hash.clone().unwrap());
-                                let strong = compute_strong_signature(block);
-                                index_table.add(rolling.clone(), strong, i);
-                                prev_hash = Some(rolling);
-                            }
-                        }
-                    }
-
-                    let msg = Message::Data(DataMessage {
-                        map: index_table,
-                        file_index: index,
-                    });
-                    tunnel.write_message(msg).await?;
-                }
-                _ => {
-                    let msg = Message::Error(SSHMessageError::FatalError(
-                        "Unknown message received".to_string(),
-                    ));
-                    tunnel.write_message(msg).await?;
-                }
+        let tunnel = HeartbeatTunnel::new(Box::new(ReceiverSSHTunnel::new()), HEARTBEAT_INTERVAL);
+        run_server(tunnel, Some(signature_cache)).await?;
+    } else if let Some(batch) = &cli.read_batch {
+        let destination = expand_tilde(&cli.from.clone().unwrap());
+        let mut opts = ClientServerOpts::from(&cli);
+        opts.resolve_chown()?;
+        let stats = Batch::read(batch)?.apply(&destination, &opts)?;
+        println!("Applied {} files from {:?}", stats.files_transferred, batch);
+        if !stats.failures.is_empty() {
+            for failure in &stats.failures {
+                eprintln!("{}", failure.reason);
             }
+            eprintln!("{} files failed to apply", stats.failures.len());
+            return Ok(ExitCode::PartialFailure);
         }
     } else {
         println!("Client mode");
-        let to = cli.to.clone().unwrap().to_string_lossy().to_string();
-        let regex = Regex::new(r"^([a-zA-Z0-9._-]+)@([a-zA-Z0-9.-]+):(.*)$")?;
-        let caps = regex.captures(&to).unwrap();
-        let username = caps.get(1).unwrap().as_str();
-        let host = caps.get(2).unwrap().as_str();
-        let remote_path = caps.get(3).unwrap().as_str();
-        let port = cli.port;
-        let opts = ClientServerOpts {
-            to: PathBuf::from(remote_path),
-            ..(&cli).into()
+        // Remote paths never start with `~`, which is expanded on the server instead. Only
+        // listings may leave out `to`, which is then empty
+        let [from, to] = [&cli.from, &cli.to].map(|path| {
+            path.as_deref()
+                .map(|path| expand_tilde(path).to_string_lossy().to_string())
+                .unwrap_or_default()
+        });
+        let [from_remote, to_remote] = [&from, &to].map(|path| SSHCommand::parse_remote(path));
+        let (from_remote, to_remote) = (from_remote?, to_remote?);
+        if cli.to.is_none() && from_remote.is_none() {
+            return Err(cli::Error::ListWithoutRemote.into());
+        }
+        let remote = match (from_remote, to_remote) {
+            (None, Some(parsed)) => Some((Direction::Push, parsed, &from)),
+            (Some(parsed), None) => Some((Direction::Pull, parsed, &to)),
+            (None, None) => None,
+            (Some(_), Some(_)) => return Err(cli::Error::BothRemote.into()),
         };
-
-        let mut pipeline = Pipeline::new(SSHCommand {
-            host: host.into(),
-            port,
-            username: username.into(),
-            password: None,
-            remote_cmd: "/Users/jayansunil/Dev/rust/oxide_sync/target/debug/oxide_sync --server"
-                .to_string(),
-        })
-        .await?;
-        pipeline.init().await?;
-        pipeline.send_arguments(opts).await?;
-        pipeline.tunnel.write_message(Message::ACK).await?;
-        pipeline.receive_flist().await?;
-        for entry in pipeline.flist {
-            println!("{:?}", entry);
-            pipeline
-                .tunnel
-                .write_message(Message::FileIndex(entry.index))
-                .await?;
-            let msg = pipeline.tunnel.read_message().await?;
-            if let Message::Data(data) = msg {
-                let path = PathBuf::from(&entry.filename);
-                let path = match path.strip_prefix(cli.to.clone().expect("to is not set")) {
-                    Ok(path) => cli.from.clone().unwrap().join(path),
-                    Err(_) => path,
+        let (mut opts, local_root, command) = match remote {
+            None => {
+                check_not_nested(Path::new(&from), Path::new(&to))?;
+                let mut opts = ClientServerOpts {
+                    to: PathBuf::from(&to),
+                    direction: Direction::Push,
+                    ..(&cli).into()
                 };
-
-                let Ok(mut file_) = File::open(path) else {
-                    println!("error opening file {:?}", entry.clone());
-                    continue;
-                };
-                let mut delta = Delta::new();
-                let block_size = 128;
-                let mut new = Vec::new();
-                let index_table = data.map;
-                file_.read_to_end(&mut new)?;
-
-                // If the new file is shorter than block_size, nothing to roll — emit whole new as block.
-                if new.len() < block_size {
-                    if !new.is_empty() {
-                        delta.add_block(new.to_vec());
+                opts.assume_local(&cli);
+                (opts, &from, None)
+            }
+            Some((direction, (parsed, remote_path), local_root)) => {
+                if remote_path.is_empty() {
+                    return Err(cli::Error::NoPathAfterHost {
+                        username: parsed.username.into(),
+                        host: parsed.host.into(),
                     }
-                    println!("{:?}", delta);
-                    continue;
+                    .into());
                 }
-
-                // Prepare to scan `new`
-                let signer_new = WeakSignature::new(block_size, new.clone().into());
-                let mut unmatched_buffer: Vec<u8> = Vec::new();
-                let mut i: usize = 0;
-
-                // Initialize prev_hash for position 0
-                let mut prev_hash: Option<WeakSignatureBlock> = Some(signer_new.sign(0));
-
-                // Slide while there is a full window
-                while i + block_size <= new.len() {
-                    // Ensure we have a hash for current position
-                    let cur_hash = match prev_hash.clone() {
-                        Some(h) => h,
-                        None => {
-                            // If we don't have a prev_hash, compute it directly
-                            let s = signer_new.sign(i);
-                            prev_hash = Some(s.clone());
-                            s
-                        }
-                    };
-
-                    // Check index table for weak match
-                    if let Some((base_index, strong)) = index_table.find(cur_hash.get_signature()) {
-                        // Verify with strong signature on the new window
-                        let strong2 = compute_strong_signature(&new[i..i + block_size]);
-                        if strong == strong2 {
-                            // Found a match — flush any unmatched data first
-                            if !unmatched_buffer.is_empty() {
-                                delta.add_block(mem::take(&mut unmatched_buffer));
-                            }
-                            // Emit index referring to base block
-                            delta.add_index(base_index);
-
-                            // Jump forward by a full block
-                            i += block_size;
-
-                            // If we still can produce full windows, set prev_hash to sign(i)
-                            if i + block_size <= new.len() {
-                                prev_hash = Some(signer_new.sign(i));
-                            } else {
-                                prev_hash = None;
-                            }
-                            continue;
-                        }
+                let password = match &cli.password_env {
+                    Some(var) => {
+                        Some(env::var(var).map_err(|_| cli::Error::PasswordVarUnset(var.clone()))?)
                     }
+                    None => None,
+                };
+                let opts = ClientServerOpts {
+                    to: PathBuf::from(remote_path),
+                    direction,
+                    ..(&cli).into()
+                };
+                let command = SSHCommand {
+                    port: cli.port,
+                    password,
+                    rsh: cli.rsh.clone(),
+                    ssh_options: cli.ssh_options.clone(),
+                    remote_cmd:
+                        "/Users/jayansunil/Dev/rust/oxide_sync/target/debug/oxide_sync --server"
+                            .to_string(),
+                    ..parsed
+                };
+                (opts, local_root, Some(command))
+            }
+        };
+        opts.read_lists(&cli)?;
+        opts.validate()?;
 
-                    // No match at current window:
-                    // Append a single byte (the current byte) to unmatched buffer and slide by 1
-                    unmatched_buffer.push(new[i]);
-                    i += 1;
-
-                    // Update rolling hash for the new window if possible
-                    if i + block_size <= new.len() {
-                        // roll from previous cur_hash
-                        let next_hash = signer_new.compute_next_signature(cur_hash);
-                        prev_hash = Some(next_hash);
+        // Each reconnect of --retries starts from a pipeline set up the same way
+        let cancel = CancellationToken::new();
+        let connect = || {
+            let command = command.clone();
+            let signature_cache = signature_cache.clone();
+            let cancel = cancel.clone();
+            let cli = &cli;
+            async move {
+                let mut pipeline = match command {
+                    Some(command) => Pipeline::new(command).await?.with_heartbeat(cli.heartbeat),
+                    None => Pipeline::local(),
+                };
+                pipeline.signature_cache = Some(signature_cache);
+                pipeline.compress = cli.compress;
+                pipeline.cancel = cancel;
+                if cli.write_batch.is_some() {
+                    pipeline.batch = Some(Batch::default());
+                }
+                pipeline.hooks = Hooks {
+                    pre: cli.pre_cmd.clone(),
+                    post: cli.post_cmd.clone(),
+                };
+                if cli.progress {
+                    let human_readable = cli.human_readable;
+                    pipeline =
+                        pipeline.with_progress(move |event| print_progress(event, human_readable));
+                }
+                Ok::<_, pipeline::Error>(pipeline)
+            }
+        };
+        let interrupted = cancel.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                eprintln!("Interrupted, stopping after the current file");
+                interrupted.cancel();
+            }
+        });
+        let local_root = Path::new(local_root);
+        let (pipeline, outcome) = if cli.retries > 0 && !opts.list_only && !opts.verify {
+            let policy = RetryPolicy {
+                retries: cli.retries,
+                delay: cli.retry_delay,
+            };
+            let (pipeline, stats) =
+                Pipeline::sync_with_retries(connect, policy, local_root, opts).await?;
+            (pipeline, ClientOutcome::Synced(stats))
+        } else {
+            let mut pipeline = connect().await?;
+            let outcome = pipeline.run_client(local_root, opts).await?;
+            (pipeline, outcome)
+        };
+        let code = outcome.exit_code();
+        let stats = match outcome {
+            ClientOutcome::Listed(flist) => {
+                for entry in flist {
+                    if cli.json {
+                        println!("{}", list_json(&entry));
                     } else {
-                        // not enough bytes left for a full window -> no further rolling hashes
-                        prev_hash = None;
+                        println!("{}", list_line(&entry, cli.human_readable));
                     }
                 }
-
-                // Append any remaining tail bytes (less than a full block) to the unmatched buffer
-                if i < new.len() {
-                    unmatched_buffer.extend_from_slice(&new[i..]);
+                return Ok(code);
+            }
+            ClientOutcome::Verified(report) => {
+                for (label, files) in [
+                    ("mismatched", &report.mismatched),
+                    ("missing", &report.missing),
+                    ("extra", &report.extra),
+                ] {
+                    for file in files {
+                        println!("{}: {}", label, file);
+                    }
                 }
-
-                // Flush unmatched buffer if non-empty
-                if !unmatched_buffer.is_empty() {
-                    delta.add_block(unmatched_buffer);
+                if !report.is_clean() {
+                    eprintln!("Destination does not match the source");
                 }
-                println!("{:?}", delta);
+                return Ok(code);
             }
+            ClientOutcome::Synced(stats) => stats,
+        };
+        if let (Some(path), Some(batch)) = (&cli.write_batch, &pipeline.batch) {
+            batch.write(path)?;
+        }
+        if stats.cancelled {
+            eprintln!("Cancelled after {} files", stats.files_transferred);
+        }
+        if !cli.quiet {
+            for line in &stats.itemized {
+                println!("{}", line);
+            }
+        }
+        if pipeline.flist.is_empty() {
+            println!("0 files to sync");
+        }
+        if cli.stats {
+            let bytes = |n: u64| {
+                if cli.human_readable {
+                    human_bytes(n)
+                } else {
+                    format!("{} bytes", n)
+                }
+            };
+            for (index, file) in &stats.files {
+                println!(
+                    "{}: {} matched, {} literal blocks, {} reused, {} sent",
+                    pipeline.flist[*index as usize].filename,
+                    file.matched_blocks,
+                    file.literal_blocks,
+                    bytes(file.bytes_reused),
+                    bytes(file.bytes_sent)
+                );
+            }
+        }
+        if !stats.failures.is_empty() {
+            eprintln!(
+                "{} of {} files failed to transfer",
+                stats.failures.len(),
+                pipeline.flist.len()
+            );
+        }
+        return Ok(code);
+    }
+    Ok(ExitCode::Success)
+}
+
+/// Prints the `--progress` lines for `event` to stderr, keeping stdout for the listing and
+/// statistics.
+fn print_progress(event: ProgressEvent, human_readable: bool) {
+    let bytes = |n: u64| {
+        if human_readable {
+            human_bytes(n)
+        } else {
+            format!("{} bytes", n)
+        }
+    };
+    match event {
+        ProgressEvent::FileStarted { filename, size, .. } => {
+            eprintln!("{} ({})", filename, bytes(size))
         }
+        ProgressEvent::BytesProcessed { bytes: n, .. } => eprintln!("  {} transferred", bytes(n)),
+        ProgressEvent::FileCompleted {
+            error: Some(error), ..
+        } => eprintln!("  failed: {}", error),
+        ProgressEvent::FileCompleted { error: None, .. } | ProgressEvent::Finished(_) => {}
     }
-    Ok(())
 }
@@ -0,0 +1,96 @@
+//! Recording the deltas of a sync to a file and replaying them later without the source, like
+//! `rsync --write-batch`/`--read-batch`.
+//!
+//! A batch holds the flist and the delta each transferred file was finally rebuilt from, so it
+//! only applies to a destination that still matches the one it was recorded against. Every
+//! delta carries its file's checksum, so replaying against a different base fails that file
+//! instead of corrupting it. Hardlinks and special files are not recorded.
+
+use std::{fs, path::Path};
+
+use tracing::{info, warn};
+
+use crate::{cli::ClientServerOpts, filesystem::RealFs};
+
+use super::{
+    Batch, DeltaMessage, Error, FileError, FileStats, Result, TransferStats, fuzzy, transfer,
+};
+
+/// Leading bytes of every batch file.
+const BATCH_MAGIC: &[u8; 8] = b"OXSBATCH";
+
+impl Batch {
+    /// Writes the batch to `path`, replacing any existing file.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut contents = BATCH_MAGIC.to_vec();
+        contents.extend(bincode::serde::encode_to_vec(
+            self,
+            bincode::config::standard(),
+        )?);
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = fs::read(path)?;
+        let body = contents
+            .strip_prefix(BATCH_MAGIC)
+            .ok_or_else(|| Error::NotABatch(path.to_path_buf()))?;
+        let (batch, _) = bincode::serde::decode_from_slice(body, bincode::config::standard())?;
+        Ok(batch)
+    }
+
+    /// Rebuilds every recorded file below `local_root`. Files that fail, including those whose
+    /// base differs from the one the batch was recorded against, are reported in the returned
+    /// stats and left untouched.
+    pub fn apply(&self, local_root: &Path, opts: &ClientServerOpts) -> Result<TransferStats> {
+        let mut stats = TransferStats::default();
+        let candidates = fuzzy::Candidates::default();
+        for DeltaMessage {
+            delta,
+            file_index,
+            checksum,
+        } in &self.deltas
+        {
+            let entry = self
+                .flist
+                .get(*file_index as usize)
+                .ok_or(Error::UnknownFileIndex(*file_index))?;
+            let path = local_root.join(&entry.filename);
+            let applied = transfer::apply_delta(
+                &RealFs,
+                &candidates,
+                local_root,
+                entry,
+                delta,
+                checksum.as_deref(),
+                opts,
+            );
+            let reason = match applied {
+                Ok(Some(_)) => {
+                    info!("applied batch delta to {}", entry.filename);
+                    if let Err(e) = transfer::apply_metadata(&RealFs, &path, entry, opts) {
+                        warn!("failed to set metadata of {}: {}", entry.filename, e);
+                    }
+                    stats.files_transferred += 1;
+                    stats
+                        .files
+                        .insert(*file_index, FileStats::from_delta(delta, entry.size));
+                    continue;
+                }
+                Ok(None) => format!(
+                    "{}: checksum mismatch, is this the base the batch was written against?",
+                    entry.filename
+                ),
+                Err(e) => format!("{}: {}", entry.filename, e),
+            };
+            warn!("failed to apply batch delta: {}", reason);
+            stats.failures.push(FileError {
+                file_index: *file_index,
+                filename: entry.filename.to_string(),
+                reason,
+            });
+        }
+        Ok(stats)
+    }
+}
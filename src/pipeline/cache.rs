@@ -0,0 +1,110 @@
+//! On-disk cache of the signature tables built for base files.
+//!
+//! Each table is stored under a name derived from the file's absolute path and the chunker,
+//! together with the size and mtime the file had when it was signed. A table is reused only
+//! while both still match.
+
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{
+    cli::Chunker,
+    cryptography::{IndexTable, compute_strong_signature},
+    filesystem::FileSystem,
+};
+
+use super::transfer::{read_base, signatures};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    table: IndexTable,
+}
+
+#[derive(Debug, Clone)]
+pub struct SignatureCache {
+    dir: PathBuf,
+    /// Tables served from the cache
+    pub hits: u32,
+    /// Tables that had to be computed
+    pub misses: u32,
+}
+
+impl SignatureCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Signature table for the file at `path` on `fs`, read from the cache when the file is
+    /// unchanged since it was last signed. A missing file has an empty table and is never
+    /// cached. The cache itself is always on disk.
+    pub fn signatures(
+        &mut self,
+        fs: &dyn FileSystem,
+        path: &Path,
+        chunker: Chunker,
+    ) -> io::Result<IndexTable> {
+        let metadata = match fs.metadata(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(IndexTable::new()),
+            result => result?,
+        };
+        let cache_path = self.entry_path(fs, path, chunker);
+        if let Some(entry) = read_entry(&cache_path)
+            && entry.size == metadata.len
+            && entry.mtime == metadata.mtime
+            && entry.mtime_nsec == metadata.mtime_nsec
+        {
+            debug!("signature cache hit for {:?}", path);
+            self.hits += 1;
+            return Ok(entry.table);
+        }
+
+        self.misses += 1;
+        let table = signatures(&read_base(fs, path)?, chunker);
+        let entry = CacheEntry {
+            size: metadata.len,
+            mtime: metadata.mtime,
+            mtime_nsec: metadata.mtime_nsec,
+            table,
+        };
+        if let Err(e) = write_entry(&cache_path, &entry) {
+            warn!("failed to cache signatures for {:?}: {}", path, e);
+        }
+        Ok(entry.table)
+    }
+
+    fn entry_path(&self, fs: &dyn FileSystem, path: &Path, chunker: Chunker) -> PathBuf {
+        let path = fs.canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let key = format!("{:?}:{}", chunker, path.display());
+        self.dir
+            .join(format!("{}.bin", compute_strong_signature(key.as_bytes())))
+    }
+}
+
+fn read_entry(path: &Path) -> Option<CacheEntry> {
+    let bytes = fs::read(path).ok()?;
+    bincode::serde::decode_from_slice(&bytes, bincode::config::standard())
+        .ok()
+        .map(|(entry, _)| entry)
+}
+
+fn write_entry(path: &Path, entry: &CacheEntry) -> io::Result<()> {
+    let bytes = bincode::serde::encode_to_vec(entry, bincode::config::standard())
+        .map_err(io::Error::other)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, bytes)
+}
@@ -0,0 +1,71 @@
+//! Compression of frame payloads, negotiated in the `Hello` exchange, see [`Codec`].
+//!
+//! Each side lists the codecs it supports in its `Hello`, and both pick the first one the
+//! client offered that the server supports, falling back to [`Codec::None`]. A client only
+//! offers codecs when asked to compress, and a peer without a codec in common never sees a
+//! compressed frame. Frames are compressed only where that makes them smaller, and say so
+//! with [`COMPRESSED_FRAME`] in their length prefix, so small ones such as `Ping` stay raw.
+
+use std::io::{self, Read, Write};
+
+use super::Codec;
+
+/// Set in the length prefix of a frame whose payload is compressed with the tunnel's codec.
+pub(super) const COMPRESSED_FRAME: u32 = 1 << 31;
+
+/// Compression level for zstd, its default.
+const ZSTD_LEVEL: i32 = 3;
+
+impl Codec {
+    /// Every codec this build supports, best first.
+    pub const SUPPORTED: [Codec; 2] = [Codec::Zstd, Codec::Gzip];
+
+    /// The first of the `offered` codecs that is also `supported`, or `Codec::None`.
+    pub fn negotiate(offered: &[Codec], supported: &[Codec]) -> Codec {
+        offered
+            .iter()
+            .copied()
+            .find(|codec| *codec != Codec::None && supported.contains(codec))
+            .unwrap_or(Codec::None)
+    }
+
+    pub(super) fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+            Codec::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Decompresses a frame payload, failing rather than inflating it past `max` bytes.
+    pub(super) fn decompress(self, data: &[u8], max: usize) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let limit = max as u64 + 1;
+        match self {
+            Codec::None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "compressed frame without a negotiated codec",
+                ));
+            }
+            Codec::Zstd => zstd::Decoder::new(data)?
+                .take(limit)
+                .read_to_end(&mut out)?,
+            Codec::Gzip => flate2::read::GzDecoder::new(data)
+                .take(limit)
+                .read_to_end(&mut out)?,
+        };
+        if out.len() > max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("compressed frame inflates past the {} byte limit", max),
+            ));
+        }
+        Ok(out)
+    }
+}
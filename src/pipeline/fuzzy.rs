@@ -0,0 +1,112 @@
+//! Picking a stand-in base for a file missing on the receiver, for `--fuzzy`, see [`basis`].
+//!
+//! A renamed or re-exported file usually still sits next to where it is going, under its old
+//! name. Diffing against that file instead of against nothing lets most of it be copied rather
+//! than sent. The choice only depends on the directory's contents as first listed in the run, so
+//! the receiver picks the same file when it signs the base and when it rebuilds from the delta.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::filesystem::{FileSystem, FsMetadata};
+
+use super::{FlistEntry, transfer::TMP_SUFFIX};
+
+/// The files of each directory [`basis`] looked in, listed once per run since every missing
+/// file is looked up twice, when its base is signed and when it is rebuilt.
+#[derive(Debug, Default)]
+pub(super) struct Candidates(Mutex<HashMap<PathBuf, Listing>>);
+
+/// The regular files of a directory with their metadata, sorted by name.
+type Listing = Arc<[(PathBuf, FsMetadata)]>;
+
+impl Candidates {
+    /// Forgets every listing, for a new run.
+    pub(super) fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+
+    /// The files in `dir`, without temp files of files being rebuilt.
+    fn listing(&self, fs: &dyn FileSystem, dir: &Path) -> Option<Listing> {
+        if let Some(listing) = self.0.lock().unwrap().get(dir) {
+            return Some(Arc::clone(listing));
+        }
+        let mut listing: Vec<_> = fs
+            .read_dir(dir)
+            .ok()?
+            .into_iter()
+            .filter(|candidate| {
+                // Temp files of files being rebuilt are never a basis
+                !candidate
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().ends_with(TMP_SUFFIX))
+            })
+            .filter_map(|candidate| {
+                let metadata = fs.metadata(&candidate).ok().filter(|m| !m.is_dir)?;
+                Some((candidate, metadata))
+            })
+            .collect();
+        listing.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let listing: Listing = listing.into();
+        self.0
+            .lock()
+            .unwrap()
+            .insert(dir.to_path_buf(), Arc::clone(&listing));
+        Some(listing)
+    }
+}
+
+/// A file next to the missing `path` to use as the base of `entry`, if one looks similar: one
+/// with the same size and mtime, as a renamed file would have, or failing that the one whose
+/// name shares the longest prefix and suffix with it, as long as that is at least half its
+/// name. Ties go to the closest size, then the first name. The directory is listed through
+/// `candidates`.
+pub(super) fn basis(
+    fs: &dyn FileSystem,
+    candidates: &Candidates,
+    path: &Path,
+    entry: &FlistEntry,
+) -> Option<PathBuf> {
+    let name = path.file_name()?.as_encoded_bytes();
+    let listing = candidates.listing(fs, path.parent()?)?;
+    let candidates = listing.iter().filter(|(candidate, _)| candidate != path);
+
+    if let Some((renamed, _)) = candidates
+        .clone()
+        .find(|(_, metadata)| metadata.len == entry.size && metadata.mtime == entry.mtime)
+    {
+        return Some(renamed.clone());
+    }
+    candidates
+        .filter_map(|(candidate, metadata)| {
+            let score = similarity(name, candidate.file_name()?.as_encoded_bytes());
+            (score * 2 >= name.len()).then_some((
+                score,
+                metadata.len.abs_diff(entry.size),
+                candidate,
+            ))
+        })
+        // Highest score first, then closest size, then the first name
+        .min_by(|(a, a_size, a_path), (b, b_size, b_path)| {
+            b.cmp(a).then(a_size.cmp(b_size)).then(a_path.cmp(b_path))
+        })
+        .map(|(_, _, candidate)| candidate.clone())
+}
+
+/// Length of the common prefix plus the common suffix of `a` and `b`, never counting a byte
+/// twice.
+fn similarity(a: &[u8], b: &[u8]) -> usize {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let longest = a.len().min(b.len()) - prefix;
+    let suffix = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take(longest)
+        .take_while(|(x, y)| x == y)
+        .count();
+    prefix + suffix
+}
@@ -0,0 +1,131 @@
+//! Keeping idle connections alive, see [`HeartbeatTunnel`].
+//!
+//! Middleboxes and ssh's own `ServerAliveInterval` can drop a connection that carries nothing
+//! for a while, such as when one side spends minutes signing a huge base file before it
+//! replies. The side doing the work keeps sending `Ping`s meanwhile, which the other side
+//! answers with `Pong`s from inside its blocked read.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use tracing::debug;
+
+use super::{Codec, HeartbeatTunnel, Hello, Message, Pipeline, Result, Tunnel};
+
+/// Idle time after which a [`HeartbeatTunnel`] pings, unless configured otherwise.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+impl HeartbeatTunnel {
+    /// Wraps `inner`, pinging after every `interval` without traffic, or never for a zero
+    /// `interval`. Must be called from within a tokio runtime.
+    pub fn new(inner: Box<dyn Tunnel>, interval: Duration) -> Self {
+        let inner = Arc::new(tokio::sync::Mutex::new(inner));
+        let last_activity = Arc::new(Mutex::new(Instant::now()));
+        let task = tokio::spawn(beat(
+            Arc::clone(&inner),
+            Arc::clone(&last_activity),
+            interval,
+        ));
+        Self {
+            inner,
+            last_activity,
+            task,
+        }
+    }
+
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+}
+
+/// Pings through `inner` whenever it has been idle for `interval`, until a ping fails.
+async fn beat(
+    inner: Arc<tokio::sync::Mutex<Box<dyn Tunnel>>>,
+    last_activity: Arc<Mutex<Instant>>,
+    interval: Duration,
+) {
+    if interval.is_zero() {
+        return;
+    }
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        if last_activity.lock().unwrap().elapsed() < interval {
+            continue;
+        }
+        // A read or write in progress holds the lock, and is traffic enough
+        let Ok(mut tunnel) = inner.try_lock() else {
+            continue;
+        };
+        debug!("idle for {:?}, pinging", interval);
+        if tunnel.write_message(Message::Ping).await.is_err() {
+            break;
+        }
+        *last_activity.lock().unwrap() = Instant::now();
+    }
+}
+
+impl Drop for HeartbeatTunnel {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[async_trait]
+impl Tunnel for HeartbeatTunnel {
+    async fn write_message(&mut self, msg: Message) -> Result<()> {
+        let result = self.inner.lock().await.write_message(msg).await;
+        self.touch();
+        result
+    }
+
+    async fn read_message(&mut self) -> Result<Message> {
+        let result = self.inner.lock().await.read_message().await;
+        self.touch();
+        result
+    }
+
+    async fn write_message_buffered(&mut self, msg: Message) -> Result<()> {
+        let result = self.inner.lock().await.write_message_buffered(msg).await;
+        self.touch();
+        result
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.inner.lock().await.flush().await
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        // A ping after this would fail on the closed tunnel
+        self.task.abort();
+        self.inner.lock().await.shutdown().await
+    }
+
+    async fn set_codec(&mut self, codec: Codec) {
+        self.inner.lock().await.set_codec(codec).await
+    }
+
+    async fn read_hello(&mut self) -> Result<Hello> {
+        let result = self.inner.lock().await.read_hello().await;
+        self.touch();
+        result
+    }
+}
+
+impl Pipeline {
+    /// Pings the other side after every `interval` without traffic, see [`HeartbeatTunnel`].
+    /// A zero `interval` leaves the pipeline as it is.
+    pub fn with_heartbeat(self, interval: Duration) -> Self {
+        if interval.is_zero() {
+            return self;
+        }
+        Self {
+            tunnel: Box::new(HeartbeatTunnel::new(self.tunnel, interval)),
+            ..self
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Local shell commands run around a sync, see [`Hooks`].
+
+use std::{future::Future, process::ExitStatus};
+
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use super::{Error, Hooks, Result, TransferStats};
+
+/// Environment variable telling the post command how the run ended: `0` if every file was
+/// transferred, `1` otherwise.
+pub const EXIT_STATUS_ENV: &str = "OXIDE_SYNC_EXIT_STATUS";
+
+impl Hooks {
+    /// Runs the pre command, then `run`, then the post command. A failing pre command aborts
+    /// before `run` is started; the post command runs whatever `run` returned.
+    pub async fn around<F>(&self, run: F) -> Result<TransferStats>
+    where
+        F: Future<Output = Result<TransferStats>>,
+    {
+        if let Some(pre) = &self.pre {
+            let status = run_shell(pre, None).await?;
+            if !status.success() {
+                return Err(Error::PreCommand {
+                    command: pre.clone(),
+                    status,
+                });
+            }
+        }
+        let result = run.await;
+        if let Some(post) = &self.post {
+            let exit_status = match &result {
+                Ok(stats) if stats.failures.is_empty() => "0",
+                _ => "1",
+            };
+            match run_shell(post, Some(exit_status)).await {
+                Ok(status) if !status.success() => {
+                    warn!("post command {:?} exited with {}", post, status)
+                }
+                Ok(_) => {}
+                Err(e) => warn!("failed to run post command {:?}: {}", post, e),
+            }
+        }
+        result
+    }
+}
+
+/// Runs `command` with `sh -c`, logging its output.
+async fn run_shell(command: &str, exit_status: Option<&str>) -> Result<ExitStatus> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    if let Some(exit_status) = exit_status {
+        cmd.env(EXIT_STATUS_ENV, exit_status);
+    }
+    info!("running {:?}", command);
+    let output = cmd.output().await.map_err(Error::HookSpawn)?;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        info!("{}: {}", command, line);
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        warn!("{}: {}", command, line);
+    }
+    Ok(output.status)
+}
@@ -0,0 +1,57 @@
+//! rsync-style itemized change lines.
+//!
+//! Each line is eight flag characters, a space and the filename:
+//!
+//! ```text
+//! YXcstpog name
+//! ```
+//!
+//! - `Y`: `>` if new content was written, `.` if the contents were already identical
+//! - `X`: the entry type, `f` for a regular file
+//! - `c`: contents differ, `s`: size differs, `t`: mtime differs, `p`: permissions differ,
+//!   `o`: owner differs, `g`: group differs
+//!
+//! An attribute that did not change is shown as `.`. A file that did not exist before shows `+`
+//! in every attribute position.
+
+use crate::{filesystem::FsMetadata, flist};
+
+use super::FlistEntry;
+
+/// Permission bits compared for the `p` flag.
+const MODE_MASK: u32 = 0o7777;
+
+/// Describes how the receiver's copy of `entry` changed. `existing` is the metadata the file
+/// had before the transfer, if it existed.
+pub fn itemize(
+    entry: &FlistEntry,
+    existing: Option<&FsMetadata>,
+    content_changed: bool,
+    numeric_ids: bool,
+) -> String {
+    let kind = if entry.is_dir {
+        'd'
+    } else if entry.is_symlink {
+        'L'
+    } else {
+        'f'
+    };
+    let Some(existing) = existing else {
+        return format!(">{}++++++ {}", kind, entry.filename);
+    };
+
+    let (uid, gid) = flist::resolve_ids(entry, numeric_ids);
+    let flag = |changed: bool, c: char| if changed { c } else { '.' };
+    format!(
+        "{}{}{}{}{}{}{}{} {}",
+        flag(content_changed, '>'),
+        kind,
+        flag(content_changed, 'c'),
+        flag(existing.len != entry.size, 's'),
+        flag(existing.mtime != entry.mtime, 't'),
+        flag(existing.mode & MODE_MASK != entry.mode & MODE_MASK, 'p'),
+        flag(uid.is_some_and(|uid| uid != existing.uid), 'o'),
+        flag(gid.is_some_and(|gid| gid != existing.gid), 'g'),
+        entry.filename
+    )
+}
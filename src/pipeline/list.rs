@@ -0,0 +1,126 @@
+//! Listing the server's files without transferring anything, like `rsync --list-only`.
+//!
+//! The server answers the handshake with its flist and stops there; the client never asks for
+//! any file indices.
+
+use std::fmt::Write as _;
+
+use tracing::info;
+
+use crate::cli::{ClientServerOpts, human_bytes};
+
+use super::{FlistEntry, Message, Pipeline, Result, SpecialFile};
+
+impl Pipeline {
+    /// Runs the client side of a listing and returns the server's flist.
+    pub async fn list(&mut self, opts: ClientServerOpts) -> Result<Vec<FlistEntry>> {
+        opts.validate()?;
+        self.init().await?;
+        self.send_arguments(opts).await?;
+        self.tunnel.write_message(Message::ACK).await?;
+        self.receive_flist().await?;
+        info!("listed {} entries", self.flist.len());
+        Ok(self.flist.clone())
+    }
+}
+
+/// Formats `entry` as a columnar line: permissions, size, modification time (UTC) and path. With
+/// `human_readable`, the size is formatted by [`human_bytes`].
+pub fn list_line(entry: &FlistEntry, human_readable: bool) -> String {
+    let size = if human_readable {
+        human_bytes(entry.size)
+    } else {
+        entry.size.to_string()
+    };
+    format!(
+        "{} {:>14} {} {}",
+        mode_string(entry),
+        size,
+        format_mtime(entry.mtime),
+        entry.filename
+    )
+}
+
+/// Formats `entry` as a single-line JSON object.
+pub fn list_json(entry: &FlistEntry) -> String {
+    format!(
+        r#"{{"path":{},"type":"{}","size":{},"mtime":{},"mode":{}}}"#,
+        json_string(&entry.filename.to_string()),
+        entry_type(entry),
+        entry.size,
+        entry.mtime,
+        entry.mode & 0o7777
+    )
+}
+
+fn entry_type(entry: &FlistEntry) -> &'static str {
+    match entry.special {
+        Some(SpecialFile::Fifo) => "fifo",
+        Some(SpecialFile::CharDevice { .. }) => "char-device",
+        Some(SpecialFile::BlockDevice { .. }) => "block-device",
+        None if entry.is_dir => "dir",
+        None if entry.is_symlink => "symlink",
+        None => "file",
+    }
+}
+
+/// `ls -l` style permission string, e.g. `-rw-r--r--`.
+fn mode_string(entry: &FlistEntry) -> String {
+    let kind = match entry.special {
+        Some(SpecialFile::Fifo) => 'p',
+        Some(SpecialFile::CharDevice { .. }) => 'c',
+        Some(SpecialFile::BlockDevice { .. }) => 'b',
+        None if entry.is_dir => 'd',
+        None if entry.is_symlink => 'l',
+        None => '-',
+    };
+    let mut s = String::from(kind);
+    for shift in [6, 3, 0] {
+        let bits = entry.mode >> shift;
+        s.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        s.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    s
+}
+
+/// Formats seconds since the epoch as `YYYY/MM/DD HH:MM:SS` in UTC.
+fn format_mtime(mtime: i64) -> String {
+    let (days, secs) = (mtime.div_euclid(86_400), mtime.rem_euclid(86_400));
+    // Civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}/{:02}/{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -1,19 +1,60 @@
+mod batch;
+mod cache;
+mod codec;
+mod fuzzy;
+mod heartbeat;
+mod hooks;
+mod itemize;
+mod list;
+mod retry;
+mod run;
 mod structs;
-use std::{fmt::Display, process::Stdio};
+mod transfer;
+mod verify;
+use std::{
+    collections::VecDeque,
+    ffi::OsStr,
+    fmt::Display,
+    io,
+    path::Path,
+    pin::Pin,
+    process::Stdio,
+    sync::Arc,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
 #[cfg(test)]
 mod tests;
 
 use async_trait::async_trait;
 use bincode::error::EncodeError;
+use strum::EnumDiscriminants;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    process::{ChildStdin, ChildStdout, Command},
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+        DuplexStream, duplex, split,
+    },
+    process::{ChildStderr, ChildStdout, Command},
 };
 
+pub use cache::SignatureCache;
+pub use heartbeat::HEARTBEAT_INTERVAL;
+pub use hooks::EXIT_STATUS_ENV;
+pub use itemize::itemize;
+pub use list::{list_json, list_line};
+pub use run::{ClientOutcome, ExitCode, run_client, run_server};
 pub use structs::*;
-use tracing::info;
+use tracing::{debug, info, instrument, warn};
+pub use transfer::{DELTA_CHUNK_SIZE, FLIST_BATCH_SIZE};
+
+use crate::{
+    cli::{ClientServerOpts, Direction, expand_tilde},
+    filesystem::{FileSystem, RealFs},
+};
+use codec::COMPRESSED_FRAME;
 
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, thiserror::Error, EnumDiscriminants)]
+#[strum_discriminants(name(ErrorKind), derive(Hash))]
 pub enum Error {
     #[error("Eror while reading or writing to the SSH tunnel: {0}")]
     Message(#[from] SSHMessageError),
@@ -23,20 +64,245 @@ pub enum Error {
     Encoding(#[from] EncodeError),
     #[error("Error while decoding: {0}")]
     Decoding(#[from] bincode::error::DecodeError),
+    #[error("Message of {len} bytes exceeds the {max} byte limit")]
+    MessageTooLarge { len: usize, max: usize },
+    #[error("Error while decoding a {len} byte message starting with {head}: {source}")]
+    DecodeFramed {
+        len: usize,
+        head: String,
+        source: bincode::error::DecodeError,
+    },
+    #[error("Corrupted {len} byte message: checksum {actual:08x}, expected {expected:08x}")]
+    ChecksumMismatch {
+        len: usize,
+        expected: u32,
+        actual: u32,
+    },
     #[error("Unexpected message: {0}")]
-    UnexpectedMessage(Message),
+    UnexpectedMessage(Box<Message>),
     #[error("NACK received")]
     Nack,
+    #[error(
+        "No handshake in the first {0} bytes from the remote side. Does its shell print to \
+         stdout on login?"
+    )]
+    NoHello(usize),
+    #[error("Client finished the handshake without sending its arguments")]
+    MissingArguments,
     #[error("IO timeout")]
     IoTimeout,
+    #[error("Error while transferring {filename}: {reason}")]
+    FileTransfer { filename: String, reason: String },
+    #[error("Pre command {command:?} exited with {status}")]
+    PreCommand {
+        command: String,
+        status: std::process::ExitStatus,
+    },
+    #[error("Failed to run hook command: {0}")]
+    HookSpawn(std::io::Error),
+    #[error("Transfer cancelled")]
+    Cancelled,
+    #[error("Receiver declined file {0}")]
+    NoSend(u32),
+    #[error("{0:?} is not a batch file")]
+    NotABatch(std::path::PathBuf),
+    #[error("Parent directory of the destination {0:?} does not exist, see --mkpath")]
+    MissingDestination(std::path::PathBuf),
+    #[error("Unknown file index {0}")]
+    UnknownFileIndex(u32),
+    #[error("Invalid options: {0}")]
+    InvalidOptions(#[from] crate::cli::Error),
+    #[error("Invalid remote {input:?}: {reason}")]
+    InvalidRemote { input: String, reason: String },
+    #[error("Failed to start ssh (is it installed and on PATH?): {0}")]
+    SshSpawn(std::io::Error),
+    #[error("Remote side exited with {status}{}", format_stderr(stderr))]
+    RemoteExited {
+        status: std::process::ExitStatus,
+        stderr: Vec<String>,
+    },
 }
 
 type Result<T> = color_eyre::Result<T, Error>;
 
+impl Error {
+    /// The variant of this error without its payload, for comparing errors.
+    pub fn kind(&self) -> ErrorKind {
+        self.into()
+    }
+
+    /// The exit code the binary reports this error with.
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Error::InvalidOptions(_)
+            | Error::NotABatch(_)
+            | Error::InvalidRemote { .. }
+            | Error::MissingDestination(_) => ExitCode::Usage,
+            Error::FileTransfer { .. } | Error::Cancelled => ExitCode::PartialFailure,
+            _ => ExitCode::Protocol,
+        }
+    }
+}
+
+fn format_stderr(stderr: &[String]) -> String {
+    if stderr.is_empty() {
+        String::new()
+    } else {
+        format!(":\n{}", stderr.join("\n"))
+    }
+}
+
+/// Number of stderr lines of the remote process kept for `Error::RemoteExited`.
+const REMOTE_STDERR_LINES: usize = 20;
+
+/// Bytes buffered in each direction of the in-process tunnel used by [`Pipeline::local`].
+const LOCAL_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// How long to wait for the remote process to exit once its pipes have closed.
+const REMOTE_EXIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Bytes buffered on either side of a tunnel's pipes, so small frames don't cost a syscall
+/// each and reads of a frame's header and body are served from one read.
+const TUNNEL_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Number of leading bytes of an undecodable message quoted in `Error::DecodeFramed`.
+const FRAME_HEAD_LEN: usize = 16;
+
+/// Carried by every [`Message::Hello`], which is what [`Tunnel::read_hello`] looks for.
+pub const HELLO_MAGIC: [u8; 8] = *b"oxsync\x00\x01";
+
+/// Most bytes skipped looking for the other side's `Hello` before giving up.
+const MAX_BANNER_LEN: usize = 64 * 1024;
+
+/// Bytes of a frame ahead of its payload: the length prefix and the checksum.
+const FRAME_HEADER_LEN: usize = 8;
+
+/// Reads a length prefix, refusing lengths above `max` before the body is allocated. Also
+/// returns whether the payload is compressed, see [`codec::COMPRESSED_FRAME`].
+fn frame_len(len_buf: [u8; 4], max: usize) -> Result<(usize, bool)> {
+    let prefix = u32::from_be_bytes(len_buf);
+    let len = (prefix & !COMPRESSED_FRAME) as usize;
+    if len > max {
+        return Err(Error::MessageTooLarge { len, max });
+    }
+    Ok((len, prefix & COMPRESSED_FRAME != 0))
+}
+
+/// Encodes `msg` as a frame: its length and CRC32 as big-endian `u32`s, then the payload,
+/// compressed with `codec` where that makes it smaller.
+fn encode_frame(msg: Message, codec: Codec) -> Result<Vec<u8>> {
+    let mut payload = bincode::serde::encode_to_vec(msg, bincode::config::standard())?;
+    let mut flags = 0;
+    if codec != Codec::None {
+        let compressed = codec.compress(&payload)?;
+        if compressed.len() < payload.len() {
+            (payload, flags) = (compressed, COMPRESSED_FRAME);
+        }
+    }
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32 | flags).to_be_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Reads the checksum and payload of a frame whose length prefix has been read already, and
+/// checks one against the other.
+async fn read_payload<R: AsyncRead + Unpin>(reader: &mut R, len: usize) -> Result<Vec<u8>> {
+    let mut crc_buf = [0u8; 4];
+    reader.read_exact(&mut crc_buf).await?;
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).await?;
+    let expected = u32::from_be_bytes(crc_buf);
+    let actual = crc32fast::hash(&buf);
+    if actual != expected {
+        return Err(Error::ChecksumMismatch {
+            len,
+            expected,
+            actual,
+        });
+    }
+    Ok(buf)
+}
+
+/// Discards bytes from `reader` up to a `Hello` frame, found by the variant tag and magic its
+/// payload starts with, and reads that frame. Anything before it, most likely a banner or MOTD
+/// printed by the remote shell, is logged.
+async fn skip_to_hello<R: AsyncRead + Unpin>(reader: &mut R, max: usize) -> Result<Hello> {
+    let mut marker = bincode::serde::encode_to_vec(
+        Message::Hello(Hello::new(Vec::new())),
+        bincode::config::standard(),
+    )?;
+    // The length of the empty codec list
+    marker.pop();
+    let mut seen = Vec::new();
+    while seen.len() < FRAME_HEADER_LEN + marker.len() || !seen.ends_with(&marker) {
+        if seen.len() >= MAX_BANNER_LEN + FRAME_HEADER_LEN + marker.len() {
+            return Err(Error::NoHello(MAX_BANNER_LEN));
+        }
+        seen.push(reader.read_u8().await?);
+    }
+    let start = seen.len() - marker.len() - FRAME_HEADER_LEN;
+    let mut payload = seen.split_off(start + FRAME_HEADER_LEN);
+    let header = seen.split_off(start);
+    let (len, _) = frame_len(header[..4].try_into().unwrap(), max)?;
+    if len > payload.len() {
+        let read = payload.len();
+        payload.resize(len, 0);
+        reader.read_exact(&mut payload[read..]).await?;
+    }
+    let expected = u32::from_be_bytes(header[4..].try_into().unwrap());
+    let actual = crc32fast::hash(&payload);
+    if actual != expected {
+        return Err(Error::ChecksumMismatch {
+            len,
+            expected,
+            actual,
+        });
+    }
+    let banner = seen;
+    if !banner.is_empty() {
+        warn!(
+            "skipped {} bytes before the handshake: {:?}",
+            banner.len(),
+            String::from_utf8_lossy(&banner)
+        );
+    }
+    match decode_frame(&payload)? {
+        Message::Hello(hello) => Ok(hello),
+        msg => Err(Error::UnexpectedMessage(Box::new(msg))),
+    }
+}
+
+/// Decodes a payload read with [`read_payload`], decompressing it with `codec` first if its
+/// frame is marked as compressed.
+fn decode_payload(buf: &[u8], compressed: bool, codec: Codec, max: usize) -> Result<Message> {
+    if compressed {
+        decode_frame(&codec.decompress(buf, max)?)
+    } else {
+        decode_frame(buf)
+    }
+}
+
+/// Decodes the body of a length-prefixed message.
+fn decode_frame(buf: &[u8]) -> Result<Message> {
+    bincode::serde::decode_from_slice(buf, bincode::config::standard())
+        .map(|(msg, _)| msg)
+        .map_err(|source| Error::DecodeFramed {
+            len: buf.len(),
+            head: buf
+                .iter()
+                .take(FRAME_HEAD_LEN)
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            source,
+        })
+}
+
 impl SSHCommand {
     pub fn new(
         host: String,
-        port: u16,
+        port: Option<u16>,
         username: String,
         password: Option<String>,
         remote_cmd: String,
@@ -47,29 +313,114 @@ impl SSHCommand {
             username: username.into_boxed_str(),
             password,
             remote_cmd,
+            rsh: None,
+            ssh_options: Vec::new(),
         }
     }
-}
 
-impl From<String> for SSHCommand {
-    fn from(s: String) -> Self {
-        let mut split = s.split('@');
-        let username = split.next().unwrap().to_string().into_boxed_str();
-        let host = split.next().unwrap();
-        let mut split = host.split(':');
-        let host = split.next().unwrap().to_string().into_boxed_str();
-        let port = match split.next() {
-            Some(port) => port.parse().unwrap(),
-            None => 22,
+    /// Splits a `user@host:path` argument into the command for `user@host` and the path,
+    /// returning `None` for a local path. An IPv6 host is bracketed, as in `user@[::1]:path`.
+    pub fn parse_remote(arg: &str) -> Result<Option<(SSHCommand, &str)>> {
+        let invalid = |reason: &str| Error::InvalidRemote {
+            input: arg.to_string(),
+            reason: reason.to_string(),
+        };
+        let Some((username, rest)) = arg.split_once('@') else {
+            return Ok(None);
+        };
+        if username.contains('/') {
+            return Ok(None);
+        }
+        let host_end = match rest.strip_prefix('[') {
+            Some(bracketed) => {
+                bracketed
+                    .find(']')
+                    .ok_or_else(|| invalid("unclosed `[` around the host"))?
+                    + 2
+            }
+            None => rest.find(':').unwrap_or(rest.len()),
         };
+        let (host, path) = rest.split_at(host_end);
+        // Like `user@host` without a path, a `/` before the `:` makes it a local file name
+        let path = match path.strip_prefix(':') {
+            Some(_) if host.contains('/') => return Ok(None),
+            Some(path) => path,
+            None if path.is_empty() => return Ok(None),
+            None => return Err(invalid("expected `:path` after `]`")),
+        };
+        let remote = &arg[..username.len() + 1 + host.len()];
+        Ok(Some((SSHCommand::try_from(remote)?, path)))
+    }
+}
 
-        SSHCommand {
-            host,
-            port,
-            username,
-            password: None,
-            remote_cmd: String::new(),
+/// Parses `user@host[:port]`, OpenSSH style: without a port ssh uses its configured one, and
+/// an IPv6 host is bracketed, as in `user@[::1]:2222`.
+impl TryFrom<&str> for SSHCommand {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::InvalidRemote {
+            input: s.to_string(),
+            reason: reason.to_string(),
+        };
+        let (username, rest) = s
+            .split_once('@')
+            .ok_or_else(|| invalid("expected user@host"))?;
+        if username.is_empty() {
+            return Err(invalid("the user is empty"));
         }
+        let (host, port) = match rest.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, after) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| invalid("unclosed `[` around the host"))?;
+                let port = match after {
+                    "" => None,
+                    _ => Some(
+                        after
+                            .strip_prefix(':')
+                            .ok_or_else(|| invalid("expected `:port` after `]`"))?,
+                    ),
+                };
+                (host, port)
+            }
+            None if rest.matches(':').count() > 1 => {
+                return Err(invalid("IPv6 hosts must be bracketed, e.g. user@[::1]:22"));
+            }
+            None => match rest.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (rest, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(invalid("the host is empty"));
+        }
+        if host.contains(['@', '[', ']', '/']) || host.chars().any(char::is_whitespace) {
+            return Err(invalid("the host contains invalid characters"));
+        }
+        let port = port
+            .map(|port| {
+                port.parse()
+                    .ok()
+                    .filter(|&port| port != 0)
+                    .ok_or_else(|| invalid("the port must be a number from 1 to 65535"))
+            })
+            .transpose()?;
+        Ok(SSHCommand::new(
+            host.to_string(),
+            port,
+            username.to_string(),
+            None,
+            String::new(),
+        ))
+    }
+}
+
+/// Only for tests, where the input is known to be well-formed.
+#[cfg(test)]
+impl From<String> for SSHCommand {
+    fn from(s: String) -> Self {
+        Self::try_from(s.as_str()).expect("well-formed user@host[:port]")
     }
 }
 
@@ -79,64 +430,301 @@ impl Display for SSHCommand {
     }
 }
 
-impl SSHTunnel<ChildStdin, ChildStdout> {
-    pub async fn new(command: SSHCommand) -> Self {
-        let mut cmd = Command::new("ssh");
+impl SSHTunnel<RemoteStdin, ChildStdout> {
+    pub async fn new(command: SSHCommand) -> Result<Self> {
+        Self::spawn("ssh", &command)
+    }
+
+    /// Starts `program` as the ssh client for `command` and talks over its stdin/stdout.
+    fn spawn(program: impl AsRef<OsStr>, command: &SSHCommand) -> Result<Self> {
+        let mut cmd = ssh_command(program.as_ref(), command);
         cmd.stdin(Stdio::piped());
         cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        // The environment may hold the password, so only the argv and the redacted command
+        // are logged, never `cmd` itself
+        debug!(
+            "spawning {:?} {:?} for {:?}",
+            cmd.as_std().get_program(),
+            cmd.as_std().get_args().collect::<Vec<_>>(),
+            command
+        );
+        let mut child = cmd.spawn().map_err(Error::SshSpawn)?;
+        let (Some(stdin), Some(stdout), Some(stderr)) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        else {
+            return Err(Error::SshSpawn(io::Error::other(
+                "ssh stdin/stdout/stderr were not captured",
+            )));
+        };
 
-        cmd.arg(format!("{}@{}", command.username, command.host)); // "username@host"
-        cmd.arg(command.remote_cmd.clone());
-        dbg!(&cmd);
-        let mut child = cmd.spawn().unwrap();
-        let stdin = child.stdin.take().unwrap();
-        let stdout = child.stdout.take().unwrap();
+        let mut tunnel = SSHTunnel::from_io(RemoteStdin(Some(stdin)), stdout);
+        tunnel.remote = Some(RemoteProcess {
+            child,
+            stderr: tokio::spawn(drain_stderr(stderr)),
+        });
+        Ok(tunnel)
+    }
+}
 
-        SSHTunnel { stdin, stdout }
+impl AsyncWrite for RemoteStdin {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.0 {
+            Some(stdin) => Pin::new(stdin).poll_write(cx, buf),
+            None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.0 {
+            Some(stdin) => Pin::new(stdin).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(stdin) = &mut self.0 {
+            ready!(Pin::new(stdin).poll_flush(cx))?;
+        }
+        self.0 = None;
+        Poll::Ready(Ok(()))
     }
 }
 
-#[async_trait]
-impl Tunnel for SSHTunnel<ChildStdin, ChildStdout> {
-    async fn write_message(&mut self, msg: Message) -> Result<()> {
-        let bin_msg = bincode::serde::encode_to_vec(msg, bincode::config::standard())?;
-        let msg_len = bin_msg.len() as u32;
-        self.stdin.write_all(&msg_len.to_be_bytes()).await?;
-        self.stdin.write_all(&bin_msg).await?;
-        self.stdin.flush().await?;
+/// Reads `stderr` until it closes, logging every line under the `remote` target and keeping
+/// the last `REMOTE_STDERR_LINES` lines. This is where ssh's own errors and anything the
+/// remote server prints end up.
+async fn drain_stderr(stderr: ChildStderr) -> Vec<String> {
+    let mut lines = BufReader::new(stderr).lines();
+    let mut tail = VecDeque::with_capacity(REMOTE_STDERR_LINES);
+    while let Ok(Some(line)) = lines.next_line().await {
+        warn!(target: "remote", "{}", line);
+        if tail.len() == REMOTE_STDERR_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line);
+    }
+    tail.into()
+}
+
+impl<W: AsyncWrite + Unpin, R: AsyncRead + Unpin> SSHTunnel<W, R> {
+    /// Wraps a writer to and a reader from the remote side, buffering both.
+    pub fn from_io(stdin: W, stdout: R) -> Self {
+        SSHTunnel {
+            stdin: BufWriter::with_capacity(TUNNEL_BUFFER_SIZE, stdin),
+            stdout: BufReader::with_capacity(TUNNEL_BUFFER_SIZE, stdout),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            remote: None,
+            codec: Codec::None,
+        }
+    }
+
+    /// Turns an I/O error on the tunnel into `Error::RemoteExited` if the remote process has
+    /// exited, which is the usual reason for its pipes to close.
+    async fn remote_error(&mut self, e: io::Error) -> Error {
+        let Some(remote) = &mut self.remote else {
+            return e.into();
+        };
+        let Ok(Ok(status)) = tokio::time::timeout(REMOTE_EXIT_TIMEOUT, remote.child.wait()).await
+        else {
+            return e.into();
+        };
+        let stderr = tokio::time::timeout(REMOTE_EXIT_TIMEOUT, &mut remote.stderr)
+            .await
+            .ok()
+            .and_then(|lines| lines.ok())
+            .unwrap_or_default();
+        Error::RemoteExited { status, stderr }
+    }
+
+    async fn write_frame(&mut self, msg: Message) -> Result<()> {
+        let frame = encode_frame(msg, self.codec)?;
+        self.stdin.write_all(&frame).await?;
         Ok(())
     }
-    async fn read_message(&mut self) -> Result<Message> {
-        dbg!("read message len");
-        let mut len_buf = [0u8; 4];
 
+    async fn read_frame(&mut self) -> Result<Message> {
+        let mut len_buf = [0u8; 4];
         self.stdout.read_exact(&mut len_buf).await?;
-        dbg!("parse message len");
-        let msg_len = u32::from_be_bytes(len_buf) as usize;
-        dbg!("read message");
-        let mut buf = vec![0u8; msg_len];
-        self.stdout.read_exact(&mut buf).await?;
-        let (msg, _): (Message, usize) =
-            bincode::serde::decode_from_slice(&buf, bincode::config::standard())?;
-        Ok(msg)
+        let (msg_len, compressed) = frame_len(len_buf, self.max_message_size)?;
+        debug!("reading a {} byte message", msg_len);
+        let buf = read_payload(&mut self.stdout, msg_len).await?;
+        decode_payload(&buf, compressed, self.codec, self.max_message_size)
+    }
+}
+
+/// Environment variable `sshpass -e` reads the password from.
+const SSHPASS_ENV: &str = "SSHPASS";
+
+/// Builds the ssh invocation for `command`, running `program` unless `command.rsh` names
+/// another shell. With a password set, ssh is run under `sshpass -e`, which reads it from
+/// the environment so it never appears in argv.
+fn ssh_command(program: &OsStr, command: &SSHCommand) -> Command {
+    let mut rsh = command.rsh.iter().flat_map(|rsh| rsh.split_whitespace());
+    let program = rsh.next().map_or(program, OsStr::new);
+    let mut cmd = match &command.password {
+        Some(password) => {
+            let mut cmd = Command::new("sshpass");
+            cmd.env(SSHPASS_ENV, password).arg("-e").arg(program);
+            cmd
+        }
+        None => Command::new(program),
+    };
+    cmd.args(rsh);
+    for option in &command.ssh_options {
+        cmd.arg("-o").arg(option);
+    }
+    if let Some(port) = command.port {
+        cmd.arg("-p").arg(port.to_string());
+    }
+    cmd.arg(format!("{}@{}", command.username, command.host)); // "username@host"
+    cmd.arg(command.remote_cmd.clone());
+    cmd
+}
+
+impl MemoryTunnel {
+    /// Creates two tunnels connected back to back in-process, each buffering up to
+    /// `max_buf_size` bytes in either direction.
+    pub fn pair(max_buf_size: usize) -> (Self, Self) {
+        let (a, b) = duplex(max_buf_size);
+        (Self::from_stream(a), Self::from_stream(b))
+    }
+
+    fn from_stream(stream: DuplexStream) -> Self {
+        let (stdout, stdin) = split(stream);
+        SSHTunnel::from_io(stdin, stdout)
+    }
+}
+
+#[async_trait]
+impl<W, R> Tunnel for SSHTunnel<W, R>
+where
+    W: AsyncWrite + Unpin + Send,
+    R: AsyncRead + Unpin + Send,
+{
+    async fn write_message(&mut self, msg: Message) -> Result<()> {
+        self.write_message_buffered(msg).await?;
+        self.flush().await
+    }
+    async fn read_message(&mut self) -> Result<Message> {
+        loop {
+            match self.read_frame().await {
+                Ok(Message::Ping) => self.write_message(Message::Pong).await?,
+                Ok(Message::Pong) => {}
+                Err(Error::IO(e)) => return Err(self.remote_error(e).await),
+                result => return result,
+            }
+        }
+    }
+    async fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+    async fn read_hello(&mut self) -> Result<Hello> {
+        match skip_to_hello(&mut self.stdout, self.max_message_size).await {
+            Err(Error::IO(e)) => Err(self.remote_error(e).await),
+            result => result,
+        }
+    }
+    async fn write_message_buffered(&mut self, msg: Message) -> Result<()> {
+        match self.write_frame(msg).await {
+            Err(Error::IO(e)) => Err(self.remote_error(e).await),
+            result => result,
+        }
+    }
+    async fn flush(&mut self) -> Result<()> {
+        match self.stdin.flush().await {
+            Err(e) => Err(self.remote_error(e).await),
+            Ok(()) => Ok(()),
+        }
+    }
+    async fn shutdown(&mut self) -> Result<()> {
+        match self.stdin.shutdown().await {
+            Err(e) => Err(self.remote_error(e).await),
+            Ok(()) => Ok(()),
+        }
     }
 }
 
 impl Pipeline {
     pub async fn new(command: SSHCommand) -> Result<Self> {
-        let tunnel = Box::new(SSHTunnel::new(command).await);
-        Ok(Self {
+        let tunnel = Box::new(SSHTunnel::new(command).await?);
+        Ok(Self::with_tunnel(tunnel))
+    }
+    /// A client connected to a server running on its own task in this process, for syncing
+    /// between two local paths without ssh.
+    pub fn local() -> Self {
+        let (client, server) = MemoryTunnel::pair(LOCAL_BUFFER_SIZE);
+        tokio::spawn(async move {
+            if let Err(e) = run_server(server, None).await {
+                warn!("local server failed: {}", e);
+            }
+        });
+        Self::with_tunnel(Box::new(client))
+    }
+    pub fn with_tunnel(tunnel: Box<dyn Tunnel>) -> Self {
+        Self {
             tunnel,
             connected: PipelineState::Disconnected,
             flist: Vec::new(),
-            stats: Vec::new(),
-        })
+            stats: TransferStats::default(),
+            signature_cache: None,
+            cancel: CancellationToken::new(),
+            hooks: Hooks::default(),
+            batch: None,
+            fs: Arc::new(RealFs),
+            compress: false,
+            codecs: Codec::SUPPORTED.to_vec(),
+            codec: Codec::None,
+            progress: None,
+            fuzzy: Default::default(),
+        }
+    }
+    /// Reads and writes the synced files through `fs` instead of the local disk.
+    pub fn with_fs(self, fs: Arc<dyn FileSystem>) -> Self {
+        Self { fs, ..self }
     }
+    /// Calls `progress` with each [`ProgressEvent`] of a sync.
+    pub fn with_progress(self, progress: impl Fn(ProgressEvent) + Send + Sync + 'static) -> Self {
+        Self {
+            progress: Some(Arc::new(progress)),
+            ..self
+        }
+    }
+    fn report(&self, event: ProgressEvent) {
+        if let Some(progress) = &self.progress {
+            progress(event);
+        }
+    }
+    /// Greets the server with `Hello` and `SYNC`, skipping anything printed ahead of its
+    /// `Hello` in reply, and waits for its `ACK`.
     pub async fn init(&mut self) -> Result<()> {
+        let offered = if self.compress {
+            self.codecs.clone()
+        } else {
+            Vec::new()
+        };
+        self.tunnel
+            .write_message_buffered(Message::Hello(Hello::new(offered.clone())))
+            .await?;
         self.tunnel.write_message(Message::SYNC).await?;
         self.connected = PipelineState::Connecting;
+        let hello = self.tunnel.read_hello().await?;
+        let codec = Codec::negotiate(&offered, &hello.codecs);
+        match codec {
+            Codec::None if self.compress => {
+                warn!("the server supports none of {:?}, not compressing", offered)
+            }
+            Codec::None => {}
+            codec => info!("compressing with {}", codec),
+        }
+        self.codec = codec;
+        self.tunnel.set_codec(codec).await;
         let msg = self.tunnel.read_message().await?;
-        dbg!(&msg);
+        debug!("handshake answered with {}", msg);
         match msg {
             Message::ACK => {
                 self.connected = PipelineState::Connected;
@@ -148,32 +736,38 @@ impl Pipeline {
                 Err(Error::Nack)
             }
             _ => {
-                self.connected = PipelineState::Error(Error::UnexpectedMessage(msg.clone()));
-                Err(Error::UnexpectedMessage(msg))
+                self.connected =
+                    PipelineState::Error(Error::UnexpectedMessage(Box::new(msg.clone())));
+                Err(Error::UnexpectedMessage(Box::new(msg)))
             }
         }
     }
-    pub async fn send_arguments(&mut self, opts: crate::cli::ClientServerOpts) -> Result<()> {
+    pub async fn send_arguments(&mut self, opts: ClientServerOpts) -> Result<()> {
         self.tunnel
-            .write_message(Message::Arguments(opts.clone()))
+            .write_message(Message::Arguments(Box::new(opts)))
             .await?;
         Ok(())
     }
     pub async fn receive_flist(&mut self) -> Result<()> {
         loop {
-            dbg!("receive flist");
             let msg = self.tunnel.read_message().await?;
-            dbg!(&msg);
             match msg {
                 Message::FlistEntry(entry) => {
+                    check_flist_entry(&self.flist, &entry)?;
                     self.flist.push(entry);
                 }
+                Message::FlistBatch(entries) => {
+                    for entry in entries {
+                        check_flist_entry(&self.flist, &entry)?;
+                        self.flist.push(entry);
+                    }
+                }
                 Message::FlistEnd => {
-                    dbg!("flist end");
+                    debug!("received {} flist entries", self.flist.len());
                     return Ok(());
                 }
                 _ => {
-                    //   self.connected = PipelineState::Error(Error::UnexpectedMessage(msg.clone()));
+                    //   self.connected = PipelineState::Error(Error::UnexpectedMessage(Box::new(msg.clone())));
                     //  return Err(Error::UnexpectedMessage(msg));
                     continue;
                 }
@@ -195,22 +789,212 @@ impl Pipeline {
                     return Err(Error::IoTimeout);
                 }
                 _ => {
-                    self.connected = PipelineState::Error(Error::UnexpectedMessage(msg.clone()));
-                    return Err(Error::UnexpectedMessage(msg));
+                    self.connected =
+                        PipelineState::Error(Error::UnexpectedMessage(Box::new(msg.clone())));
+                    return Err(Error::UnexpectedMessage(Box::new(msg)));
+                }
+            }
+        }
+    }
+}
+
+/// Fails on an flist entry from the other side whose filename would reach outside the local
+/// root it is joined onto, or that is a hard link to anything but a regular file among the
+/// `earlier` entries.
+fn check_flist_entry(earlier: &[FlistEntry], entry: &FlistEntry) -> io::Result<()> {
+    if !entry.filename.is_below_root() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "refusing file name {:?} outside the root",
+                entry.filename.as_path()
+            ),
+        ));
+    }
+    if let Some(target) = entry.hardlink_to
+        && !earlier
+            .get(target as usize)
+            .is_some_and(FlistEntry::is_regular)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "refusing hard link {} to entry {}, which is not an earlier regular file",
+                entry.filename, target
+            ),
+        ));
+    }
+    Ok(())
+}
+
+impl Pipeline {
+    /// Runs the client side of a sync against the files below `local_root`, sending or
+    /// receiving them depending on `opts.direction`, wrapped in the configured [`Hooks`].
+    pub async fn sync(
+        &mut self,
+        local_root: &Path,
+        mut opts: ClientServerOpts,
+    ) -> Result<TransferStats> {
+        opts.validate()?;
+        if opts.direction == Direction::Pull {
+            opts.resolve_chown()?;
+        }
+        let hooks = self.hooks.clone();
+        let stats = hooks.around(self.run_sync(local_root, opts)).await?;
+        self.report(ProgressEvent::Finished(stats.clone()));
+        Ok(stats)
+    }
+
+    async fn run_sync(
+        &mut self,
+        local_root: &Path,
+        opts: ClientServerOpts,
+    ) -> Result<TransferStats> {
+        self.init().await?;
+        self.send_arguments(opts.clone()).await?;
+        self.tunnel.write_message(Message::ACK).await?;
+        let stats = match opts.direction {
+            Direction::Push => {
+                let flist = self.fs.build_flist(local_root, &opts, &mut self.stats)?;
+                self.send_flist(flist).await?;
+                self.log_empty_flist();
+                self.process_flist(local_root, &opts).await
+            }
+            Direction::Pull => {
+                self.make_root(local_root, &opts)?;
+                self.receive_flist().await?;
+                self.log_empty_flist();
+                // Still waits for the sender's `Done`, so an empty run ends like any other
+                self.receive_files(local_root, &opts).await
+            }
+        };
+        if let Some(batch) = &mut self.batch {
+            batch.flist = self.flist.clone();
+        }
+        stats
+    }
+
+    fn log_empty_flist(&self) {
+        if self.flist.is_empty() {
+            info!("0 files to sync");
+        }
+    }
+
+    /// Runs the server side of a sync: answers the handshake, then plays the role opposite to
+    /// the client's on the files below `opts.to`.
+    pub async fn serve(&mut self) -> Result<TransferStats> {
+        // The client's `Arguments` and closing `ACK` may come in either order
+        let mut args = None;
+        let mut acked = false;
+        let opts = loop {
+            let msg = self.tunnel.read_message().await?;
+            self.handle_handshake(msg, &mut args, &mut acked).await?;
+            if acked && let Some(opts) = args.take() {
+                break opts;
+            }
+        };
+        let root = expand_tilde(&opts.to);
+        if opts.verify {
+            let opts = verify::listing_opts(&opts, opts.direction == Direction::Pull);
+            let flist = self.fs.build_flist(&root, &opts, &mut self.stats)?;
+            self.send_flist(flist).await?;
+            self.send_checksums(&root, &opts).await?;
+            return Ok(self.stats.clone());
+        }
+        if opts.list_only {
+            let flist = self.fs.build_flist(&root, &opts, &mut self.stats)?;
+            self.send_flist(flist).await?;
+            return Ok(self.stats.clone());
+        }
+        match opts.direction {
+            Direction::Push => {
+                if let Err(e) = self.make_root(&root, &opts) {
+                    let msg = Message::Error(SSHMessageError::FatalError(e.to_string()));
+                    self.tunnel.write_message(msg).await?;
+                    return Err(e);
                 }
+                self.receive_flist().await?;
+                self.receive_files(&root, &opts).await
+            }
+            Direction::Pull => {
+                let flist = self.fs.build_flist(&root, &opts, &mut self.stats)?;
+                self.send_flist(flist).await?;
+                self.process_flist(&root, &opts).await
             }
         }
     }
-    pub async fn process_flist(&mut self) -> Result<()> {
-        todo!()
+
+    /// Handles one handshake message on the server, storing the client's options in `args`
+    /// and setting `acked` on its closing `ACK`. Anything but `Arguments` after that `ACK` is
+    /// an error, since the client has moved on without them.
+    #[instrument(skip(self, msg, args, acked), fields(kind = %msg))]
+    async fn handle_handshake(
+        &mut self,
+        msg: Message,
+        args: &mut Option<ClientServerOpts>,
+        acked: &mut bool,
+    ) -> Result<()> {
+        match msg {
+            Message::Hello(hello) => {
+                info!("Hello offering {:?}", hello.codecs);
+                self.tunnel
+                    .write_message(Message::Hello(Hello::new(self.codecs.clone())))
+                    .await?;
+                // Only frames written after this `Hello` may be compressed
+                let codec = Codec::negotiate(&hello.codecs, &self.codecs);
+                self.codec = codec;
+                self.tunnel.set_codec(codec).await;
+            }
+            Message::SYNC => {
+                info!("SYNC");
+                self.connected = PipelineState::Connected;
+                self.tunnel.write_message(Message::ACK).await?;
+            }
+            Message::Arguments(mut opts) => {
+                info!("arguments: {:?}", opts);
+                let checked = opts.validate().and_then(|()| match opts.direction {
+                    Direction::Push => opts.resolve_chown(),
+                    Direction::Pull => Ok(()),
+                });
+                if let Err(e) = checked {
+                    let msg = Message::Error(SSHMessageError::FatalError(e.to_string()));
+                    self.tunnel.write_message(msg).await?;
+                    return Err(e.into());
+                }
+                *args = Some(*opts);
+            }
+            Message::ACK => {
+                info!("ACK");
+                *acked = true;
+            }
+            _ if *acked => {
+                let msg = Message::Error(SSHMessageError::FatalError(
+                    Error::MissingArguments.to_string(),
+                ));
+                self.tunnel.write_message(msg).await?;
+                return Err(Error::MissingArguments);
+            }
+            _ => {
+                let msg = Message::Error(SSHMessageError::FatalError(
+                    "Unknown message received".to_string(),
+                ));
+                self.tunnel.write_message(msg).await?;
+            }
+        }
+        Ok(())
     }
 }
 
 impl ReceiverSSHTunnel {
     pub fn new() -> Self {
-        let stdin = tokio::io::stdin();
-        let stdout = tokio::io::stdout();
-        ReceiverSSHTunnel { stdin, stdout }
+        let stdin = BufReader::with_capacity(TUNNEL_BUFFER_SIZE, tokio::io::stdin());
+        let stdout = BufWriter::with_capacity(TUNNEL_BUFFER_SIZE, tokio::io::stdout());
+        ReceiverSSHTunnel {
+            stdin,
+            stdout,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            codec: Codec::None,
+        }
     }
 }
 
@@ -223,26 +1007,43 @@ impl Default for ReceiverSSHTunnel {
 #[async_trait]
 impl Tunnel for ReceiverSSHTunnel {
     async fn write_message(&mut self, msg: Message) -> Result<()> {
-        let bin_msg = bincode::serde::encode_to_vec(msg, bincode::config::standard())?;
-        let msg_len = bin_msg.len() as u32;
-        info!("write message len {}", msg_len);
-        self.stdout.write_all(&msg_len.to_be_bytes()).await?;
-        self.stdout.write_all(&bin_msg).await?;
+        self.write_message_buffered(msg).await?;
+        self.flush().await
+    }
+    async fn read_message(&mut self) -> Result<Message> {
+        loop {
+            let mut len_buf = [0u8; 4];
+            self.stdin.read_exact(&mut len_buf).await?;
+            let (msg_len, compressed) = frame_len(len_buf, self.max_message_size)?;
+            debug!("reading a {} byte message", msg_len);
+            let buf = read_payload(&mut self.stdin, msg_len).await?;
+            let msg = decode_payload(&buf, compressed, self.codec, self.max_message_size)?;
+            debug!("read {}", msg);
+            match msg {
+                Message::Ping => self.write_message(Message::Pong).await?,
+                Message::Pong => {}
+                msg => return Ok(msg),
+            }
+        }
+    }
+    async fn write_message_buffered(&mut self, msg: Message) -> Result<()> {
+        let frame = encode_frame(msg, self.codec)?;
+        info!("write message len {}", frame.len() - FRAME_HEADER_LEN);
+        self.stdout.write_all(&frame).await?;
+        Ok(())
+    }
+    async fn flush(&mut self) -> Result<()> {
         self.stdout.flush().await?;
         Ok(())
     }
-    async fn read_message(&mut self) -> Result<Message> {
-        let mut len_buf = [0u8; 4];
-        dbg!("read message len");
-        self.stdin.read_exact(&mut len_buf).await?;
-        let msg_len = u32::from_be_bytes(len_buf) as usize;
-
-        dbg!("read message");
-        let mut buf = vec![0u8; msg_len];
-        self.stdin.read_exact(&mut buf).await?;
-        let (msg, _): (Message, usize) =
-            bincode::serde::decode_from_slice(&buf, bincode::config::standard())?;
-        dbg!(&msg);
-        Ok(msg)
+    async fn shutdown(&mut self) -> Result<()> {
+        self.stdout.shutdown().await?;
+        Ok(())
+    }
+    async fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+    async fn read_hello(&mut self) -> Result<Hello> {
+        skip_to_hello(&mut self.stdin, self.max_message_size).await
     }
 }
@@ -0,0 +1,91 @@
+//! Reconnecting after the connection to the server was lost, see
+//! [`Pipeline::sync_with_retries`].
+//!
+//! A frame cut off halfway leaves nothing to pick up on the same stream, and a dead ssh
+//! process no stream at all, so a retry starts over on a fresh connection. Files that already
+//! made it are sent again, but as a delta that only references the receiver's copy (or not at
+//! all with `--checksum`), and with `--partial-dir` a file that was cut off resumes from what
+//! reached the receiver. Errors other than a lost
+//! connection, in particular a frame that fails to decode or a server that refused the
+//! options, are returned straight away.
+
+use std::{future::Future, io, path::Path};
+
+use tracing::warn;
+
+use super::{Error, Pipeline, Result, RetryPolicy, TransferStats};
+use crate::cli::ClientServerOpts;
+
+/// Exit status of ssh when the connection itself failed, rather than the remote command.
+const SSH_CONNECTION_ERROR: i32 = 255;
+
+impl Error {
+    /// Whether the connection to the other side broke, so a fresh one may get further.
+    pub fn is_connection_lost(&self) -> bool {
+        match self {
+            Error::IO(e) => matches!(
+                e.kind(),
+                io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::TimedOut
+            ),
+            Error::RemoteExited { status, .. } => status.code() == Some(SSH_CONNECTION_ERROR),
+            _ => false,
+        }
+    }
+}
+
+impl Pipeline {
+    /// Syncs like [`Pipeline::sync`] over a pipeline made by `connect`, and whenever the
+    /// connection is lost, connects again and starts over, up to `policy.retries` times. The
+    /// [`super::Hooks`] of the first pipeline run once around all attempts, and the stats are
+    /// those of the attempt that finished. Returns that attempt's pipeline, its tunnel shut
+    /// down.
+    pub async fn sync_with_retries<F, Fut>(
+        mut connect: F,
+        policy: RetryPolicy,
+        local_root: &Path,
+        opts: ClientServerOpts,
+    ) -> Result<(Pipeline, TransferStats)>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<Pipeline>>,
+    {
+        let mut pipeline = connect().await?;
+        let hooks = std::mem::take(&mut pipeline.hooks);
+        let mut attempt = 0;
+        let stats = hooks
+            .around(async {
+                loop {
+                    match pipeline.sync(local_root, opts.clone()).await {
+                        Err(e) if e.is_connection_lost() && attempt < policy.retries => {
+                            backoff(&policy, attempt, &e).await;
+                            attempt += 1;
+                            pipeline = connect().await?;
+                            pipeline.hooks = Default::default();
+                        }
+                        result => return result,
+                    }
+                }
+            })
+            .await?;
+        pipeline.tunnel.shutdown().await?;
+        Ok((pipeline, stats))
+    }
+}
+
+/// Sleeps for `policy.delay`, doubled for every earlier attempt.
+async fn backoff(policy: &RetryPolicy, attempt: u32, error: &Error) {
+    let delay = policy.delay.saturating_mul(1 << attempt.min(16));
+    warn!(
+        "{}, reconnecting in {:?} ({}/{})",
+        error,
+        delay,
+        attempt + 1,
+        policy.retries
+    );
+    tokio::time::sleep(delay).await;
+}
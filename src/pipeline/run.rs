@@ -0,0 +1,99 @@
+//! The two ends of a run as the binary drives them, usable over any [`Tunnel`].
+//!
+//! `oxide_sync --server` is [`run_server`] over stdin/stdout; the client is
+//! [`Pipeline::run_client`] over ssh, or over an in-process tunnel for local syncs.
+
+use std::path::Path;
+
+use super::{FlistEntry, Pipeline, Result, SignatureCache, TransferStats, Tunnel, VerifyReport};
+use crate::cli::ClientServerOpts;
+
+/// What a client run produced, depending on `opts.list_only` and `opts.verify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientOutcome {
+    Synced(TransferStats),
+    Listed(Vec<FlistEntry>),
+    Verified(VerifyReport),
+}
+
+impl ClientOutcome {
+    /// The exit code the binary reports this outcome with.
+    pub fn exit_code(&self) -> ExitCode {
+        let partial = match self {
+            ClientOutcome::Synced(stats) => !stats.failures.is_empty() || stats.cancelled,
+            ClientOutcome::Listed(_) => false,
+            ClientOutcome::Verified(report) => !report.is_clean(),
+        };
+        if partial {
+            ExitCode::PartialFailure
+        } else {
+            ExitCode::Success
+        }
+    }
+}
+
+/// How a run of the binary ended, as its process exit code:
+///
+/// | Code | Meaning |
+/// |------|---------|
+/// | 0 | Everything was transferred, listed or verified |
+/// | 1 | Some files failed to transfer, the run was cancelled, or verification found differences |
+/// | 2 | The run failed as a whole: connection, protocol or I/O errors |
+/// | 3 | Invalid arguments, options or config |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Success = 0,
+    PartialFailure = 1,
+    Protocol = 2,
+    Usage = 3,
+}
+
+impl From<ExitCode> for std::process::ExitCode {
+    fn from(code: ExitCode) -> Self {
+        std::process::ExitCode::from(code as u8)
+    }
+}
+
+/// Serves a single client at the other end of `tunnel`.
+pub async fn run_server<T: Tunnel + 'static>(
+    tunnel: T,
+    signature_cache: Option<SignatureCache>,
+) -> Result<TransferStats> {
+    let mut pipeline = Pipeline::with_tunnel(Box::new(tunnel));
+    pipeline.signature_cache = signature_cache;
+    let stats = pipeline.serve().await?;
+    pipeline.tunnel.shutdown().await?;
+    Ok(stats)
+}
+
+/// Runs the client side against the server at the other end of `tunnel` with a default
+/// [`Pipeline`]; see [`Pipeline::run_client`] to configure it first.
+pub async fn run_client<T: Tunnel + 'static>(
+    tunnel: T,
+    local_root: &Path,
+    opts: ClientServerOpts,
+) -> Result<ClientOutcome> {
+    Pipeline::with_tunnel(Box::new(tunnel))
+        .run_client(local_root, opts)
+        .await
+}
+
+impl Pipeline {
+    /// Lists, verifies or syncs the files below `local_root`, whichever `opts` asks for, then
+    /// shuts the tunnel down so the server reads EOF and exits.
+    pub async fn run_client(
+        &mut self,
+        local_root: &Path,
+        opts: ClientServerOpts,
+    ) -> Result<ClientOutcome> {
+        let outcome = if opts.list_only {
+            ClientOutcome::Listed(self.list(opts).await?)
+        } else if opts.verify {
+            ClientOutcome::Verified(self.verify(local_root, opts).await?)
+        } else {
+            ClientOutcome::Synced(self.sync(local_root, opts).await?)
+        };
+        self.tunnel.shutdown().await?;
+        Ok(outcome)
+    }
+}
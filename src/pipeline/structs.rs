@@ -1,34 +1,190 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
 use async_trait::async_trait;
 use derive_setters::Setters;
 use serde::{Deserialize, Serialize};
 use strum::Display;
-use tokio::io::{AsyncRead, AsyncWrite, Stdin, Stdout};
+use tokio::{
+    io::{
+        AsyncRead, AsyncWrite, BufReader, BufWriter, DuplexStream, ReadHalf, Stdin, Stdout,
+        WriteHalf,
+    },
+    process::{Child, ChildStdin},
+    task::JoinHandle,
+};
 
-use crate::{cli::ClientServerOpts, cryptography::IndexTable};
+use crate::{
+    cli::ClientServerOpts,
+    cryptography::{Delta, IndexTable, Ops},
+    filesystem::FileSystem,
+};
 
 use super::Result;
 
-#[derive(Debug, Clone, Setters)]
+#[derive(Clone, Setters)]
 pub struct SSHCommand {
     #[setters(generate = false)]
     pub host: Box<str>,
-    pub port: u16,
+    /// Passed as `-p` if set, otherwise ssh picks the port from its configuration
+    pub port: Option<u16>,
     pub username: Box<str>,
     #[setters(generate)]
     pub password: Option<String>,
     pub remote_cmd: String,
+    /// Program and extra arguments, split on whitespace, run instead of `ssh`
+    pub rsh: Option<String>,
+    /// `KEY=VALUE` settings each passed as `-o KEY=VALUE`
+    pub ssh_options: Vec<String>,
+}
+
+impl std::fmt::Debug for SSHCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SSHCommand")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("remote_cmd", &self.remote_cmd)
+            .field("rsh", &self.rsh)
+            .field("ssh_options", &self.ssh_options)
+            .finish()
+    }
 }
 
-#[derive(Debug, Clone)]
+/// Largest message a tunnel accepts unless configured otherwise.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
+#[derive(Debug, Setters)]
 pub struct SSHTunnel<W: AsyncWrite + Unpin, R: AsyncRead + Unpin> {
-    pub stdin: W,
-    pub stdout: R,
+    #[setters(skip)]
+    pub stdin: BufWriter<W>,
+    #[setters(skip)]
+    pub stdout: BufReader<R>,
+    /// Length prefixes above this are rejected before anything is allocated
+    pub max_message_size: usize,
+    /// The process at the other end, if the tunnel spawned one
+    #[setters(skip)]
+    pub remote: Option<RemoteProcess>,
+    /// Compresses outgoing frames and decompresses incoming ones, see [`Tunnel::set_codec`]
+    #[setters(skip)]
+    pub codec: Codec,
+}
+
+/// The ssh child behind a tunnel, kept to report why the connection dropped.
+#[derive(Debug)]
+pub struct RemoteProcess {
+    pub child: Child,
+    /// Drains the child's stderr, returning its last lines once it closes
+    pub stderr: JoinHandle<Vec<String>>,
 }
 
+/// The stdin of the ssh child behind a tunnel. Tokio only closes a child's stdin when it is
+/// dropped, so this drops it on shutdown to let the remote side read EOF.
+#[derive(Debug)]
+pub struct RemoteStdin(pub(super) Option<ChildStdin>);
+
+/// A tunnel over an in-process stream, for running the protocol without SSH. See
+/// [`MemoryTunnel::pair`].
+pub type MemoryTunnel = SSHTunnel<WriteHalf<DuplexStream>, ReadHalf<DuplexStream>>;
+
+/// The signature table of a base file, serialized so the receiver can check it before
+/// decoding, see [`DataMessage::table`].
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct DataMessage {
-    pub map: IndexTable,
+    pub table: Vec<u8>,
+    /// CRC32 of `table`
+    pub checksum: u32,
+    pub file_index: u32,
+    /// Where the table starts in the base: the receiver keeps everything before it, see
+    /// [`FlistEntry::synced_size`]
+    pub offset: u64,
+}
+
+impl DataMessage {
+    pub fn new(map: &IndexTable, file_index: u32) -> Result<Self> {
+        let table = bincode::serde::encode_to_vec(map, bincode::config::standard())?;
+        Ok(Self {
+            checksum: crc32fast::hash(&table),
+            table,
+            file_index,
+            offset: 0,
+        })
+    }
+
+    /// Decodes the table, failing with [`super::Error::ChecksumMismatch`] if it does not match
+    /// its checksum.
+    pub fn table(&self) -> Result<IndexTable> {
+        let actual = crc32fast::hash(&self.table);
+        if actual != self.checksum {
+            return Err(super::Error::ChecksumMismatch {
+                len: self.table.len(),
+                expected: self.checksum,
+                actual,
+            });
+        }
+        let (map, _) = bincode::serde::decode_from_slice(&self.table, bincode::config::standard())?;
+        Ok(map)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DeltaMessage {
+    pub delta: Delta,
+    pub file_index: u32,
+    /// Strong signature of the whole source file, checked after reconstruction; `None` skips
+    /// the check
+    pub checksum: Option<String>,
+}
+
+/// Consecutive ops of a delta too large for a single `Delta`, see [`Delta::chunks`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DeltaChunk {
     pub file_index: u32,
+    pub ops: Vec<Ops>,
+}
+
+/// What each side sends in its [`Message::Hello`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Hello {
+    /// Always [`super::HELLO_MAGIC`]
+    pub magic: [u8; 8],
+    /// Codecs the side can compress frames with, best first, see [`Codec::negotiate`]
+    pub codecs: Vec<Codec>,
+}
+
+impl Hello {
+    pub fn new(codecs: Vec<Codec>) -> Self {
+        Self {
+            magic: super::HELLO_MAGIC,
+            codecs,
+        }
+    }
+}
+
+/// How frame payloads are compressed, see [`super::codec`].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default, Display)]
+pub enum Codec {
+    #[default]
+    None,
+    Zstd,
+    Gzip,
+}
+
+/// Strong signature of a whole file, `None` if the sending side could not read it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FileChecksum {
+    pub file_index: u32,
+    pub strong: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Display)]
@@ -36,8 +192,10 @@ pub enum Message {
     SYNC,
     ACK,
     NACK,
-    Arguments(ClientServerOpts),
+    Arguments(Box<ClientServerOpts>),
     Data(DataMessage),
+    Delta(DeltaMessage),
+    Checksum(FileChecksum),
     Redo(u32),
     Done,                   // MSG_DONE
     Error(SSHMessageError), // MSG_ERROR
@@ -50,9 +208,25 @@ pub enum Message {
     Deleted(u32),           // MSG_DELETED
     Success(u32),           // MSG_SUCCESS
     Degenerate(u32),        // MSG_DEGENERATE
-    Stats(Vec<u8>),         // MSG_STATS
+    Stats(TransferStats),   // MSG_STATS
     IoTimeout,              // MSG_IO_TIMEOUT
+    /// Answers a `FileIndex` for a file the receiver doesn't want, e.g. with `--existing`
     NoSend(u32),
+    /// Consecutive flist entries sent in one frame, see [`super::FLIST_BATCH_SIZE`]
+    FlistBatch(Vec<FlistEntry>),
+    /// Keeps an idle connection alive, see [`HeartbeatTunnel`]. Tunnels answer it with `Pong`
+    /// while reading, and skip `Pong`s, so neither ever reaches the protocol.
+    Ping,
+    Pong,
+    /// Opens a connection. The client sends it ahead of `SYNC` and the server answers with
+    /// its own, so the client can skip any banner the remote shell printed before it, see
+    /// [`Tunnel::read_hello`].
+    Hello(Hello),
+    /// Part of a delta larger than [`super::DELTA_CHUNK_SIZE`], which is sent as a run of
+    /// chunks instead of a single `Delta`
+    DeltaChunk(DeltaChunk),
+    /// Ends a run of `DeltaChunk`s, carrying the checksum a `Delta` would
+    DeltaEnd(FileChecksum),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, thiserror::Error, Display, PartialEq, Eq)]
@@ -64,22 +238,260 @@ pub enum SSHMessageError {
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct FlistEntry {
-    pub index: u32,       // file index (assigned by sender)
-    pub filename: String, // path relative to the sync root
-    pub size: u64,        // file size in bytes
-    pub mtime: i64,       // modification time (epoch seconds)
-    pub mode: u32,        // permissions (POSIX-style)
-    pub uid: Option<u32>, // optional owner user id
-    pub gid: Option<u32>, // optional group id
-    pub is_dir: bool,     // directory marker
-    pub is_symlink: bool, // symlink marker
+    pub index: u32,                   // file index (assigned by sender)
+    pub filename: FileName,           // path relative to the sync root
+    pub size: u64,                    // file size in bytes
+    pub mtime: i64,                   // modification time (epoch seconds)
+    pub mode: u32,                    // permissions (POSIX-style)
+    pub uid: Option<u32>,             // optional owner user id
+    pub gid: Option<u32>,             // optional group id
+    pub is_dir: bool,                 // directory marker
+    pub is_symlink: bool,             // symlink marker
+    pub hardlink_to: Option<u32>,     // earlier entry sharing the same inode
+    pub user: Option<String>,         // owner name on the sending host
+    pub group: Option<String>,        // group name on the sending host
+    pub special: Option<SpecialFile>, // device or FIFO node, recreated rather than transferred
+    pub checksum: Option<String>,     // strong signature of the contents, if asked for
+    /// Size of the receiver's copy, which it keeps as is with `--append`. Only ever set on the
+    /// receiver's flist
+    pub synced_size: Option<u64>,
+}
+
+impl FlistEntry {
+    /// Whether the entry has contents to transfer.
+    pub fn is_regular(&self) -> bool {
+        !self.is_dir && !self.is_symlink && self.special.is_none()
+    }
+
+    /// Whether both entries have the same contents by their checksums, `None` unless both
+    /// carry one.
+    pub fn same_contents(&self, other: &FlistEntry) -> Option<bool> {
+        Some(self.size == other.size && self.checksum.as_ref()? == other.checksum.as_ref()?)
+    }
+}
+
+/// A path relative to the sync root, kept as the raw bytes of the sender's `OsStr` so names
+/// that are not valid UTF-8 arrive intact. Only its [`Display`](std::fmt::Display) is lossy.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct FileName(pub Vec<u8>);
+
+impl FileName {
+    pub fn as_path(&self) -> &Path {
+        Path::new(OsStr::from_bytes(&self.0))
+    }
+
+    /// Whether the name stays below the root it is joined to: relative, and without `.` or
+    /// `..` components.
+    pub fn is_below_root(&self) -> bool {
+        self.as_path()
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+    }
+}
+
+impl From<&Path> for FileName {
+    fn from(path: &Path) -> Self {
+        Self(path.as_os_str().as_bytes().to_vec())
+    }
+}
+
+impl From<&str> for FileName {
+    fn from(name: &str) -> Self {
+        Self(name.as_bytes().to_vec())
+    }
+}
+
+impl AsRef<Path> for FileName {
+    fn as_ref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl PartialEq<str> for FileName {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for FileName {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl std::fmt::Display for FileName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.as_path().display().fmt(f)
+    }
+}
+
+/// Special files recreated on the receiver with `mknod`. Sockets are never listed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SpecialFile {
+    Fifo,
+    CharDevice { major: u32, minor: u32 },
+    BlockDevice { major: u32, minor: u32 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct TransferStats {
+    pub files_transferred: u32,
+    pub failures: Vec<FileError>,
+    pub excluded_by_size: u32,
+    pub excluded_by_age: u32,
+    /// One line per transferred file when itemizing changes, see [`super::itemize`]
+    pub itemized: Vec<String>,
+    /// The run was stopped early through [`Pipeline::cancel`]
+    pub cancelled: bool,
+    /// Block-level breakdown of every transferred file, by flist index
+    pub files: BTreeMap<u32, FileStats>,
+}
+
+/// How much of a file's delta was copied from the base and how much was sent.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct FileStats {
+    /// Blocks or chunks copied from the base
+    pub matched_blocks: u32,
+    pub literal_blocks: u32,
+    pub bytes_reused: u64,
+    /// Literal bytes carried by the delta
+    pub bytes_sent: u64,
+}
+
+impl FileStats {
+    /// Counts the ops of `delta`, which rebuilds a `total_len` byte file.
+    pub fn from_delta(delta: &Delta, total_len: u64) -> Self {
+        let stats = delta.ops.iter().fold(Self::default(), |mut stats, op| {
+            match op {
+                Ops::Index(_) | Ops::Chunk(_) => stats.matched_blocks += 1,
+                Ops::Block(bytes) | Ops::CompressedBlock(bytes) => {
+                    stats.literal_blocks += 1;
+                    stats.bytes_sent += bytes.len() as u64;
+                }
+            }
+            stats
+        });
+        Self {
+            bytes_reused: total_len.saturating_sub(stats.bytes_sent),
+            ..stats
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FileError {
+    pub file_index: u32,
+    pub filename: String,
+    pub reason: String,
+}
+
+/// Outcome of comparing a destination against its source, by path relative to the sync root.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VerifyReport {
+    /// Present on both sides with different contents
+    pub mismatched: Vec<String>,
+    /// Present in the source only
+    pub missing: Vec<String>,
+    /// Present in the destination only
+    pub extra: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty() && self.extra.is_empty()
+    }
 }
 
 pub struct Pipeline {
     pub tunnel: Box<dyn Tunnel>,
     pub connected: PipelineState,
     pub flist: Vec<FlistEntry>,
-    pub stats: Vec<u8>,
+    pub stats: TransferStats,
+    /// Where the receiving side keeps signature tables of unchanged base files, if anywhere
+    pub signature_cache: Option<super::SignatureCache>,
+    /// Stops the run between files when cancelled, see [`CancellationToken`]
+    pub cancel: CancellationToken,
+    /// Local commands run before and after [`Pipeline::sync`]
+    pub hooks: Hooks,
+    /// Collects the deltas of the files this side sends or receives, if set
+    pub batch: Option<Batch>,
+    /// Where the files this side sends or receives live, see [`crate::filesystem`]
+    pub fs: Arc<dyn FileSystem>,
+    /// Whether a client offers its `codecs` to the server to compress the connection
+    pub compress: bool,
+    /// Codecs this side accepts for compressing the connection, best first
+    pub codecs: Vec<Codec>,
+    /// The codec agreed on in the `Hello` exchange, see [`Codec::negotiate`]
+    pub codec: Codec,
+    /// Called with each [`ProgressEvent`] of a sync, see [`Pipeline::with_progress`]
+    pub progress: Option<ProgressCallback>,
+    /// The directories `--fuzzy` looked for a basis in this run
+    pub(super) fuzzy: super::fuzzy::Candidates,
+}
+
+/// What a sync reports to its [`ProgressCallback`] as it goes.
+///
+/// Every file that is started is completed, unless the run is cancelled in the middle of it.
+/// Files the receiver declines complete without an error and without any bytes processed;
+/// files that are resent after a checksum mismatch report their bytes again from zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// The exchange of a file began; `size` is its length on the sending side
+    FileStarted {
+        file_index: u32,
+        filename: String,
+        size: u64,
+    },
+    /// Literal bytes of the file's delta sent or received so far
+    BytesProcessed { file_index: u32, bytes: u64 },
+    /// The file was transferred, or failed with `error`
+    FileCompleted {
+        file_index: u32,
+        error: Option<String>,
+    },
+    /// The sync ended with these stats
+    Finished(TransferStats),
+}
+
+/// Receives the [`ProgressEvent`]s of a sync, e.g. to render them in a UI.
+pub type ProgressCallback = Arc<dyn Fn(ProgressEvent) + Send + Sync>;
+
+/// The flist and final per-file deltas of a sync, see [`super::batch`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Batch {
+    pub flist: Vec<FlistEntry>,
+    pub deltas: Vec<DeltaMessage>,
+}
+
+/// Shell commands run on the client around a sync, e.g. to snapshot a database first.
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    /// Run before the handshake; the sync is aborted if it exits non-zero
+    pub pre: Option<String>,
+    /// Run after the sync, even if it failed, see [`super::EXIT_STATUS_ENV`]
+    pub post: Option<String>,
+}
+
+/// Requests a graceful stop of a running sync. Clones share the same flag, so a clone can be
+/// kept by the caller and cancelled from another task while the sync runs.
+///
+/// The sync stops before the next file, never in the middle of one, and still returns its
+/// stats with `cancelled` set.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
 }
 
 #[derive(Debug, Default)]
@@ -91,25 +503,84 @@ pub enum PipelineState {
     Error(super::Error),
 }
 
+/// Error states are equal when their errors are of the same [`super::ErrorKind`].
 impl PartialEq for PipelineState {
     fn eq(&self, other: &Self) -> bool {
-        matches!(
-            (self, other),
-            (PipelineState::Disconnected, PipelineState::Disconnected)
-                | (PipelineState::Connecting, PipelineState::Connecting)
-                | (PipelineState::Connected, PipelineState::Connected)
-                | (PipelineState::Error(_), PipelineState::Error(_))
-        )
+        match (self, other) {
+            (PipelineState::Error(a), PipelineState::Error(b)) => a.kind() == b.kind(),
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
     }
 }
 
+#[derive(Setters)]
 pub struct ReceiverSSHTunnel {
-    pub stdin: Stdin,
-    pub stdout: Stdout,
+    #[setters(skip)]
+    pub stdin: BufReader<Stdin>,
+    #[setters(skip)]
+    pub stdout: BufWriter<Stdout>,
+    /// Length prefixes above this are rejected before anything is allocated
+    pub max_message_size: usize,
+    /// Compresses outgoing frames and decompresses incoming ones, see [`Tunnel::set_codec`]
+    #[setters(skip)]
+    pub codec: Codec,
+}
+
+/// How often and how patiently [`Pipeline::sync_with_retries`] reconnects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Connections after the first one; `0` disables retrying
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each one after it
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Wraps another tunnel, sending a `Ping` from a background task whenever no message went
+/// through it for an interval.
+pub struct HeartbeatTunnel {
+    pub(super) inner: Arc<tokio::sync::Mutex<Box<dyn Tunnel>>>,
+    /// When a message last went through, in either direction
+    pub(super) last_activity: Arc<std::sync::Mutex<Instant>>,
+    pub(super) task: JoinHandle<()>,
 }
 
 #[async_trait]
-pub trait Tunnel {
+pub trait Tunnel: Send {
     async fn write_message(&mut self, msg: Message) -> Result<()>;
     async fn read_message(&mut self) -> Result<Message>;
+    /// Like [`Tunnel::write_message`], but the message may stay buffered until the next
+    /// [`Tunnel::flush`] or unbuffered write. For runs of messages the other side doesn't
+    /// answer one by one, such as the flist.
+    async fn write_message_buffered(&mut self, msg: Message) -> Result<()> {
+        self.write_message(msg).await
+    }
+    /// Sends any messages still buffered by [`Tunnel::write_message_buffered`].
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Reads the other side's `Hello`. Tunnels over a byte stream first skip whatever
+    /// precedes it, such as a login banner.
+    async fn read_hello(&mut self) -> Result<Hello> {
+        match self.read_message().await? {
+            Message::Hello(hello) if hello.magic == super::HELLO_MAGIC => Ok(hello),
+            msg => Err(super::Error::UnexpectedMessage(Box::new(msg))),
+        }
+    }
+    /// Compresses the frames written from now on with `codec`, and decompresses those read
+    /// that are marked as compressed. Tunnels that don't frame messages themselves ignore it.
+    async fn set_codec(&mut self, _codec: Codec) {}
+    /// Sends any buffered messages, then closes this side of the tunnel, so the other side
+    /// reads EOF right after the last of them. Nothing may be written afterwards.
+    async fn shutdown(&mut self) -> Result<()> {
+        self.flush().await
+    }
 }
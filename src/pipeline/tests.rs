@@ -1,6 +1,15 @@
-use std::path::PathBuf;
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
 use super::*;
+use crate::cli::{self, Chunker, Cli, ClientServerOpts, Direction};
+use crate::cryptography::{ChunkRef, Delta, IndexTable, Ops};
+use crate::filesystem::{FileSystem, MemFs, RealFs};
+use crate::flist;
+use clap::Parser;
 use pretty_assertions::assert_eq;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
 
@@ -48,11 +57,13 @@ async fn ssh_send_receive_roundtrip() -> Result<()> {
         username: whoami::username().into_boxed_str(),
         host: "127.0.0.1".to_string().into_boxed_str(),
         password: None,
-        port: 22,
+        port: None,
         remote_cmd: "cat".to_string(),
+        rsh: None,
+        ssh_options: Vec::new(),
     };
 
-    let mut tunnel = SSHTunnel::new(cmd).await;
+    let mut tunnel = SSHTunnel::new(cmd).await?;
 
     // Send a test message
     let msg_out = Message::Done;
@@ -70,6 +81,371 @@ async fn ssh_send_receive_roundtrip() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn remotes_parse_with_an_optional_port() {
+    let cmd = SSHCommand::try_from("backup@example.com:2222").unwrap();
+    assert_eq!(
+        (&*cmd.username, &*cmd.host, cmd.port),
+        ("backup", "example.com", Some(2222))
+    );
+    let cmd = SSHCommand::try_from("backup@example.com").unwrap();
+    assert_eq!((&*cmd.host, cmd.port), ("example.com", None));
+    let cmd: SSHCommand = "user@10.0.0.1:22".to_string().into();
+    assert_eq!((&*cmd.host, cmd.port), ("10.0.0.1", Some(22)));
+}
+
+#[test]
+fn bracketed_ipv6_remotes_parse() {
+    let cmd = SSHCommand::try_from("user@[::1]:2222").unwrap();
+    assert_eq!(
+        (&*cmd.username, &*cmd.host, cmd.port),
+        ("user", "::1", Some(2222))
+    );
+    let cmd = SSHCommand::try_from("user@[fe80::1%eth0]").unwrap();
+    assert_eq!((&*cmd.host, cmd.port), ("fe80::1%eth0", None));
+}
+
+#[test]
+fn malformed_remotes_are_errors() {
+    for input in [
+        "example.com",
+        "@example.com",
+        "user@",
+        "user@:22",
+        "user@::1",
+        "user@[::1",
+        "user@[::1]2222",
+        "user@host:port",
+        "user@host:0",
+        "user@host:65536",
+        "user@host:",
+        "user@other@host",
+    ] {
+        match SSHCommand::try_from(input) {
+            Err(Error::InvalidRemote {
+                input: reported, ..
+            }) => assert_eq!(reported, input),
+            other => panic!("{:?} parsed as {:?}", input, other),
+        }
+    }
+}
+
+#[test]
+fn remote_arguments_are_split_from_their_path() {
+    let (cmd, path) = SSHCommand::parse_remote("user@example.com:dir/file")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        (&*cmd.username, &*cmd.host, cmd.port),
+        ("user", "example.com", None)
+    );
+    assert_eq!(path, "dir/file");
+    let (cmd, path) = SSHCommand::parse_remote("user@[::1]:/srv/a:b")
+        .unwrap()
+        .unwrap();
+    assert_eq!((&*cmd.username, &*cmd.host), ("user", "::1"));
+    assert_eq!(path, "/srv/a:b");
+    let (_, path) = SSHCommand::parse_remote("user@host:").unwrap().unwrap();
+    assert_eq!(path, "");
+
+    for local in [
+        "dir/file",
+        "user@host",
+        "user@[::1]",
+        "dir/user@host:x",
+        "user@dir/host:x",
+    ] {
+        assert!(
+            SSHCommand::parse_remote(local).unwrap().is_none(),
+            "{:?} is local",
+            local
+        );
+    }
+    for input in [
+        "@host:path",
+        "user@:path",
+        "user@[::1:path",
+        "user@[::1]x:path",
+    ] {
+        assert!(
+            matches!(
+                SSHCommand::parse_remote(input),
+                Err(Error::InvalidRemote { .. })
+            ),
+            "{:?} is not a valid remote",
+            input
+        );
+    }
+}
+
+#[tokio::test]
+async fn missing_ssh_binary_is_an_error() {
+    let cmd = SSHCommand::new(
+        "127.0.0.1".to_string(),
+        None,
+        "user".to_string(),
+        None,
+        "oxide_sync --server".to_string(),
+    );
+
+    let result = SSHTunnel::spawn("/nonexistent/oxide_sync/ssh", &cmd);
+
+    assert!(matches!(result, Err(Error::SshSpawn(e)) if e.kind() == std::io::ErrorKind::NotFound));
+}
+
+#[tokio::test]
+async fn remote_exit_is_reported_with_its_status() {
+    let cmd = SSHCommand::new(
+        "127.0.0.1".to_string(),
+        None,
+        "user".to_string(),
+        None,
+        "oxide_sync --server".to_string(),
+    );
+    // `false` ignores the ssh arguments and exits with 1 straight away
+    let mut tunnel = SSHTunnel::spawn("false", &cmd).unwrap();
+
+    let result = tunnel.read_message().await;
+
+    assert!(
+        matches!(&result, Err(Error::RemoteExited { status, .. }) if status.code() == Some(1)),
+        "{:?}",
+        result
+    );
+}
+
+#[tokio::test]
+async fn remote_stderr_is_captured() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let stub = dir.path().join("ssh");
+    std::fs::write(
+        &stub,
+        "#!/bin/sh\necho 'Host key verification failed.' >&2\necho 'second line' >&2\nexit 255\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&stub, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let cmd = SSHCommand::new(
+        "127.0.0.1".to_string(),
+        None,
+        "user".to_string(),
+        None,
+        "oxide_sync --server".to_string(),
+    );
+    let mut tunnel = SSHTunnel::spawn(&stub, &cmd).unwrap();
+
+    let err = tunnel.read_message().await.unwrap_err();
+
+    let Error::RemoteExited { status, stderr } = &err else {
+        panic!("expected RemoteExited, got {:?}", err);
+    };
+    assert_eq!(status.code(), Some(255));
+    assert_eq!(
+        stderr,
+        &["Host key verification failed.", "second line"].map(String::from)
+    );
+    assert!(err.to_string().contains("Host key verification failed."));
+}
+
+#[tokio::test]
+async fn buffered_messages_arrive_before_eof_on_shutdown() {
+    let (mut client, mut server) = MemoryTunnel::pair(4096);
+    let entries: Vec<FlistEntry> = (0..FLIST_BATCH_SIZE as u32)
+        .map(|index| flist_entry(index, &format!("file-{}", index)))
+        .collect();
+    let batch = Message::FlistBatch(entries);
+
+    // Larger than the duplex buffer, so the server must read while the client shuts down
+    let (shutdown, received) = tokio::join!(
+        async {
+            client.write_message_buffered(batch.clone()).await?;
+            client.write_message_buffered(Message::Done).await?;
+            client.shutdown().await
+        },
+        async {
+            let first = server.read_message().await.unwrap();
+            let second = server.read_message().await.unwrap();
+            (first, second, server.read_message().await)
+        }
+    );
+
+    shutdown.unwrap();
+    let (first, second, eof) = received;
+    assert_eq!(first, batch);
+    assert_eq!(second, Message::Done);
+    assert!(
+        matches!(&eof, Err(Error::IO(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof),
+        "{:?}",
+        eof
+    );
+}
+
+#[tokio::test]
+async fn shutdown_closes_the_remote_stdin() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let received = dir.path().join("received");
+    let stub = dir.path().join("ssh");
+    // Exits only once its stdin is closed
+    std::fs::write(&stub, format!("#!/bin/sh\ncat > {:?}\n", received)).unwrap();
+    std::fs::set_permissions(&stub, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let cmd = SSHCommand::new(
+        "127.0.0.1".to_string(),
+        None,
+        "user".to_string(),
+        None,
+        "oxide_sync --server".to_string(),
+    );
+    let mut tunnel = SSHTunnel::spawn(&stub, &cmd).unwrap();
+
+    tunnel.write_message_buffered(Message::Done).await.unwrap();
+    tunnel.shutdown().await.unwrap();
+    let remote = tunnel.remote.as_mut().unwrap();
+    let status = tokio::time::timeout(Duration::from_secs(10), remote.child.wait())
+        .await
+        .expect("the remote side still waits for input")
+        .unwrap();
+
+    assert!(status.success());
+    assert_eq!(
+        std::fs::read(&received).unwrap(),
+        encode_frame(Message::Done, Codec::None).unwrap()
+    );
+    // Writing after the shutdown fails instead of going nowhere
+    assert!(tunnel.write_message(Message::Done).await.is_err());
+}
+
+#[test]
+fn password_is_passed_to_sshpass_through_the_environment() {
+    let cmd = SSHCommand::new(
+        "example.com".to_string(),
+        Some(2222),
+        "user".to_string(),
+        Some("hunter2".to_string()),
+        "oxide_sync --server".to_string(),
+    );
+
+    let command = ssh_command("ssh".as_ref(), &cmd);
+    let command = command.as_std();
+    let args: Vec<_> = command.get_args().collect();
+
+    assert_eq!(command.get_program(), "sshpass");
+    assert_eq!(
+        args,
+        [
+            "-e",
+            "ssh",
+            "-p",
+            "2222",
+            "user@example.com",
+            "oxide_sync --server"
+        ]
+    );
+    assert!(
+        command
+            .get_envs()
+            .any(|(key, value)| key == "SSHPASS" && value == Some("hunter2".as_ref()))
+    );
+    assert!(!format!("{:?}", cmd).contains("hunter2"));
+}
+
+#[test]
+fn rsh_replaces_the_ssh_program_and_keeps_its_arguments() {
+    let cli = Cli::parse_from([
+        "oxide_sync",
+        "--rsh",
+        "ssh -o StrictHostKeyChecking=no",
+        "a",
+        "user@example.com:b",
+    ]);
+    let cmd = SSHCommand {
+        rsh: cli.rsh,
+        ..SSHCommand::new(
+            "example.com".to_string(),
+            None,
+            "user".to_string(),
+            None,
+            "oxide_sync --server".to_string(),
+        )
+    };
+
+    let command = ssh_command("default-ssh".as_ref(), &cmd);
+    let command = command.as_std();
+    let args: Vec<_> = command.get_args().collect();
+
+    assert_eq!(command.get_program(), "ssh");
+    assert_eq!(
+        args,
+        [
+            "-o",
+            "StrictHostKeyChecking=no",
+            "user@example.com",
+            "oxide_sync --server"
+        ]
+    );
+}
+
+#[test]
+fn ssh_options_are_passed_in_order() {
+    let cli = Cli::parse_from([
+        "oxide_sync",
+        "--ssh-option",
+        "ControlMaster=auto",
+        "--ssh-option",
+        "ControlPath=~/.ssh/cm-%r@%h:%p",
+        "a",
+        "user@example.com:b",
+    ]);
+    let cmd = SSHCommand {
+        ssh_options: cli.ssh_options,
+        ..SSHCommand::new(
+            "example.com".to_string(),
+            None,
+            "user".to_string(),
+            None,
+            "oxide_sync --server".to_string(),
+        )
+    };
+
+    let command = ssh_command("ssh".as_ref(), &cmd);
+    let args: Vec<_> = command.as_std().get_args().collect();
+
+    assert_eq!(
+        args,
+        [
+            "-o",
+            "ControlMaster=auto",
+            "-o",
+            "ControlPath=~/.ssh/cm-%r@%h:%p",
+            "user@example.com",
+            "oxide_sync --server"
+        ]
+    );
+    assert!(Cli::try_parse_from(["oxide_sync", "--ssh-option", "novalue", "a", "b"]).is_err());
+}
+
+#[tokio::test]
+async fn spawning_never_logs_the_password() {
+    let (captured, _guard) = capture_logs(tracing::Level::TRACE);
+    let cmd = SSHCommand::new(
+        "example.com".to_string(),
+        None,
+        "user".to_string(),
+        Some("hunter2".to_string()),
+        "oxide_sync --server".to_string(),
+    );
+
+    // sshpass may be missing, either way the invocation has been logged by now
+    let _ = SSHTunnel::spawn("true", &cmd);
+
+    let output = captured.contents();
+    assert!(output.contains("spawning"), "{}", output);
+    assert!(output.contains("<redacted>"), "{}", output);
+    assert!(!output.contains("hunter2"), "{}", output);
+}
+
 #[test]
 fn test_exclude() {
     let exclude = [PathBuf::from("delta.rs")];
@@ -82,3 +458,3201 @@ fn test_exclude() {
         true
     );
 }
+
+/// Answers every `FileIndex` with an empty signature table and every `Delta` with `Success`,
+/// recording which indices were asked for.
+struct FakeServer {
+    pending: VecDeque<Message>,
+    requested: Arc<Mutex<Vec<u32>>>,
+}
+
+#[async_trait]
+impl Tunnel for FakeServer {
+    async fn write_message(&mut self, msg: Message) -> Result<()> {
+        match msg {
+            Message::FileIndex(index) => {
+                self.requested.lock().unwrap().push(index);
+                self.pending.push_back(Message::Data(
+                    DataMessage::new(&IndexTable::new(), index).unwrap(),
+                ));
+            }
+            Message::Delta(delta) => self.pending.push_back(Message::Success(delta.file_index)),
+            _ => {}
+        }
+        Ok(())
+    }
+    async fn read_message(&mut self) -> Result<Message> {
+        self.pending.pop_front().ok_or(Error::IoTimeout)
+    }
+}
+
+fn flist_entry(index: u32, filename: &str) -> FlistEntry {
+    FlistEntry {
+        index,
+        filename: filename.into(),
+        size: 0,
+        mtime: 0,
+        mode: 0o644,
+        uid: None,
+        gid: None,
+        is_dir: false,
+        is_symlink: false,
+        hardlink_to: None,
+        user: None,
+        group: None,
+        special: None,
+        checksum: None,
+        synced_size: None,
+    }
+}
+
+fn fake_pipeline(filenames: &[&str]) -> (Pipeline, Arc<Mutex<Vec<u32>>>) {
+    let requested = Arc::default();
+    let mut pipeline = Pipeline::with_tunnel(Box::new(FakeServer {
+        pending: Default::default(),
+        requested: Arc::clone(&requested),
+    }));
+    pipeline.flist = filenames
+        .iter()
+        .zip(0..)
+        .map(|(name, index)| flist_entry(index, name))
+        .collect();
+    (pipeline, requested)
+}
+
+#[tokio::test]
+async fn unreadable_file_does_not_abort_the_run() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.txt"), "first").unwrap();
+    std::fs::write(dir.path().join("c.txt"), "third").unwrap();
+    let (mut pipeline, requested) = fake_pipeline(&["a.txt", "b.txt", "c.txt"]);
+    let opts = ClientServerOpts::default();
+
+    let stats = pipeline.process_flist(dir.path(), &opts).await.unwrap();
+
+    assert_eq!(stats.files_transferred, 2);
+    assert_eq!(stats.failures.len(), 1);
+    assert_eq!(stats.failures[0].file_index, 1);
+    assert_eq!(*requested.lock().unwrap(), vec![0, 2]);
+}
+
+#[tokio::test]
+async fn stop_on_error_aborts_at_first_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("c.txt"), "third").unwrap();
+    let (mut pipeline, requested) = fake_pipeline(&["a.txt", "b.txt", "c.txt"]);
+    let opts = ClientServerOpts {
+        stop_on_error: true,
+        ..Default::default()
+    };
+
+    let result = pipeline.process_flist(dir.path(), &opts).await;
+
+    assert!(matches!(result, Err(Error::FileTransfer { .. })));
+    assert!(requested.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn memory_tunnel_pair_exchanges_messages() {
+    let (mut client, mut server) = MemoryTunnel::pair(1024);
+
+    client.write_message(Message::SYNC).await.unwrap();
+    assert_eq!(server.read_message().await.unwrap(), Message::SYNC);
+    server.write_message(Message::ACK).await.unwrap();
+    assert_eq!(client.read_message().await.unwrap(), Message::ACK);
+}
+
+/// Two pipelines connected back to back over an in-process duplex stream.
+fn duplex_pipelines() -> (Pipeline, Pipeline) {
+    let (client, server) = MemoryTunnel::pair(64 * 1024);
+    (
+        Pipeline::with_tunnel(Box::new(client)),
+        Pipeline::with_tunnel(Box::new(server)),
+    )
+}
+
+/// Writes `files` below `root`, creating parent directories as needed.
+fn write_tree(root: &std::path::Path, files: &[(&str, &[u8])]) {
+    for (name, contents) in files {
+        let path = root.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+}
+
+/// Forwards to `inner`, letting a test watch and tamper with the traffic: each message
+/// written first goes through `on_write`, each one read through `on_read`, and each codec set
+/// through `on_codec`. Once `reads` reads went through, `inner` is dropped and every call after
+/// fails with `error`, like a connection that died.
+struct ForwardingTunnel {
+    inner: Option<MemoryTunnel>,
+    on_write: Box<dyn FnMut(&mut Message) + Send>,
+    on_read: Box<dyn FnMut(&Message) + Send>,
+    on_codec: Box<dyn FnMut(Codec) + Send>,
+    dies_on: Box<dyn FnMut(&Message) -> bool + Send>,
+    reads: u32,
+    error: fn() -> Error,
+}
+
+impl ForwardingTunnel {
+    fn new(inner: MemoryTunnel) -> Self {
+        Self {
+            inner: Some(inner),
+            on_write: Box::new(|_| {}),
+            on_read: Box::new(|_| {}),
+            on_codec: Box::new(|_| {}),
+            dies_on: Box::new(|_| false),
+            reads: u32::MAX,
+            error: || Error::IO(std::io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn on_write(self, on_write: impl FnMut(&mut Message) + Send + 'static) -> Self {
+        Self {
+            on_write: Box::new(on_write),
+            ..self
+        }
+    }
+
+    fn on_read(self, on_read: impl FnMut(&Message) + Send + 'static) -> Self {
+        Self {
+            on_read: Box::new(on_read),
+            ..self
+        }
+    }
+
+    fn on_codec(self, on_codec: impl FnMut(Codec) + Send + 'static) -> Self {
+        Self {
+            on_codec: Box::new(on_codec),
+            ..self
+        }
+    }
+
+    /// Records every message written into `sent`.
+    fn recording(self, sent: &Arc<Mutex<Vec<Message>>>) -> Self {
+        let sent = Arc::clone(sent);
+        self.on_write(move |msg| sent.lock().unwrap().push(msg.clone()))
+    }
+
+    /// Dies with `error` after `reads` reads.
+    fn dying_after_reads(self, reads: u32, error: fn() -> Error) -> Self {
+        Self {
+            reads,
+            error,
+            ..self
+        }
+    }
+
+    /// Dies with an unexpected EOF instead of writing the first message `dies_on` picks.
+    fn dying_on_write(self, dies_on: impl FnMut(&Message) -> bool + Send + 'static) -> Self {
+        Self {
+            dies_on: Box::new(dies_on),
+            ..self
+        }
+    }
+
+    /// The tunnel for one more read, unless the reads ran out, in which case it is dropped.
+    fn read_from(&mut self) -> Option<&mut MemoryTunnel> {
+        if self.reads == 0 {
+            self.inner = None;
+        }
+        self.reads = self.reads.saturating_sub(1);
+        self.inner.as_mut()
+    }
+}
+
+#[async_trait]
+impl Tunnel for ForwardingTunnel {
+    async fn write_message(&mut self, mut msg: Message) -> Result<()> {
+        (self.on_write)(&mut msg);
+        if (self.dies_on)(&msg) {
+            self.inner = None;
+        }
+        match &mut self.inner {
+            Some(inner) => inner.write_message(msg).await,
+            None => Err((self.error)()),
+        }
+    }
+    async fn read_message(&mut self) -> Result<Message> {
+        let msg = match self.read_from() {
+            Some(inner) => inner.read_message().await?,
+            None => return Err((self.error)()),
+        };
+        (self.on_read)(&msg);
+        Ok(msg)
+    }
+    async fn read_hello(&mut self) -> Result<Hello> {
+        match self.read_from() {
+            Some(inner) => inner.read_hello().await,
+            None => Err((self.error)()),
+        }
+    }
+    async fn set_codec(&mut self, codec: Codec) {
+        (self.on_codec)(codec);
+        if let Some(inner) = &mut self.inner {
+            inner.set_codec(codec).await
+        }
+    }
+}
+
+/// Pushes `source` to `opts.to` from a client whose end of a [`MemoryTunnel`] pair goes
+/// through `tunnel` to a server on the other end. Returns the client, which keeps a batch of
+/// the deltas it sent, with the stats of both sides.
+async fn push_with(
+    source: &std::path::Path,
+    opts: ClientServerOpts,
+    tunnel: impl FnOnce(MemoryTunnel) -> ForwardingTunnel,
+) -> (Pipeline, Result<TransferStats>, Result<TransferStats>) {
+    let (client, server) = MemoryTunnel::pair(64 * 1024);
+    let mut client = Pipeline::with_tunnel(Box::new(tunnel(client)));
+    client.batch = Some(Batch::default());
+    let mut server = Pipeline::with_tunnel(Box::new(server));
+    let (client_stats, server_stats) = tokio::join!(client.sync(source, opts), server.serve());
+    (client, client_stats, server_stats)
+}
+
+async fn sync_over_duplex(direction: Direction) {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let changed: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+    let mut original = changed.clone();
+    original[2000] ^= 0xff;
+    write_tree(
+        source.path(),
+        &[("changed.bin", &changed), ("nested/new.txt", b"new file")],
+    );
+    write_tree(destination.path(), &[("changed.bin", &original)]);
+
+    let (local, remote) = match direction {
+        Direction::Push => (&source, &destination),
+        Direction::Pull => (&destination, &source),
+    };
+    let opts = ClientServerOpts {
+        to: remote.path().to_path_buf(),
+        direction,
+        recursive: true,
+        ..Default::default()
+    };
+    let (mut client, mut server) = duplex_pipelines();
+    let (client_stats, server_stats) =
+        tokio::join!(client.sync(local.path(), opts), server.serve());
+    let (client_stats, server_stats) = (client_stats.unwrap(), server_stats.unwrap());
+
+    assert_eq!(client_stats.files_transferred, 2);
+    assert_eq!(server_stats.files_transferred, 2);
+    assert_eq!(
+        std::fs::read(destination.path().join("changed.bin")).unwrap(),
+        changed
+    );
+    assert_eq!(
+        std::fs::read(destination.path().join("nested/new.txt")).unwrap(),
+        b"new file"
+    );
+}
+
+#[tokio::test]
+async fn push_over_duplex() {
+    sync_over_duplex(Direction::Push).await;
+}
+
+#[tokio::test]
+async fn pull_over_duplex() {
+    sync_over_duplex(Direction::Pull).await;
+}
+
+#[tokio::test]
+async fn banner_before_the_handshake_is_skipped() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("motd.txt", b"synced past the banner")]);
+
+    let (client, server) = duplex(64 * 1024);
+    let (stdout, stdin) = tokio::io::split(client);
+    let (server_stdout, mut server_stdin) = tokio::io::split(server);
+    // What a remote shell might print on login, including bytes that look like a frame header
+    server_stdin
+        .write_all(b"Welcome to backup-host\n\0\0\0\x08\xff\xfe last login: never\n")
+        .await
+        .unwrap();
+    let mut client = Pipeline::with_tunnel(Box::new(SSHTunnel::from_io(stdin, stdout)));
+    let mut server =
+        Pipeline::with_tunnel(Box::new(SSHTunnel::from_io(server_stdin, server_stdout)));
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        ..Default::default()
+    };
+    let (client_stats, server_stats) =
+        tokio::join!(client.sync(source.path(), opts), server.serve());
+
+    assert_eq!(client_stats.unwrap().files_transferred, 1);
+    server_stats.unwrap();
+    assert_eq!(
+        std::fs::read(destination.path().join("motd.txt")).unwrap(),
+        b"synced past the banner"
+    );
+}
+
+#[tokio::test]
+async fn missing_hello_gives_up_after_the_banner_limit() {
+    let (mut remote, local) = duplex(1024);
+    let (stdout, stdin) = tokio::io::split(local);
+    let mut tunnel = SSHTunnel::from_io(stdin, stdout);
+    tokio::spawn(async move {
+        let noise = [b'x'; 1024];
+        while remote.write_all(&noise).await.is_ok() {}
+    });
+
+    assert!(matches!(
+        tunnel.read_hello().await,
+        Err(Error::NoHello(MAX_BANNER_LEN))
+    ));
+}
+
+/// Pushes a compressible file from a client asking for compression to a server supporting
+/// `server_codecs`, returning the codecs each side settled on and whether the delta carried
+/// compressed blocks.
+async fn push_compressed(server_codecs: Vec<Codec>) -> (Vec<Codec>, Vec<Codec>, bool) {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let contents = b"a line that repeats\n".repeat(10_000);
+    write_tree(source.path(), &[("repeats.txt", &contents)]);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        ..Default::default()
+    };
+    let (client, server) = MemoryTunnel::pair(64 * 1024);
+    let (client_codecs, server_codecs_used) = (Arc::default(), Arc::default());
+    let recording = |tunnel, codecs: &Arc<Mutex<Vec<Codec>>>| {
+        let codecs = Arc::clone(codecs);
+        ForwardingTunnel::new(tunnel).on_codec(move |codec| codecs.lock().unwrap().push(codec))
+    };
+    let mut client = Pipeline::with_tunnel(Box::new(recording(client, &client_codecs)));
+    client.compress = true;
+    client.batch = Some(Batch::default());
+    let mut server = Pipeline::with_tunnel(Box::new(recording(server, &server_codecs_used)));
+    server.codecs = server_codecs;
+
+    let (client_stats, server_stats) =
+        tokio::join!(client.sync(source.path(), opts), server.serve());
+    assert_eq!(client_stats.unwrap().files_transferred, 1);
+    server_stats.unwrap();
+    assert_eq!(
+        std::fs::read(destination.path().join("repeats.txt")).unwrap(),
+        contents
+    );
+    let used = |codecs: Arc<Mutex<Vec<Codec>>>| codecs.lock().unwrap().clone();
+    let compressed_blocks = client.batch.unwrap().deltas.iter().any(|sent| {
+        sent.delta
+            .ops
+            .iter()
+            .any(|op| matches!(op, Ops::CompressedBlock(_)))
+    });
+    (
+        used(client_codecs),
+        used(server_codecs_used),
+        compressed_blocks,
+    )
+}
+
+#[tokio::test]
+async fn compression_uses_the_best_common_codec() {
+    assert_eq!(
+        push_compressed(Codec::SUPPORTED.to_vec()).await,
+        (vec![Codec::Zstd], vec![Codec::Zstd], false)
+    );
+    assert_eq!(
+        push_compressed(vec![Codec::Gzip]).await,
+        (vec![Codec::Gzip], vec![Codec::Gzip], false)
+    );
+}
+
+#[tokio::test]
+async fn compression_falls_back_to_compressed_blocks_without_a_common_codec() {
+    assert_eq!(
+        push_compressed(Vec::new()).await,
+        (vec![Codec::None], vec![Codec::None], true)
+    );
+}
+
+#[test]
+fn compressed_frames_round_trip_and_small_ones_stay_raw() {
+    for codec in Codec::SUPPORTED {
+        let msg = Message::Info("compressible ".repeat(100));
+        let frame = encode_frame(msg.clone(), codec).unwrap();
+        let (len, compressed) = frame_len(frame[..4].try_into().unwrap(), usize::MAX).unwrap();
+        assert!(compressed, "{}", codec);
+        let payload = &frame[FRAME_HEADER_LEN..];
+        assert_eq!(len, payload.len());
+        assert_eq!(decode_payload(payload, true, codec, 10_000).unwrap(), msg);
+        assert!(decode_payload(payload, true, codec, 100).is_err());
+        assert!(decode_payload(payload, true, Codec::None, 10_000).is_err());
+
+        let frame = encode_frame(Message::Ping, codec).unwrap();
+        assert!(
+            !frame_len(frame[..4].try_into().unwrap(), usize::MAX)
+                .unwrap()
+                .1
+        );
+    }
+}
+
+#[tokio::test]
+async fn verify_reports_tampered_file() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let files: &[(&str, &[u8])] = &[
+        ("a.txt", b"alpha"),
+        ("nested/b.txt", b"bravo"),
+        ("c.txt", b"charlie"),
+    ];
+    write_tree(source.path(), files);
+    write_tree(destination.path(), files);
+    write_tree(destination.path(), &[("nested/b.txt", b"tampered")]);
+
+    let opts = ClientServerOpts {
+        to: source.path().to_path_buf(),
+        direction: Direction::Pull,
+        recursive: true,
+        verify: true,
+        ..Default::default()
+    };
+    let (mut client, mut server) = duplex_pipelines();
+    let (report, served) = tokio::join!(client.verify(destination.path(), opts), server.serve());
+    served.unwrap();
+
+    assert_eq!(
+        report.unwrap(),
+        VerifyReport {
+            mismatched: vec!["nested/b.txt".to_string()],
+            ..Default::default()
+        }
+    );
+    assert_eq!(
+        std::fs::read(destination.path().join("nested/b.txt")).unwrap(),
+        b"tampered"
+    );
+}
+
+#[tokio::test]
+async fn hard_links_are_preserved() {
+    use std::os::unix::fs::MetadataExt;
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("a.txt", b"shared contents")]);
+    std::fs::create_dir(source.path().join("nested")).unwrap();
+    std::fs::hard_link(
+        source.path().join("a.txt"),
+        source.path().join("nested/b.txt"),
+    )
+    .unwrap();
+
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        hard_links: true,
+        ..Default::default()
+    };
+    let (_, client_stats, server_stats) =
+        push_with(source.path(), opts, ForwardingTunnel::new).await;
+    let (client_stats, server_stats) = (client_stats.unwrap(), server_stats.unwrap());
+
+    assert_eq!(client_stats.files_transferred, 1);
+    assert!(server_stats.failures.is_empty());
+    let a = std::fs::metadata(destination.path().join("a.txt")).unwrap();
+    let b = std::fs::metadata(destination.path().join("nested/b.txt")).unwrap();
+    assert_eq!(a.ino(), b.ino());
+    assert_eq!(
+        std::fs::read(destination.path().join("nested/b.txt")).unwrap(),
+        b"shared contents"
+    );
+}
+
+#[tokio::test]
+async fn garbage_frame_reports_length_and_head() {
+    let (mut remote, local) = duplex(1024);
+    let (stdout, stdin) = tokio::io::split(local);
+    let mut tunnel = SSHTunnel::from_io(stdin, stdout);
+    let garbage = [0xc8, 0x01, 0x02, 0x03, 0x04];
+    remote
+        .write_all(&(garbage.len() as u32).to_be_bytes())
+        .await
+        .unwrap();
+    remote
+        .write_all(&crc32fast::hash(&garbage).to_be_bytes())
+        .await
+        .unwrap();
+    remote.write_all(&garbage).await.unwrap();
+
+    match tunnel.read_message().await {
+        Err(Error::DecodeFramed { len, head, .. }) => {
+            assert_eq!(len, garbage.len());
+            assert_eq!(head, "c801020304");
+        }
+        other => panic!("expected a DecodeFramed error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn oversized_length_prefix_is_rejected() {
+    let (mut remote, local) = duplex(1024);
+    let (stdout, stdin) = tokio::io::split(local);
+    let mut tunnel = SSHTunnel::from_io(stdin, stdout);
+    remote.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+
+    let result = tunnel.read_message().await;
+
+    assert!(matches!(
+        result,
+        // The top bit only marks the frame as compressed
+        Err(Error::MessageTooLarge { len, max: DEFAULT_MAX_MESSAGE_SIZE })
+            if len == !COMPRESSED_FRAME as usize
+    ));
+}
+
+#[tokio::test]
+async fn max_message_size_is_configurable() {
+    let (remote, local) = MemoryTunnel::pair(1024);
+    let mut remote = remote.max_message_size(4);
+    let mut local = local.max_message_size(4);
+
+    local.write_message(Message::Done).await.unwrap();
+    assert_eq!(remote.read_message().await.unwrap(), Message::Done);
+    local
+        .write_message(Message::Info("too long".to_string()))
+        .await
+        .unwrap();
+    assert!(matches!(
+        remote.read_message().await,
+        Err(Error::MessageTooLarge { max: 4, .. })
+    ));
+}
+
+#[test]
+fn unchanged_base_reuses_cached_signatures() {
+    let dir = tempfile::tempdir().unwrap();
+    let base = dir.path().join("base.bin");
+    std::fs::write(&base, vec![7u8; 1024]).unwrap();
+    let mut cache = SignatureCache::new(dir.path().join("cache"));
+
+    let first = cache.signatures(&RealFs, &base, Chunker::Fixed).unwrap();
+    let second = cache.signatures(&RealFs, &base, Chunker::Fixed).unwrap();
+    assert_eq!((cache.misses, cache.hits), (1, 1));
+    assert_eq!(first, second);
+
+    // A fresh cache over the same directory still finds the table on disk
+    let mut reopened = SignatureCache::new(dir.path().join("cache"));
+    reopened.signatures(&RealFs, &base, Chunker::Fixed).unwrap();
+    assert_eq!((reopened.misses, reopened.hits), (0, 1));
+
+    // Any metadata change invalidates the entry
+    std::fs::write(&base, vec![8u8; 2048]).unwrap();
+    let changed = reopened.signatures(&RealFs, &base, Chunker::Fixed).unwrap();
+    assert_eq!((reopened.misses, reopened.hits), (1, 1));
+    assert_ne!(changed, first);
+}
+
+async fn itemize_over_duplex(direction: Direction) -> Vec<String> {
+    use std::{fs, os::unix::fs::PermissionsExt, time::SystemTime};
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("content.txt", b"new contents"), ("meta.txt", b"same")],
+    );
+    write_tree(
+        destination.path(),
+        &[("content.txt", b"old contents"), ("meta.txt", b"same")],
+    );
+    let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+    for root in [source.path(), destination.path()] {
+        for name in ["content.txt", "meta.txt"] {
+            let path = root.join(name);
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        }
+    }
+    fs::set_permissions(
+        source.path().join("meta.txt"),
+        fs::Permissions::from_mode(0o600),
+    )
+    .unwrap();
+
+    let (local, remote) = match direction {
+        Direction::Push => (&source, &destination),
+        Direction::Pull => (&destination, &source),
+    };
+    let opts = ClientServerOpts {
+        to: remote.path().to_path_buf(),
+        direction,
+        recursive: true,
+        itemize_changes: true,
+        ..Default::default()
+    };
+    let (mut client, mut server) = duplex_pipelines();
+    let (client_stats, server_stats) =
+        tokio::join!(client.sync(local.path(), opts), server.serve());
+    server_stats.unwrap();
+    let mut itemized = client_stats.unwrap().itemized;
+    itemized.sort();
+    itemized
+}
+
+#[tokio::test]
+async fn itemize_distinguishes_content_and_metadata_changes() {
+    for direction in [Direction::Push, Direction::Pull] {
+        assert_eq!(
+            itemize_over_duplex(direction).await,
+            vec![".f...p.. meta.txt", ">fc..... content.txt"]
+        );
+    }
+}
+
+#[tokio::test]
+async fn fifo_is_recreated_on_the_destination() {
+    use std::os::unix::fs::FileTypeExt;
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("regular.txt", b"contents")]);
+    let fifo = std::ffi::CString::new(
+        source
+            .path()
+            .join("pipe")
+            .into_os_string()
+            .into_encoded_bytes(),
+    )
+    .unwrap();
+    // SAFETY: `fifo` is a valid NUL-terminated path.
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o640) }, 0);
+
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        devices: true,
+        ..Default::default()
+    };
+    let (_, client_stats, server_stats) =
+        push_with(source.path(), opts, ForwardingTunnel::new).await;
+    let (client_stats, server_stats) = (client_stats.unwrap(), server_stats.unwrap());
+
+    assert_eq!(client_stats.files_transferred, 1);
+    assert!(server_stats.failures.is_empty());
+    let metadata = std::fs::symlink_metadata(destination.path().join("pipe")).unwrap();
+    assert!(metadata.file_type().is_fifo());
+}
+
+#[tokio::test]
+async fn special_files_are_refused_without_devices() {
+    use std::os::unix::fs::FileTypeExt;
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("regular.txt", b"contents"), ("pipe", b"")],
+    );
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        ..Default::default()
+    };
+    // A sender that lists a FIFO although the receiver never asked for them
+    let (_, client_stats, server_stats) = push_with(source.path(), opts, |tunnel| {
+        ForwardingTunnel::new(tunnel).on_write(|msg| {
+            if let Message::FlistBatch(entries) = msg {
+                for entry in entries
+                    .iter_mut()
+                    .filter(|e| e.filename.as_path() == Path::new("pipe"))
+                {
+                    entry.special = Some(SpecialFile::Fifo);
+                }
+            }
+        })
+    })
+    .await;
+    client_stats.unwrap();
+    server_stats.unwrap();
+
+    assert!(destination.path().join("regular.txt").exists());
+    let pipe = std::fs::symlink_metadata(destination.path().join("pipe"));
+    assert!(!pipe.is_ok_and(|metadata| metadata.file_type().is_fifo()));
+}
+
+#[tokio::test]
+async fn list_only_returns_the_server_flist_without_requesting_files() {
+    let remote = tempfile::tempdir().unwrap();
+    write_tree(remote.path(), &[("a.txt", b"a"), ("nested/b.txt", b"bb")]);
+    let (client, server) = MemoryTunnel::pair(64 * 1024);
+    let sent = Arc::default();
+    let mut client =
+        Pipeline::with_tunnel(Box::new(ForwardingTunnel::new(client).recording(&sent)));
+    let mut server = Pipeline::with_tunnel(Box::new(server));
+    let opts = ClientServerOpts {
+        to: remote.path().to_path_buf(),
+        recursive: true,
+        list_only: true,
+        ..Default::default()
+    };
+
+    let (listed, served) = tokio::join!(client.list(opts), server.serve());
+    served.unwrap();
+
+    assert_eq!(listed.unwrap(), server.flist);
+    assert_eq!(server.flist.len(), 2);
+    assert!(
+        !sent
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|msg| matches!(msg, Message::FileIndex(_)))
+    );
+}
+
+#[test]
+fn list_lines_are_columnar_and_json_is_escaped() {
+    let mut entry = flist_entry(0, "dir/\"quoted\".txt");
+    entry.size = 1234;
+    entry.mtime = 1_700_000_000;
+
+    assert_eq!(
+        list_line(&entry, false),
+        "-rw-r--r--           1234 2023/11/14 22:13:20 dir/\"quoted\".txt"
+    );
+    assert_eq!(
+        list_line(&entry, true),
+        "-rw-r--r--        1.2 KiB 2023/11/14 22:13:20 dir/\"quoted\".txt"
+    );
+    assert_eq!(
+        list_json(&entry),
+        r#"{"path":"dir/\"quoted\".txt","type":"file","size":1234,"mtime":1700000000,"mode":420}"#
+    );
+}
+
+async fn cancel_over_duplex(direction: Direction) {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let names = ["a.txt", "b.txt", "c.txt"];
+    for name in names {
+        write_tree(source.path(), &[(name, b"new contents")]);
+        write_tree(destination.path(), &[(name, b"old")]);
+    }
+
+    let (local, remote) = match direction {
+        Direction::Push => (&source, &destination),
+        Direction::Pull => (&destination, &source),
+    };
+    let opts = ClientServerOpts {
+        to: remote.path().to_path_buf(),
+        direction,
+        recursive: true,
+        ..Default::default()
+    };
+    let (client, server) = MemoryTunnel::pair(64 * 1024);
+    let token = CancellationToken::new();
+    // Cancels once the first `Success` passes through in either direction
+    let (on_write, on_read) = (token.clone(), token.clone());
+    let tunnel = ForwardingTunnel::new(client)
+        .on_write(move |msg| {
+            if matches!(msg, Message::Success(_)) {
+                on_write.cancel();
+            }
+        })
+        .on_read(move |msg| {
+            if matches!(msg, Message::Success(_)) {
+                on_read.cancel();
+            }
+        });
+    let mut client = Pipeline::with_tunnel(Box::new(tunnel));
+    client.cancel = token;
+    let mut server = Pipeline::with_tunnel(Box::new(server));
+
+    let (client_stats, server_stats) =
+        tokio::join!(client.sync(local.path(), opts), server.serve());
+    let (client_stats, server_stats) = (client_stats.unwrap(), server_stats.unwrap());
+
+    assert!(client_stats.cancelled);
+    assert_eq!(client_stats.files_transferred, 1);
+    assert_eq!(server_stats.files_transferred, 1);
+    let mut contents: Vec<Vec<u8>> = names
+        .iter()
+        .map(|name| std::fs::read(destination.path().join(name)).unwrap())
+        .collect();
+    contents.sort();
+    assert_eq!(
+        contents,
+        vec![b"new contents".to_vec(), b"old".to_vec(), b"old".to_vec()]
+    );
+    let leftovers = std::fs::read_dir(destination.path()).unwrap().count();
+    assert_eq!(leftovers, names.len());
+}
+
+#[tokio::test]
+async fn cancelling_a_push_after_the_first_file_leaves_the_rest_untouched() {
+    cancel_over_duplex(Direction::Push).await;
+}
+
+#[tokio::test]
+async fn cancelling_a_pull_after_the_first_file_leaves_the_rest_untouched() {
+    cancel_over_duplex(Direction::Pull).await;
+}
+
+#[tokio::test]
+async fn relative_recreates_the_source_path_on_the_destination() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("a/b/c.txt", b"nested")]);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        relative: true,
+        ..Default::default()
+    };
+    let (mut client, mut server) = duplex_pipelines();
+
+    let local = source.path().join("a/b/c.txt");
+    let (client_stats, server_stats) = tokio::join!(client.sync(&local, opts), server.serve());
+    client_stats.unwrap();
+    server_stats.unwrap();
+
+    let recreated = destination.path().join(local.strip_prefix("/").unwrap());
+    assert_eq!(std::fs::read(recreated).unwrap(), b"nested");
+}
+
+#[tokio::test]
+async fn verify_matches_relative_names_on_both_sides() {
+    // The destination walk skips hidden directories like the default `.tmp` prefix
+    let source = tempfile::Builder::new().prefix("source").tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("src/sub/a.txt", b"a"), ("src/sub/b/c.txt", b"c")],
+    );
+    let local = source.path().join("src/sub");
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        relative: true,
+        ..Default::default()
+    };
+    let (mut client, mut server) = duplex_pipelines();
+    let (client_stats, server_stats) =
+        tokio::join!(client.sync(&local, opts.clone()), server.serve());
+    client_stats.unwrap();
+    server_stats.unwrap();
+
+    let verify = ClientServerOpts {
+        verify: true,
+        ..opts
+    };
+    let (mut client, mut server) = duplex_pipelines();
+    let (report, served) = tokio::join!(client.verify(&local, verify), server.serve());
+    served.unwrap();
+
+    assert_eq!(report.unwrap(), VerifyReport::default());
+}
+
+#[tokio::test]
+async fn verify_reads_a_single_source_file_once() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("src/sub/x.txt", b"single")]);
+    let local = source.path().join("src/sub/x.txt");
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        ..Default::default()
+    };
+    let (mut client, mut server) = duplex_pipelines();
+    let (client_stats, server_stats) =
+        tokio::join!(client.sync(&local, opts.clone()), server.serve());
+    client_stats.unwrap();
+    server_stats.unwrap();
+
+    let verify = ClientServerOpts {
+        verify: true,
+        ..opts
+    };
+    let (mut client, mut server) = duplex_pipelines();
+    let (report, served) = tokio::join!(client.verify(&local, verify), server.serve());
+    served.unwrap();
+
+    assert_eq!(report.unwrap(), VerifyReport::default());
+}
+
+#[tokio::test]
+async fn failing_pre_command_aborts_before_the_handshake() {
+    let (client, _server) = MemoryTunnel::pair(1024);
+    let sent = Arc::default();
+    let mut client =
+        Pipeline::with_tunnel(Box::new(ForwardingTunnel::new(client).recording(&sent)));
+    client.hooks.pre = Some("exit 3".to_string());
+    let opts = ClientServerOpts {
+        to: PathBuf::from("/unused"),
+        ..Default::default()
+    };
+
+    let result = client.sync(std::path::Path::new("."), opts).await;
+
+    assert!(matches!(result, Err(Error::PreCommand { status, .. }) if status.code() == Some(3)));
+    assert!(sent.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn post_command_runs_with_the_exit_status() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let marker = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("a.txt", b"a")]);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        ..Default::default()
+    };
+    let (mut client, mut server) = duplex_pipelines();
+    client.hooks = Hooks {
+        pre: Some(format!("touch {:?}", marker.path().join("pre"))),
+        post: Some(format!(
+            "echo ${} > {:?}",
+            EXIT_STATUS_ENV,
+            marker.path().join("post")
+        )),
+    };
+
+    let (client_stats, server_stats) =
+        tokio::join!(client.sync(source.path(), opts), server.serve());
+    client_stats.unwrap();
+    server_stats.unwrap();
+
+    assert!(marker.path().join("pre").exists());
+    assert_eq!(
+        std::fs::read_to_string(marker.path().join("post")).unwrap(),
+        "0\n"
+    );
+}
+
+#[tokio::test]
+async fn whole_file_skips_signatures_and_still_reconstructs() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let contents: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+    let mut original = contents.clone();
+    original[100] ^= 0xff;
+    write_tree(source.path(), &[("file.bin", &contents)]);
+    write_tree(destination.path(), &[("file.bin", &original)]);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        whole_file: true,
+        ..Default::default()
+    };
+    let (client_sent, server_sent) = (Arc::default(), Arc::new(Mutex::new(Vec::new())));
+    let received = Arc::clone(&server_sent);
+
+    let (_, client_stats, server_stats) = push_with(source.path(), opts, |tunnel| {
+        ForwardingTunnel::new(tunnel)
+            .recording(&client_sent)
+            .on_read(move |msg| received.lock().unwrap().push(msg.clone()))
+    })
+    .await;
+    client_stats.unwrap();
+    server_stats.unwrap();
+
+    let tables: Vec<bool> = server_sent
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|msg| match msg {
+            Message::Data(data) => Some(data.table().unwrap().is_empty()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(tables, vec![true]);
+    let deltas: Vec<_> = client_sent
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|msg| match msg {
+            Message::Delta(delta) => Some(delta.delta.ops.clone()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(deltas, vec![vec![Ops::Block(contents.clone())]]);
+    assert_eq!(
+        std::fs::read(destination.path().join("file.bin")).unwrap(),
+        contents
+    );
+}
+
+#[tokio::test]
+async fn files_below_the_delta_threshold_are_sent_whole() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let contents: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+    let mut original = contents.clone();
+    // Changing every other block leaves about half of the file reusable
+    for offset in (0..contents.len()).step_by(256) {
+        original[offset] ^= 0xff;
+    }
+    write_tree(source.path(), &[("file.bin", &contents)]);
+    write_tree(destination.path(), &[("file.bin", &original)]);
+
+    for (threshold, whole) in [(None, false), (Some(90), true)] {
+        std::fs::write(destination.path().join("file.bin"), &original).unwrap();
+        let opts = ClientServerOpts {
+            to: destination.path().to_path_buf(),
+            direction: Direction::Push,
+            delta_threshold: threshold,
+            ..Default::default()
+        };
+        let sent = Arc::default();
+        let (_, client_stats, server_stats) = push_with(source.path(), opts, |tunnel| {
+            ForwardingTunnel::new(tunnel).recording(&sent)
+        })
+        .await;
+        client_stats.unwrap();
+        server_stats.unwrap();
+
+        let ops = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .find_map(|msg| match msg {
+                Message::Delta(delta) => Some(delta.delta.ops.clone()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            ops == vec![Ops::Block(contents.clone())],
+            whole,
+            "{:?}",
+            threshold
+        );
+        assert_eq!(
+            std::fs::read(destination.path().join("file.bin")).unwrap(),
+            contents
+        );
+    }
+}
+
+#[tokio::test]
+async fn large_deltas_are_streamed_in_chunks() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let single = tempfile::tempdir().unwrap();
+    let mut state = 7u64;
+    let contents: Vec<u8> = (0..3 * DELTA_CHUNK_SIZE)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    let original = &contents[..DELTA_CHUNK_SIZE / 2];
+    write_tree(source.path(), &[("big.bin", &contents)]);
+    write_tree(destination.path(), &[("big.bin", original)]);
+    write_tree(single.path(), &[("big.bin", original)]);
+
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        ..Default::default()
+    };
+    let sent = Arc::default();
+    let (_, client_stats, server_stats) = push_with(source.path(), opts.clone(), |tunnel| {
+        ForwardingTunnel::new(tunnel).recording(&sent)
+    })
+    .await;
+    let (client_stats, server_stats) = (client_stats.unwrap(), server_stats.unwrap());
+
+    let sent = sent.lock().unwrap();
+    assert!(!sent.iter().any(|msg| matches!(msg, Message::Delta(_))));
+    let chunks: Vec<_> = sent
+        .iter()
+        .filter_map(|msg| match msg {
+            Message::DeltaChunk(chunk) => Some(chunk.ops.clone()),
+            _ => None,
+        })
+        .collect();
+    assert!(chunks.len() > 2, "{}", chunks.len());
+    let checksum = sent.iter().find_map(|msg| match msg {
+        Message::DeltaEnd(end) => end.strong.clone(),
+        _ => None,
+    });
+    assert_eq!(
+        std::fs::read(destination.path().join("big.bin")).unwrap(),
+        contents
+    );
+    let (server_file, client_file) = (&server_stats.files[&0], &client_stats.files[&0]);
+    assert_eq!(server_file.bytes_sent, client_file.bytes_sent);
+    assert_eq!(server_file.bytes_reused, client_file.bytes_reused);
+
+    // The same ops sent as a single delta rebuild the same file
+    let delta = Delta {
+        ops: chunks.concat(),
+    };
+    let changed = transfer::apply_delta(
+        &RealFs,
+        &Default::default(),
+        single.path(),
+        &flist_entry(0, "big.bin"),
+        &delta,
+        checksum.as_deref(),
+        &ClientServerOpts {
+            to: single.path().to_path_buf(),
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(changed, Some(true));
+    assert_eq!(
+        std::fs::read(single.path().join("big.bin")).unwrap(),
+        contents
+    );
+}
+
+async fn push_appending(source: &[u8], destination: &[u8]) -> (Vec<Ops>, Vec<u8>) {
+    let source_dir = tempfile::tempdir().unwrap();
+    let destination_dir = tempfile::tempdir().unwrap();
+    write_tree(source_dir.path(), &[("app.log", source)]);
+    write_tree(destination_dir.path(), &[("app.log", destination)]);
+    let opts = ClientServerOpts {
+        to: destination_dir.path().to_path_buf(),
+        direction: Direction::Push,
+        append: true,
+        ..Default::default()
+    };
+    let sent = Arc::default();
+    let (_, client_stats, server_stats) = push_with(source_dir.path(), opts, |tunnel| {
+        ForwardingTunnel::new(tunnel).recording(&sent)
+    })
+    .await;
+    client_stats.unwrap();
+    server_stats.unwrap();
+
+    let ops = sent
+        .lock()
+        .unwrap()
+        .iter()
+        .find_map(|msg| match msg {
+            Message::Delta(delta) => Some(delta.delta.ops.clone()),
+            _ => None,
+        })
+        .unwrap();
+    (
+        ops,
+        std::fs::read(destination_dir.path().join("app.log")).unwrap(),
+    )
+}
+
+#[tokio::test]
+async fn append_keeps_the_synced_prefix_and_sends_the_tail() {
+    let original: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+    let mut grown = original.clone();
+    grown.extend_from_slice(b"2026-10-15 12:00:00 INFO appended line\n");
+
+    let (ops, rebuilt) = push_appending(&grown, &original).await;
+
+    assert_eq!(
+        ops,
+        vec![
+            Ops::Chunk(ChunkRef {
+                offset: 0,
+                len: original.len()
+            }),
+            Ops::Block(grown[original.len()..].to_vec()),
+        ]
+    );
+    assert_eq!(rebuilt, grown);
+
+    // A copy that did not only grow fails its checksum and is sent again whole
+    let mut changed = grown.clone();
+    changed[10] ^= 0xff;
+    let (_, rebuilt) = push_appending(&changed, &original).await;
+    assert_eq!(rebuilt, changed);
+}
+
+async fn push_with_corrupt_checksums(corrupt: usize) -> (TransferStats, Vec<u32>, Vec<u8>) {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("file.txt", b"new contents")]);
+    write_tree(destination.path(), &[("file.txt", b"old")]);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        ..Default::default()
+    };
+    let redos = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&redos);
+    // Replaces the checksum of the first `corrupt` deltas written and counts the `Redo`s
+    // read back
+    let mut corrupt = corrupt;
+    let tunnel = |tunnel| {
+        ForwardingTunnel::new(tunnel)
+            .on_write(move |msg| {
+                if let Message::Delta(delta) = msg
+                    && corrupt > 0
+                {
+                    corrupt -= 1;
+                    delta.checksum = Some("not the checksum".to_string());
+                }
+            })
+            .on_read(move |msg| {
+                if let Message::Redo(index) = msg {
+                    recorded.lock().unwrap().push(*index);
+                }
+            })
+    };
+
+    let (_, client_stats, server_stats) = push_with(source.path(), opts, tunnel).await;
+    server_stats.unwrap();
+    let redos = redos.lock().unwrap().clone();
+    let contents = std::fs::read(destination.path().join("file.txt")).unwrap();
+    (client_stats.unwrap(), redos, contents)
+}
+
+#[tokio::test]
+async fn checksum_mismatch_is_redone_as_a_whole_file() {
+    let (stats, redos, contents) = push_with_corrupt_checksums(1).await;
+
+    assert_eq!(redos, vec![0]);
+    assert_eq!(stats.files_transferred, 1);
+    assert_eq!(contents, b"new contents");
+}
+
+#[tokio::test]
+async fn redos_are_capped() {
+    let (stats, redos, contents) = push_with_corrupt_checksums(usize::MAX).await;
+
+    assert_eq!(redos, vec![0, 0, 0]);
+    assert_eq!(stats.files_transferred, 0);
+    assert_eq!(stats.failures.len(), 1);
+    assert!(stats.failures[0].reason.contains("checksum mismatch"));
+    assert_eq!(contents, b"old");
+}
+
+#[tokio::test]
+async fn capped_redos_are_reported_by_a_pulling_receiver() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("file.txt", b"new contents")]);
+    write_tree(destination.path(), &[("file.txt", b"old")]);
+    let opts = ClientServerOpts {
+        to: source.path().to_path_buf(),
+        direction: Direction::Pull,
+        ..Default::default()
+    };
+    let (client, server) = MemoryTunnel::pair(64 * 1024);
+    let mut client = Pipeline::with_tunnel(Box::new(client));
+    // The server sends the deltas, all with a wrong checksum
+    let mut server =
+        Pipeline::with_tunnel(Box::new(ForwardingTunnel::new(server).on_write(|msg| {
+            if let Message::Delta(delta) = msg {
+                delta.checksum = Some("not the checksum".to_string());
+            }
+        })));
+
+    let (client_stats, server_stats) =
+        tokio::join!(client.sync(destination.path(), opts), server.serve());
+    let client_stats = client_stats.unwrap();
+    server_stats.unwrap();
+
+    assert_eq!(client_stats.files_transferred, 0);
+    assert_eq!(
+        client_stats.failures.len(),
+        1,
+        "{:?}",
+        client_stats.failures
+    );
+    assert_eq!(client_stats.failures[0].filename, "file.txt");
+    assert!(
+        client_stats.failures[0]
+            .reason
+            .contains("checksum mismatch")
+    );
+    assert_eq!(
+        std::fs::read(destination.path().join("file.txt")).unwrap(),
+        b"old"
+    );
+}
+
+#[tokio::test]
+async fn progress_reports_each_file_then_the_stats() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let files: [(&str, &[u8]); 2] = [("a.txt", b"alpha"), ("b.txt", b"bravo, longer")];
+    write_tree(source.path(), &files);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        ..Default::default()
+    };
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    let mut pipeline =
+        Pipeline::local().with_progress(move |event| recorded.lock().unwrap().push(event));
+
+    let stats = pipeline.sync(source.path(), opts).await.unwrap();
+
+    // Files are sent in flist order, which need not be sorted
+    let mut expected = Vec::new();
+    for entry in pipeline.flist.iter().filter(|entry| entry.is_regular()) {
+        let file_index = entry.index;
+        let (name, contents) = files
+            .iter()
+            .find(|(name, _)| entry.filename.as_path() == std::path::Path::new(name))
+            .unwrap();
+        expected.extend([
+            ProgressEvent::FileStarted {
+                file_index,
+                filename: name.to_string(),
+                size: contents.len() as u64,
+            },
+            ProgressEvent::BytesProcessed {
+                file_index,
+                bytes: contents.len() as u64,
+            },
+            ProgressEvent::FileCompleted {
+                file_index,
+                error: None,
+            },
+        ]);
+    }
+    assert_eq!(stats.files_transferred, 2);
+    expected.push(ProgressEvent::Finished(stats));
+    assert_eq!(*events.lock().unwrap(), expected);
+}
+
+#[tokio::test]
+async fn mkpath_creates_a_missing_nested_destination() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("a.txt", b"alpha"), ("nested/b.txt", b"bravo")],
+    );
+    let root = destination.path().join("backups/fresh/target");
+    let opts = ClientServerOpts {
+        to: root.clone(),
+        direction: Direction::Push,
+        recursive: true,
+        mkpath: true,
+        chmod: Some(cli::parse_chmod("D750").unwrap()),
+        ..Default::default()
+    };
+
+    let stats = Pipeline::local().sync(source.path(), opts).await.unwrap();
+
+    assert_eq!(stats.files_transferred, 2);
+    assert!(root.is_dir());
+    assert_eq!(std::fs::read(root.join("a.txt")).unwrap(), b"alpha");
+    assert_eq!(std::fs::read(root.join("nested/b.txt")).unwrap(), b"bravo");
+    for dir in ["backups", "backups/fresh", "backups/fresh/target"] {
+        let mode = std::fs::metadata(destination.path().join(dir))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o750, "{}", dir);
+    }
+}
+
+#[tokio::test]
+async fn missing_destination_parents_are_an_error_without_mkpath() {
+    let destination = tempfile::tempdir().unwrap();
+    let pipeline = Pipeline::local();
+    let opts = ClientServerOpts::default();
+
+    // Only the last component may be missing, and an empty sync still creates it
+    let root = destination.path().join("fresh");
+    pipeline.make_root(&root, &opts).unwrap();
+    assert!(root.is_dir());
+
+    let nested = destination.path().join("missing/fresh");
+    assert!(matches!(
+        pipeline.make_root(&nested, &opts),
+        Err(Error::MissingDestination(path)) if path == nested
+    ));
+    assert!(!nested.parent().unwrap().exists());
+}
+
+#[tokio::test]
+async fn local_sync_copies_the_tree_byte_for_byte() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let binary: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 256) as u8).collect();
+    write_tree(
+        source.path(),
+        &[
+            ("a.txt", b"alpha"),
+            ("nested/deeper/b.bin", &binary),
+            ("empty", b""),
+        ],
+    );
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        whole_file: true,
+        ..Default::default()
+    };
+
+    let stats = Pipeline::local().sync(source.path(), opts).await.unwrap();
+
+    assert_eq!(stats.files_transferred, 3);
+    for name in ["a.txt", "nested/deeper/b.bin", "empty"] {
+        assert_eq!(
+            std::fs::read(destination.path().join(name)).unwrap(),
+            std::fs::read(source.path().join(name)).unwrap(),
+            "{}",
+            name
+        );
+    }
+}
+
+#[tokio::test]
+async fn corrupted_payload_fails_the_checksum() {
+    let (mut remote, local) = duplex(1024);
+    let (stdout, stdin) = tokio::io::split(local);
+    let mut tunnel = SSHTunnel::from_io(stdin, stdout);
+    // Flipping the last byte of the string still decodes, just to the wrong message
+    let mut frame = encode_frame(Message::Info("hello".to_string()), Codec::None).unwrap();
+    *frame.last_mut().unwrap() ^= 0x01;
+    let payload = &frame[8..];
+    assert_eq!(
+        decode_frame(payload).unwrap(),
+        Message::Info("helln".to_string())
+    );
+    remote.write_all(&frame).await.unwrap();
+
+    let result = tunnel.read_message().await;
+
+    assert!(
+        matches!(result, Err(Error::ChecksumMismatch { len, .. }) if len == payload.len()),
+        "{:?}",
+        result
+    );
+}
+
+#[tokio::test]
+async fn batch_replays_a_sync_against_a_copy_of_the_base() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let copy = tempfile::tempdir().unwrap();
+    let changed: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+    let mut original = changed.clone();
+    original[2000] ^= 0xff;
+    write_tree(
+        source.path(),
+        &[("changed.bin", &changed), ("nested/new.txt", b"new file")],
+    );
+    for base in [&destination, &copy] {
+        write_tree(base.path(), &[("changed.bin", &original)]);
+    }
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        ..Default::default()
+    };
+    let (client, client_stats, server_stats) =
+        push_with(source.path(), opts.clone(), ForwardingTunnel::new).await;
+    client_stats.unwrap();
+    server_stats.unwrap();
+    let path = copy.path().join("changes.batch");
+    let batch = client.batch.unwrap();
+    let changed_delta = batch
+        .deltas
+        .iter()
+        .find(|d| batch.flist[d.file_index as usize].filename == "changed.bin")
+        .unwrap();
+    assert!(
+        changed_delta
+            .delta
+            .ops
+            .iter()
+            .any(|op| matches!(op, Ops::Index(_))),
+        "the changed file should be sent as a delta"
+    );
+    batch.write(&path).unwrap();
+
+    let stats = Batch::read(&path)
+        .unwrap()
+        .apply(copy.path(), &opts)
+        .unwrap();
+
+    assert_eq!(stats.files_transferred, 2);
+    assert_eq!(stats.failures, vec![]);
+    for name in ["changed.bin", "nested/new.txt"] {
+        assert_eq!(
+            std::fs::read(copy.path().join(name)).unwrap(),
+            std::fs::read(source.path().join(name)).unwrap(),
+            "{}",
+            name
+        );
+    }
+    // Replaying against a base that has moved on fails instead of corrupting it
+    std::fs::write(copy.path().join("changed.bin"), b"moved on").unwrap();
+    let stats = Batch::read(&path)
+        .unwrap()
+        .apply(copy.path(), &opts)
+        .unwrap();
+    assert_eq!(stats.failures.len(), 1);
+    assert_eq!(
+        std::fs::read(copy.path().join("changed.bin")).unwrap(),
+        b"moved on"
+    );
+    assert!(matches!(
+        Batch::read(&copy.path().join("changed.bin")),
+        Err(Error::NotABatch(_))
+    ));
+}
+
+#[tokio::test]
+async fn file_stats_split_one_changed_block_from_the_matched_ones() {
+    for direction in [Direction::Push, Direction::Pull] {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        // 32 blocks of 128 bytes without repeats, the 16th of which differs
+        let changed: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let mut original = changed.clone();
+        original[2000] ^= 0xff;
+        write_tree(source.path(), &[("changed.bin", &changed)]);
+        write_tree(destination.path(), &[("changed.bin", &original)]);
+        let (local, remote) = match direction {
+            Direction::Push => (&source, &destination),
+            Direction::Pull => (&destination, &source),
+        };
+        let opts = ClientServerOpts {
+            to: remote.path().to_path_buf(),
+            direction,
+            recursive: true,
+            ..Default::default()
+        };
+        let (mut client, mut server) = duplex_pipelines();
+        let (client_stats, server_stats) =
+            tokio::join!(client.sync(local.path(), opts), server.serve());
+        let (client_stats, server_stats) = (client_stats.unwrap(), server_stats.unwrap());
+
+        let expected = FileStats {
+            matched_blocks: 31,
+            literal_blocks: 1,
+            bytes_reused: 31 * 128,
+            bytes_sent: 128,
+        };
+        assert_eq!(
+            client_stats.files.get(&0),
+            Some(&expected),
+            "{:?}",
+            direction
+        );
+        assert_eq!(client_stats.files, server_stats.files);
+    }
+}
+
+/// Connects a client to a server of its own on every call, the first `failing` of them over a
+/// [`ForwardingTunnel`] that dies after `reads` reads. Also returns the number of connections
+/// made.
+fn flaky_connect(
+    failing: u32,
+    reads: u32,
+    error: fn() -> Error,
+) -> (
+    impl FnMut() -> std::future::Ready<Result<Pipeline>>,
+    Arc<Mutex<u32>>,
+) {
+    let connections = Arc::new(Mutex::new(0));
+    let counter = Arc::clone(&connections);
+    let connect = move || {
+        let (client, server) = MemoryTunnel::pair(64 * 1024);
+        tokio::spawn(async move { Pipeline::with_tunnel(Box::new(server)).serve().await });
+        let mut connections = counter.lock().unwrap();
+        *connections += 1;
+        let client: Box<dyn Tunnel> = if *connections <= failing {
+            Box::new(ForwardingTunnel::new(client).dying_after_reads(reads, error))
+        } else {
+            Box::new(client)
+        };
+        std::future::ready(Ok(Pipeline::with_tunnel(client)))
+    };
+    (connect, connections)
+}
+
+const RETRY_POLICY: RetryPolicy = RetryPolicy {
+    retries: 3,
+    delay: std::time::Duration::from_millis(1),
+};
+
+#[tokio::test]
+async fn lost_connections_are_reconnected_until_the_sync_completes() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("a.txt", b"alpha"), ("b.txt", b"bravo")]);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        ..Default::default()
+    };
+    let eof = || Error::IO(std::io::ErrorKind::UnexpectedEof.into());
+    // Dies after the handshake and the first file's signature
+    let (connect, connections) = flaky_connect(2, 3, eof);
+
+    let (_, stats) = Pipeline::sync_with_retries(connect, RETRY_POLICY, source.path(), opts)
+        .await
+        .unwrap();
+
+    assert!(stats.failures.is_empty(), "{:?}", stats.failures);
+    assert_eq!(*connections.lock().unwrap(), 3);
+    for (name, contents) in [("a.txt", b"alpha"), ("b.txt", b"bravo")] {
+        assert_eq!(
+            std::fs::read(destination.path().join(name)).unwrap(),
+            contents
+        );
+    }
+}
+
+#[tokio::test]
+async fn reconnecting_gives_up_after_the_policy_and_on_other_errors() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("a.txt", b"alpha")]);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        ..Default::default()
+    };
+    let broken_pipe = || Error::IO(std::io::ErrorKind::BrokenPipe.into());
+    let (connect, connections) = flaky_connect(4, 0, broken_pipe);
+    let result =
+        Pipeline::sync_with_retries(connect, RETRY_POLICY, source.path(), opts.clone()).await;
+    assert!(matches!(result, Err(Error::IO(_))));
+    assert_eq!(*connections.lock().unwrap(), 4);
+
+    let decode_error =
+        || Error::Decoding(bincode::error::DecodeError::UnexpectedEnd { additional: 1 });
+    let (connect, connections) = flaky_connect(1, 0, decode_error);
+    let result = Pipeline::sync_with_retries(connect, RETRY_POLICY, source.path(), opts).await;
+    assert!(matches!(result, Err(Error::Decoding(_))));
+    assert_eq!(*connections.lock().unwrap(), 1);
+}
+
+#[test]
+fn error_states_compare_by_error_kind() {
+    assert_ne!(
+        PipelineState::Error(Error::Nack),
+        PipelineState::Error(Error::IoTimeout)
+    );
+    assert_eq!(
+        PipelineState::Error(Error::UnknownFileIndex(1)),
+        PipelineState::Error(Error::UnknownFileIndex(2))
+    );
+    assert_ne!(PipelineState::Error(Error::Nack), PipelineState::Connected);
+    assert_eq!(PipelineState::Connected, PipelineState::Connected);
+    assert_eq!(Error::IoTimeout.kind(), ErrorKind::IoTimeout);
+}
+
+#[tokio::test]
+async fn perms_and_times_are_applied_without_the_owner() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("a.txt", b"alpha")]);
+    let path = source.path().join("a.txt");
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+    let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+    std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+    if flist::can_chown() {
+        std::os::unix::fs::chown(&path, Some(12345), Some(23456)).unwrap();
+    }
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        perms: true,
+        times: true,
+        ..Default::default()
+    };
+    let (_, client_stats, server_stats) =
+        push_with(source.path(), opts, ForwardingTunnel::new).await;
+    client_stats.unwrap();
+    assert!(server_stats.unwrap().failures.is_empty());
+
+    let metadata = std::fs::metadata(destination.path().join("a.txt")).unwrap();
+    assert_eq!(metadata.mode() & 0o7777, 0o640);
+    assert_eq!(metadata.modified().unwrap(), mtime);
+    // SAFETY: geteuid and getegid have no preconditions and cannot fail.
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    assert_eq!((metadata.uid(), metadata.gid()), (uid, gid));
+}
+
+#[tokio::test]
+async fn syncing_an_empty_directory_transfers_nothing() {
+    for direction in [Direction::Push, Direction::Pull] {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let (local, remote) = match direction {
+            Direction::Push => (&source, &destination),
+            Direction::Pull => (&destination, &source),
+        };
+        let opts = ClientServerOpts {
+            to: remote.path().to_path_buf(),
+            direction,
+            recursive: true,
+            ..Default::default()
+        };
+        let (mut client, mut server) = duplex_pipelines();
+        let (client_stats, server_stats) =
+            tokio::join!(client.sync(local.path(), opts), server.serve());
+
+        assert_eq!(
+            client_stats.unwrap(),
+            TransferStats::default(),
+            "{:?}",
+            direction
+        );
+        assert_eq!(server_stats.unwrap(), TransferStats::default());
+        assert!(client.flist.is_empty());
+        assert_eq!(std::fs::read_dir(destination.path()).unwrap().count(), 0);
+    }
+}
+
+#[tokio::test]
+async fn pushed_delta_is_reconstructed_in_place_on_the_server() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let original: Vec<u8> = (0..64 * 1024u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+        .collect();
+    let mut modified = original.clone();
+    modified[10_000..10_100].fill(0xaa);
+    modified.extend_from_slice(b"appended tail");
+    write_tree(source.path(), &[("data.bin", &modified)]);
+    write_tree(destination.path(), &[("data.bin", &original)]);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        ..Default::default()
+    };
+
+    let (_, client_stats, server_stats) =
+        push_with(source.path(), opts, ForwardingTunnel::new).await;
+    client_stats.unwrap();
+    let server_stats = server_stats.unwrap();
+
+    assert_eq!(server_stats.files_transferred, 1);
+    assert!(server_stats.files[&0].matched_blocks > 0);
+    assert_eq!(
+        std::fs::read(destination.path().join("data.bin")).unwrap(),
+        modified
+    );
+    // Only the file itself is left, no temp file
+    assert_eq!(std::fs::read_dir(destination.path()).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn client_and_server_loops_sync_a_directory_over_a_duplex() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[
+            ("a.txt", b"alpha"),
+            ("nested/b.txt", b"bravo"),
+            ("nested/deeper/c.txt", b"charlie"),
+        ],
+    );
+    write_tree(destination.path(), &[("a.txt", b"stale")]);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        ..Default::default()
+    };
+    let (client, server) = MemoryTunnel::pair(64 * 1024);
+
+    let (outcome, server_stats) = tokio::join!(
+        run_client(client, source.path(), opts.clone()),
+        run_server(server, None)
+    );
+
+    let ClientOutcome::Synced(stats) = outcome.unwrap() else {
+        panic!("expected a sync");
+    };
+    assert_eq!(stats.files_transferred, 3);
+    assert_eq!(server_stats.unwrap().files_transferred, 3);
+    for name in ["a.txt", "nested/b.txt", "nested/deeper/c.txt"] {
+        assert_eq!(
+            std::fs::read(destination.path().join(name)).unwrap(),
+            std::fs::read(source.path().join(name)).unwrap(),
+            "{}",
+            name
+        );
+    }
+
+    // The same loops answer a verification of the result
+    let (client, server) = MemoryTunnel::pair(64 * 1024);
+    let opts = ClientServerOpts {
+        verify: true,
+        ..opts
+    };
+    let (outcome, _) = tokio::join!(
+        run_client(client, source.path(), opts),
+        run_server(server, None)
+    );
+    assert!(matches!(outcome.unwrap(), ClientOutcome::Verified(report) if report.is_clean()));
+}
+
+#[tokio::test]
+async fn temp_dir_holds_files_while_they_are_rebuilt() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let scratch = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("a.txt", b"alpha"), ("nested/b.txt", b"bravo")],
+    );
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        temp_dir: Some(scratch.path().to_path_buf()),
+        ..Default::default()
+    };
+    let (_, client_stats, server_stats) =
+        push_with(source.path(), opts, ForwardingTunnel::new).await;
+    client_stats.unwrap();
+    assert_eq!(server_stats.unwrap().files_transferred, 2);
+
+    assert_eq!(
+        std::fs::read(destination.path().join("nested/b.txt")).unwrap(),
+        b"bravo"
+    );
+    assert_eq!(std::fs::read_dir(scratch.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn finished_files_are_renamed_or_copied_across_filesystems() {
+    let dir = tempfile::tempdir().unwrap();
+    let tmp = dir.path().join("scratch");
+    let path = dir.path().join("dest.txt");
+    std::fs::write(&path, b"old").unwrap();
+
+    std::fs::write(&tmp, b"renamed").unwrap();
+    assert!(transfer::move_into_place(&RealFs, &tmp, &path).unwrap());
+    assert_eq!(std::fs::read(&path).unwrap(), b"renamed");
+    assert!(!tmp.exists());
+
+    std::fs::write(&tmp, b"copied").unwrap();
+    let cross_device = |from: &Path, to: &Path| {
+        if from == tmp.as_path() {
+            Err(std::io::ErrorKind::CrossesDevices.into())
+        } else {
+            std::fs::rename(from, to)
+        }
+    };
+    assert!(!transfer::move_into_place_with(&RealFs, &tmp, &path, cross_device).unwrap());
+    assert_eq!(std::fs::read(&path).unwrap(), b"copied");
+    assert!(!tmp.exists());
+    // Only the destination is left, no staged copy
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn deltas_saving_less_than_the_min_transfer_ratio_are_sent_whole() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let contents: Vec<u8> = (0..4096u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+        .collect();
+    // Only the first two of 32 blocks survive
+    let mut original = contents.clone();
+    for byte in &mut original[256..] {
+        *byte ^= 0xff;
+    }
+    write_tree(source.path(), &[("file.bin", &contents)]);
+
+    for (ratio, whole) in [(None, false), (Some(0.5), true)] {
+        write_tree(destination.path(), &[("file.bin", &original)]);
+        let opts = ClientServerOpts {
+            to: destination.path().to_path_buf(),
+            direction: Direction::Push,
+            min_transfer_ratio: ratio.map(|r| crate::cli::Ratio::new(r).unwrap()),
+            ..Default::default()
+        };
+        let (_, client_stats, server_stats) =
+            push_with(source.path(), opts, ForwardingTunnel::new).await;
+        server_stats.unwrap();
+
+        let file = client_stats.unwrap().files[&0];
+        assert_eq!(file.matched_blocks == 0, whole, "{:?}", ratio);
+        assert_eq!(
+            std::fs::read(destination.path().join("file.bin")).unwrap(),
+            contents
+        );
+    }
+}
+
+#[tokio::test]
+async fn batched_flist_matches_individual_entries() {
+    let flist: Vec<_> = (0..2500)
+        .map(|i| flist_entry(i, &format!("file-{}.txt", i)))
+        .collect();
+
+    let (client, server) = MemoryTunnel::pair(64 * 1024);
+    let sent = Arc::default();
+    let mut sender =
+        Pipeline::with_tunnel(Box::new(ForwardingTunnel::new(client).recording(&sent)));
+    let mut batched = Pipeline::with_tunnel(Box::new(server));
+    let (sent_flist, received) =
+        tokio::join!(sender.send_flist(flist.clone()), batched.receive_flist());
+    sent_flist.unwrap();
+    received.unwrap();
+
+    let (mut client, server) = MemoryTunnel::pair(64 * 1024);
+    let mut legacy = Pipeline::with_tunnel(Box::new(server));
+    let send_legacy = async {
+        for entry in &flist {
+            client
+                .write_message(Message::FlistEntry(entry.clone()))
+                .await?;
+        }
+        client.write_message(Message::FlistEnd).await
+    };
+    let (sent_legacy, received) = tokio::join!(send_legacy, legacy.receive_flist());
+    sent_legacy.unwrap();
+    received.unwrap();
+
+    assert_eq!(batched.flist, flist);
+    assert_eq!(legacy.flist, flist);
+    let batches = sent
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|msg| match msg {
+            Message::FlistBatch(entries) => Some(entries.len()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(batches, vec![FLIST_BATCH_SIZE, FLIST_BATCH_SIZE, 500]);
+}
+
+#[tokio::test]
+async fn flist_entries_outside_the_root_are_refused() {
+    for filename in ["../escape", "/abs", "nested/../../escape", "./a.txt"] {
+        let (mut server, client) = MemoryTunnel::pair(64 * 1024);
+        let mut receiver = Pipeline::with_tunnel(Box::new(client));
+        let send = async {
+            server
+                .write_message(Message::FlistBatch(vec![
+                    flist_entry(0, "a.txt"),
+                    flist_entry(1, filename),
+                ]))
+                .await?;
+            server.write_message(Message::FlistEnd).await
+        };
+        let (sent, received) = tokio::join!(send, receiver.receive_flist());
+        sent.unwrap();
+
+        assert!(
+            matches!(received, Err(Error::IO(ref e)) if e.kind() == std::io::ErrorKind::InvalidData),
+            "{}: {:?}",
+            filename,
+            received
+        );
+        assert_eq!(
+            receiver.flist,
+            vec![flist_entry(0, "a.txt")],
+            "{}",
+            filename
+        );
+    }
+}
+
+#[tokio::test]
+async fn hard_links_must_name_an_earlier_regular_entry() {
+    let dir = FlistEntry {
+        is_dir: true,
+        ..flist_entry(1, "dir")
+    };
+    for target in [1, 2, 3] {
+        let (mut server, client) = MemoryTunnel::pair(64 * 1024);
+        let mut receiver = Pipeline::with_tunnel(Box::new(client));
+        let link = FlistEntry {
+            hardlink_to: Some(target),
+            ..flist_entry(2, "link")
+        };
+        let flist = vec![flist_entry(0, "a.txt"), dir.clone(), link];
+        let send = async {
+            server.write_message(Message::FlistBatch(flist)).await?;
+            server.write_message(Message::FlistEnd).await
+        };
+        let (sent, received) = tokio::join!(send, receiver.receive_flist());
+        sent.unwrap();
+
+        assert!(
+            matches!(received, Err(Error::IO(ref e)) if e.kind() == std::io::ErrorKind::InvalidData),
+            "{}: {:?}",
+            target,
+            received
+        );
+    }
+}
+
+/// Answers every `FileIndex` with a signature table that has one byte flipped in transit.
+struct TamperedTables {
+    base: Vec<u8>,
+    pending: VecDeque<Message>,
+}
+
+#[async_trait]
+impl Tunnel for TamperedTables {
+    async fn write_message(&mut self, msg: Message) -> Result<()> {
+        if let Message::FileIndex(index) = msg {
+            let mut data =
+                DataMessage::new(&transfer::signatures(&self.base, Chunker::Fixed), index)?;
+            let last = data.table.len() - 1;
+            data.table[last] ^= 1;
+            self.pending.push_back(Message::Data(data));
+        }
+        Ok(())
+    }
+    async fn read_message(&mut self) -> Result<Message> {
+        self.pending.pop_front().ok_or(Error::IoTimeout)
+    }
+}
+
+#[tokio::test]
+async fn tampered_signature_table_is_rejected_before_diffing() {
+    let dir = tempfile::tempdir().unwrap();
+    let contents = vec![7u8; 1024];
+    std::fs::write(dir.path().join("a.bin"), &contents).unwrap();
+    let mut pipeline = Pipeline::with_tunnel(Box::new(TamperedTables {
+        base: contents,
+        pending: Default::default(),
+    }));
+    pipeline.flist = vec![flist_entry(0, "a.bin")];
+
+    let result = pipeline
+        .process_flist(dir.path(), &ClientServerOpts::default())
+        .await;
+
+    assert!(
+        matches!(result, Err(Error::ChecksumMismatch { .. })),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn signature_tables_roundtrip_through_their_checksum() {
+    let table = transfer::signatures(&[3u8; 512], Chunker::Fixed);
+    let data = DataMessage::new(&table, 4).unwrap();
+
+    assert_eq!(data.table().unwrap(), table);
+}
+
+/// Log output captured by [`capture_logs`].
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CapturedLogs {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+/// Captures the events of this thread at `level` and above until the guard is dropped.
+fn capture_logs(level: tracing::Level) -> (CapturedLogs, tracing::subscriber::DefaultGuard) {
+    let captured = CapturedLogs::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .with_max_level(level)
+        .finish();
+    (captured, tracing::subscriber::set_default(subscriber))
+}
+
+#[tokio::test]
+async fn file_index_events_are_tagged_with_the_index() {
+    let (captured, _guard) = capture_logs(tracing::Level::INFO);
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("a.txt", b"first"), ("b.txt", b"second")]);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        ..Default::default()
+    };
+    let (mut client, mut server) = duplex_pipelines();
+    let (client_stats, server_stats) =
+        tokio::join!(client.sync(source.path(), opts), server.serve());
+    client_stats.unwrap();
+    server_stats.unwrap();
+
+    let output = captured.contents();
+    for (index, name) in server.flist.iter().map(|e| (e.index, &e.filename)) {
+        let signing = format!("signing {}", name);
+        let line = output
+            .lines()
+            .find(|line| line.contains(&signing))
+            .unwrap_or_else(|| panic!("no event for {} in {}", name, output));
+        assert!(
+            line.contains(&format!(
+                "handle_file_index{{file_index={} kind=\"FileIndex\"}}",
+                index
+            )),
+            "{}",
+            line
+        );
+    }
+    assert!(output.contains("handle_handshake{kind=SYNC}"), "{}", output);
+}
+
+async fn push_twice(opts: ClientServerOpts, first: &[u8], second: &[u8]) {
+    let source = tempfile::tempdir().unwrap();
+    for contents in [first, second] {
+        write_tree(source.path(), &[("nested/file.txt", contents)]);
+        let (mut client, mut server) = duplex_pipelines();
+        let (client_stats, server_stats) =
+            tokio::join!(client.sync(source.path(), opts.clone()), server.serve());
+        client_stats.unwrap();
+        server_stats.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn backup_keeps_the_replaced_version() {
+    let destination = tempfile::tempdir().unwrap();
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        backup: true,
+        ..Default::default()
+    };
+
+    push_twice(opts, b"old contents", b"new contents").await;
+
+    let read = |name: &str| std::fs::read(destination.path().join(name)).unwrap();
+    assert_eq!(read("nested/file.txt"), b"new contents");
+    assert_eq!(read("nested/file.txt~"), b"old contents");
+}
+
+#[tokio::test]
+async fn unchanged_files_are_not_backed_up() {
+    let destination = tempfile::tempdir().unwrap();
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        backup: true,
+        ..Default::default()
+    };
+
+    push_twice(opts, b"same", b"same").await;
+
+    assert!(!destination.path().join("nested/file.txt~").exists());
+}
+
+#[tokio::test]
+async fn backup_dir_mirrors_relative_paths() {
+    let destination = tempfile::tempdir().unwrap();
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        backup: true,
+        backup_dir: Some(PathBuf::from("backups")),
+        backup_suffix: Some(".bak".to_string()),
+        ..Default::default()
+    };
+
+    push_twice(opts, b"old contents", b"new contents").await;
+
+    assert_eq!(
+        std::fs::read(destination.path().join("backups/nested/file.txt.bak")).unwrap(),
+        b"old contents"
+    );
+    assert!(!destination.path().join("nested/file.txt.bak").exists());
+}
+
+#[tokio::test]
+async fn non_utf8_filenames_survive_the_flist() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let name = OsStr::from_bytes(b"caf\xe9 \xff.txt");
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    std::fs::write(source.path().join(name), b"latin-1").unwrap();
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        ..Default::default()
+    };
+
+    let (mut client, mut server) = duplex_pipelines();
+    let (client_stats, server_stats) =
+        tokio::join!(client.sync(source.path(), opts), server.serve());
+    client_stats.unwrap();
+    server_stats.unwrap();
+
+    assert_eq!(server.flist.len(), 1);
+    assert_eq!(server.flist[0].filename.0, name.as_bytes());
+    assert_eq!(
+        server.flist[0].filename.to_string(),
+        "caf\u{fffd} \u{fffd}.txt"
+    );
+    assert_eq!(
+        std::fs::read(destination.path().join(name)).unwrap(),
+        b"latin-1"
+    );
+}
+
+#[tokio::test]
+async fn arguments_after_ack_still_configure_the_server() {
+    let source = tempfile::tempdir().unwrap();
+    std::fs::write(source.path().join("top.txt"), b"top").unwrap();
+    std::fs::create_dir(source.path().join("nested")).unwrap();
+    std::fs::write(source.path().join("nested/deep.txt"), b"deep").unwrap();
+
+    let (client, server) = MemoryTunnel::pair(64 * 1024);
+    let mut client = Pipeline::with_tunnel(Box::new(client));
+    let mut server = Pipeline::with_tunnel(Box::new(server));
+    let opts = ClientServerOpts {
+        to: source.path().to_path_buf(),
+        recursive: true,
+        list_only: true,
+        sort: true,
+        ..Default::default()
+    };
+    let handshake = async {
+        client.tunnel.write_message(Message::SYNC).await?;
+        assert_eq!(client.tunnel.read_message().await?, Message::ACK);
+        client.tunnel.write_message(Message::ACK).await?;
+        client
+            .tunnel
+            .write_message(Message::Arguments(Box::new(opts)))
+            .await?;
+        client.receive_flist().await
+    };
+    let (received, served) = tokio::join!(handshake, server.serve());
+    received.unwrap();
+    served.unwrap();
+
+    let names = client
+        .flist
+        .iter()
+        .map(|e| e.filename.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["nested/deep.txt", "top.txt"]);
+}
+
+#[tokio::test]
+async fn walking_without_arguments_is_an_error() {
+    let (mut client, server) = MemoryTunnel::pair(64 * 1024);
+    let mut server = Pipeline::with_tunnel(Box::new(server));
+    let handshake = async {
+        client.write_message(Message::ACK).await?;
+        client.write_message(Message::FlistEnd).await?;
+        client.read_message().await
+    };
+    let (reply, served) = tokio::join!(handshake, server.serve());
+
+    assert!(matches!(served, Err(Error::MissingArguments)));
+    assert!(matches!(
+        reply.unwrap(),
+        Message::Error(SSHMessageError::FatalError(_))
+    ));
+}
+
+#[tokio::test]
+async fn reflinked_files_match_the_source() {
+    let destination = tempfile::tempdir().unwrap();
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        reflink: true,
+        ..Default::default()
+    };
+    let first = b"unchanged block ".repeat(512);
+    let mut second = first.clone();
+    second.splice(3000..3010, b"edited".iter().copied());
+
+    push_twice(opts, &first, &second).await;
+
+    assert_eq!(
+        std::fs::read(destination.path().join("nested/file.txt")).unwrap(),
+        second
+    );
+}
+
+#[tokio::test]
+async fn outcomes_and_errors_map_to_exit_codes() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("a.txt", b"alpha")]);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        ..Default::default()
+    };
+    let (client, server) = MemoryTunnel::pair(64 * 1024);
+    let (outcome, _) = tokio::join!(
+        run_client(client, source.path(), opts.clone()),
+        run_server(server, None)
+    );
+    assert_eq!(outcome.unwrap().exit_code(), ExitCode::Success);
+
+    // Differences found by a verification are a partial failure
+    write_tree(destination.path(), &[("a.txt", b"changed")]);
+    let (client, server) = MemoryTunnel::pair(64 * 1024);
+    let verify = ClientServerOpts {
+        verify: true,
+        ..opts.clone()
+    };
+    let (outcome, _) = tokio::join!(
+        run_client(client, source.path(), verify),
+        run_server(server, None)
+    );
+    assert_eq!(outcome.unwrap().exit_code(), ExitCode::PartialFailure);
+
+    let failed = TransferStats {
+        failures: vec![FileError {
+            file_index: 0,
+            filename: "a.txt".to_string(),
+            reason: "unreadable".to_string(),
+        }],
+        ..Default::default()
+    };
+    assert_eq!(
+        ClientOutcome::Synced(failed).exit_code(),
+        ExitCode::PartialFailure
+    );
+    let aborted = Error::FileTransfer {
+        filename: "a.txt".to_string(),
+        reason: "unreadable".to_string(),
+    };
+    assert_eq!(aborted.exit_code(), ExitCode::PartialFailure);
+
+    // Options the server rejects are a usage error
+    let (mut client, server) = MemoryTunnel::pair(64 * 1024);
+    let invalid = ClientServerOpts {
+        delete: true,
+        recursive: false,
+        ..opts
+    };
+    let handshake = async {
+        client.write_message(Message::SYNC).await?;
+        client.read_message().await?;
+        client
+            .write_message(Message::Arguments(Box::new(invalid)))
+            .await
+    };
+    let (sent, served) = tokio::join!(handshake, run_server(server, None));
+    sent.unwrap();
+    assert_eq!(served.unwrap_err().exit_code(), ExitCode::Usage);
+
+    // And a broken handshake is a protocol error
+    let (mut client, server) = MemoryTunnel::pair(64 * 1024);
+    let handshake = async {
+        client.write_message(Message::ACK).await?;
+        client.write_message(Message::FlistEnd).await
+    };
+    let (sent, served) = tokio::join!(handshake, run_server(server, None));
+    sent.unwrap();
+    assert_eq!(served.unwrap_err().exit_code(), ExitCode::Protocol);
+}
+
+/// Counts the flushes passing through to `inner`.
+struct CountingWriter<W> {
+    inner: W,
+    flushes: Arc<Mutex<usize>>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<io::Result<usize>> {
+        std::pin::Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        *self.flushes.lock().unwrap() += 1;
+        std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn large_flists_are_flushed_once() {
+    let flist: Vec<_> = (0..10_000)
+        .map(|i| flist_entry(i, &format!("dir/file-{}.txt", i)))
+        .collect();
+    let (a, b) = duplex(64 * 1024);
+    let (reader, writer) = tokio::io::split(a);
+    let flushes = Arc::default();
+    let mut sender = Pipeline::with_tunnel(Box::new(SSHTunnel::from_io(
+        CountingWriter {
+            inner: writer,
+            flushes: Arc::clone(&flushes),
+        },
+        reader,
+    )));
+    let mut receiver = Pipeline::with_tunnel(Box::new(MemoryTunnel::from_stream(b)));
+
+    let (sent, received) = tokio::join!(sender.send_flist(flist.clone()), receiver.receive_flist());
+    sent.unwrap();
+    received.unwrap();
+
+    assert_eq!(receiver.flist, flist);
+    // One flush at `FlistEnd` rather than one for each of the 11 messages
+    assert_eq!(*flushes.lock().unwrap(), 1);
+}
+
+#[tokio::test]
+async fn existing_only_updates_files_already_on_the_destination() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("kept.txt", b"updated"), ("nested/new.txt", b"new")],
+    );
+    write_tree(destination.path(), &[("kept.txt", b"stale")]);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        existing: true,
+        ..Default::default()
+    };
+
+    let (_, client_stats, server_stats) =
+        push_with(source.path(), opts, ForwardingTunnel::new).await;
+    let (client_stats, server_stats) = (client_stats.unwrap(), server_stats.unwrap());
+
+    assert_eq!(client_stats.files_transferred, 1);
+    assert!(client_stats.failures.is_empty() && server_stats.failures.is_empty());
+    assert_eq!(
+        std::fs::read(destination.path().join("kept.txt")).unwrap(),
+        b"updated"
+    );
+    assert!(!destination.path().join("nested").exists());
+}
+
+#[tokio::test]
+async fn ignore_existing_only_creates_new_files() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("kept.txt", b"updated"), ("nested/new.txt", b"new")],
+    );
+    write_tree(destination.path(), &[("kept.txt", b"original")]);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        ignore_existing: true,
+        ..Default::default()
+    };
+
+    let (_, client_stats, server_stats) =
+        push_with(source.path(), opts, ForwardingTunnel::new).await;
+    let (client_stats, server_stats) = (client_stats.unwrap(), server_stats.unwrap());
+
+    assert_eq!(client_stats.files_transferred, 1);
+    assert!(client_stats.failures.is_empty() && server_stats.failures.is_empty());
+    let read = |name: &str| std::fs::read(destination.path().join(name)).unwrap();
+    assert_eq!(read("kept.txt"), b"original");
+    assert_eq!(read("nested/new.txt"), b"new");
+}
+
+#[tokio::test]
+async fn pings_are_answered_between_real_messages() {
+    let (mut client, mut server) = MemoryTunnel::pair(1024);
+    client.write_message(Message::Ping).await.unwrap();
+    client.write_message(Message::FileIndex(3)).await.unwrap();
+
+    assert_eq!(server.read_message().await.unwrap(), Message::FileIndex(3));
+    assert_eq!(client.read_frame().await.unwrap(), Message::Pong);
+}
+
+#[tokio::test]
+async fn idle_heartbeat_tunnels_ping_without_disturbing_the_exchange() {
+    let (client, mut server) = MemoryTunnel::pair(64 * 1024);
+    let mut client = HeartbeatTunnel::new(Box::new(client), Duration::from_millis(10));
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(server.read_frame().await.unwrap(), Message::Ping);
+
+    client.write_message(Message::FileIndex(1)).await.unwrap();
+    // Any further pings are answered and skipped on the way
+    assert_eq!(server.read_message().await.unwrap(), Message::FileIndex(1));
+    server.write_message(Message::Done).await.unwrap();
+    assert_eq!(client.read_message().await.unwrap(), Message::Done);
+}
+
+#[tokio::test]
+async fn zero_heartbeat_interval_never_pings() {
+    let (client, mut server) = MemoryTunnel::pair(64 * 1024);
+    let mut client = HeartbeatTunnel::new(Box::new(client), Duration::ZERO);
+    // The pinging task ends straight away instead of panicking on a zero interval
+    (&mut client.task).await.unwrap();
+    client.write_message(Message::FileIndex(1)).await.unwrap();
+    assert_eq!(server.read_frame().await.unwrap(), Message::FileIndex(1));
+
+    let (mut client, mut server) = duplex_pipelines();
+    client = client.with_heartbeat(Duration::ZERO);
+    client.tunnel.write_message(Message::Done).await.unwrap();
+    assert_eq!(server.tunnel.read_message().await.unwrap(), Message::Done);
+}
+
+#[tokio::test]
+async fn update_skips_files_newer_on_the_destination() {
+    let set_mtime = |path: &std::path::Path, secs: u64| {
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    };
+    for (update, modify_window, expected) in [
+        (true, 0, &b"local edit"[..]),
+        (false, 0, &b"source"[..]),
+        // Within the window the times count as equal, so the file is not newer
+        (true, 120, &b"source"[..]),
+    ] {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        write_tree(source.path(), &[("a.txt", b"source")]);
+        write_tree(destination.path(), &[("a.txt", b"local edit")]);
+        set_mtime(&source.path().join("a.txt"), 1_700_000_000);
+        set_mtime(&destination.path().join("a.txt"), 1_700_000_060);
+        let opts = ClientServerOpts {
+            to: destination.path().to_path_buf(),
+            direction: Direction::Push,
+            recursive: true,
+            update,
+            modify_window,
+            ..Default::default()
+        };
+
+        let (_, client_stats, server_stats) =
+            push_with(source.path(), opts, ForwardingTunnel::new).await;
+        client_stats.unwrap();
+        server_stats.unwrap();
+
+        assert_eq!(
+            std::fs::read(destination.path().join("a.txt")).unwrap(),
+            expected,
+            "update {} window {}",
+            update,
+            modify_window
+        );
+    }
+}
+
+#[tokio::test]
+async fn chown_overrides_the_owner_of_received_files() {
+    use std::os::unix::fs::MetadataExt;
+
+    if !flist::can_chown() {
+        return;
+    }
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("a.txt", b"alpha"), ("nested/b.txt", b"beta")],
+    );
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        chown: Some(cli::parse_chown("12345:23456").unwrap()),
+        ..Default::default()
+    };
+    let (_, client_stats, server_stats) =
+        push_with(source.path(), opts, ForwardingTunnel::new).await;
+    client_stats.unwrap();
+    assert!(server_stats.unwrap().failures.is_empty());
+
+    for file in ["a.txt", "nested/b.txt"] {
+        let metadata = std::fs::metadata(destination.path().join(file)).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (12345, 23456), "{}", file);
+    }
+}
+
+#[tokio::test]
+async fn chown_to_an_unknown_user_fails_before_transferring() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("a.txt", b"alpha")]);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        chown: Some(cli::parse_chown("no-such-user-oxide").unwrap()),
+        ..Default::default()
+    };
+    let (_, client_stats, server_stats) =
+        push_with(source.path(), opts, ForwardingTunnel::new).await;
+
+    assert!(client_stats.is_err());
+    assert!(matches!(
+        server_stats,
+        Err(Error::InvalidOptions(cli::Error::UnknownUser(ref user))) if user == "no-such-user-oxide"
+    ));
+    assert!(!destination.path().join("a.txt").exists());
+}
+
+#[tokio::test]
+async fn partial_files_are_resumed_from() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let contents = (0..200)
+        .map(|i| format!("line {:04} of the new version\n", i))
+        .collect::<String>();
+    let (done, _) = contents.as_bytes().split_at(contents.len() / 2);
+    write_tree(source.path(), &[("nested/a.txt", contents.as_bytes())]);
+    write_tree(
+        destination.path(),
+        &[
+            ("nested/a.txt", &b"x".repeat(contents.len())),
+            (".partial/nested/a.txt", done),
+        ],
+    );
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        partial_dir: Some(".partial".into()),
+        ..Default::default()
+    };
+
+    let (_, client_stats, server_stats) =
+        push_with(source.path(), opts, ForwardingTunnel::new).await;
+    let client_stats = client_stats.unwrap();
+    server_stats.unwrap();
+
+    assert_eq!(
+        std::fs::read(destination.path().join("nested/a.txt")).unwrap(),
+        contents.as_bytes()
+    );
+    // Only the half missing from the partial file was sent
+    let file_stats = &client_stats.files[&0];
+    assert_eq!(file_stats.bytes_reused, done.len() as u64 / 128 * 128);
+    assert!(file_stats.bytes_sent < contents.len() as u64 / 2 + 128);
+    assert!(!destination.path().join(".partial/nested").exists());
+}
+
+#[tokio::test]
+async fn partial_files_survive_a_lost_connection_and_are_resumed() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let mut state = 11u64;
+    let contents: Vec<u8> = (0..4 * DELTA_CHUNK_SIZE)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    write_tree(source.path(), &[("big.bin", &contents)]);
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        partial_dir: Some(".partial".into()),
+        ..Default::default()
+    };
+    // The first connection dies before the third chunk of the delta, and every connection
+    // waits for the server before it to give up, so the partial file is complete
+    let server = Arc::new(Mutex::new(
+        None::<tokio::task::JoinHandle<Result<TransferStats>>>,
+    ));
+    let connections = Arc::new(Mutex::new(0));
+    let connect = || {
+        let server = Arc::clone(&server);
+        let connections = Arc::clone(&connections);
+        async move {
+            let previous = server.lock().unwrap().take();
+            if let Some(previous) = previous {
+                assert!(previous.await.unwrap().is_err());
+            }
+            let (client, remote) = MemoryTunnel::pair(64 * 1024);
+            *server.lock().unwrap() = Some(tokio::spawn(async move {
+                Pipeline::with_tunnel(Box::new(remote)).serve().await
+            }));
+            let mut connections = connections.lock().unwrap();
+            *connections += 1;
+            let client: Box<dyn Tunnel> = if *connections == 1 {
+                let mut chunks = 0;
+                Box::new(ForwardingTunnel::new(client).dying_on_write(move |msg| {
+                    chunks += u32::from(matches!(msg, Message::DeltaChunk(_)));
+                    chunks == 3
+                }))
+            } else {
+                Box::new(client)
+            };
+            Ok(Pipeline::with_tunnel(client))
+        }
+    };
+
+    let (_, stats) = Pipeline::sync_with_retries(connect, RETRY_POLICY, source.path(), opts)
+        .await
+        .unwrap();
+
+    assert_eq!(*connections.lock().unwrap(), 2);
+    assert_eq!(
+        std::fs::read(destination.path().join("big.bin")).unwrap(),
+        contents
+    );
+    // The two chunks that made it were not sent again
+    let file_stats = &stats.files[&0];
+    assert!(file_stats.bytes_reused >= 2 * DELTA_CHUNK_SIZE as u64 - 128);
+    assert!(file_stats.bytes_sent < 3 * DELTA_CHUNK_SIZE as u64);
+    assert!(!destination.path().join(".partial/big.bin").exists());
+}
+
+#[test]
+fn stale_partial_files_are_removed() {
+    let dir = tempfile::tempdir().unwrap();
+    write_tree(
+        dir.path(),
+        &[
+            ("old/a.txt", b"old"),
+            ("b.txt", b"recent"),
+            ("unlisted.txt", b"old"),
+        ],
+    );
+    for name in ["old/a.txt", "unlisted.txt"] {
+        std::fs::File::options()
+            .write(true)
+            .open(dir.path().join(name))
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - Duration::from_secs(3600))
+            .unwrap();
+    }
+
+    let names = [Path::new("old/a.txt"), Path::new("b.txt")];
+    transfer::remove_stale_partials(&RealFs, dir.path(), names, Duration::from_secs(60));
+
+    assert!(!dir.path().join("old").exists());
+    assert!(dir.path().join("b.txt").exists());
+    // Only files named in the file list are partial files
+    assert!(dir.path().join("unlisted.txt").exists());
+}
+
+#[tokio::test]
+async fn syncs_run_entirely_in_memory() {
+    for direction in [Direction::Push, Direction::Pull] {
+        let source = MemFs::new();
+        let destination = MemFs::new();
+        let contents = b"shared block ".repeat(64);
+        let mut edited = contents.clone();
+        edited.splice(300..310, b"edited".iter().copied());
+        source.write("/mem/source/a.txt", b"alpha").unwrap();
+        source.write("/mem/source/nested/b.txt", &edited).unwrap();
+        destination
+            .write("/mem/destination/nested/b.txt", &contents)
+            .unwrap();
+        let (local, remote) = match direction {
+            Direction::Push => ("/mem/source", "/mem/destination"),
+            Direction::Pull => ("/mem/destination", "/mem/source"),
+        };
+        let (local_fs, remote_fs) = match direction {
+            Direction::Push => (&source, &destination),
+            Direction::Pull => (&destination, &source),
+        };
+        let opts = ClientServerOpts {
+            to: remote.into(),
+            direction,
+            recursive: true,
+            perms: true,
+            ..Default::default()
+        };
+
+        let (client, server) = duplex_pipelines();
+        let mut client = client.with_fs(Arc::new(local_fs.clone()));
+        let mut server = server.with_fs(Arc::new(remote_fs.clone()));
+        let (client_stats, server_stats) = tokio::join!(
+            client.sync(std::path::Path::new(local), opts),
+            server.serve()
+        );
+        let (client_stats, server_stats) = (client_stats.unwrap(), server_stats.unwrap());
+
+        let sender_stats = match direction {
+            Direction::Push => client_stats,
+            Direction::Pull => server_stats,
+        };
+        assert_eq!(sender_stats.files_transferred, 2, "{:?}", direction);
+        let nested = sender_stats
+            .files
+            .values()
+            .find(|file| file.matched_blocks > 0)
+            .expect("the edited file was sent as a delta");
+        assert!(nested.bytes_sent < edited.len() as u64);
+        for (file, expected) in [("a.txt", &b"alpha"[..]), ("nested/b.txt", &edited)] {
+            let path = std::path::Path::new("/mem/destination").join(file);
+            assert_eq!(destination.read(&path).unwrap(), expected, "{}", file);
+        }
+        assert_eq!(
+            destination.read_dir("/mem/destination".as_ref()).unwrap(),
+            ["/mem/destination/a.txt", "/mem/destination/nested"].map(PathBuf::from)
+        );
+        assert!(!std::path::Path::new("/mem").exists());
+    }
+}
+
+#[tokio::test]
+async fn backups_and_cached_signatures_stay_in_memory() {
+    let source = MemFs::new();
+    let destination = MemFs::new();
+    let contents = b"shared block ".repeat(64);
+    let mut edited = contents.clone();
+    edited.splice(300..310, b"edited".iter().copied());
+    source.write("/mem/source/b.txt", &edited).unwrap();
+    destination
+        .write("/mem/destination/b.txt", &contents)
+        .unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let opts = ClientServerOpts {
+        to: "/mem/destination".into(),
+        direction: Direction::Push,
+        recursive: true,
+        backup: true,
+        ..Default::default()
+    };
+
+    let (client, server) = duplex_pipelines();
+    let mut client = client.with_fs(Arc::new(source.clone()));
+    let mut server = server.with_fs(Arc::new(destination.clone()));
+    server.signature_cache = Some(SignatureCache::new(cache.path().to_path_buf()));
+    let (client_stats, server_stats) = tokio::join!(
+        client.sync(std::path::Path::new("/mem/source"), opts),
+        server.serve()
+    );
+    let client_stats = client_stats.unwrap();
+    server_stats.unwrap();
+
+    assert!(
+        client_stats.failures.is_empty(),
+        "{:?}",
+        client_stats.failures
+    );
+    assert!(client_stats.files[&0].matched_blocks > 0);
+    assert_eq!(
+        destination.read("/mem/destination/b.txt".as_ref()).unwrap(),
+        edited
+    );
+    assert_eq!(
+        destination
+            .read("/mem/destination/b.txt~".as_ref())
+            .unwrap(),
+        contents
+    );
+    assert_eq!(server.signature_cache.unwrap().misses, 1);
+    assert!(!std::path::Path::new("/mem").exists());
+}
+
+#[tokio::test]
+async fn fuzzy_basis_turns_a_rename_into_an_all_index_delta() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let contents: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 31 % 251) as u8).collect();
+    write_tree(source.path(), &[("renamed.bin", &contents)]);
+    write_tree(destination.path(), &[("original.bin", &contents)]);
+    // A rename keeps the mtime, which is what singles the old name out
+    let mtime = std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    for path in [
+        source.path().join("renamed.bin"),
+        destination.path().join("original.bin"),
+    ] {
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        fuzzy: true,
+        ..Default::default()
+    };
+
+    let (client, client_stats, server_stats) =
+        push_with(source.path(), opts, ForwardingTunnel::new).await;
+    let (client_stats, _) = (client_stats.unwrap(), server_stats.unwrap());
+
+    let deltas = &client.batch.as_ref().unwrap().deltas;
+    assert_eq!(deltas.len(), 1);
+    assert!(!deltas[0].delta.ops.is_empty());
+    assert!(
+        deltas[0]
+            .delta
+            .ops
+            .iter()
+            .all(|op| matches!(op, Ops::Index(_))),
+        "{:?}",
+        deltas[0].delta.ops
+    );
+    assert_eq!(client_stats.files[&0].bytes_sent, 0);
+    assert_eq!(
+        std::fs::read(destination.path().join("renamed.bin")).unwrap(),
+        contents
+    );
+    // The basis is only read, not moved
+    assert!(destination.path().join("original.bin").exists());
+}
+
+#[tokio::test]
+async fn fuzzy_bases_are_found_for_several_files_in_one_directory() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let contents: Vec<Vec<u8>> = (1..=3u32)
+        .map(|n| {
+            (0..32 * 1024u32)
+                .map(|i| (i * n * 31 % 251) as u8)
+                .collect()
+        })
+        .collect();
+    let mtime = |n: u64| std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + n);
+    for (n, contents) in (0..).zip(&contents) {
+        let (old, new) = (format!("dir/old-{}.bin", n), format!("dir/new-{}.bin", n));
+        write_tree(source.path(), &[(&new, contents)]);
+        write_tree(destination.path(), &[(&old, contents)]);
+        for path in [source.path().join(&new), destination.path().join(&old)] {
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(mtime(n))
+                .unwrap();
+        }
+    }
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        fuzzy: true,
+        ..Default::default()
+    };
+
+    let (client, client_stats, server_stats) =
+        push_with(source.path(), opts, ForwardingTunnel::new).await;
+    let (client_stats, _) = (client_stats.unwrap(), server_stats.unwrap());
+
+    assert_eq!(client_stats.files_transferred, 3);
+    for sent in &client.batch.as_ref().unwrap().deltas {
+        assert!(
+            sent.delta.ops.iter().all(|op| matches!(op, Ops::Index(_))),
+            "{:?}",
+            sent.delta.ops
+        );
+    }
+    for (n, contents) in contents.iter().enumerate() {
+        let path = destination.path().join(format!("dir/new-{}.bin", n));
+        assert_eq!(&std::fs::read(path).unwrap(), contents);
+    }
+}
+
+#[test]
+fn fuzzy_candidates_are_listed_once_per_run() {
+    let fs = MemFs::new();
+    fs.write("/dest/a-old.txt", b"a").unwrap();
+    let candidates = fuzzy::Candidates::default();
+    let basis = |name: &str| {
+        let path = std::path::Path::new("/dest").join(name);
+        fuzzy::basis(&fs, &candidates, &path, &flist_entry(0, name))
+    };
+
+    assert_eq!(basis("a-new.txt"), Some(PathBuf::from("/dest/a-old.txt")));
+    // Files written during the run, such as the ones just rebuilt, are not picked up
+    fs.write("/dest/b-old.txt", b"b").unwrap();
+    assert_eq!(basis("b-new.txt"), None);
+    candidates.clear();
+    assert_eq!(basis("b-new.txt"), Some(PathBuf::from("/dest/b-old.txt")));
+}
+
+#[test]
+fn fuzzy_basis_falls_back_to_the_most_similar_name() {
+    let fs = MemFs::new();
+    fs.write("/dest/report-2023.pdf", b"last year").unwrap();
+    fs.write("/dest/report-2023.pdf.bak", b"older").unwrap();
+    fs.write("/dest/notes.txt", b"unrelated").unwrap();
+    fs.write("/dest/.report-2024.pdf.oxide_sync.tmp", b"")
+        .unwrap();
+    let basis = |name: &str| {
+        let path = std::path::Path::new("/dest").join(name);
+        fuzzy::basis(&fs, &Default::default(), &path, &flist_entry(0, name))
+    };
+
+    assert_eq!(
+        basis("report-2024.pdf"),
+        Some(PathBuf::from("/dest/report-2023.pdf"))
+    );
+    assert_eq!(basis("invoice.odt"), None);
+}
+
+#[tokio::test]
+async fn chmod_overrides_the_source_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("secret.txt", b"hush"), ("nested/key", b"k")],
+    );
+    std::fs::create_dir(source.path().join("empty")).unwrap();
+    for name in ["secret.txt", "nested/key"] {
+        std::fs::set_permissions(
+            source.path().join(name),
+            std::fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+    }
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        perms: true,
+        chmod: Some(cli::parse_chmod("F600,D750").unwrap()),
+        ..Default::default()
+    };
+
+    // Waits for the server, which changes the directories after the last file
+    let (mut client, mut server) = duplex_pipelines();
+    let (stats, served) = tokio::join!(client.sync(source.path(), opts), server.serve());
+    let (stats, _) = (stats.unwrap(), served.unwrap());
+
+    assert_eq!(stats.files_transferred, 2);
+    let mode = |name: &str| {
+        std::fs::metadata(destination.path().join(name))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o7777
+    };
+    assert_eq!(mode("secret.txt"), 0o600);
+    assert_eq!(mode("nested/key"), 0o600);
+    assert_eq!(mode("nested"), 0o750);
+    assert_eq!(mode(""), 0o750);
+
+    // Without `recursive` the directories are flist entries of their own, empty ones included
+    std::fs::create_dir(destination.path().join("empty")).unwrap();
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        chmod: Some(cli::parse_chmod("D700").unwrap()),
+        ..Default::default()
+    };
+    let (_, stats, served) = push_with(source.path(), opts, ForwardingTunnel::new).await;
+    stats.unwrap();
+    served.unwrap();
+    assert_eq!(mode("empty"), 0o700);
+    assert_eq!(mode("nested"), 0o700);
+}
+
+#[tokio::test]
+async fn checksum_declines_identical_files_with_another_mtime() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("same.txt", b"same contents"), ("new.txt", b"new")],
+    );
+    write_tree(destination.path(), &[("same.txt", b"same contents")]);
+    std::fs::File::options()
+        .write(true)
+        .open(destination.path().join("same.txt"))
+        .unwrap()
+        .set_modified(std::time::UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+        .unwrap();
+    let opts = ClientServerOpts {
+        to: destination.path().to_path_buf(),
+        direction: Direction::Push,
+        recursive: true,
+        flist_checksums: true,
+        ..Default::default()
+    };
+    let (client, client_stats, server_stats) =
+        push_with(source.path(), opts, ForwardingTunnel::new).await;
+    assert_eq!(client_stats.unwrap().files_transferred, 1);
+    server_stats.unwrap();
+
+    let new = client
+        .flist
+        .iter()
+        .find(|entry| entry.filename.as_path() == Path::new("new.txt"))
+        .unwrap()
+        .index;
+    let sent: Vec<_> = client
+        .batch
+        .unwrap()
+        .deltas
+        .iter()
+        .map(|d| d.file_index)
+        .collect();
+    assert_eq!(sent, [new]);
+    assert_eq!(
+        std::fs::read(destination.path().join("new.txt")).unwrap(),
+        b"new"
+    );
+}
@@ -0,0 +1,1236 @@
+//! Per-file exchange between the side holding the source files (the sender) and the side
+//! holding the destination (the receiver).
+//!
+//! The sender drives the exchange: for each flist entry it asks for the receiver's signatures
+//! with `FileIndex`, answers the returned `Data` with a `Delta` and waits for `Success` or an
+//! `Error`. When itemizing changes, the receiver precedes `Success` with an `Info` carrying the
+//! file's itemized line. A final `Done` releases the receiver, which then recreates hardlinked entries from
+//! the files they point at.
+//!
+//! The receiver checks each reconstructed file against the sender's whole-file checksum and
+//! answers a mismatch with `Redo`, upon which the sender sends the file again as a single
+//! literal block, up to `MAX_REDOS` times. Past that it gives up on the file with an `Error`,
+//! which the receiver records as a failure.
+//!
+//! A delta larger than `DELTA_CHUNK_SIZE` is sent as a run of `DeltaChunk`s ended by a
+//! `DeltaEnd` in place of the single `Delta`, and written out by the receiver as each chunk
+//! arrives.
+//!
+//! Either side may cancel between files: the sender by sending `Done` early, the receiver by
+//! answering a `FileIndex` with `Done` instead of `Data`.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs::File,
+    io::{self, Read, Write},
+    mem,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use tracing::{info, instrument, warn};
+
+use crate::{
+    cli::{Chmod, Chunker, ClientServerOpts},
+    cryptography::{
+        ChunkRef, Delta, FastCdc, IndexTable, Ops, compute_strong_signature,
+        compute_strong_signature_from,
+    },
+    filesystem::{FileSystem, FsMetadata, WriteSeek},
+    flist,
+};
+
+use super::{
+    Codec, DataMessage, DeltaChunk, DeltaMessage, Error, FileChecksum, FileError, FileStats,
+    FlistEntry, Message, Pipeline, ProgressEvent, Result, SSHMessageError, SpecialFile,
+    TransferStats, fuzzy, itemize,
+};
+
+const BLOCK_SIZE: usize = 128;
+
+/// Most flist entries sent in a single [`Message::FlistBatch`].
+pub const FLIST_BATCH_SIZE: usize = 1000;
+
+/// Deltas estimated to take more than this many bytes on the wire are sent in chunks of about
+/// this size, see [`Message::DeltaChunk`].
+pub const DELTA_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Number of times a file is resent whole after failing its checksum before giving up.
+const MAX_REDOS: u32 = 2;
+
+/// Appended to the names of the temp files files are rebuilt in, see [`tmp_path`].
+pub(super) const TMP_SUFFIX: &str = ".oxide_sync.tmp";
+
+/// Age past which a file left in `--partial-dir` is deleted instead of resumed from.
+pub const STALE_PARTIAL_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+impl Pipeline {
+    pub async fn send_flist(&mut self, flist: Vec<FlistEntry>) -> Result<()> {
+        for batch in flist.chunks(FLIST_BATCH_SIZE) {
+            for entry in batch {
+                info!("flist entry: {:?}", entry);
+            }
+            self.tunnel
+                .write_message_buffered(Message::FlistBatch(batch.to_vec()))
+                .await?;
+        }
+        // Flushes the whole flist at once
+        self.tunnel.write_message(Message::FlistEnd).await?;
+        self.flist = flist;
+        Ok(())
+    }
+
+    /// Sends a delta for every regular file in the flist, reading the sources from below
+    /// `local_root`. Failures on individual files are recorded in the returned stats and the
+    /// remaining entries are still processed, unless `opts.stop_on_error` is set.
+    pub async fn process_flist(
+        &mut self,
+        local_root: &Path,
+        opts: &ClientServerOpts,
+    ) -> Result<TransferStats> {
+        let mut stats = mem::take(&mut self.stats);
+        let files = self.flist.clone();
+        // Filenames are relative to this rather than to `local_root` itself, see `flist::build`
+        let source_root = flist::name_base(&*self.fs, local_root, opts);
+        for entry in files
+            .iter()
+            .filter(|e| e.is_regular() && e.hardlink_to.is_none())
+        {
+            if self.cancel.is_cancelled() {
+                info!("cancelled before {}", entry.filename);
+                stats.cancelled = true;
+                break;
+            }
+            self.report_started(entry);
+            match self.process_entry(entry, &source_root, opts).await {
+                Ok((itemized, file_stats)) => {
+                    stats.files_transferred += 1;
+                    stats.itemized.extend(itemized);
+                    stats.files.insert(entry.index, file_stats);
+                    self.report_completed(entry.index, None);
+                }
+                Err(Error::FileTransfer { filename, reason }) => {
+                    warn!("failed to transfer {}: {}", filename, reason);
+                    self.report_completed(entry.index, Some(reason.clone()));
+                    if opts.stop_on_error {
+                        return Err(Error::FileTransfer { filename, reason });
+                    }
+                    stats.failures.push(FileError {
+                        file_index: entry.index,
+                        filename,
+                        reason,
+                    });
+                }
+                Err(Error::NoSend(_)) => {
+                    info!("receiver declined {}", entry.filename);
+                    self.report_completed(entry.index, None);
+                }
+                Err(Error::Cancelled) => {
+                    info!("receiver cancelled before {}", entry.filename);
+                    stats.cancelled = true;
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        self.tunnel.write_message(Message::Done).await?;
+        self.stats = stats.clone();
+        Ok(stats)
+    }
+
+    async fn process_entry(
+        &mut self,
+        entry: &FlistEntry,
+        source_root: &Path,
+        opts: &ClientServerOpts,
+    ) -> Result<(Option<String>, FileStats)> {
+        let file_error = |reason: String| Error::FileTransfer {
+            filename: entry.filename.to_string(),
+            reason,
+        };
+        let path = source_root.join(&entry.filename);
+        let new = self
+            .fs
+            .read(&path)
+            .map_err(|e| file_error(format!("{:?}: {}", path, e)))?;
+
+        self.tunnel
+            .write_message(Message::FileIndex(entry.index))
+            .await?;
+        let (index_table, offset) = match self.tunnel.read_message().await? {
+            Message::Data(data) => (data.table()?, data.offset as usize),
+            Message::Done => return Err(Error::Cancelled),
+            Message::NoSend(index) if index == entry.index => return Err(Error::NoSend(index)),
+            Message::Error(SSHMessageError::IoError(reason)) => return Err(file_error(reason)),
+            msg => return Err(Error::UnexpectedMessage(Box::new(msg))),
+        };
+
+        let checksum = compute_strong_signature(&new);
+        // The receiver keeps its copy up to `offset` and only signed what follows. If the file
+        // shrank since, it cannot have only grown, so all of it is compared
+        let prefix = if offset <= new.len() { offset } else { 0 };
+        let tail = &new[prefix..];
+        // Nothing to match against, so skip the scan
+        let mut delta = if opts.whole_file || index_table.is_empty() {
+            Delta::whole_file(tail)
+        } else {
+            let delta = match opts.chunker {
+                Chunker::Cdc => Delta::diff_chunks(&index_table, tail, &FastCdc::default()),
+                Chunker::Fixed if opts.weak_only => {
+                    Delta::diff_table_weak_only(&index_table, tail, BLOCK_SIZE)
+                }
+                Chunker::Fixed => Delta::diff_table(&index_table, tail, BLOCK_SIZE),
+            };
+            if let Some(reason) = whole_file_reason(&delta, tail.len(), opts) {
+                info!("{} {}, sending it whole", entry.filename, reason);
+                Delta::whole_file(tail)
+            } else {
+                delta
+            }
+        };
+        // Frames are already compressed as a whole when a codec was agreed on, so only
+        // compress the literal blocks on their own when asked to and none was
+        if self.compress && self.codec == Codec::None {
+            delta.compress_blocks();
+        }
+        if prefix > 0 {
+            delta.ops.insert(
+                0,
+                Ops::Chunk(ChunkRef {
+                    offset: 0,
+                    len: prefix,
+                }),
+            );
+        }
+        info!("delta for {}: {:?}", entry.filename, delta);
+        let mut sent = DeltaMessage {
+            delta,
+            file_index: entry.index,
+            checksum: Some(checksum.clone()),
+        };
+        self.send_delta(&sent).await?;
+        let mut itemized = None;
+        let mut redos = 0;
+        loop {
+            match self.tunnel.read_message().await? {
+                Message::Info(line) if opts.itemize_changes => itemized = Some(line),
+                Message::Success(index) if index == entry.index => {
+                    let file_stats = FileStats::from_delta(&sent.delta, new.len() as u64);
+                    if let Some(batch) = &mut self.batch {
+                        batch.deltas.push(sent);
+                    }
+                    return Ok((itemized, file_stats));
+                }
+                Message::Redo(index) if index == entry.index => {
+                    redos += 1;
+                    if redos > MAX_REDOS {
+                        let reason =
+                            format!("checksum mismatch after resending {} times", MAX_REDOS);
+                        // The receiver waits for another delta otherwise
+                        self.tunnel
+                            .write_message(Message::Error(SSHMessageError::IoError(format!(
+                                "{}: {}",
+                                entry.filename, reason
+                            ))))
+                            .await?;
+                        return Err(file_error(reason));
+                    }
+                    warn!(
+                        "checksum mismatch on {}, resending it whole",
+                        entry.filename
+                    );
+                    sent.delta = Delta::whole_file(&new);
+                    self.send_delta(&sent).await?;
+                }
+                Message::Error(SSHMessageError::IoError(reason)) => return Err(file_error(reason)),
+                msg => return Err(Error::UnexpectedMessage(Box::new(msg))),
+            }
+        }
+    }
+
+    /// Sends `sent` as a single `Delta`, or as `DeltaChunk`s ended by a `DeltaEnd` if it is larger
+    /// than [`DELTA_CHUNK_SIZE`].
+    async fn send_delta(&mut self, sent: &DeltaMessage) -> Result<()> {
+        if sent.delta.transfer_size() <= DELTA_CHUNK_SIZE {
+            self.tunnel
+                .write_message(Message::Delta(sent.clone()))
+                .await?;
+            self.report(ProgressEvent::BytesProcessed {
+                file_index: sent.file_index,
+                bytes: sent.delta.literal_size() as u64,
+            });
+            return Ok(());
+        }
+        let mut bytes = 0;
+        for ops in sent.delta.chunks(DELTA_CHUNK_SIZE) {
+            let chunk = Delta { ops };
+            bytes += chunk.literal_size() as u64;
+            self.tunnel
+                .write_message_buffered(Message::DeltaChunk(DeltaChunk {
+                    file_index: sent.file_index,
+                    ops: chunk.ops,
+                }))
+                .await?;
+            self.report(ProgressEvent::BytesProcessed {
+                file_index: sent.file_index,
+                bytes,
+            });
+        }
+        self.tunnel
+            .write_message(Message::DeltaEnd(FileChecksum {
+                file_index: sent.file_index,
+                strong: sent.checksum.clone(),
+            }))
+            .await
+    }
+
+    /// Answers the sender's requests until it sends `Done`, reconstructing each file below
+    /// `local_root` from its current contents and the received delta.
+    pub async fn receive_files(
+        &mut self,
+        local_root: &Path,
+        opts: &ClientServerOpts,
+    ) -> Result<TransferStats> {
+        let mut stats = TransferStats::default();
+        let declined = self.declined(local_root, opts);
+        self.fuzzy.clear();
+        if let Some(dir) = &opts.partial_dir {
+            let names = self.flist.iter().map(|entry| entry.filename.as_path());
+            remove_stale_partials(&*self.fs, &local_root.join(dir), names, STALE_PARTIAL_AGE);
+        }
+        if opts.append {
+            self.mark_synced_sizes(local_root, opts);
+        }
+        let mut streamed = None;
+        // The file of the last delta, which an `Error` from the sender gives up on
+        let mut last_delta = None;
+        loop {
+            let msg = match self.tunnel.read_message().await {
+                Ok(msg) => msg,
+                Err(e) => {
+                    // A partial file cut off with the connection is what the next run resumes
+                    // from, while a temp file is of no use
+                    if let Some(StreamedDelta {
+                        rebuild: Ok(rebuild),
+                        ..
+                    }) = streamed.take()
+                    {
+                        rebuild.discard(&*self.fs);
+                    }
+                    return Err(e);
+                }
+            };
+            match msg {
+                Message::FileIndex(_) if self.cancel.is_cancelled() => {
+                    // Stop the sender, then wait for its `Done`
+                    stats.cancelled = true;
+                    self.tunnel.write_message(Message::Done).await?;
+                }
+                Message::FileIndex(index) if declined.contains(&index) => {
+                    self.report_started(&self.flist_entry(index)?);
+                    self.tunnel.write_message(Message::NoSend(index)).await?;
+                    self.report_completed(index, None);
+                }
+                Message::FileIndex(index) => {
+                    self.handle_file_index(index, local_root, opts).await?
+                }
+                Message::Delta(delta) => {
+                    last_delta = Some(delta.file_index);
+                    self.handle_delta(delta, local_root, opts, &mut stats)
+                        .await?
+                }
+                Message::DeltaChunk(chunk) => {
+                    self.handle_delta_chunk(chunk, &mut streamed, local_root, opts)?
+                }
+                Message::DeltaEnd(end) => match streamed.take() {
+                    Some(delta) if delta.file_index == end.file_index => {
+                        last_delta = Some(end.file_index);
+                        self.handle_delta_end(delta, end, local_root, opts, &mut stats)
+                            .await?
+                    }
+                    _ => return Err(Error::UnexpectedMessage(Box::new(Message::DeltaEnd(end)))),
+                },
+                Message::Error(SSHMessageError::IoError(reason)) => {
+                    let Some(file_index) = last_delta.take() else {
+                        let msg = Message::Error(SSHMessageError::IoError(reason));
+                        return Err(Error::UnexpectedMessage(Box::new(msg)));
+                    };
+                    let entry = self.flist_entry(file_index)?;
+                    warn!("sender gave up on {}: {}", entry.filename, reason);
+                    self.report_completed(entry.index, Some(reason.clone()));
+                    stats.failures.push(FileError {
+                        file_index: entry.index,
+                        filename: entry.filename.to_string(),
+                        reason,
+                    });
+                }
+                Message::Done => break,
+                msg => return Err(Error::UnexpectedMessage(Box::new(msg))),
+            }
+        }
+        if self.cancel.is_cancelled() {
+            // Links and special files may point at files that were never transferred
+            stats.cancelled = true;
+        } else {
+            self.link_hardlinks(local_root, &declined, &mut stats)?;
+            self.create_specials(local_root, &declined, opts);
+            if let Some(chmod) = &opts.chmod {
+                self.chmod_dirs(local_root, chmod);
+            }
+        }
+        self.stats = stats.clone();
+        Ok(stats)
+    }
+
+    /// Answers a `FileIndex` with the signature table of the file's current contents, or of the
+    /// partial file left by an interrupted run.
+    #[instrument(skip(self, local_root, opts), fields(kind = "FileIndex"))]
+    async fn handle_file_index(
+        &mut self,
+        file_index: u32,
+        local_root: &Path,
+        opts: &ClientServerOpts,
+    ) -> Result<()> {
+        let entry = self.flist_entry(file_index)?;
+        info!("signing {}", entry.filename);
+        self.report_started(&entry);
+        let offset = entry
+            .synced_size
+            .map_or(0, |size| kept_prefix(size, opts.chunker));
+        let path = basis_path(&*self.fs, &self.fuzzy, local_root, &entry, opts);
+        let table = match &mut self.signature_cache {
+            _ if offset > 0 => read_tail(&*self.fs, &path, offset)
+                .map(|tail| tail_signatures(&tail, offset, opts.chunker)),
+            // The sender ignores the base, so there is nothing to sign
+            _ if opts.whole_file => Ok(IndexTable::new()),
+            Some(cache) if !opts.no_cache => cache.signatures(&*self.fs, &path, opts.chunker),
+            _ => read_base(&*self.fs, &path).map(|base| signatures(&base, opts.chunker)),
+        };
+        let msg = match table {
+            Ok(map) => Message::Data(DataMessage {
+                offset,
+                ..DataMessage::new(&map, file_index)?
+            }),
+            Err(e) => {
+                warn!("error reading {}: {}", entry.filename, e);
+                let reason = format!("{}: {}", entry.filename, e);
+                self.report_completed(file_index, Some(reason.clone()));
+                Message::Error(SSHMessageError::IoError(reason))
+            }
+        };
+        self.tunnel.write_message(msg).await
+    }
+
+    /// Rebuilds a file from its delta, answering with `Success`, `Redo` or `Error`.
+    #[instrument(
+        skip_all,
+        fields(kind = "Delta", file_index = delta_message.file_index)
+    )]
+    async fn handle_delta(
+        &mut self,
+        delta_message: DeltaMessage,
+        local_root: &Path,
+        opts: &ClientServerOpts,
+        stats: &mut TransferStats,
+    ) -> Result<()> {
+        let entry = self.flist_entry(delta_message.file_index)?;
+        let existing = self.fs.metadata(&local_root.join(&entry.filename)).ok();
+        let result = apply_delta(
+            &*self.fs,
+            &self.fuzzy,
+            local_root,
+            &entry,
+            &delta_message.delta,
+            delta_message.checksum.as_deref(),
+            opts,
+        );
+        let file_stats = FileStats::from_delta(&delta_message.delta, entry.size);
+        self.report(ProgressEvent::BytesProcessed {
+            file_index: entry.index,
+            bytes: file_stats.bytes_sent,
+        });
+        self.report_rebuild(
+            &entry,
+            existing,
+            result,
+            file_stats,
+            delta_message,
+            local_root,
+            opts,
+            stats,
+        )
+        .await
+    }
+
+    /// Writes out the ops of a `DeltaChunk`, starting to rebuild its file with the first one.
+    /// Errors are kept in `streamed` until its `DeltaEnd`, and later chunks skipped.
+    #[instrument(skip_all, fields(kind = "DeltaChunk", file_index = chunk.file_index))]
+    fn handle_delta_chunk(
+        &self,
+        chunk: DeltaChunk,
+        streamed: &mut Option<StreamedDelta>,
+        local_root: &Path,
+        opts: &ClientServerOpts,
+    ) -> Result<()> {
+        let entry = self.flist_entry(chunk.file_index)?;
+        let streamed = streamed.get_or_insert_with(|| StreamedDelta {
+            file_index: chunk.file_index,
+            existing: self.fs.metadata(&local_root.join(&entry.filename)).ok(),
+            rebuild: Rebuild::start(&*self.fs, &self.fuzzy, local_root, &entry, opts),
+            stats: FileStats::default(),
+            delta: Delta::new(),
+        });
+        if streamed.file_index != chunk.file_index {
+            return Err(Error::UnexpectedMessage(Box::new(Message::DeltaChunk(
+                chunk,
+            ))));
+        }
+        let delta = Delta { ops: chunk.ops };
+        if let Ok(rebuild) = &mut streamed.rebuild
+            && let Err(e) = rebuild.write(&delta, opts.sparse)
+            && let Ok(rebuild) = mem::replace(&mut streamed.rebuild, Err(e))
+        {
+            rebuild.discard(&*self.fs);
+        }
+        let chunk_stats = FileStats::from_delta(&delta, 0);
+        streamed.stats.matched_blocks += chunk_stats.matched_blocks;
+        streamed.stats.literal_blocks += chunk_stats.literal_blocks;
+        streamed.stats.bytes_sent += chunk_stats.bytes_sent;
+        self.report(ProgressEvent::BytesProcessed {
+            file_index: chunk.file_index,
+            bytes: streamed.stats.bytes_sent,
+        });
+        if self.batch.is_some() {
+            streamed.delta.ops.extend(delta.ops);
+        }
+        Ok(())
+    }
+
+    /// Finishes a file rebuilt from `DeltaChunk`s, answering like [`Pipeline::handle_delta`].
+    #[instrument(skip_all, fields(kind = "DeltaEnd", file_index = end.file_index))]
+    async fn handle_delta_end(
+        &mut self,
+        streamed: StreamedDelta,
+        end: FileChecksum,
+        local_root: &Path,
+        opts: &ClientServerOpts,
+        stats: &mut TransferStats,
+    ) -> Result<()> {
+        let entry = self.flist_entry(end.file_index)?;
+        let result = streamed.rebuild.and_then(|rebuild| {
+            rebuild.finish(
+                &*self.fs,
+                local_root,
+                entry.filename.as_path(),
+                end.strong.as_deref(),
+                opts,
+            )
+        });
+        let file_stats = FileStats {
+            bytes_reused: entry.size.saturating_sub(streamed.stats.bytes_sent),
+            ..streamed.stats
+        };
+        let delta_message = DeltaMessage {
+            delta: streamed.delta,
+            file_index: end.file_index,
+            checksum: end.strong,
+        };
+        self.report_rebuild(
+            &entry,
+            streamed.existing,
+            result,
+            file_stats,
+            delta_message,
+            local_root,
+            opts,
+            stats,
+        )
+        .await
+    }
+
+    /// Answers the outcome of rebuilding `entry` with `Success`, `Redo` or `Error`, applying its
+    /// metadata and recording it in `stats` and the batch on success. `existing` is the
+    /// metadata of the file it replaced, if any.
+    #[allow(clippy::too_many_arguments)]
+    async fn report_rebuild(
+        &mut self,
+        entry: &FlistEntry,
+        existing: Option<FsMetadata>,
+        result: io::Result<Option<bool>>,
+        file_stats: FileStats,
+        delta_message: DeltaMessage,
+        local_root: &Path,
+        opts: &ClientServerOpts,
+        stats: &mut TransferStats,
+    ) -> Result<()> {
+        let file_index = entry.index;
+        let path = local_root.join(&entry.filename);
+        let msg = match result {
+            Ok(None) => {
+                warn!("checksum mismatch on {}, asking for a redo", entry.filename);
+                Message::Redo(file_index)
+            }
+            Ok(Some(content_changed)) => {
+                if let Err(e) = apply_metadata(&*self.fs, &path, entry, opts) {
+                    warn!("failed to set metadata of {}: {}", entry.filename, e);
+                }
+                if opts.itemize_changes {
+                    let line = itemize(entry, existing.as_ref(), content_changed, opts.numeric_ids);
+                    self.tunnel
+                        .write_message(Message::Info(line.clone()))
+                        .await?;
+                    stats.itemized.push(line);
+                }
+                stats.files_transferred += 1;
+                stats.files.insert(file_index, file_stats);
+                if let Some(batch) = &mut self.batch {
+                    batch.deltas.push(delta_message);
+                }
+                self.report_completed(file_index, None);
+                Message::Success(file_index)
+            }
+            Err(e) => {
+                warn!("failed to reconstruct {}: {}", entry.filename, e);
+                let reason = format!("{}: {}", entry.filename, e);
+                self.report_completed(file_index, Some(reason.clone()));
+                stats.failures.push(FileError {
+                    file_index,
+                    filename: entry.filename.to_string(),
+                    reason: reason.clone(),
+                });
+                Message::Error(SSHMessageError::IoError(reason))
+            }
+        };
+        self.tunnel.write_message(msg).await
+    }
+
+    /// Points every entry with `hardlink_to` at the file reconstructed for its target, unless
+    /// that target failed to transfer.
+    fn link_hardlinks(
+        &self,
+        local_root: &Path,
+        declined: &HashSet<u32>,
+        stats: &mut TransferStats,
+    ) -> Result<()> {
+        for entry in &self.flist {
+            let Some(target_index) = entry.hardlink_to else {
+                continue;
+            };
+            if declined.contains(&entry.index) || declined.contains(&target_index) {
+                continue;
+            }
+            let target = self.flist_entry(target_index)?;
+            let result = if stats.failures.iter().any(|f| f.file_index == target_index) {
+                Err(io::Error::other(format!(
+                    "{} failed to transfer",
+                    target.filename
+                )))
+            } else {
+                hard_link(
+                    &*self.fs,
+                    &local_root.join(&target.filename),
+                    &local_root.join(&entry.filename),
+                )
+            };
+            if let Err(e) = result {
+                warn!("failed to link {}: {}", entry.filename, e);
+                stats.failures.push(FileError {
+                    file_index: entry.index,
+                    filename: entry.filename.to_string(),
+                    reason: format!("{}: {}", entry.filename, e),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Recreates the device and FIFO nodes in the flist, if `opts.devices` asks for them
+    /// rather than only the sender. Creating device nodes usually needs root, so failures are
+    /// only warned about.
+    fn create_specials(&self, local_root: &Path, declined: &HashSet<u32>, opts: &ClientServerOpts) {
+        for entry in &self.flist {
+            let Some(special) = entry.special else {
+                continue;
+            };
+            if !opts.devices {
+                warn!("refusing to create {} without --devices", entry.filename);
+                continue;
+            }
+            if declined.contains(&entry.index) {
+                continue;
+            }
+            let path = local_root.join(&entry.filename);
+            if let Err(e) = make_special(&*self.fs, &path, special, entry.mode) {
+                warn!("failed to create {}: {}", entry.filename, e);
+            }
+        }
+    }
+
+    /// Applies `chmod` to `local_root`, every directory in the flist and every directory below
+    /// `local_root` holding an flist entry.
+    fn chmod_dirs(&self, local_root: &Path, chmod: &Chmod) {
+        let dirs: BTreeSet<_> = self
+            .flist
+            .iter()
+            .flat_map(|entry| {
+                let skip = usize::from(!entry.is_dir);
+                entry.filename.as_path().ancestors().skip(skip)
+            })
+            .map(|dir| local_root.join(dir))
+            .chain([local_root.to_path_buf()])
+            .collect();
+        for dir in dirs {
+            let result = self.fs.metadata(&dir).and_then(|metadata| {
+                self.fs
+                    .set_permissions(&dir, chmod.apply(metadata.mode, true))
+            });
+            if let Err(e) = result {
+                warn!("failed to change the permissions of {:?}: {}", dir, e);
+            }
+        }
+    }
+
+    /// Sets the `synced_size` of each regular file in the flist to the size of its base below
+    /// `local_root`, for `opts.append`. A base larger than the source did not only grow, so is
+    /// left out.
+    fn mark_synced_sizes(&mut self, local_root: &Path, opts: &ClientServerOpts) {
+        for entry in self.flist.iter_mut().filter(|entry| entry.is_regular()) {
+            let path = base_path(&*self.fs, local_root, entry.filename.as_path(), opts);
+            entry.synced_size = self
+                .fs
+                .metadata(&path)
+                .ok()
+                .filter(|metadata| !metadata.is_dir && metadata.len <= entry.size)
+                .map(|metadata| metadata.len);
+        }
+    }
+
+    /// Indices of the flist entries the receiver leaves alone: with `opts.existing`, those
+    /// missing below `local_root`, with `opts.ignore_existing`, those already there, with
+    /// `opts.update`, those whose copy there is newer, and with `opts.flist_checksums`, those
+    /// whose copy there has the listed size and checksum.
+    fn declined(&self, local_root: &Path, opts: &ClientServerOpts) -> HashSet<u32> {
+        if !opts.existing && !opts.ignore_existing && !opts.update && !opts.flist_checksums {
+            return HashSet::new();
+        }
+        let window = i64::from(opts.modify_window);
+        self.flist
+            .iter()
+            .filter(|entry| {
+                let path = local_root.join(&entry.filename);
+                let reason = match self.fs.symlink_metadata(&path) {
+                    Err(_) if opts.existing => "not creating",
+                    Ok(_) if opts.ignore_existing => "skipping existing",
+                    Ok(metadata) if opts.update && metadata.mtime > entry.mtime + window => {
+                        "skipping newer"
+                    }
+                    Ok(metadata)
+                        if metadata.is_file()
+                            && metadata.len == entry.size
+                            && entry.checksum.is_some()
+                            && self.local_checksum(&path) == entry.checksum =>
+                    {
+                        "skipping unchanged"
+                    }
+                    _ => return false,
+                };
+                info!("{} {}", reason, entry.filename);
+                true
+            })
+            .map(|entry| entry.index)
+            .collect()
+    }
+
+    /// Strong signature of the local file at `path`, as listed by `--checksum`.
+    fn local_checksum(&self, path: &Path) -> Option<String> {
+        self.fs
+            .open(path)
+            .and_then(|file| compute_strong_signature_from(io::BufReader::new(file)))
+            .ok()
+    }
+
+    /// Creates `local_root` if it is missing, along with its missing parents if `opts.mkpath`
+    /// is set. Without it, only `local_root` itself may be missing, as with `mkdir`. The
+    /// directories created get the `opts.chmod` rules for directories.
+    pub fn make_root(&self, local_root: &Path, opts: &ClientServerOpts) -> Result<()> {
+        if self.fs.is_dir(local_root) {
+            return Ok(());
+        }
+        let parent = local_root.parent().filter(|p| !p.as_os_str().is_empty());
+        if !opts.mkpath && parent.is_some_and(|parent| !self.fs.is_dir(parent)) {
+            return Err(Error::MissingDestination(local_root.to_path_buf()));
+        }
+        info!("creating {:?}", local_root);
+        let missing: Vec<_> = local_root
+            .ancestors()
+            .take_while(|dir| !dir.as_os_str().is_empty() && !self.fs.is_dir(dir))
+            .collect();
+        self.fs.create_dir_all(local_root)?;
+        if let Some(chmod) = &opts.chmod {
+            for dir in missing {
+                let mode = self.fs.metadata(dir)?.mode;
+                self.fs.set_permissions(dir, chmod.apply(mode, true))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn report_started(&self, entry: &FlistEntry) {
+        self.report(ProgressEvent::FileStarted {
+            file_index: entry.index,
+            filename: entry.filename.to_string(),
+            size: entry.size,
+        });
+    }
+
+    fn report_completed(&self, file_index: u32, error: Option<String>) {
+        self.report(ProgressEvent::FileCompleted { file_index, error });
+    }
+
+    pub(super) fn flist_entry(&self, index: u32) -> Result<FlistEntry> {
+        self.flist
+            .get(index as usize)
+            .cloned()
+            .ok_or(Error::UnknownFileIndex(index))
+    }
+}
+
+/// Why `delta`, for a `len` byte file, is not worth sending instead of the whole file under
+/// `opts.delta_threshold` and `opts.min_transfer_ratio`, if it is not.
+fn whole_file_reason(delta: &Delta, len: usize, opts: &ClientServerOpts) -> Option<String> {
+    let reuse = delta.reuse_ratio(len);
+    if opts
+        .delta_threshold
+        .is_some_and(|percent| reuse * 100.0 < f64::from(percent))
+    {
+        return Some(format!("reuses {:.0}% of its base", reuse * 100.0));
+    }
+    if len > 0 {
+        let saving = 1.0 - delta.transfer_size() as f64 / len as f64;
+        if opts
+            .min_transfer_ratio
+            .is_some_and(|ratio| saving < ratio.get())
+        {
+            return Some(format!("saves {:.0}% as a delta", saving.max(0.0) * 100.0));
+        }
+    }
+    None
+}
+
+/// Reads the receiver's current copy of a file; a missing file is an empty base.
+pub(super) fn read_base(fs: &dyn FileSystem, path: &Path) -> io::Result<Vec<u8>> {
+    match fs.read(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+pub(super) fn signatures(base: &[u8], chunker: Chunker) -> IndexTable {
+    match chunker {
+        Chunker::Cdc => IndexTable::from_chunks(base, &FastCdc::default()),
+        Chunker::Fixed => IndexTable::from_bytes(base, BLOCK_SIZE),
+    }
+}
+
+/// How much of a base of `size` bytes the receiver keeps with `--append`: all of it, except
+/// for the fixed chunker a trailing partial block, so block indices still line up.
+fn kept_prefix(size: u64, chunker: Chunker) -> u64 {
+    match chunker {
+        Chunker::Cdc => size,
+        Chunker::Fixed => size - size % BLOCK_SIZE as u64,
+    }
+}
+
+/// Reads a base file from `offset` on; a missing file has an empty tail.
+fn read_tail(fs: &dyn FileSystem, path: &Path, offset: u64) -> io::Result<Vec<u8>> {
+    let mut file = match fs.open(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        result => result?,
+    };
+    io::copy(&mut (&mut file).take(offset), &mut io::sink())?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    Ok(tail)
+}
+
+/// Signatures of `tail`, the part of a base from `offset` on, placed where it lies in the base.
+fn tail_signatures(tail: &[u8], offset: u64, chunker: Chunker) -> IndexTable {
+    if tail.is_empty() {
+        return IndexTable::new();
+    }
+    signatures(tail, chunker).shifted(offset as usize, BLOCK_SIZE)
+}
+
+/// The file a delta for `filename` is computed against: the partial file left below
+/// `opts.partial_dir` by an interrupted run if there is one, otherwise the current copy.
+fn base_path(
+    fs: &dyn FileSystem,
+    local_root: &Path,
+    filename: &Path,
+    opts: &ClientServerOpts,
+) -> PathBuf {
+    opts.partial_path(local_root, filename)
+        .filter(|partial| fs.is_file(partial))
+        .unwrap_or_else(|| local_root.join(filename))
+}
+
+/// The file the receiver signs and rebuilds `entry` from: its [`base_path`], or with
+/// `opts.fuzzy` a similar file next to it if there is none, see [`fuzzy::basis`].
+fn basis_path(
+    fs: &dyn FileSystem,
+    candidates: &fuzzy::Candidates,
+    local_root: &Path,
+    entry: &FlistEntry,
+    opts: &ClientServerOpts,
+) -> PathBuf {
+    let path = base_path(fs, local_root, entry.filename.as_path(), opts);
+    if opts.fuzzy
+        && !fs.is_file(&path)
+        && let Some(basis) = fuzzy::basis(fs, candidates, &path, entry)
+    {
+        info!("diffing {} against {:?}", entry.filename, basis);
+        return basis;
+    }
+    path
+}
+
+/// Rebuilds `path` from its [`basis_path`] and `delta`, streaming the result into a temp file
+/// that replaces `path` only once it is complete, with zero runs left as holes if `opts.sparse`
+/// is set. The temp file is written next to `path`, in `opts.temp_dir`, or at
+/// [`ClientServerOpts::partial_path`], where it is kept if writing fails. Returns whether the
+/// contents changed, or `None` without touching `path` if the result does not match `checksum`.
+pub(super) fn apply_delta(
+    fs: &dyn FileSystem,
+    candidates: &fuzzy::Candidates,
+    local_root: &Path,
+    entry: &FlistEntry,
+    delta: &Delta,
+    checksum: Option<&str>,
+    opts: &ClientServerOpts,
+) -> io::Result<Option<bool>> {
+    let mut rebuild = Rebuild::start(fs, candidates, local_root, entry, opts)?;
+    // Cloning from the file being truncated would lose the blocks it shares
+    let clone = opts.reflink && rebuild.base_path != rebuild.tmp_path;
+    let written = match rebuild.file.as_file() {
+        Some(out) if clone => apply_cloned(&rebuild.base_path, delta, out),
+        _ => Ok(false),
+    }
+    .and_then(|cloned| {
+        if cloned {
+            Ok(())
+        } else {
+            rebuild.write(delta, opts.sparse)
+        }
+    });
+    if let Err(e) = written {
+        rebuild.discard(fs);
+        return Err(e);
+    }
+    rebuild.finish(fs, local_root, entry.filename.as_path(), checksum, opts)
+}
+
+/// A file being rebuilt from `DeltaChunk`s, until its `DeltaEnd`.
+struct StreamedDelta {
+    file_index: u32,
+    /// Metadata of the file being replaced, if any
+    existing: Option<FsMetadata>,
+    /// The first error rebuilding the file, after which further chunks are skipped
+    rebuild: io::Result<Rebuild>,
+    stats: FileStats,
+    /// The ops received so far, only kept when writing a batch
+    delta: Delta,
+}
+
+/// The temp file a file is rebuilt into by [`apply_delta`], which may be written to in several
+/// steps before it replaces the file.
+struct Rebuild {
+    path: PathBuf,
+    base_path: PathBuf,
+    base: Vec<u8>,
+    tmp_path: PathBuf,
+    /// Set if the temp file is a partial file, kept when rebuilding fails
+    partial: Option<PathBuf>,
+    file: Box<dyn WriteSeek>,
+}
+
+impl Rebuild {
+    /// Reads the base of `filename` and creates the temp file it is rebuilt into.
+    fn start(
+        fs: &dyn FileSystem,
+        candidates: &fuzzy::Candidates,
+        local_root: &Path,
+        entry: &FlistEntry,
+        opts: &ClientServerOpts,
+    ) -> io::Result<Self> {
+        let filename = entry.filename.as_path();
+        let path = local_root.join(filename);
+        let base_path = basis_path(fs, candidates, local_root, entry, opts);
+        let base = read_base(fs, &base_path)?;
+        let parent = path.parent().unwrap_or(Path::new("."));
+        fs.create_dir_all(parent)?;
+        let partial = opts.partial_path(local_root, filename);
+        let tmp_path = match &partial {
+            Some(partial) => {
+                fs.create_dir_all(partial.parent().unwrap_or(Path::new(".")))?;
+                partial.clone()
+            }
+            None => tmp_path(opts.temp_dir.as_deref().unwrap_or(parent), &path),
+        };
+        let file = fs.create(&tmp_path)?;
+        Ok(Self {
+            path,
+            base_path,
+            base,
+            tmp_path,
+            partial,
+            file,
+        })
+    }
+
+    /// Appends the part of the file `delta` rebuilds.
+    fn write(&mut self, delta: &Delta, sparse: bool) -> io::Result<()> {
+        delta.apply_to(&self.base, BLOCK_SIZE, &mut self.file, sparse)
+    }
+
+    /// Gives up on the file, removing the temp file unless it is a partial file.
+    fn discard(self, fs: &dyn FileSystem) {
+        drop(self.file);
+        if self.partial.is_none() {
+            let _ = fs.remove_file(&self.tmp_path);
+        }
+    }
+
+    /// Checks the rebuilt file against `checksum` and moves it into place, as described on
+    /// [`apply_delta`].
+    fn finish(
+        self,
+        fs: &dyn FileSystem,
+        local_root: &Path,
+        filename: &Path,
+        checksum: Option<&str>,
+        opts: &ClientServerOpts,
+    ) -> io::Result<Option<bool>> {
+        let Self {
+            path,
+            base_path,
+            base,
+            tmp_path,
+            partial,
+            mut file,
+        } = self;
+        let written = file.flush().and_then(|()| {
+            drop(file);
+            compute_strong_signature_from(io::BufReader::new(fs.open(&tmp_path)?))
+        });
+        let actual = match written {
+            Ok(actual) if checksum.is_none_or(|checksum| actual == checksum) => actual,
+            Ok(_) => {
+                let _ = fs.remove_file(&tmp_path);
+                return Ok(None);
+            }
+            Err(e) => {
+                // What was written of a partial file is still a useful base for the next run
+                if partial.is_none() {
+                    let _ = fs.remove_file(&tmp_path);
+                }
+                return Err(e);
+            }
+        };
+        // Whether it changed is still measured against the copy it replaces
+        let current = if base_path == path {
+            None
+        } else {
+            Some(read_base(fs, &path)?)
+        };
+        let changed = actual != compute_strong_signature(current.as_deref().unwrap_or(&base));
+        if changed
+            && fs.is_file(&path)
+            && let Some(backup) = opts.backup_path(local_root, filename)
+            && let Err(e) = back_up(fs, &path, &backup)
+        {
+            let _ = fs.remove_file(&tmp_path);
+            return Err(e);
+        }
+        move_into_place(fs, &tmp_path, &path)?;
+        if let Some(dir) = &opts.partial_dir {
+            remove_empty_parents(fs, &tmp_path, &local_root.join(dir));
+        }
+        Ok(Some(changed))
+    }
+}
+
+/// Removes the directories between `path` and `root`, both excluded, that are left empty.
+fn remove_empty_parents(fs: &dyn FileSystem, path: &Path, root: &Path) {
+    for dir in path.ancestors().skip(1) {
+        if !dir.starts_with(root) || dir == root || fs.remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// Deletes the partial files of `names` below the partial directory `dir` last written more
+/// than `max_age` ago, and the directories that leaves empty. Other files in `dir` are left
+/// alone, and failures are only warned about.
+pub(super) fn remove_stale_partials<'a>(
+    fs: &dyn FileSystem,
+    dir: &Path,
+    names: impl IntoIterator<Item = &'a Path>,
+    max_age: Duration,
+) {
+    for name in names {
+        let path = dir.join(name);
+        let stale = fs.symlink_metadata(&path).is_ok_and(|metadata| {
+            let modified = UNIX_EPOCH + Duration::from_secs(metadata.mtime.max(0) as u64);
+            metadata.is_file() && modified.elapsed().is_ok_and(|age| age > max_age)
+        });
+        if !stale {
+            continue;
+        }
+        info!("removing stale partial file {:?}", path);
+        match fs.remove_file(&path) {
+            Ok(()) => remove_empty_parents(fs, &path, dir),
+            Err(e) => warn!("failed to remove stale partial file {:?}: {}", path, e),
+        }
+    }
+}
+
+/// Rebuilds `path` into `out` with [`Delta::apply_cloned`] when the old version is on the same
+/// filesystem as `out`. Returns `false`, with `out` emptied, where that isn't possible.
+fn apply_cloned(path: &Path, delta: &Delta, out: &File) -> io::Result<bool> {
+    let Ok(base) = File::open(path) else {
+        return Ok(false);
+    };
+    if base.metadata()?.dev() != out.metadata()?.dev() {
+        return Ok(false);
+    }
+    match delta.apply_cloned(&base, BLOCK_SIZE, out) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+            info!("cannot clone blocks of {:?}, copying them: {}", path, e);
+            out.set_len(0)?;
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Keeps a copy of `path` at `backup`, replacing any older backup. `path` itself stays in
+/// place until the new version is renamed over it.
+fn back_up(fs: &dyn FileSystem, path: &Path, backup: &Path) -> io::Result<()> {
+    if let Some(parent) = backup.parent() {
+        fs.create_dir_all(parent)?;
+    }
+    match fs.remove_file(backup) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    info!("backing up {:?} to {:?}", path, backup);
+    // A hard link is free, but fails if the backup is on another filesystem
+    fs.hard_link(path, backup)
+        .or_else(|_| io::copy(&mut fs.open(path)?, &mut fs.create(backup)?).map(|_| ()))
+}
+
+/// Where the file for `path` is rebuilt before it replaces `path`.
+fn tmp_path(dir: &Path, path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    dir.join(format!(".{}{}", file_name, TMP_SUFFIX))
+}
+
+/// Moves the finished temp file `tmp` over `path`. Returns `false` if `tmp` was on another
+/// filesystem and had to be copied instead.
+pub(super) fn move_into_place(fs: &dyn FileSystem, tmp: &Path, path: &Path) -> io::Result<bool> {
+    move_into_place_with(fs, tmp, path, |from, to| fs.rename(from, to))
+}
+
+/// [`move_into_place`] with the rename it tries first swapped out.
+pub(super) fn move_into_place_with(
+    fs: &dyn FileSystem,
+    tmp: &Path,
+    path: &Path,
+    rename: impl Fn(&Path, &Path) -> io::Result<()>,
+) -> io::Result<bool> {
+    match rename(tmp, path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            // Copy next to `path` first, so `path` is still replaced in one step
+            let staged = tmp_path(path.parent().unwrap_or(Path::new(".")), path);
+            let result = fs
+                .open(tmp)
+                .and_then(|mut from| io::copy(&mut from, &mut fs.create(&staged)?))
+                .and_then(|_| fs.rename(&staged, path));
+            if result.is_err() {
+                let _ = fs.remove_file(&staged);
+            }
+            result?;
+            fs.remove_file(tmp)?;
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Applies the owner, group, modification time and permissions of `entry` to `path`, each only
+/// if `opts` asks to preserve it. Failing to set the owner is only logged.
+pub(super) fn apply_metadata(
+    fs: &dyn FileSystem,
+    path: &Path,
+    entry: &FlistEntry,
+    opts: &ClientServerOpts,
+) -> io::Result<()> {
+    // Before chmod, since chown clears the setuid and setgid bits. Only root may give files
+    // away, so an unprivileged `-a` is expected to fail here and still gets times and perms
+    if let Err(e) = flist::apply_owner(path, entry, opts) {
+        warn!("failed to set the owner of {:?}: {}", path, e);
+    }
+    // Before chmod too, which may take away the write access this needs
+    if opts.times {
+        let mtime = match u64::try_from(entry.mtime) {
+            Ok(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+            Err(_) => UNIX_EPOCH - Duration::from_secs(entry.mtime.unsigned_abs()),
+        };
+        fs.set_modified(path, mtime)?;
+    }
+    if opts.perms || opts.chmod.is_some() {
+        let mode = if opts.perms {
+            entry.mode
+        } else {
+            fs.metadata(path)?.mode
+        };
+        let mode = opts
+            .chmod
+            .as_ref()
+            .map_or(mode, |chmod| chmod.apply(mode, false));
+        fs.set_permissions(path, mode)?;
+    }
+    Ok(())
+}
+
+/// Replaces `link` with a hardlink to `target`.
+fn hard_link(fs: &dyn FileSystem, target: &Path, link: &Path) -> io::Result<()> {
+    if let Some(parent) = link.parent() {
+        fs.create_dir_all(parent)?;
+    }
+    match fs.remove_file(link) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs.hard_link(target, link)
+}
+
+/// Replaces whatever is at `path` with a fresh `special` node with permission bits from `mode`.
+fn make_special(
+    fs: &dyn FileSystem,
+    path: &Path,
+    special: SpecialFile,
+    mode: u32,
+) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs.create_dir_all(parent)?;
+    }
+    match fs.remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs.make_special(path, special, mode)
+}
@@ -0,0 +1,130 @@
+//! Checking a destination against its source without transferring anything.
+//!
+//! The server advertises its flist followed by a `Checksum` of the full contents of every
+//! regular file, then `Done`. The client recomputes the same checksums locally and compares the
+//! two sides, taking whichever one `opts.direction` names as the source. Only the source lists
+//! its files with `--relative`, as the destination holds them under those names already.
+
+use std::{collections::BTreeMap, path::Path};
+
+use tracing::{info, warn};
+
+use crate::{
+    cli::{ClientServerOpts, Direction},
+    cryptography::compute_strong_signature_from,
+    filesystem::FileSystem,
+    flist,
+};
+
+use super::{Error, FileChecksum, FileName, FlistEntry, Message, Pipeline, Result, VerifyReport};
+
+/// Strong signature of each regular file, keyed by its path relative to the sync root. `None`
+/// marks a file that is listed but could not be read.
+type Checksums = BTreeMap<FileName, Option<String>>;
+
+impl Pipeline {
+    /// Runs the client side of a verification of the files below `local_root`.
+    pub async fn verify(
+        &mut self,
+        local_root: &Path,
+        opts: ClientServerOpts,
+    ) -> Result<VerifyReport> {
+        opts.validate()?;
+        self.init().await?;
+        self.send_arguments(opts.clone()).await?;
+        self.tunnel.write_message(Message::ACK).await?;
+        self.receive_flist().await?;
+        let remote = self.receive_checksums().await?;
+
+        let opts = listing_opts(&opts, opts.direction == Direction::Push);
+        let local_flist = self.fs.build_flist(local_root, &opts, &mut self.stats)?;
+        let base = flist::name_base(&*self.fs, local_root, &opts);
+        let local = checksums(&*self.fs, &base, &local_flist);
+        let report = match opts.direction {
+            Direction::Push => compare(&local, &remote),
+            Direction::Pull => compare(&remote, &local),
+        };
+        info!("verify report: {:?}", report);
+        Ok(report)
+    }
+
+    /// Sends the checksum of every regular file in the flist of `root`, listed with `opts`,
+    /// followed by `Done`.
+    pub async fn send_checksums(&mut self, root: &Path, opts: &ClientServerOpts) -> Result<()> {
+        let base = flist::name_base(&*self.fs, root, opts);
+        let files = self.flist.clone();
+        for entry in files.iter().filter(|e| e.is_regular()) {
+            self.tunnel
+                .write_message(Message::Checksum(FileChecksum {
+                    file_index: entry.index,
+                    strong: checksum(&*self.fs, &base, entry),
+                }))
+                .await?;
+        }
+        self.tunnel.write_message(Message::Done).await?;
+        Ok(())
+    }
+
+    async fn receive_checksums(&mut self) -> Result<Checksums> {
+        let mut checksums = Checksums::new();
+        loop {
+            match self.tunnel.read_message().await? {
+                Message::Checksum(FileChecksum { file_index, strong }) => {
+                    let entry = self.flist_entry(file_index)?;
+                    checksums.insert(entry.filename, strong);
+                }
+                Message::Done => return Ok(checksums),
+                msg => return Err(Error::UnexpectedMessage(Box::new(msg))),
+            }
+        }
+    }
+}
+
+/// `opts` for the side of a verification that holds the source files if `is_source`.
+pub(super) fn listing_opts(opts: &ClientServerOpts, is_source: bool) -> ClientServerOpts {
+    ClientServerOpts {
+        relative: opts.relative && is_source,
+        ..opts.clone()
+    }
+}
+
+/// Checksums of the regular files in `flist`, whose names are relative to `base`.
+fn checksums(fs: &dyn FileSystem, base: &Path, flist: &[FlistEntry]) -> Checksums {
+    flist
+        .iter()
+        .filter(|e| e.is_regular())
+        .map(|e| (e.filename.clone(), checksum(fs, base, e)))
+        .collect()
+}
+
+fn checksum(fs: &dyn FileSystem, base: &Path, entry: &FlistEntry) -> Option<String> {
+    match fs
+        .open(&base.join(&entry.filename))
+        .and_then(compute_strong_signature_from)
+    {
+        Ok(strong) => Some(strong),
+        Err(e) => {
+            warn!("error reading {}: {}", entry.filename, e);
+            None
+        }
+    }
+}
+
+fn compare(source: &Checksums, destination: &Checksums) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    for (filename, strong) in source {
+        match destination.get(filename) {
+            None => report.missing.push(filename.to_string()),
+            Some(other) if strong.is_none() || other != strong => {
+                report.mismatched.push(filename.to_string())
+            }
+            Some(_) => {}
+        }
+    }
+    report.extra = destination
+        .keys()
+        .filter(|filename| !source.contains_key(*filename))
+        .map(FileName::to_string)
+        .collect();
+    report
+}